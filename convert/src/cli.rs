@@ -1,5 +1,11 @@
 use structopt::StructOpt;
 use std::path::PathBuf;
+use std::sync::Arc;
+use oscar_utils::tiff_encoder::TiffCompression;
+use oscar_utils::{
+    CameraModel, CfaPattern, ColorMatrix, CropRect, DemosaicAlgorithm,
+    ResampleFilter, ResampleSize, Rotation, Undistorter, WhiteBalance, WbGains,
+};
 
 #[derive(StructOpt)]
 #[structopt(name = "convert",
@@ -18,6 +24,20 @@ pub enum Cli {
         #[structopt(flatten)]
         opt: ConvertStereoOpt,
     },
+    /// Build master calibration frame (dark or flat) by averaging frames of
+    /// the calibration recording
+    #[structopt(name = "masters")]
+    Masters {
+        #[structopt(flatten)]
+        opt: MastersOpt,
+    },
+    /// Detect defective (hot, dead and stuck) pixels over the recording and
+    /// save their map. Use normally exposed recording with varied content.
+    #[structopt(name = "defects")]
+    Defects {
+        #[structopt(flatten)]
+        opt: DefectsOpt,
+    },
 }
 
 #[derive(StructOpt, Copy, Clone, Eq, PartialEq)]
@@ -25,6 +45,11 @@ pub enum Format {
     Pnm,
     Png,
     Jpeg,
+    Tiff,
+    Webp,
+    Dng,
+    Y4m,
+    Avi,
 }
 
 impl ::std::str::FromStr for Format {
@@ -35,11 +60,28 @@ impl ::std::str::FromStr for Format {
             "pnm" => Ok(Format::Pnm),
             "png" => Ok(Format::Png),
             "jpeg" => Ok(Format::Jpeg),
+            "tiff" => Ok(Format::Tiff),
+            "webp" => Ok(Format::Webp),
+            "dng" => Ok(Format::Dng),
+            "y4m" => Ok(Format::Y4m),
+            "avi" => Ok(Format::Avi),
             _ => Err("unexpected format")
         }
     }
 }
 
+impl Format {
+    /// Whether all frames are written into a single video file
+    pub fn is_video(self) -> bool {
+        self == Format::Y4m || self == Format::Avi
+    }
+
+    /// Whether images are compressed as JPEG (including Motion JPEG video)
+    pub fn is_jpeg(self) -> bool {
+        self == Format::Jpeg || self == Format::Avi
+    }
+}
+
 impl Default for Format {
     fn default() -> Self {
         Format::Pnm
@@ -54,16 +96,71 @@ fn parse_scale(s: &str) -> Result<u8, String> {
     }
 }
 
+fn load_ccm(s: &str) -> Result<ColorMatrix, String> {
+    ColorMatrix::load(s.as_ref()).map_err(|err| format!("{}", err))
+}
+
+fn parse_clahe_tile(s: &str) -> Result<usize, String> {
+    match s.parse().map_err(|err| format!("{}", err))? {
+        0 => Err("tile size must be positive".to_string()),
+        res => Ok(res),
+    }
+}
+
+fn parse_wb_smooth(s: &str) -> Result<usize, String> {
+    match s.parse().map_err(|err| format!("{}", err))? {
+        res if res % 2 == 0 && res != 0 => {
+            Err("smoothing window must be odd".to_string())
+        },
+        res => Ok(res),
+    }
+}
+
+fn parse_clahe_clip(s: &str) -> Result<f32, String> {
+    let res: f32 = s.parse().map_err(|err| format!("{}", err))?;
+    if res > 0.0 {
+        Ok(res)
+    } else {
+        Err("clip limit must be positive".to_string())
+    }
+}
+
+fn parse_raw_crop(s: &str) -> Result<CropRect, String> {
+    let rect: CropRect = s.parse()?;
+    if rect.is_cfa_aligned() {
+        Ok(rect)
+    } else {
+        Err("raw crop rectangle values must be even".to_string())
+    }
+}
+
+fn load_camera(s: &str) -> Result<Arc<Undistorter>, String> {
+    let model = CameraModel::load(s.as_ref())
+        .map_err(|err| format!("{}", err))?;
+    Ok(Arc::new(Undistorter::new(model)))
+}
+
 #[derive(StructOpt, Clone)]
 pub struct ConvertOpt {
     #[structopt(flatten)]
     pub format: FormatOpt,
+    /// Radius of the temporal denoising: each frame is averaged with up to N
+    /// preceding and N following frames, 0 disables denoising. Supported only
+    /// for directory input.
+    #[structopt(long = "denoise", default_value = "0")]
+    pub denoise: usize,
+    /// Maximum difference of the neighbouring frame samples used by temporal
+    /// denoising (fraction of the full range), larger differences are
+    /// considered to be motion
+    #[structopt(long = "denoise_threshold", default_value = "0.05")]
+    pub denoise_threshold: f32,
     /// Skip first N images
     #[structopt(short = "n", default_value = "0")]
     pub skip: u32,
-    /// Input directory with FLIF images, path or HTTP link to TAR
+    /// Input directory with FLIF or raw PNM images, path or HTTP link to TAR
     pub input: String,
-    /// Output directory
+    /// Output directory, or output file for video formats (`-` writes video
+    /// into stdout)
     #[structopt(parse(from_os_str))]
     pub output: PathBuf,
 }
@@ -78,33 +175,208 @@ pub struct ConvertStereoOpt {
     /// Ignore empty pairs (without left and right image)
     #[structopt(long = "ignore_empty")]
     pub ignore_empty: bool,
+    /// Master dark frame of the right camera, by default --dark is used for
+    /// both cameras
+    #[structopt(long = "dark_right", parse(from_os_str))]
+    pub dark_right: Option<PathBuf>,
+    /// Master flat frame of the right camera, by default --flat is used for
+    /// both cameras
+    #[structopt(long = "flat_right", parse(from_os_str))]
+    pub flat_right: Option<PathBuf>,
+    /// Defect map of the right camera, by default --defects is used for both
+    /// cameras
+    #[structopt(long = "defects_right", parse(from_os_str))]
+    pub defects_right: Option<PathBuf>,
+    /// OpenCV YAML file with stereo calibration (`M1`, `D1`, `M2`, `D2`, `R`
+    /// and `T` entries), which is used for rectification of demosaiced
+    /// pairs. Can be specified twice for separate intrinsics and extrinsics
+    /// files.
+    #[structopt(
+        long = "rectify", parse(from_os_str), raw(number_of_values = "1")
+    )]
+    pub rectify: Vec<PathBuf>,
+    /// Save left and right images into `left` and `right` subdirectories of
+    /// the output directory instead of joining them side by side
+    #[structopt(long = "split")]
+    pub split: bool,
     /// Skip first N pairs (including partial and full)
     #[structopt(short = "n", default_value = "0")]
     pub skip: u32,
-    /// Input directory
+    /// Input directory with left and right directories of FLIF or raw PNM
+    /// images
+    #[structopt(parse(from_os_str))]
+    pub input: PathBuf,
+    /// Output directory, or output file for video formats (`-` writes video
+    /// into stdout)
+    #[structopt(parse(from_os_str))]
+    pub output: PathBuf,
+}
+
+#[derive(StructOpt, Clone)]
+pub struct MastersOpt {
+    /// CFA pattern of the sensor used for recording of the raw PNM frames
+    /// (before flipping performed by pnm2flif). Supported patterns: rggb,
+    /// bggr, grbg, gbrg.
+    #[structopt(long = "cfa", default_value = "rggb", parse(try_from_str))]
+    pub cfa: CfaPattern,
+    /// Input directory with FLIF frames or raw PNM frames
+    #[structopt(parse(from_os_str))]
+    pub input: PathBuf,
+    /// Output file, master frame is saved as 16-bit raw PNM
+    #[structopt(parse(from_os_str))]
+    pub output: PathBuf,
+}
+
+#[derive(StructOpt, Clone)]
+pub struct DefectsOpt {
+    /// CFA pattern of the sensor used for recording of the raw PNM frames
+    /// (before flipping performed by pnm2flif). Supported patterns: rggb,
+    /// bggr, grbg, gbrg.
+    #[structopt(long = "cfa", default_value = "rggb", parse(try_from_str))]
+    pub cfa: CfaPattern,
+    /// Maximum deviation of the pixel average value from the median of its
+    /// same colour neighbours as a fraction of the full range, pixels
+    /// which deviate more are classified as defective
+    #[structopt(long = "threshold", default_value = "0.1")]
+    pub threshold: f32,
+    /// Input directory with FLIF frames or raw PNM frames
     #[structopt(parse(from_os_str))]
     pub input: PathBuf,
-    /// Output directory
+    /// Output defect map file
     #[structopt(parse(from_os_str))]
     pub output: PathBuf,
 }
 
 #[derive(StructOpt, Clone)]
 pub struct FormatOpt {
-    /// Apply bi-linear demosaicing
+    /// Apply demosaicing
     #[structopt(short = "d")]
     pub demosaic: bool,
-    /// Apply histogram equalization filter
+    /// Demosaicing algorithm. Supported algorithms: bilinear, mhc
+    /// (Malvar-He-Cutler gradient-corrected interpolation), ahd (adaptive
+    /// homogeneity-directed interpolation, slow but with the best quality),
+    /// superpixel (fast conversion of each 2x2 Bayer quad into a single pixel,
+    /// requires scale factor of 2 or higher).
+    #[structopt(long = "demosaic_algo", default_value = "bilinear",
+        parse(try_from_str))]
+    pub demosaic_algo: DemosaicAlgorithm,
+    /// CFA pattern of the sensor used for recording of the raw PNM frames
+    /// (before flipping performed by pnm2flif). Supported patterns: rggb,
+    /// bggr, grbg, gbrg.
+    #[structopt(long = "cfa", default_value = "rggb", parse(try_from_str))]
+    pub cfa: CfaPattern,
+    /// Master dark frame (raw PNM or FLIF, see the masters subcommand),
+    /// which is subtracted from frames before demosaicing
+    #[structopt(long = "dark", parse(from_os_str))]
+    pub dark: Option<PathBuf>,
+    /// Master flat frame used for flat-field correction before demosaicing
+    #[structopt(long = "flat", parse(from_os_str))]
+    pub flat: Option<PathBuf>,
+    /// Defect map (see the defects subcommand), defective pixels are
+    /// interpolated from their same colour neighbours before demosaicing
+    #[structopt(long = "defects", parse(from_os_str))]
+    pub defects: Option<PathBuf>,
+    /// White balance mode. Supported modes: none, fixed (gains set by
+    /// --wb_gains), gray_world (assumes that average colour of the frame is
+    /// gray), white_patch (assumes that the brightest parts of the frame are
+    /// white).
+    #[structopt(long = "wb", default_value = "none", parse(try_from_str))]
+    pub wb: WhiteBalance,
+    /// Gains of the fixed white balance in the R,G,B format
+    #[structopt(long = "wb_gains", default_value = "1,1,1",
+        parse(try_from_str))]
+    pub wb_gains: WbGains,
+    /// Smooth estimated white balance gains over the given odd number of
+    /// consecutive frames to prevent colour flickering, 0 disables smoothing.
+    /// Requires additional pass over the recording and is not supported for
+    /// TAR input.
+    #[structopt(long = "wb_smooth", default_value = "0",
+        parse(try_from_str = "parse_wb_smooth"))]
+    pub wb_smooth: usize,
+    /// Convert demosaiced images from camera RGB into sRGB using colour
+    /// correction matrix and the sRGB transfer curve
+    #[structopt(long = "srgb")]
+    pub srgb: bool,
+    /// Camera calibration file with 3x3 camera RGB to linear sRGB matrix
+    /// (9 row-major coefficients, `#` starts a comment). Identity matrix is
+    /// used by default. The matrix is also saved into DNG files.
+    #[structopt(long = "ccm", parse(try_from_str = "load_ccm"))]
+    pub ccm: Option<ColorMatrix>,
+    /// OpenCV YAML file with camera intrinsics and distortion coefficients,
+    /// which are used for undistortion of demosaiced frames. Supported only
+    /// for mono recordings.
+    #[structopt(long = "undistort", parse(try_from_str = "load_camera"))]
+    pub undistort: Option<Arc<Undistorter>>,
+    /// Crop raw frames before demosaicing, the rectangle is specified as
+    /// X,Y,WIDTH,HEIGHT in pixels of the full resolution frame and all its
+    /// values must be even
+    #[structopt(long = "raw_crop", parse(try_from_str = "parse_raw_crop"))]
+    pub raw_crop: Option<CropRect>,
+    /// Crop images after downscaling and lens correction, the rectangle is
+    /// specified as X,Y,WIDTH,HEIGHT in pixels of the downscaled image
+    #[structopt(long = "crop", parse(try_from_str))]
+    pub crop: Option<CropRect>,
+    /// Rotate images clockwise after cropping. Supported angles: 0, 90, 180,
+    /// 270.
+    #[structopt(long = "rotate", default_value = "0", parse(try_from_str))]
+    pub rotate: Rotation,
+    /// Flip images horizontally after rotation
+    #[structopt(long = "flip_h")]
+    pub flip_h: bool,
+    /// Flip images vertically after rotation
+    #[structopt(long = "flip_v")]
+    pub flip_v: bool,
+    /// Apply global histogram equalization to the image luminance
     #[structopt(long = "histeq")]
     pub histeq: bool,
-    /// Format of output files. Supported formats: pnm, png, jpeg.
+    /// Apply contrast limited adaptive histogram equalization (CLAHE) to the
+    /// image luminance
+    #[structopt(long = "clahe")]
+    pub clahe: bool,
+    /// CLAHE tile size in pixels of the output image
+    #[structopt(
+        long = "clahe_tile", default_value = "64",
+        parse(try_from_str = "parse_clahe_tile")
+    )]
+    pub clahe_tile: usize,
+    /// CLAHE clip limit relative to the average height of histogram bins,
+    /// lower values give less contrast and noise amplification
+    #[structopt(
+        long = "clahe_clip", default_value = "2.0",
+        parse(try_from_str = "parse_clahe_clip")
+    )]
+    pub clahe_clip: f32,
+    /// Format of output files. Supported formats: pnm, png, jpeg, tiff,
+    /// webp, dng (raw CFA data, can't be used with demosaicing), y4m
+    /// (uncompressed video stream), avi (Motion JPEG video).
     #[structopt(short = "f", parse(try_from_str), default_value = "png")]
     pub format: Format,
     /// Downscale images using given scale factor. Can be used only with enabled
     /// demosaicing. Accepted values: 1, 2, 4, 8, 16.
     #[structopt(short = "s", default_value = "1", parse(try_from_str="parse_scale"))]
     pub scale: u8,
-    /// Encoding quality (usable only with the format equal to jpeg)
+    /// Resample images to the given size (e.g. 1280x720) or by the given
+    /// scale factor (e.g. 0.4) after downscaling with -s. Resampling is done
+    /// in linear light before the sRGB conversion.
+    #[structopt(long = "resize", parse(try_from_str))]
+    pub resize: Option<ResampleSize>,
+    /// Filter used by --resize. Supported filters: bilinear, bicubic,
+    /// lanczos.
+    #[structopt(long = "resize_filter", default_value = "lanczos",
+        parse(try_from_str))]
+    pub resize_filter: ResampleFilter,
+    /// Encoding quality (usable only with jpeg, avi and lossy webp formats)
     #[structopt(short = "q", default_value = "90")]
     pub quality: u8,
+    /// Compression of TIFF files. Supported compressions: none, lzw,
+    /// deflate.
+    #[structopt(long = "tiff_compression", default_value = "none",
+        parse(try_from_str))]
+    pub tiff_compression: TiffCompression,
+    /// Use lossy compression of WebP files with the quality set by -q
+    #[structopt(long = "webp_lossy")]
+    pub webp_lossy: bool,
+    /// Black level of the raw samples saved into DNG files
+    #[structopt(long = "black_level", default_value = "0")]
+    pub black_level: u32,
 }
@@ -2,9 +2,11 @@ use structopt::StructOpt;
 
 mod cli;
 mod utils;
+mod masters;
 mod mono;
 mod mono_tar;
 mod stereo;
+mod video;
 
 use crate::cli::Cli;
 
@@ -17,9 +19,11 @@ fn main() {
             mono::convert(opt)
         },
         Cli::Stereo { opt } => stereo::convert(opt),
+        Cli::Masters { opt } => masters::build(opt),
+        Cli::Defects { opt } => masters::detect_defects(opt),
     };
     match res {
         Ok(()) => (),
-        Err(err) => println!("Error: {:?}", err),
+        Err(err) => eprintln!("Error: {:?}", err),
     }
 }
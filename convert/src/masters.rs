@@ -0,0 +1,90 @@
+use std::path::{Path, PathBuf};
+use std::{error, fs};
+
+use indicatif::{ProgressBar, ProgressStyle, ParallelProgressIterator};
+use rayon::iter::{ParallelIterator, IntoParallelRefIterator};
+
+use super::cli::{DefectsOpt, MastersOpt};
+use super::utils::save_pnm;
+use oscar_utils::load_frames::{is_raw_pnm, load_packed};
+use oscar_utils::{
+    AnyPackedFrame, CfaPattern, DefectStats, Error, ErrorStats,
+    FrameSum, PBAR_TEMPLATE,
+};
+
+/// Accumulate all FLIF and raw PNM frames of the directory in parallel,
+/// invalid frames are reported and skipped
+fn accumulate<A, F, M>(
+    dir: &Path, cfa: CfaPattern, add: F, merge: M,
+) -> Result<A, Box<dyn error::Error>>
+    where
+        A: Default + Send,
+        F: Fn(&mut A, &AnyPackedFrame) -> Result<(), Error> + Sync,
+        M: Fn(A, A) -> Result<A, Error> + Sync + Send,
+{
+    println!("Processing: {}", dir.display());
+    let mut paths: Vec<PathBuf> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_flif = path.extension().map(|ext| ext == "flif")
+            .unwrap_or(false);
+        if is_flif || is_raw_pnm(&path) {
+            paths.push(path);
+        }
+    }
+    if paths.is_empty() {
+        Err("no frames found in the input directory")?
+    }
+
+    let stats = ErrorStats::new();
+    let bar = ProgressBar::new(paths.len() as u64);
+    bar.set_style(ProgressStyle::default_bar().template(PBAR_TEMPLATE));
+    let acc = paths.par_iter()
+        .progress_with(bar)
+        .fold(A::default, |mut acc, path| {
+            if stats.is_aborted() { return acc; }
+            let res = load_packed(path, cfa.flipped())
+                .and_then(|frame| add(&mut acc, &frame));
+            if let Err(err) = res {
+                stats.report(&path.display(), err);
+            }
+            acc
+        })
+        .map(Ok)
+        .try_reduce(A::default, merge)?;
+    stats.finish()?;
+    Ok(acc)
+}
+
+/// Average frames of the input directory and save the result as 16-bit raw
+/// PNM
+pub fn build(opt: MastersOpt) -> Result<(), Box<dyn error::Error>> {
+    let add = |sum: &mut FrameSum, frame: &AnyPackedFrame| match frame {
+        AnyPackedFrame::U8(f) => sum.add(f),
+        AnyPackedFrame::U16(f) => sum.add(f),
+    };
+    let sum = accumulate(&opt.input, opt.cfa, add, FrameSum::merge)?;
+
+    let master = sum.mean().ok_or("all frames failed to load")?;
+    println!("Averaged frames: {}", sum.frames());
+    let frame = master.to_raw();
+    let (w, h) = (frame.geometry.width as u32, frame.geometry.height as u32);
+    save_pnm(&opt.output, &frame.data, w, h, false)?;
+    Ok(())
+}
+
+/// Detect defective pixels over frames of the input directory and save
+/// their map
+pub fn detect_defects(opt: DefectsOpt) -> Result<(), Box<dyn error::Error>> {
+    let add = |stats: &mut DefectStats, frame: &AnyPackedFrame| match frame {
+        AnyPackedFrame::U8(f) => stats.add(f),
+        AnyPackedFrame::U16(f) => stats.add(f),
+    };
+    let stats = accumulate(&opt.input, opt.cfa, add, DefectStats::merge)?;
+
+    let map = stats.detect(opt.threshold).ok_or("all frames failed to load")?;
+    println!("Frames: {}, defective pixels: {}",
+        stats.frames(), map.pixels.len());
+    map.save(&opt.output)?;
+    Ok(())
+}
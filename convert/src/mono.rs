@@ -1,20 +1,32 @@
+use std::collections::BTreeMap;
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::{io, fs, error};
+use std::{io, fs, cmp, error};
 
-use indicatif::{ProgressBar, ProgressStyle, ParallelProgressIterator};
-use rayon::iter::{ParallelIterator, IntoParallelRefIterator};
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::iter::{ParallelIterator, IntoParallelIterator};
 
 use super::cli::{ConvertOpt, Format};
-use super::utils::{save_img, get_timestamp, Timestamp};
-use oscar_utils::load_frames::load_flif;
-use oscar_utils::{WIDTH, HEIGHT, PBAR_TEMPLATE};
+use super::utils::{
+    save_img, get_timestamp, frame_gains, recording_gains, check_dng,
+    process_index, Corrections, ImageInfo, Output, Timestamp,
+};
+use super::video::VideoSink;
+use oscar_utils::load_frames::load_packed;
+use oscar_utils::y4m_encoder::FrameRate;
+use oscar_utils::{
+    AnyPackedFrame, DemosaicAlgorithm, Error, ErrorStats, TemporalDenoise,
+    PBAR_TEMPLATE,
+};
+
+/// Number of frames processed by each thread between loads of the sliding
+/// window frames
+const WINDOW_BATCH_PER_THREAD: usize = 2;
 
 type MonoIndex = Vec<(usize, PathBuf, Timestamp)>;
 
 fn construct_index(dir_path: &str) -> io::Result<MonoIndex> {
-    print!("Building list of images... ");
-    io::stdout().flush()?;
+    eprint!("Building list of images... ");
     let mut index = fs::read_dir(dir_path)?
         .map(|entry| {
             let path = entry?.path();
@@ -28,7 +40,7 @@ fn construct_index(dir_path: &str) -> io::Result<MonoIndex> {
         .rev()
         .map(|(i, e)| (i, e.0, e.1))
         .collect();
-    println!("Done. Images found: {}", index.len());
+    eprintln!("Done. Images found: {}", index.len());
     Ok(index)
 }
 
@@ -51,43 +63,172 @@ fn save_index(index: &MonoIndex, dir: &Path) -> io::Result<()>{
     Ok(())
 }
 
+/// Process frames in the index order together with up to `radius` preceding
+/// and following frames. Frames are loaded and processed in parallel
+/// batches, each frame is loaded only once.
+fn process_windowed<L, P>(
+    index: &MonoIndex, radius: usize, load: L, process: P,
+    stats: &ErrorStats, bar: &ProgressBar, output: Output,
+)
+    where
+        L: Fn(&Path) -> Result<AnyPackedFrame, Error> + Sync,
+        P: Fn(usize, &AnyPackedFrame, &[&AnyPackedFrame]) -> Result<(), Error>
+            + Sync,
+{
+    let batch = WINDOW_BATCH_PER_THREAD*rayon::current_num_threads();
+    let mut window: BTreeMap<usize, Option<AnyPackedFrame>> = BTreeMap::new();
+    for start in (0..index.len()).step_by(cmp::max(batch, 1)) {
+        if stats.is_aborted() { break; }
+        let end = cmp::min(start + batch, index.len());
+        let lo = start.saturating_sub(radius);
+        let hi = cmp::min(end + radius, index.len());
+        // drop frames which are not needed anymore, invalid frames are kept
+        // as `None`, so they are loaded and reported only once
+        window = window.split_off(&lo);
+        let first = window.keys().next_back().map(|i| i + 1).unwrap_or(lo);
+        let loaded: Vec<(usize, Option<AnyPackedFrame>)> = (first..hi)
+            .into_par_iter()
+            .map(|i| {
+                let path = &index[i].1;
+                let frame = load(path)
+                    .map_err(|err| stats.report(&path.display(), err))
+                    .ok();
+                (i, frame)
+            })
+            .collect();
+        window.extend(loaded);
+
+        (start..end).into_par_iter().for_each(|i| {
+            bar.inc(1);
+            if stats.is_aborted() { return; }
+            let frame = match window.get(&i) {
+                Some(Some(frame)) => frame,
+                _ => return output.skip(index[i].0),
+            };
+            let neighbours: Vec<&AnyPackedFrame> = window
+                .range(i.saturating_sub(radius)..=i + radius)
+                .filter(|(&j, _)| j != i)
+                .filter_map(|(_, f)| f.as_ref())
+                .collect();
+            if let Err(err) = process(i, frame, &neighbours) {
+                stats.report(&index[i].1.display(), err);
+                output.skip(index[i].0);
+            }
+        });
+    }
+    bar.finish();
+}
+
 pub fn convert(opt: ConvertOpt) -> Result<(), Box<dyn error::Error>> {
+    if opt.format.raw_crop.is_some() && opt.format.undistort.is_some() {
+        Err("raw crop can't be used with undistortion")?
+    }
+    if opt.denoise_threshold.is_nan() || opt.denoise_threshold < 0.0 {
+        Err("denoising threshold must not be negative")?
+    }
+    if opt.format.histeq && opt.format.clahe {
+        Err("--histeq and --clahe can't be used together")?
+    }
+    check_dng(&opt.format)?;
     if !opt.format.demosaic {
         if opt.format.scale != 1 {
             Err("can't downscale image without demosaicing")?
         }
-        if opt.format.format == Format::Jpeg {
+        if opt.format.format.is_jpeg() {
             Err("don't use JPEG without demosaicing")?
         }
-        if opt.format.histeq {
-            Err("can't apply histogram equalization without demosaicing")?
+        if opt.format.srgb {
+            Err("can't apply colour correction without demosaicing")?
+        }
+        if opt.format.undistort.is_some() {
+            Err("can't apply undistortion without demosaicing")?
         }
+    } else if opt.format.demosaic_algo == DemosaicAlgorithm::Superpixel
+        && opt.format.scale == 1
+    {
+        Err("superpixel demosaicing requires scale factor of 2 or higher")?
+    }
+    if opt.format.ccm.is_some() && !opt.format.srgb
+        && opt.format.format != Format::Dng
+    {
+        Err("colour correction matrix can be used only with --srgb or DNG")?
     }
-    println!("Processing: {}", opt.input);
+    if opt.format.wb_smooth != 0 && !opt.format.wb.is_adaptive() {
+        Err("white balance smoothing requires gray_world or white_patch mode")?
+    }
+    eprintln!("Processing: {}", opt.input);
     let mut index = construct_index(&opt.input)?;
-    fs::create_dir_all(&opt.output)?;
-    save_index(&index, &opt.output)?;
+    let is_video = opt.format.format.is_video();
+    if !is_video {
+        fs::create_dir_all(&opt.output)?;
+        save_index(&index, &opt.output)?;
+    }
 
     let n = index.len();
     index.truncate(n - opt.skip as usize);
+    let video = if is_video {
+        // index is in the reversed order, while video frames are processed
+        // and written in the recording order
+        index.reverse();
+        let ts: Vec<u64> = index.iter().map(|(_, _, t)| t.os).collect();
+        let rate = FrameRate::from_timestamps(&ts).unwrap_or_default();
+        let (format, first) = (opt.format.format, opt.skip as usize);
+        Some(VideoSink::create(&opt.output, format, first, Some(rate))?)
+    } else {
+        None
+    };
+    let output = match &video {
+        Some(sink) => Output::Video(sink),
+        None => Output::Dir(&opt.output),
+    };
 
+    let cfa = opt.format.cfa.flipped();
+    let corrections = Corrections::load(&opt.format)?;
+    let load = |path: &Path| {
+        load_packed(path, cfa).and_then(|f| corrections.apply(f))
+    };
+    let smoothed = if opt.format.wb_smooth != 0 {
+        Some(recording_gains(&index, &opt.format, |(_, path, _)| {
+            let frame = load(path).ok()?;
+            frame_gains(&frame, &opt.format)
+        }))
+    } else {
+        None
+    };
+
+    let process = |i: usize, frame: AnyPackedFrame| {
+        let gains = match &smoothed {
+            Some(gains) => Some(gains[i]),
+            None => frame_gains(&frame, &opt.format),
+        };
+        let pos = index[i].0;
+        let name = format!("{:#06}", pos);
+        let info = ImageInfo { name: &name, pos, timestamp: Some(index[i].2) };
+        save_img(&info, frame, gains, &opt.format, output)
+    };
+
+    let stats = ErrorStats::new();
     let bar = ProgressBar::new(index.len() as u64);
     bar.set_style(ProgressStyle::default_bar().template(PBAR_TEMPLATE));
-    index.par_iter()
-        .progress_with(bar)
-        .for_each(|(n, path, _)| {
-            let res = load_flif(&path)
-                .and_then(|img_data| {
-                    let file_name = format!("{:#06}", n);
-                    save_img(
-                        &file_name, img_data, &opt.format, &opt.output,
-                        WIDTH as u32, HEIGHT as u32,
-                    )
-                });
-            if let Err(err) = res {
-                eprintln!("Error: {:?} {}\n", path, err);
+    if opt.denoise != 0 {
+        let denoise = TemporalDenoise {
+            radius: opt.denoise,
+            threshold: opt.denoise_threshold,
+        };
+        process_windowed(&index, opt.denoise, load, |i, frame, neighbours| {
+            process(i, denoise.apply(frame, neighbours))
+        }, &stats, &bar, output);
+    } else {
+        process_index(&index, output, &bar, |i, (pos, path, _)| {
+            if stats.is_aborted() { return; }
+            if let Err(err) = load(path).and_then(|frame| process(i, frame)) {
+                stats.report(&path.display(), err);
+                output.skip(*pos);
             }
         });
+    }
 
+    stats.finish()?;
+    if let Some(sink) = video { sink.finish()?; }
     Ok(())
-}
\ No newline at end of file
+}
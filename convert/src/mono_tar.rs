@@ -1,31 +1,57 @@
 use crate::cli::{ConvertOpt, Format};
-use crate::utils::{save_img, get_timestamp};
+use crate::utils::{
+    save_img, get_timestamp, frame_gains, check_dng, Corrections, ImageInfo,
+    Output,
+};
+use crate::video::VideoSink;
 use std::{io, fs, error, thread};
+use std::sync::Arc;
 use std::io::{Read, Write};
 use std::path::{PathBuf, Path};
 use indicatif::{ProgressBar, ProgressStyle};
 
-use oscar_utils::{WIDTH, HEIGHT};
+use oscar_utils::{DemosaicAlgorithm, ErrorStats};
+use oscar_utils::load_frames::{decode_packed, is_raw_pnm};
+use oscar_utils::y4m_encoder::FrameRate;
 
 const TEMPLATE: &str = "\
     {wide_bar} {percent:>3}% {bytes}/{total_bytes} \
     Elapsed: {elapsed_precise} ETA: {eta_precise}\
 ";
-
-fn worker(pos: usize, data: Box<[u8]>, opt: &ConvertOpt) {
-    let res = oscar_utils::load_frames::decode_flif(&data)
-        .and_then(|img_data| {
-            let file_name = format!("{:#06}", pos);
-            save_img(
-                &file_name, img_data, &opt.format, &opt.output,
-                WIDTH as u32, HEIGHT as u32,
-            )
+/// Number of the first archive entries used for estimation of the video
+/// frame rate, frames are held by the video sink until it's known
+const RATE_FRAMES: usize = 30;
+
+fn worker(
+    pos: usize, path: &Path, data: Box<[u8]>, opt: &ConvertOpt,
+    corrections: &Corrections, stats: &ErrorStats, output: Output,
+) {
+    let cfa = opt.format.cfa.flipped();
+    let timestamp = get_timestamp(path).ok();
+    let res = decode_packed(&data, is_raw_pnm(path), cfa)
+        .and_then(|frame| corrections.apply(frame))
+        .and_then(|frame| {
+            let gains = frame_gains(&frame, &opt.format);
+            let name = format!("{:#06}", pos);
+            let info = ImageInfo { name: &name, pos, timestamp };
+            save_img(&info, frame, gains, &opt.format, output)?;
+            Ok(())
         });
     if let Err(err) = res {
-        eprintln!("Error: {} {}\n", pos, err);
+        stats.report(&pos, err);
+        output.skip(pos);
     }
 }
 
+/// Frame rate of the video output estimated from timestamps of the index
+fn video_rate(index: &[(usize, PathBuf)]) -> FrameRate {
+    let ts: Vec<u64> = index.iter()
+        .filter_map(|(_, path)| get_timestamp(path).ok())
+        .map(|t| t.os)
+        .collect();
+    FrameRate::from_timestamps(&ts).unwrap_or_default()
+}
+
 /// Save index data to TSV file
 fn save_index(index: Vec<(usize, PathBuf)>, dir: &Path) -> io::Result<()>{
     let index_path = dir.join("index.tsv");
@@ -45,18 +71,43 @@ fn save_index(index: Vec<(usize, PathBuf)>, dir: &Path) -> io::Result<()>{
 }
 
 pub fn convert(opt: ConvertOpt) -> Result<(), Box<dyn error::Error>> {
+    if opt.format.raw_crop.is_some() && opt.format.undistort.is_some() {
+        Err("raw crop can't be used with undistortion")?
+    }
+    if opt.format.histeq && opt.format.clahe {
+        Err("--histeq and --clahe can't be used together")?
+    }
+    check_dng(&opt.format)?;
     if !opt.format.demosaic {
         if opt.format.scale != 1 {
             Err("can't downscale image without demosaicing")?
         }
-        if opt.format.format == Format::Jpeg {
+        if opt.format.format.is_jpeg() {
             Err("don't use JPEG without demosaicing")?
         }
-        if opt.format.histeq {
-            Err("can't apply histogram equalization without demosaicing")?
+        if opt.format.srgb {
+            Err("can't apply colour correction without demosaicing")?
+        }
+        if opt.format.undistort.is_some() {
+            Err("can't apply undistortion without demosaicing")?
         }
+    } else if opt.format.demosaic_algo == DemosaicAlgorithm::Superpixel
+        && opt.format.scale == 1
+    {
+        Err("superpixel demosaicing requires scale factor of 2 or higher")?
     }
-    println!("Processing: {}", opt.input);
+    if opt.format.ccm.is_some() && !opt.format.srgb
+        && opt.format.format != Format::Dng
+    {
+        Err("colour correction matrix can be used only with --srgb or DNG")?
+    }
+    if opt.format.wb_smooth != 0 {
+        Err("white balance smoothing is not supported for TAR input")?
+    }
+    if opt.denoise != 0 {
+        Err("temporal denoising is not supported for TAR input")?
+    }
+    eprintln!("Processing: {}", opt.input);
 
     let (reader, tar_size) = if opt.input.starts_with("http://") {
         let r = reqwest::get(&opt.input)?;
@@ -69,20 +120,40 @@ pub fn convert(opt: ConvertOpt) -> Result<(), Box<dyn error::Error>> {
     };
     let mut input_tar = tar::Archive::new(reader);
 
-    fs::create_dir_all(&opt.output)?;
+    let is_video = opt.format.format.is_video();
+    let sink = if is_video {
+        let (format, first) = (opt.format.format, opt.skip as usize);
+        let sink = VideoSink::create(&opt.output, format, first, None)?;
+        Some(Arc::new(sink))
+    } else {
+        fs::create_dir_all(&opt.output)?;
+        None
+    };
 
     let mut index = Vec::new();
 
     let num = num_cpus::get();
     let (frames_in, frames_out) = crossbeam_channel::bounded(2*num);
+    let stats = Arc::new(ErrorStats::new());
+    let corrections = Arc::new(Corrections::load(&opt.format)?);
 
     let handles: Vec<_> = (0..num)
         .map(|_| {
             let rx = frames_out.clone();
             let opt = opt.clone();
+            let stats = stats.clone();
+            let corrections = corrections.clone();
+            let sink = sink.clone();
             thread::spawn(move|| {
-                for (pos, data) in rx {
-                    worker(pos, data, &opt);
+                let output = match &sink {
+                    Some(sink) => Output::Video(sink),
+                    None => Output::Dir(&opt.output),
+                };
+                for (pos, path, data) in rx {
+                    let path: PathBuf = path;
+                    worker(
+                        pos, &path, data, &opt, &corrections, &stats, output,
+                    );
                 }
             })
         })
@@ -92,12 +163,19 @@ pub fn convert(opt: ConvertOpt) -> Result<(), Box<dyn error::Error>> {
     bar.set_style(ProgressStyle::default_bar().template(TEMPLATE));
 
     for (pos, file) in input_tar.entries()?.enumerate() {
+        if stats.is_aborted() { break; }
         let mut file = file?;
         let path = file.header().path()?;
         let size = file.header().size()?;
         bar.set_position(file.raw_file_position() + size);
 
-        index.push((pos, path.into_owned()));
+        let path = path.into_owned();
+        index.push((pos, path.clone()));
+        if let Some(sink) = &sink {
+            if index.len() == RATE_FRAMES {
+                sink.set_rate(video_rate(&index))?;
+            }
+        }
 
         if pos < opt.skip as usize { continue; }
 
@@ -107,14 +185,28 @@ pub fn convert(opt: ConvertOpt) -> Result<(), Box<dyn error::Error>> {
         let mut buf = Vec::with_capacity(size as usize);
         file.read_to_end(&mut buf)?;
 
-        frames_in.send((pos, buf.into_boxed_slice()))?;
+        frames_in.send((pos, path, buf.into_boxed_slice()))?;
     }
     drop(frames_in);
     for handle in handles {
         handle.join().expect("failed to join thread");
     }
     bar.finish();
-    save_index(index, &opt.output)?;
 
+    let stats = Arc::try_unwrap(stats).ok()
+        .expect("worker threads are joined");
+    match sink {
+        Some(sink) => {
+            let sink = Arc::try_unwrap(sink).ok()
+                .expect("worker threads are joined");
+            if index.len() < RATE_FRAMES { sink.set_rate(video_rate(&index))?; }
+            stats.finish()?;
+            sink.finish()?;
+        },
+        None => {
+            save_index(index, &opt.output)?;
+            stats.finish()?;
+        },
+    }
     Ok(())
 }
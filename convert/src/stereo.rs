@@ -2,13 +2,20 @@ use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::{io, fs, cmp, error};
 
-use indicatif::{ProgressBar, ProgressStyle, ParallelProgressIterator};
-use rayon::iter::{ParallelIterator, IntoParallelRefIterator};
+use indicatif::{ProgressBar, ProgressStyle};
 
-use super::cli::{ConvertStereoOpt, Format};
-use super::utils::{save_stereo_img, get_timestamps, Timestamp};
-use oscar_utils::load_frames::load_flif;
-use oscar_utils::{WIDTH, HEIGHT, PBAR_TEMPLATE};
+use super::cli::{ConvertStereoOpt, Format, FormatOpt};
+use super::utils::{
+    save_stereo_img, get_timestamps, frame_gains, recording_gains, check_dng,
+    process_index, Corrections, ImageInfo, Output, StereoOutput, Timestamp,
+};
+use super::video::VideoSink;
+use oscar_utils::load_frames::load_packed;
+use oscar_utils::y4m_encoder::FrameRate;
+use oscar_utils::{
+    CfaPattern, DemosaicAlgorithm, PackedFrame, AnyPackedFrame,
+    Error, ErrorStats, Rectifier, StereoModel, WbGains, PBAR_TEMPLATE,
+};
 
 const FPS: u64 = 30;
 
@@ -16,10 +23,16 @@ type Pair = (Option<Timestamp>, Option<Timestamp>);
 type StereoIndex = Vec<(usize, Pair)>;
 
 
+/// Path of the FLIF frame with the given timestamp, or of the raw PNM frame
+/// if there is no FLIF one
 fn to_path(dir: &Path, ts: Timestamp) -> PathBuf {
-    let mut path = dir.to_path_buf();
-    path.push(format!("{}_{}.flif", ts.unix, ts.os));
-    path
+    let stem = format!("{}_{}", ts.unix, ts.os);
+    let path = dir.join(format!("{}.flif", stem));
+    if path.exists() { return path; }
+    ["pnm", "pgm"].iter()
+        .map(|ext| dir.join(format!("{}.{}", stem, ext)))
+        .find(|path| path.exists())
+        .unwrap_or(path)
 }
 
 fn grow_index_half(index: &mut Vec<Option<Timestamp>>, ts: &[Timestamp]) {
@@ -38,8 +51,7 @@ fn grow_index_half(index: &mut Vec<Option<Timestamp>>, ts: &[Timestamp]) {
 }
 
 fn construct_index(opt: &ConvertStereoOpt) -> io::Result<StereoIndex> {
-    print!("Building list of images... ");
-    io::stdout().flush()?;
+    eprint!("Building list of images... ");
 
     let mut left = opt.input.clone();
     left.push("left");
@@ -97,20 +109,64 @@ fn construct_index(opt: &ConvertStereoOpt) -> io::Result<StereoIndex> {
 
     res.reverse();
 
-    println!("Done. Pairs: full {}, partial {}, empty {}",
+    eprintln!("Done. Pairs: full {}, partial {}, empty {}",
         counter_full, counter_part, counter_empty);
 
     Ok(res)
 }
 
-/// returns empty image if `ts` is None
-fn read_flif2(ts: Option<Timestamp>, dir: &Path) -> io::Result<Box<[u8]>> {
+/// returns `empty` image if `ts` is None
+fn read_flif2(
+    ts: Option<Timestamp>, dir: &Path, empty: &AnyPackedFrame,
+    corrections: &Corrections,
+) -> Result<AnyPackedFrame, Error> {
     match ts {
-        Some(ts) => load_flif(&to_path(dir, ts)),
-        None => Ok(vec![0; WIDTH*HEIGHT].into_boxed_slice()),
+        Some(ts) => {
+            let cfa = empty.geometry().cfa;
+            corrections.apply(load_packed(&to_path(dir, ts), cfa)?)
+        },
+        None => Ok(empty.clone()),
     }
 }
 
+/// Read geometry and bit depth of the recording from its first frame and
+/// return empty frame with them, it is used for generation of missing frames
+fn probe_empty_frame(
+    index: &StereoIndex, dir: &Path, cfa: CfaPattern,
+) -> io::Result<AnyPackedFrame> {
+    let (side, ts) = index.iter().rev()
+        .filter_map(|(_, pair)| match pair {
+            (Some(l), _) => Some(("left", *l)),
+            (None, Some(r)) => Some(("right", *r)),
+            (None, None) => None,
+        })
+        .next()
+        .ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput, "no frames found in the recording",
+        ))?;
+    Ok(match load_packed(&to_path(&dir.join(side), ts), cfa)? {
+        AnyPackedFrame::U8(f) => {
+            AnyPackedFrame::U8(PackedFrame::empty(f.geometry))
+        },
+        AnyPackedFrame::U16(f) => {
+            AnyPackedFrame::U16(PackedFrame::empty(f.geometry))
+        },
+    })
+}
+
+/// White balance gains of the pair, gains of the frames present in the
+/// recording are averaged
+fn pair_gains(
+    pair: &Pair, left: &AnyPackedFrame, right: &AnyPackedFrame,
+    opt: &ConvertStereoOpt,
+) -> Option<WbGains> {
+    let frames = [(pair.0, left), (pair.1, right)];
+    let gains = frames.iter()
+        .filter(|(ts, _)| ts.is_some())
+        .filter_map(|(_, frame)| frame_gains(frame, &opt.format));
+    WbGains::average(gains)
+}
+
 /// Save index data to TSV file
 fn save_index(index: &StereoIndex, dir: &Path) -> io::Result<()>{
     let mut index_path = dir.to_path_buf();
@@ -137,53 +193,150 @@ fn save_index(index: &StereoIndex, dir: &Path) -> io::Result<()>{
 }
 
 pub fn convert(opt: ConvertStereoOpt) -> Result<(), Box<dyn error::Error>> {
+    if opt.format.raw_crop.is_some() && !opt.rectify.is_empty() {
+        Err("raw crop can't be used with rectification")?
+    }
+    if opt.format.histeq && opt.format.clahe {
+        Err("--histeq and --clahe can't be used together")?
+    }
+    check_dng(&opt.format)?;
     if !opt.format.demosaic && opt.format.scale != 1 {
         Err("can't downscale image without demosaicing")?
     }
-    if !opt.format.demosaic && opt.format.format == Format::Jpeg {
+    if !opt.format.demosaic && opt.format.format.is_jpeg() {
         Err("don't use JPEG without demosaicing")?
     }
-    println!("Processing: {}", opt.input.display());
+    if !opt.format.demosaic && opt.format.srgb {
+        Err("can't apply colour correction without demosaicing")?
+    }
+    if opt.format.ccm.is_some() && !opt.format.srgb
+        && opt.format.format != Format::Dng
+    {
+        Err("colour correction matrix can be used only with --srgb or DNG")?
+    }
+    if opt.format.undistort.is_some() {
+        Err("undistortion is supported only for mono recordings")?
+    }
+    if opt.split && opt.format.format.is_video() {
+        Err("split pairs can't be saved as video")?
+    }
+    if !opt.format.demosaic && !opt.rectify.is_empty() {
+        Err("can't rectify images without demosaicing")?
+    }
+    if opt.format.demosaic && opt.format.scale == 1
+        && opt.format.demosaic_algo == DemosaicAlgorithm::Superpixel
+    {
+        Err("superpixel demosaicing requires scale factor of 2 or higher")?
+    }
+    if opt.format.wb_smooth != 0 && !opt.format.wb.is_adaptive() {
+        Err("white balance smoothing requires gray_world or white_patch mode")?
+    }
+    eprintln!("Processing: {}", opt.input.display());
     let mut index = construct_index(&opt)?;
-    fs::create_dir_all(&opt.output)?;
-    save_index(&index, &opt.output)?;
+    let cfa = opt.format.cfa.flipped();
+    let empty = probe_empty_frame(&index, &opt.input, cfa)?;
+    let is_video = opt.format.format.is_video();
+    if !is_video {
+        fs::create_dir_all(&opt.output)?;
+        save_index(&index, &opt.output)?;
+    }
+    if opt.split {
+        fs::create_dir_all(opt.output.join("left"))?;
+        fs::create_dir_all(opt.output.join("right"))?;
+    }
+    let rectifier = if opt.rectify.is_empty() {
+        None
+    } else {
+        // calibration size defaults to the full frame size
+        let geom = empty.geometry();
+        let model = StereoModel::load(&opt.rectify)?
+            .with_default_size(geom.width, geom.height);
+        Some(Rectifier::new(&model))
+    };
+    let stereo = StereoOutput { rectifier, split: opt.split };
 
     let n = index.len();
     index.truncate(n - opt.skip as usize);
+    let video = if is_video {
+        // index is in the reversed order, while video frames are processed
+        // and written in the recording order. Pairs are indexed with the
+        // fixed frame rate.
+        index.reverse();
+        let rate = FrameRate { num: FPS as u32, den: 1 };
+        let (format, first) = (opt.format.format, opt.skip as usize);
+        Some(VideoSink::create(&opt.output, format, first, Some(rate))?)
+    } else {
+        None
+    };
+    let output = match &video {
+        Some(sink) => Output::Video(sink),
+        None => Output::Dir(&opt.output),
+    };
+
+    let left = opt.input.join("left");
+    let right = opt.input.join("right");
+    let corr_left = Corrections::load(&opt.format)?;
+    let corr_right = if opt.dark_right.is_some() || opt.flat_right.is_some()
+        || opt.defects_right.is_some()
+    {
+        Corrections::load(&FormatOpt {
+            dark: opt.dark_right.clone().or_else(|| opt.format.dark.clone()),
+            flat: opt.flat_right.clone().or_else(|| opt.format.flat.clone()),
+            defects: opt.defects_right.clone()
+                .or_else(|| opt.format.defects.clone()),
+            ..opt.format.clone()
+        })?
+    } else {
+        corr_left.clone()
+    };
+    let read_pair = |pair: &Pair| -> Result<_, Error> {
+        let l = read_flif2(pair.0, &left, &empty, &corr_left)?;
+        let r = read_flif2(pair.1, &right, &empty, &corr_right)?;
+        Ok((l, r))
+    };
+    let smoothed = if opt.format.wb_smooth != 0 {
+        Some(recording_gains(&index, &opt.format, |(_, pair)| {
+            let (l, r) = read_pair(pair).ok()?;
+            pair_gains(pair, &l, &r, &opt)
+        }))
+    } else {
+        None
+    };
 
     let bar = ProgressBar::new(index.len() as u64);
     bar.set_style(ProgressStyle::default_bar().template(PBAR_TEMPLATE));
-    index.par_iter()
-        .progress_with(bar)
-        .for_each(|(n, pair,)| {
-            let skip = match pair {
-                (Some(_), Some(_)) => false,
-                (None,  None) => opt.ignore_empty,
-                _ => opt.ignore_partial,
-            };
-            if skip { return; }
-
-            // TODO: optimize
-            let mut left = opt.input.clone();
-            left.push("left");
-            let mut right = opt.input.clone();
-            right.push("right");
-
-            let res = read_flif2(pair.0, &left)
-                .and_then(|left| Ok((left, read_flif2(pair.1, &right)?)))
-                .and_then(|(left_img, right_img)| {
-                    let file_name = format!("{:#06}", n);
-                    save_stereo_img(
-                        &file_name, left_img, right_img,
-                        &opt.format, &opt.output,
-                        WIDTH as u32, HEIGHT as u32,
-                    )
-                });
-
-            if let Err(err) = res {
-                println!("Error: {:?} {}\n", pair, err);
-            }
-        });
+    let stats = ErrorStats::new();
+    process_index(&index, output, &bar, |i, &(n, ref pair)| {
+        if stats.is_aborted() { return; }
+        let skip = match pair {
+            (Some(_), Some(_)) => false,
+            (None,  None) => opt.ignore_empty,
+            _ => opt.ignore_partial,
+        };
+        if skip { return output.skip(n); }
+
+        let res = read_pair(pair)
+            .and_then(|(left_img, right_img)| {
+                let gains = match &smoothed {
+                    Some(gains) => Some(gains[i]),
+                    None => pair_gains(pair, &left_img, &right_img, &opt),
+                };
+                let name = format!("{:#06}", n);
+                let timestamp = pair.0.or(pair.1);
+                let info = ImageInfo { name: &name, pos: n, timestamp };
+                save_stereo_img(
+                    &info, left_img, right_img, gains, &stereo,
+                    &opt.format, output,
+                )
+            });
+
+        if let Err(err) = res {
+            stats.report(&format!("{:?}", pair), err);
+            output.skip(n);
+        }
+    });
 
+    stats.finish()?;
+    if let Some(sink) = video { sink.finish()?; }
     Ok(())
 }
@@ -1,97 +1,476 @@
-use std::path::Path;
-use std::{io, fs};
+use std::path::{Path, PathBuf};
+use std::{io, fs, cmp};
 use std::io::Write;
 
+use indicatif::{ProgressBar, ProgressStyle, ParallelProgressIterator};
 use png::HasParameters;
+use rayon::iter::{
+    ParallelIterator, IndexedParallelIterator, IntoParallelRefIterator,
+};
 use jpeg_encoder::JpegEncoder;
 use jpeg_encoder;
 
-use oscar_utils::bggr_bayer;
+use oscar_utils::{
+    demosaic, camera_to_srgb, smooth_gains, Calibration, DefectMap,
+    MasterFrame, DemosaicAlgorithm, PackedFrame, AnyPackedFrame, RawFrame,
+    Geometry, Rectifier,
+    Equalization, CropRect, Rotation, resample, crop, rotate, flip,
+    Sample, Error, WhiteBalance, WbGains, PBAR_TEMPLATE,
+};
+use oscar_utils::conversions::rgba2rgb;
+use oscar_utils::load_frames::is_raw_pnm;
+use oscar_utils::dng_encoder::{encode_dng, DngMetadata};
+use oscar_utils::tiff_encoder::{
+    encode_tiff, Tag, TagValue, TiffCompression, DATE_TIME, DATE_TIME_ORIGINAL,
+    EXIF_IFD, IMAGE_DESCRIPTION,
+};
+use oscar_utils::webp_encoder::{encode_webp, WebpMode};
 use super::cli::{Format, FormatOpt};
+use super::video::{VideoFrame, VideoSink};
 
-pub fn save_img(
-    name: &str, mut data: Box<[u8]>, opt: &FormatOpt, out_dir: &Path,
-    width: u32, height: u32,
-) -> io::Result<()> {
-    assert_eq!(data.len(), (width*height) as usize);
-    let is_color = if opt.demosaic {
-        data = bggr_bayer(&data, width as usize, height as usize);
-        true
-    } else {
-        false
-    };
-    let mut width = width;
-    let mut height = height;
+/// Camera name saved into DNG files
+const DNG_CAMERA: &str = "OS:Car camera";
+/// Number of frames processed by each thread in a batch of the video output
+const VIDEO_BATCH_PER_THREAD: usize = 2;
 
-    if opt.scale != 1 {
-        data = resize(&data, width, height, opt.scale);
-        width /= opt.scale as u32;
-        height /= opt.scale as u32;
+/// Unpack and demosaic frame according to the options. Returns image data,
+/// its dimensions and whether the image is colour.
+fn develop<T: Sample>(
+    frame: PackedFrame<T>, opt: &FormatOpt,
+) -> (Box<[T]>, u32, u32, bool) {
+    let geometry = frame.geometry;
+    let width = geometry.width as u32;
+    let height = geometry.height as u32;
+    if !opt.demosaic {
+        return (frame.unpack().data, width, height, false);
+    }
+    match opt.demosaic_algo {
+        DemosaicAlgorithm::Superpixel => {
+            let mut data = vec![T::default(); 3*geometry.pixels()/4];
+            rgba2rgb(&frame.data, &mut data, geometry);
+            (data.into_boxed_slice(), width/2, height/2, true)
+        },
+        algo => {
+            let data = demosaic(&frame.unpack().data, geometry, algo);
+            (data, width, height, true)
+        },
+    }
+}
+
+/// Scale factor which should be applied after demosaicing
+fn resize_scale(opt: &FormatOpt) -> u8 {
+    if opt.demosaic && opt.demosaic_algo == DemosaicAlgorithm::Superpixel {
+        opt.scale/2
+    } else {
+        opt.scale
     }
-    if opt.histeq { histeq(&mut data); }
+}
+
+fn output_path(name: &str, opt: &FormatOpt, out_dir: &Path) -> PathBuf {
     let mut path = out_dir.to_path_buf();
     path.push(name);
     let flag = path.set_extension(match opt.format {
         Format::Pnm => "pnm",
         Format::Png => "png",
         Format::Jpeg => "jpg",
+        Format::Tiff => "tif",
+        Format::Webp => "webp",
+        Format::Dng => "dng",
+        Format::Y4m | Format::Avi => {
+            unreachable!("video frames aren't saved as files")
+        },
     });
     assert!(flag, "extension set check");
+    path
+}
+
+fn write_img<T: Sample>(
+    path: &Path, data: &[T], width: u32, height: u32, is_color: bool,
+    timestamp: Option<Timestamp>, opt: &FormatOpt,
+) -> io::Result<()> {
     match opt.format {
-        Format::Pnm => save_pnm(&path, &data, width, height, is_color),
-        Format::Png => save_png(&path, &data, width, height, is_color),
-        Format::Jpeg => save_jpeg(&path, &data, width, height, is_color, opt.quality),
+        Format::Pnm => save_pnm(path, data, width, height, is_color),
+        Format::Png => save_png(path, data, width, height, is_color),
+        Format::Jpeg => save_jpeg(path, data, width, height, is_color, opt.quality),
+        Format::Tiff => save_tiff(
+            path, data, width, height, is_color, opt.tiff_compression,
+            timestamp,
+        ),
+        Format::Webp => {
+            let mode = match opt.webp_lossy {
+                true => WebpMode::Lossy(opt.quality),
+                false => WebpMode::Lossless,
+            };
+            save_webp(path, data, width, height, is_color, mode)
+        },
+        Format::Dng => unreachable!("DNG files are saved before development"),
+        Format::Y4m | Format::Avi => {
+            unreachable!("video frames aren't saved as files")
+        },
     }
 }
 
-pub fn save_stereo_img(
-    name: &str, mut left: Box<[u8]>, mut right: Box<[u8]>,
-    opt: &FormatOpt, out_dir: &Path, width: u32, height: u32,
-) -> io::Result<()> {
-    assert_eq!(left.len(), (width*height) as usize);
-    assert_eq!(right.len(), (width*height) as usize);
-    let is_color = if opt.demosaic {
-        left = bggr_bayer(&left, width as usize, height as usize);
-        right = bggr_bayer(&right, width as usize, height as usize);
-        true
-    } else {
-        false
+/// Check that options keep the CFA mosaic intact, as required for the DNG
+/// output
+pub fn check_dng(opt: &FormatOpt) -> Result<(), &'static str> {
+    if opt.format != Format::Dng { return Ok(()); }
+    if opt.demosaic {
+        Err("DNG output can't be used with demosaicing")?
+    }
+    if opt.resize.is_some() || opt.crop.is_some()
+        || opt.rotate != Rotation::None || opt.flip_h || opt.flip_v
+    {
+        Err("DNG output supports only --raw_crop transformation")?
+    }
+    if opt.histeq || opt.clahe {
+        Err("histogram equalization can't be used with DNG output")?
+    }
+    Ok(())
+}
+
+/// Name of the output image and timestamp of the frame it's made from
+#[derive(Copy, Clone, Debug)]
+pub struct ImageInfo<'a> {
+    pub name: &'a str,
+    /// Position of the frame in the video output
+    pub pos: usize,
+    /// Timestamp embedded into output files which support it (TIFF)
+    pub timestamp: Option<Timestamp>,
+}
+
+/// Destination of the saved images
+#[derive(Copy, Clone)]
+pub enum Output<'a> {
+    /// Directory with a file for each image
+    Dir(&'a Path),
+    /// Single video stream
+    Video(&'a VideoSink),
+}
+
+impl<'a> Output<'a> {
+    /// Output directory, video output is rejected by the option checks for
+    /// DNG images and split stereo pairs
+    fn dir(self) -> &'a Path {
+        match self {
+            Output::Dir(dir) => dir,
+            Output::Video(_) => unreachable!("video output has no directory"),
+        }
+    }
+
+    /// Mark image at the given position of the video output as failed
+    pub fn skip(self, pos: usize) {
+        if let Output::Video(sink) = self { sink.skip(pos); }
+    }
+}
+
+/// Process index items in parallel. Frames of the video output are written
+/// in order, so items are processed in batches in the index order to bound
+/// the number of frames held by the reorder buffer.
+pub fn process_index<I, F>(
+    index: &[I], output: Output, bar: &ProgressBar, process: F,
+)
+    where I: Sync, F: Fn(usize, &I) + Sync
+{
+    let batch = match output {
+        Output::Video(_) => {
+            VIDEO_BATCH_PER_THREAD*rayon::current_num_threads()
+        },
+        Output::Dir(_) => index.len(),
     };
-    let mut width = width;
-    let mut height = height;
-    if opt.scale != 1 {
-        left = resize(&left, width, height, opt.scale);
-        right = resize(&right, width, height, opt.scale);
-        width /= opt.scale as u32;
-        height /= opt.scale as u32;
+    let batch = cmp::max(batch, 1);
+    for (n, chunk) in index.chunks(batch).enumerate() {
+        chunk.par_iter().enumerate().for_each(|(j, item)| {
+            bar.inc(1);
+            process(n*batch + j, item);
+        });
+    }
+    bar.finish();
+}
+
+/// Raw domain corrections applied to frames right after loading
+#[derive(Clone, Default)]
+pub struct Corrections {
+    calib: Option<Calibration>,
+    defects: Option<DefectMap>,
+}
+
+impl Corrections {
+    /// Load master calibration frames and defect map set in the options
+    pub fn load(opt: &FormatOpt) -> Result<Self, Error> {
+        let load = |path: &Option<PathBuf>| match path {
+            Some(path) => MasterFrame::load(path, opt.cfa).map(Some),
+            None => Ok(None),
+        };
+        let (dark, flat) = (load(&opt.dark)?, load(&opt.flat)?);
+        let calib = if dark.is_some() || flat.is_some() {
+            Some(Calibration::new(dark, flat)?)
+        } else {
+            None
+        };
+        let defects = match &opt.defects {
+            Some(path) => Some(
+                DefectMap::load(path)
+                    .map_err(|err| Error::from(err).with_path(path))?
+            ),
+            None => None,
+        };
+        Ok(Self { calib, defects })
+    }
+
+    /// Apply dark frame subtraction and flat-field correction followed by
+    /// interpolation of defective pixels
+    pub fn apply(
+        &self, mut frame: AnyPackedFrame,
+    ) -> Result<AnyPackedFrame, Error> {
+        if let Some(calib) = &self.calib { calib.apply(&mut frame)?; }
+        if let Some(defects) = &self.defects { defects.correct(&mut frame)?; }
+        Ok(frame)
+    }
+}
+
+/// White balance gains of the frame according to the options, `None` if
+/// white balance is disabled
+pub fn frame_gains(frame: &AnyPackedFrame, opt: &FormatOpt) -> Option<WbGains> {
+    match (opt.wb, frame) {
+        (WhiteBalance::Fixed, _) => Some(opt.wb_gains),
+        (mode, AnyPackedFrame::U8(f)) => WbGains::estimate(f, mode),
+        (mode, AnyPackedFrame::U16(f)) => WbGains::estimate(f, mode),
+    }
+}
+
+/// Estimate white balance gains of each item of the recording index and
+/// smooth them over time. `estimate` should return `None` for missing or
+/// invalid frames.
+pub fn recording_gains<I, F>(
+    index: &[I], opt: &FormatOpt, estimate: F,
+) -> Vec<WbGains>
+    where I: Sync, F: Fn(&I) -> Option<WbGains> + Sync + Send
+{
+    eprintln!("Estimating white balance");
+    let bar = ProgressBar::new(index.len() as u64);
+    bar.set_style(ProgressStyle::default_bar().template(PBAR_TEMPLATE));
+    let gains: Vec<Option<WbGains>> = index.par_iter()
+        .progress_with(bar)
+        .map(estimate)
+        .collect();
+    smooth_gains(&gains, opt.wb_smooth)
+}
+
+/// Save frame, 16-bit frames are saved with 16-bit samples if the output
+/// format supports it
+pub fn save_img(
+    info: &ImageInfo, frame: AnyPackedFrame, gains: Option<WbGains>,
+    opt: &FormatOpt, output: Output,
+) -> Result<(), Error> {
+    match frame {
+        AnyPackedFrame::U8(f) => save_img_typed(info, f, gains, opt, output),
+        AnyPackedFrame::U16(f) => save_img_typed(info, f, gains, opt, output),
+    }
+}
+
+fn save_img_typed<T: Sample>(
+    info: &ImageInfo, mut frame: PackedFrame<T>, gains: Option<WbGains>,
+    opt: &FormatOpt, output: Output,
+) -> Result<(), Error> {
+    if let Some(rect) = opt.raw_crop { frame = raw_crop(frame, rect)?; }
+    if opt.format == Format::Dng {
+        let path = output_path(info.name, opt, output.dir());
+        return write_dng(&path, &frame.unpack(), gains, info.timestamp, opt)
+            .map_err(|err| Error::from(err).with_path(&path));
+    }
+    if let Some(gains) = gains { gains.apply(&mut frame); }
+    let (mut data, mut width, mut height, is_color) = develop(frame, opt);
+
+    let scale = resize_scale(opt);
+    if scale != 1 {
+        data = resize(&data, width, height, scale);
+        width /= scale as u32;
+        height /= scale as u32;
+    }
+    if let Some(size) = opt.resize {
+        let (w, h) = (width as usize, height as usize);
+        let (new_w, new_h) = size.dimensions(w, h);
+        let channels = if is_color { 3 } else { 1 };
+        data = resample(
+            &data, w, h, channels, new_w, new_h, opt.resize_filter,
+        );
+        width = new_w as u32;
+        height = new_h as u32;
+    }
+    if let Some(undistorter) = &opt.undistort {
+        let (w, h) = (width as usize, height as usize);
+        let channels = if is_color { 3 } else { 1 };
+        data = undistorter.undistort(&data, w, h, channels);
+    }
+    let (mut data, width, height) =
+        transform(data, width, height, is_color, opt)?;
+    if opt.srgb { camera_to_srgb(&mut data, &opt.ccm.unwrap_or_default()); }
+    if let Some(eq) = equalization(opt) {
+        let channels = if is_color { 3 } else { 1 };
+        eq.apply(&mut data, width as usize, height as usize, channels);
+    }
+    write_output(info, &data, width, height, is_color, opt, output)
+}
+
+/// Write image into the output directory or add it to the video stream
+fn write_output<T: Sample>(
+    info: &ImageInfo, data: &[T], width: u32, height: u32, is_color: bool,
+    opt: &FormatOpt, output: Output,
+) -> Result<(), Error> {
+    match output {
+        Output::Dir(dir) => {
+            let path = output_path(info.name, opt, dir);
+            write_img(&path, data, width, height, is_color, info.timestamp, opt)
+                .map_err(|err| Error::from(err).with_path(&path))
+        },
+        Output::Video(sink) => {
+            let frame = VideoFrame::encode(data, width, height, is_color, opt)?;
+            sink.push(info.pos, frame)
+        },
+    }
+}
+
+/// Processing of the stereo pairs in addition to `FormatOpt`
+#[derive(Debug)]
+pub struct StereoOutput {
+    pub rectifier: Option<Rectifier>,
+    /// Save left and right images into separate subdirectories
+    pub split: bool,
+}
+
+/// Save left and right frames joined side by side, the same white balance
+/// gains are applied to both frames
+pub fn save_stereo_img(
+    info: &ImageInfo, left: AnyPackedFrame, right: AnyPackedFrame,
+    gains: Option<WbGains>, stereo: &StereoOutput, opt: &FormatOpt,
+    output: Output,
+) -> Result<(), Error> {
+    use self::AnyPackedFrame::{U8, U16};
+
+    match (left, right) {
+        (U8(l), U8(r)) => {
+            save_stereo_img_typed(info, l, r, gains, stereo, opt, output)
+        },
+        (U16(l), U16(r)) => {
+            save_stereo_img_typed(info, l, r, gains, stereo, opt, output)
+        },
+        _ => Err(Error::UnsupportedChannels(
+            "left and right frame bit depths differ".to_string(),
+        )),
+    }
+}
+
+fn save_stereo_img_typed<T: Sample>(
+    info: &ImageInfo, mut left: PackedFrame<T>, mut right: PackedFrame<T>,
+    gains: Option<WbGains>, stereo: &StereoOutput, opt: &FormatOpt,
+    output: Output,
+) -> Result<(), Error> {
+    // frames are loaded with the same CFA pattern, so only dimensions of
+    // the right frame may differ
+    if left.geometry != right.geometry {
+        let (width, height) = (right.geometry.width, right.geometry.height);
+        Err(Error::InvalidDimensions { width, height })?
+    }
+    if let Some(rect) = opt.raw_crop {
+        left = raw_crop(left, rect)?;
+        right = raw_crop(right, rect)?;
+    }
+    if opt.format == Format::Dng {
+        return save_stereo_dng(
+            info, &left, &right, gains, stereo, opt, output.dir(),
+        );
     }
+    if let Some(gains) = gains {
+        gains.apply(&mut left);
+        gains.apply(&mut right);
+    }
+    let (mut left, mut width, mut height, is_color) = develop(left, opt);
+    let (mut right, ..) = develop(right, opt);
+    let scale = resize_scale(opt);
+    if scale != 1 {
+        left = resize(&left, width, height, scale);
+        right = resize(&right, width, height, scale);
+        width /= scale as u32;
+        height /= scale as u32;
+    }
+    if let Some(size) = opt.resize {
+        let (w, h) = (width as usize, height as usize);
+        let (new_w, new_h) = size.dimensions(w, h);
+        let channels = if is_color { 3 } else { 1 };
+        let filter = opt.resize_filter;
+        left = resample(&left, w, h, channels, new_w, new_h, filter);
+        right = resample(&right, w, h, channels, new_w, new_h, filter);
+        width = new_w as u32;
+        height = new_h as u32;
+    }
+    if let Some(rectifier) = &stereo.rectifier {
+        let (w, h) = (width as usize, height as usize);
+        let channels = if is_color { 3 } else { 1 };
+        let (l, r) = rectifier.rectify(&left, &right, w, h, channels);
+        left = l;
+        right = r;
+    }
+    let (right, ..) = transform(right, width, height, is_color, opt)?;
+    let (left, width, height) = transform(left, width, height, is_color, opt)?;
+    // images are processed joined, so histogram equalization gives the same
+    // result for both of them
     let mut  data = concat_images(
         left, right, width as usize, height as usize, is_color
     );
-    if opt.histeq { histeq(&mut data); }
+    if opt.srgb { camera_to_srgb(&mut data, &opt.ccm.unwrap_or_default()); }
+    if let Some(eq) = equalization(opt) {
+        let channels = if is_color { 3 } else { 1 };
+        eq.apply(&mut data, 2*width as usize, height as usize, channels);
+    }
 
-    let mut path = out_dir.to_path_buf();
-    path.push(name);
-    let flag = path.set_extension(match opt.format {
-        Format::Pnm => "pnm",
-        Format::Png => "png",
-        Format::Jpeg => "jpg",
-    });
-    assert!(flag, "extension set check");
-    match opt.format {
-        Format::Pnm => save_pnm(&path, &data, 2*width, height, is_color),
-        Format::Png => save_png(&path, &data, 2*width, height, is_color),
-        Format::Jpeg => save_jpeg(&path, &data, 2*width, height, is_color, opt.quality),
+    if stereo.split {
+        let (left, right) = split_images(
+            &data, width as usize, height as usize, is_color,
+        );
+        for (side, data) in [("left", left), ("right", right)].iter() {
+            let dir = output.dir().join(side);
+            let output = Output::Dir(&dir);
+            write_output(info, data, width, height, is_color, opt, output)?;
+        }
+        return Ok(());
+    }
+    write_output(info, &data, 2*width, height, is_color, opt, output)
+}
+
+/// Save raw mosaics of the stereo pair as DNG, the same way as developed
+/// images
+fn save_stereo_dng<T: Sample>(
+    info: &ImageInfo, left: &PackedFrame<T>, right: &PackedFrame<T>,
+    gains: Option<WbGains>, stereo: &StereoOutput, opt: &FormatOpt,
+    out_dir: &Path,
+) -> Result<(), Error> {
+    let (left, right) = (left.unpack(), right.unpack());
+    let ts = info.timestamp;
+    if stereo.split {
+        for (side, frame) in [("left", left), ("right", right)].iter() {
+            let path = output_path(info.name, opt, &out_dir.join(side));
+            write_dng(&path, frame, gains, ts, opt)
+                .map_err(|err| Error::from(err).with_path(&path))?;
+        }
+        return Ok(());
     }
+    let Geometry { width, height, cfa } = left.geometry;
+    // width is even, so the joined mosaic keeps the CFA pattern
+    let data = concat_images(left.data, right.data, width, height, false);
+    let geometry = Geometry::new(2*width, height, cfa);
+    let frame = RawFrame { geometry, data };
+    let path = output_path(info.name, opt, out_dir);
+    write_dng(&path, &frame, gains, ts, opt)
+        .map_err(|err| Error::from(err).with_path(&path))
 }
 
-fn concat_images(
-    left: Box<[u8]>, right: Box<[u8]>, w: usize, h: usize, is_color: bool
-) -> Box<[u8]> {
+fn concat_images<T: Sample>(
+    left: Box<[T]>, right: Box<[T]>, w: usize, h: usize, is_color: bool
+) -> Box<[T]> {
     let w = if is_color { 3*w } else { w };
     assert_eq!(left.len(), w*h);
     assert_eq!(right.len(), w*h);
-    let mut out = vec![0; 2*w*h].into_boxed_slice();
+    let mut out = vec![T::default(); 2*w*h].into_boxed_slice();
     for ((l, r), o) in left.chunks(w)
         .zip(right.chunks(w))
         .zip(out.chunks_mut(2*w))
@@ -102,55 +481,87 @@ fn concat_images(
     out
 }
 
-fn histeq(data: &mut [u8]) {
-    assert_eq!(data.len() % 3, 0);
-    let mut hist = [0i32; 256];
-    // build histogram
-    for pixel in data.chunks_mut(3) {
-        let (r, g, b) = (pixel[0], pixel[1], pixel[2]);
-        let y = ((r as usize) + 2*(b as usize) + (g as usize))/4;
-        // guaranteed to be in range 0..255
-        unsafe {
-            *(hist.get_unchecked_mut(y)) += 1;
-        }
+/// Inverse of `concat_images`
+fn split_images<T: Sample>(
+    data: &[T], w: usize, h: usize, is_color: bool
+) -> (Box<[T]>, Box<[T]>) {
+    let w = if is_color { 3*w } else { w };
+    assert_eq!(data.len(), 2*w*h);
+    let mut left = Vec::with_capacity(w*h);
+    let mut right = Vec::with_capacity(w*h);
+    for row in data.chunks(2*w) {
+        left.extend_from_slice(&row[..w]);
+        right.extend_from_slice(&row[w..]);
     }
-    // accumulate histogram
-    let mut sum = 0i32;
-    for val in hist.iter_mut() {
-        sum += *val;
-        *val = sum;
+    (left.into_boxed_slice(), right.into_boxed_slice())
+}
+
+/// Crop raw frame, rectangle must be aligned to the 2x2 Bayer quads
+fn raw_crop<T: Sample>(
+    frame: PackedFrame<T>, rect: CropRect,
+) -> Result<PackedFrame<T>, Error> {
+    let (width, height) = (frame.geometry.width, frame.geometry.height);
+    if !rect.fits(width, height) {
+        Err(Error::InvalidDimensions { width, height })?
     }
-    // normalize histogram
-    let max_val = hist[255]/255;
-    let mut map = [0u8; 256];
-    for (v, m) in hist.iter().zip(map.iter_mut()) {
-        *m = (*v/max_val) as u8;
+    Ok(frame.crop(rect))
+}
+
+/// Crop, rotate and flip image according to the options, returns new image
+/// with its dimensions
+fn transform<T: Sample>(
+    mut data: Box<[T]>, width: u32, height: u32, is_color: bool,
+    opt: &FormatOpt,
+) -> Result<(Box<[T]>, u32, u32), Error> {
+    let channels = if is_color { 3 } else { 1 };
+    let (mut width, mut height) = (width as usize, height as usize);
+    if let Some(rect) = opt.crop {
+        if !rect.fits(width, height) {
+            Err(Error::InvalidDimensions { width, height })?
+        }
+        data = crop(&data, width, channels, rect);
+        width = rect.width;
+        height = rect.height;
     }
+    if opt.rotate != Rotation::None {
+        let (d, w, h) = rotate(&data, width, height, channels, opt.rotate);
+        data = d;
+        width = w;
+        height = h;
+    }
+    flip(&mut data, width, channels, opt.flip_h, opt.flip_v);
+    Ok((data, width as u32, height as u32))
+}
 
-    // map pixels
-    for val in data.iter_mut() {
-        *val = map[*val as usize];
+/// Histogram equalization selected by the options
+fn equalization(opt: &FormatOpt) -> Option<Equalization> {
+    if opt.clahe {
+        Some(Equalization::clahe(opt.clahe_tile, opt.clahe_clip))
+    } else if opt.histeq {
+        Some(Equalization::global())
+    } else {
+        None
     }
 }
 
-fn save_pnm(
-    path: &Path, data: &[u8], width: u32, height: u32, is_color: bool,
+pub fn save_pnm<T: Sample>(
+    path: &Path, data: &[T], width: u32, height: u32, is_color: bool,
 ) -> io::Result<()> {
     let mut file = fs::File::create(path)?;
     let header = if is_color {
         assert_eq!(3*width*height, data.len() as u32);
-        format!("P6\n{} {}\n255\n", width, height)
+        format!("P6\n{} {}\n{}\n", width, height, T::MAX)
     } else {
         assert_eq!(width*height, data.len() as u32);
-        format!("P5\n{} {}\n255\n", width, height)
+        format!("P5\n{} {}\n{}\n", width, height, T::MAX)
     };
     file.write_all(header.as_bytes())?;
-    file.write_all(data)?;
+    file.write_all(&T::encode_be(data))?;
     Ok(())
 }
 
-fn save_png(
-    path: &Path, data: &[u8], width: u32, height: u32, is_color: bool,
+fn save_png<T: Sample>(
+    path: &Path, data: &[T], width: u32, height: u32, is_color: bool,
 ) -> io::Result<()> {
     let target_len = if is_color { 3*width*height } else { width*height };
     assert_eq!(data.len() as u32, target_len);
@@ -163,21 +574,34 @@ fn save_png(
         true => png::ColorType::RGB,
         false => png::ColorType::Grayscale,
     };
+    let depth = match T::BITS {
+        8 => png::BitDepth::Eight,
+        _ => png::BitDepth::Sixteen,
+    };
 
-    encoder.set(color).set(png::BitDepth::Eight);
+    encoder.set(color).set(depth);
     let mut writer = encoder.write_header()?;
-    writer.write_image_data(data)
+    writer.write_image_data(&T::encode_be(data))
         .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
 }
 
-fn save_jpeg(
-    path: &Path, data: &[u8], width: u32, height: u32, is_color: bool, q: u8,
+/// JPEG supports only 8-bit samples, so 16-bit data gets truncated
+fn save_jpeg<T: Sample>(
+    path: &Path, data: &[T], width: u32, height: u32, is_color: bool, q: u8,
 ) -> io::Result<()> {
     let target_len = if is_color { 3*width*height } else { width*height };
     assert_eq!(data.len() as u32, target_len);
+    let data: Vec<u8> = data.iter().map(|v| v.to_u8()).collect();
 
     let file = fs::File::create(path)?;
-    let mut writer = io::BufWriter::new(file);
+    let writer = io::BufWriter::new(file);
+    encode_jpeg(&data, width, height, is_color, q, writer)
+}
+
+/// Encode 8-bit grayscale or RGB image as JPEG
+pub fn encode_jpeg<W: Write>(
+    data: &[u8], width: u32, height: u32, is_color: bool, q: u8, mut writer: W,
+) -> io::Result<()> {
     let mut encoder = JpegEncoder::new_with_quality(&mut writer, q);
 
     let color = match is_color {
@@ -188,29 +612,115 @@ fn save_jpeg(
     encoder.encode(data, width, height, color)
 }
 
-fn resize(data: &[u8], width: u32, height: u32, scale: u8) -> Box<[u8]> {
+/// TIFF supports both 8- and 16-bit samples, timestamp is saved into
+/// `DateTime` (UTC, whole seconds) and `ImageDescription` tags
+fn save_tiff<T: Sample>(
+    path: &Path, data: &[T], width: u32, height: u32, is_color: bool,
+    compression: TiffCompression, timestamp: Option<Timestamp>,
+) -> io::Result<()> {
+    let channels = if is_color { 3 } else { 1 };
+    let tags = timestamp.map(timestamp_tags).unwrap_or_default();
+    let file = fs::File::create(path)?;
+    let writer = io::BufWriter::new(file);
+    encode_tiff(
+        data, width as usize, height as usize, channels, compression, &tags,
+        writer,
+    )
+}
+
+/// WebP supports only 8-bit samples, so 16-bit data gets truncated
+fn save_webp<T: Sample>(
+    path: &Path, data: &[T], width: u32, height: u32, is_color: bool,
+    mode: WebpMode,
+) -> io::Result<()> {
+    let channels = if is_color { 3 } else { 1 };
+    let data: Vec<u8> = data.iter().map(|v| v.to_u8()).collect();
+    let file = fs::File::create(path)?;
+    let writer = io::BufWriter::new(file);
+    encode_webp(
+        &data, width as usize, height as usize, channels, mode, writer,
+    )
+}
+
+/// DNG keeps raw samples of the frame, white balance gains are saved only as
+/// metadata
+fn write_dng<T: Sample>(
+    path: &Path, frame: &RawFrame<T>, gains: Option<WbGains>,
+    timestamp: Option<Timestamp>, opt: &FormatOpt,
+) -> io::Result<()> {
+    let meta = DngMetadata {
+        camera: DNG_CAMERA.to_string(),
+        black_level: opt.black_level,
+        color_matrix: opt.ccm.unwrap_or_default(),
+        gains: gains.unwrap_or_default(),
+        tags: timestamp.map(timestamp_tags).unwrap_or_default(),
+    };
+    let file = fs::File::create(path)?;
+    encode_dng(frame, &meta, io::BufWriter::new(file))
+}
+
+/// TIFF tags with the frame timestamp, capture time is also saved as EXIF
+/// `DateTimeOriginal` which is preferred by raw processors
+fn timestamp_tags(ts: Timestamp) -> Vec<Tag> {
+    let datetime = tiff_datetime(ts.unix);
+    vec![
+        Tag::new(DATE_TIME, TagValue::Ascii(datetime.clone())),
+        Tag::new(EXIF_IFD, TagValue::Ifd(vec![
+            Tag::new(DATE_TIME_ORIGINAL, TagValue::Ascii(datetime)),
+        ])),
+        Tag::new(IMAGE_DESCRIPTION, TagValue::Ascii(format!(
+            "UNIX time: {} us, OS time: {} us", ts.unix, ts.os,
+        ))),
+    ]
+}
+
+/// Format UNIX time in microseconds as TIFF date and time
+/// (`YYYY:MM:DD HH:MM:SS`)
+fn tiff_datetime(unix: u64) -> String {
+    let secs = unix/1_000_000;
+    let (days, secs) = (secs/86_400, secs % 86_400);
+    // civil date of the day number, days are counted from 0000-03-01 to
+    // make leap day the last day of the year
+    let z = days + 719_468;
+    let era = z/146_097;
+    let doe = z - era*146_097;
+    let yoe = (doe - doe/1460 + doe/36_524 - doe/146_096)/365;
+    let doy = doe - (365*yoe + yoe/4 - yoe/100);
+    let mp = (5*doy + 2)/153;
+    let day = doy - (153*mp + 2)/5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = 400*era + yoe + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}:{:02}:{:02} {:02}:{:02}:{:02}",
+        year, month, day, secs/3600, secs/60 % 60, secs % 60,
+    )
+}
+
+fn resize<T: Sample>(
+    data: &[T], width: u32, height: u32, scale: u8,
+) -> Box<[T]> {
     assert_eq!(data.len() as u32, 3*width*height);
     assert!(scale == 2 || scale == 4 || scale == 8 || scale == 16 );
     // scale = 2^factor
     let factor = 7 - scale.leading_zeros();
     let w = (width as usize)/(scale as usize);
     let h = (height as usize)/(scale as usize);
-    let mut buf = vec![0u16; 3*w*h];
+    let mut buf = vec![0u32; 3*w*h];
 
     for (y, row) in data.chunks(3*width as usize).enumerate() {
         let i0 = 3*w*(y>>factor);
         for (x, pix) in row.chunks(3).enumerate() {
             let idx = i0 + 3*(x>>factor);
             unsafe {
-                *(buf.get_unchecked_mut(idx + 0)) += pix[0] as u16;
-                *(buf.get_unchecked_mut(idx + 1)) += pix[1] as u16;
-                *(buf.get_unchecked_mut(idx + 2)) += pix[2] as u16;
+                *(buf.get_unchecked_mut(idx + 0)) += pix[0].to_u32();
+                *(buf.get_unchecked_mut(idx + 1)) += pix[1].to_u32();
+                *(buf.get_unchecked_mut(idx + 2)) += pix[2].to_u32();
             }
         }
     }
 
     buf.iter()
-        .map(|v| (v >> (factor*2)) as u8)
+        .map(|v| T::from_u32(v >> (factor*2)))
         .collect::<Vec<_>>()
         .into_boxed_slice()
 }
@@ -230,8 +740,10 @@ fn invalid_input(msg: &str, path: &Path) -> io::Error {
 
 pub fn get_timestamp(path: &Path) -> io::Result<Timestamp> {
     match path.extension() {
-        Some(ext) if ext == "flif" => (),
-        _ => Err(invalid_input("expected file with flif extension", path))?,
+        Some(ext) if ext == "flif" || is_raw_pnm(path) => (),
+        _ => Err(invalid_input(
+            "expected file with flif, pnm or pgm extension", path,
+        ))?,
     };
 
     let file_name = path.file_stem()
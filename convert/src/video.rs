@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::fs;
+
+use oscar_utils::{Error, Sample};
+use oscar_utils::avi_encoder::AviWriter;
+use oscar_utils::y4m_encoder::{y4m_planes, FrameRate, Y4mWriter};
+use super::cli::{Format, FormatOpt};
+use super::utils::encode_jpeg;
+
+/// Container of the video stream, it's created with the first frame
+enum Muxer {
+    Y4m(Y4mWriter<Box<dyn Write + Send>>),
+    Avi(AviWriter<io::BufWriter<fs::File>>),
+}
+
+/// Developed image encoded for the video stream
+pub struct VideoFrame {
+    data: Vec<u8>,
+    width: u32,
+    height: u32,
+    is_color: bool,
+}
+
+impl VideoFrame {
+    /// Encode image according to the output format, video formats support
+    /// only 8-bit samples, so 16-bit data gets truncated
+    pub fn encode<T: Sample>(
+        data: &[T], width: u32, height: u32, is_color: bool, opt: &FormatOpt,
+    ) -> io::Result<Self> {
+        let channels = if is_color { 3 } else { 1 };
+        let data: Vec<u8> = data.iter().map(|v| v.to_u8()).collect();
+        let data = match opt.format {
+            Format::Y4m => y4m_planes(&data, channels),
+            Format::Avi => {
+                let mut buf = Vec::new();
+                let q = opt.quality;
+                encode_jpeg(&data, width, height, is_color, q, &mut buf)?;
+                buf
+            },
+            _ => unreachable!("not a video format"),
+        };
+        Ok(Self { data, width, height, is_color })
+    }
+}
+
+struct SinkState {
+    /// Position of the next frame written into the stream
+    next: usize,
+    /// Frames waiting for the preceding ones, skipped frames are `None`
+    pending: BTreeMap<usize, Option<VideoFrame>>,
+    /// Dimensions and colour of the first received frame
+    dims: Option<(u32, u32, bool)>,
+    rate: Option<FrameRate>,
+    /// Output file (`None` for stdout) until the muxer is created
+    file: Option<fs::File>,
+    muxer: Option<Muxer>,
+}
+
+/// Reorder buffer which writes frames processed in parallel into the video
+/// stream in the order of their positions
+pub struct VideoSink {
+    path: PathBuf,
+    format: Format,
+    state: Mutex<SinkState>,
+}
+
+impl VideoSink {
+    /// Create output file (`-` for stdout, supported only by Y4M), `first`
+    /// is the position of the first frame. Frames are held until the frame
+    /// rate is known.
+    pub fn create(
+        path: &Path, format: Format, first: usize, rate: Option<FrameRate>,
+    ) -> io::Result<Self> {
+        let file = if path == Path::new("-") {
+            if format == Format::Avi {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "AVI video can't be written into stdout",
+                ))?
+            }
+            None
+        } else {
+            Some(fs::File::create(path)?)
+        };
+        let state = SinkState {
+            next: first,
+            pending: BTreeMap::new(),
+            dims: None,
+            rate,
+            file,
+            muxer: None,
+        };
+        let path = path.to_path_buf();
+        Ok(Self { path, format, state: Mutex::new(state) })
+    }
+
+    /// Set frame rate of the stream and write frames held until now
+    pub fn set_rate(&self, rate: FrameRate) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        state.rate = Some(rate);
+        self.write_pending(&mut state, false)
+    }
+
+    /// Add frame at the given position, frames are written as soon as all
+    /// preceding frames are added or skipped. Frames must have the same
+    /// dimensions as the first added frame.
+    pub fn push(&self, pos: usize, frame: VideoFrame) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        debug_assert!(pos >= state.next, "frame is already written");
+        let (width, height) = (frame.width as usize, frame.height as usize);
+        let dims = (frame.width, frame.height, frame.is_color);
+        let is_valid = *state.dims.get_or_insert(dims) == dims;
+        state.pending.insert(pos, if is_valid { Some(frame) } else { None });
+        self.write_pending(&mut state, false)?;
+        if !is_valid {
+            Err(Error::InvalidDimensions { width, height })?
+        }
+        Ok(())
+    }
+
+    /// Skip frame which failed to process, so it doesn't hold back the
+    /// following frames
+    pub fn skip(&self, pos: usize) {
+        let mut state = self.state.lock().unwrap();
+        if pos >= state.next {
+            state.pending.entry(pos).or_insert(None);
+        }
+    }
+
+    /// Write the remaining frames (positions which were not added are
+    /// skipped) and flush the stream
+    pub fn finish(self) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.rate.is_none() { state.rate = Some(FrameRate::default()); }
+        self.write_pending(&mut state, true)?;
+        let res = match state.muxer.take() {
+            Some(Muxer::Y4m(writer)) => writer.finish().map(drop),
+            Some(Muxer::Avi(writer)) => writer.finish().map(drop),
+            None => Ok(()),
+        };
+        res.map_err(|err| Error::from(err).with_path(&self.path))
+    }
+
+    fn write_pending(
+        &self, state: &mut SinkState, all: bool,
+    ) -> Result<(), Error> {
+        let rate = match state.rate {
+            Some(rate) => rate,
+            None => return Ok(()),
+        };
+        loop {
+            let next = match state.pending.keys().next() {
+                Some(&pos) if all || pos == state.next => pos,
+                _ => return Ok(()),
+            };
+            let frame = state.pending.remove(&next).expect("pending frame");
+            state.next = next + 1;
+            if let Some(frame) = frame {
+                self.write_frame(state, &frame, rate)
+                    .map_err(|err| Error::from(err).with_path(&self.path))?;
+            }
+        }
+    }
+
+    fn write_frame(
+        &self, state: &mut SinkState, frame: &VideoFrame, rate: FrameRate,
+    ) -> io::Result<()> {
+        let muxer = match state.muxer.take() {
+            Some(muxer) => muxer,
+            None => {
+                let (w, h) = (frame.width as usize, frame.height as usize);
+                let channels = if frame.is_color { 3 } else { 1 };
+                match (self.format, state.file.take()) {
+                    (Format::Avi, Some(file)) => {
+                        let writer = io::BufWriter::new(file);
+                        Muxer::Avi(AviWriter::new(writer, w, h, rate)?)
+                    },
+                    (_, file) => {
+                        let writer: Box<dyn Write + Send> = match file {
+                            Some(file) => Box::new(io::BufWriter::new(file)),
+                            None => Box::new(io::BufWriter::new(io::stdout())),
+                        };
+                        let writer =
+                            Y4mWriter::new(writer, w, h, channels, rate)?;
+                        Muxer::Y4m(writer)
+                    },
+                }
+            },
+        };
+        match state.muxer.insert(muxer) {
+            Muxer::Y4m(writer) => writer.write_frame(&frame.data),
+            Muxer::Avi(writer) => writer.write_frame(&frame.data),
+        }
+    }
+}
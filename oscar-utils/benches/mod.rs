@@ -2,19 +2,44 @@
 extern crate test;
 
 use oscar_utils;
-use oscar_utils::{bggr_bayer, WIDTH, HEIGHT};
+use oscar_utils::{bggr_bayer, mhc_bayer, ahd_bayer, CfaPattern, Geometry};
 use oscar_utils::conversions::{rgba2raw, raw2rgba_flip, raw_flip};
+use oscar_utils::simd::{self, SimdLevel};
+use oscar_utils::webp_encoder::{encode_webp, WebpMode};
+use png::HasParameters;
+
+const GEOM: Geometry = Geometry {
+    width: 2448, height: 2048, cfa: CfaPattern::Bggr,
+};
 
 #[inline(never)]
 fn get_buf() -> Vec<u8> {
-    vec![7u8; WIDTH*HEIGHT]
+    vec![7u8; GEOM.pixels()]
 }
 
 #[bench]
 fn bench_bggr_bayer(b: &mut test::Bencher) {
     let src = get_buf();
     b.iter(|| {
-        let res = bggr_bayer(&src, WIDTH, HEIGHT);
+        let res = bggr_bayer(&src, GEOM.width, GEOM.height);
+        test::black_box(res);
+    });
+}
+
+#[bench]
+fn bench_mhc_bayer(b: &mut test::Bencher) {
+    let src = get_buf();
+    b.iter(|| {
+        let res = mhc_bayer(&src, GEOM);
+        test::black_box(res);
+    });
+}
+
+#[bench]
+fn bench_ahd_bayer(b: &mut test::Bencher) {
+    let src = get_buf();
+    b.iter(|| {
+        let res = ahd_bayer(&src, GEOM);
         test::black_box(res);
     });
 }
@@ -25,7 +50,7 @@ fn bench_raw2rgba(b: &mut test::Bencher) {
     let src = get_buf();
     let mut dst = get_buf();
     b.iter(|| {
-        raw2rgba_flip(&src, &mut dst);
+        raw2rgba_flip(&src, &mut dst, GEOM);
         test::black_box(&dst);
     });
 }
@@ -35,7 +60,7 @@ fn bench_rgba2raw(b: &mut test::Bencher) {
     let src = get_buf();
     let mut dst = get_buf();
     b.iter(|| {
-        rgba2raw(&src, &mut dst);
+        rgba2raw(&src, &mut dst, GEOM);
         test::black_box(&dst);
     });
 }
@@ -44,7 +69,103 @@ fn bench_rgba2raw(b: &mut test::Bencher) {
 fn bench_flip(b: &mut test::Bencher) {
     let mut buf = get_buf();
     b.iter(|| {
-        raw_flip(&mut buf);
+        raw_flip(&mut buf, GEOM);
+        test::black_box(&buf);
+    });
+}
+
+#[bench]
+fn bench_bggr_bayer_scalar(b: &mut test::Bencher) {
+    let src = get_buf();
+    b.iter(|| {
+        let res = simd::bggr_bayer(SimdLevel::Scalar, &src, GEOM.width, GEOM.height);
+        test::black_box(res);
+    });
+}
+
+#[bench]
+fn bench_raw2rgba_scalar(b: &mut test::Bencher) {
+    let src = get_buf();
+    let mut dst = get_buf();
+    b.iter(|| {
+        simd::raw2rgba_flip(SimdLevel::Scalar, &src, &mut dst, GEOM);
+        test::black_box(&dst);
+    });
+}
+
+#[bench]
+fn bench_rgba2raw_scalar(b: &mut test::Bencher) {
+    let src = get_buf();
+    let mut dst = get_buf();
+    b.iter(|| {
+        simd::rgba2raw(SimdLevel::Scalar, &src, &mut dst, GEOM);
+        test::black_box(&dst);
+    });
+}
+
+#[bench]
+fn bench_flip_scalar(b: &mut test::Bencher) {
+    let mut buf = get_buf();
+    b.iter(|| {
+        simd::raw_flip(SimdLevel::Scalar, &mut buf, GEOM);
         test::black_box(&buf);
     });
 }
+
+/// Smooth RGB image with mild noise, closer to demosaiced frames than
+/// a constant buffer
+fn get_rgb_buf() -> Vec<u8> {
+    let (w, h) = (GEOM.width/2, GEOM.height/2);
+    let mut state = 0x1234_5678u32;
+    let mut res = Vec::with_capacity(3*w*h);
+    for y in 0..h {
+        for x in 0..w {
+            for c in 0..3 {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                let v = (x*(c + 1) + y*(3 - c))/8 + (state % 5) as usize;
+                res.push(v as u8);
+            }
+        }
+    }
+    res
+}
+
+fn encode_png(src: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    {
+        let (w, h) = (GEOM.width/2, GEOM.height/2);
+        let mut encoder = png::Encoder::new(&mut buf, w as u32, h as u32);
+        encoder.set(png::ColorType::RGB).set(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(src).unwrap();
+    }
+    buf
+}
+
+fn bench_webp(b: &mut test::Bencher, mode: WebpMode) {
+    let src = get_rgb_buf();
+    let (w, h) = (GEOM.width/2, GEOM.height/2);
+    b.iter(|| {
+        let mut buf = Vec::new();
+        encode_webp(&src, w, h, 3, mode, &mut buf).unwrap();
+        test::black_box(buf);
+    });
+}
+
+#[bench]
+fn bench_png_encoder(b: &mut test::Bencher) {
+    let src = get_rgb_buf();
+    b.iter(|| test::black_box(encode_png(&src)));
+}
+
+#[bench]
+fn bench_webp_lossless(b: &mut test::Bencher) {
+    bench_webp(b, WebpMode::Lossless);
+}
+
+#[bench]
+fn bench_webp_lossy(b: &mut test::Bencher) {
+    bench_webp(b, WebpMode::Lossy(90));
+}
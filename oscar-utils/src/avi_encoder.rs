@@ -0,0 +1,335 @@
+//! Muxer of Motion JPEG video streams into AVI files
+//!
+//! Files are split into RIFF chunks of about `MAX_RIFF_SIZE` bytes using
+//! the OpenDML extension: every chunk is indexed by a standard index and
+//! the first one is additionally indexed by the legacy `idx1` index, so
+//! players without OpenDML support can read at least its frames.
+use std::io::{self, Seek, SeekFrom, Write};
+
+use super::y4m_encoder::FrameRate;
+
+/// Size of the RIFF chunk after which the next one is started, it's
+/// exceeded only by the last frame and indexes of the chunk
+const MAX_RIFF_SIZE: u64 = 1 << 30;
+/// Number of entries reserved in the super index, i.e. maximum number of
+/// RIFF chunks
+const MAX_RIFF_CHUNKS: usize = 256;
+/// Size of the extended AVI header (`dmlh`)
+const DMLH_SIZE: usize = 248;
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIF_ISINTERLEAVED: u32 = 0x100;
+const AVIIF_KEYFRAME: u32 = 0x10;
+const AVI_INDEX_OF_INDEXES: u8 = 0;
+const AVI_INDEX_OF_CHUNKS: u8 = 1;
+const FRAME_CHUNK_ID: &[u8; 4] = b"00dc";
+
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, v: u64) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_chunk(buf: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    buf.extend_from_slice(id);
+    put_u32(buf, data.len() as u32);
+    buf.extend_from_slice(data);
+    if data.len() % 2 == 1 { buf.push(0); }
+}
+
+fn put_list(buf: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    buf.extend_from_slice(b"LIST");
+    put_u32(buf, 4 + data.len() as u32);
+    buf.extend_from_slice(kind);
+    buf.extend_from_slice(data);
+}
+
+/// Standard index or super index chunk
+fn index_chunk(
+    id: &[u8; 4], longs_per_entry: u16, kind: u8, entries: usize,
+    base: Option<u64>, data: &[u8],
+) -> Vec<u8> {
+    let mut index = Vec::with_capacity(24 + data.len());
+    put_u16(&mut index, longs_per_entry);
+    index.push(0);
+    index.push(kind);
+    put_u32(&mut index, entries as u32);
+    index.extend_from_slice(FRAME_CHUNK_ID);
+    match base {
+        Some(base) => {
+            put_u64(&mut index, base);
+            put_u32(&mut index, 0);
+        },
+        None => index.extend_from_slice(&[0; 12]),
+    }
+    index.extend_from_slice(data);
+    let mut buf = Vec::with_capacity(8 + index.len());
+    put_chunk(&mut buf, id, &index);
+    buf
+}
+
+/// RIFF chunk with its `movi` list
+struct Segment {
+    /// Position of the RIFF chunk
+    riff_pos: u64,
+    /// Position of the `movi` list
+    movi_pos: u64,
+    /// Offsets of frame chunks relative to the `movi` list type and sizes
+    /// of the frames
+    frames: Vec<(u32, u32)>,
+}
+
+/// Writer of AVI file with a single Motion JPEG stream
+pub struct AviWriter<W: Write + Seek> {
+    writer: W,
+    pos: u64,
+    width: usize,
+    height: usize,
+    rate: FrameRate,
+    /// Position of the `hdrl` list, it's rewritten by `finish`
+    header_pos: u64,
+    segment: Segment,
+    /// Frames of the first RIFF chunk indexed by `idx1`
+    first_frames: Vec<(u32, u32)>,
+    /// Positions, sizes and durations of the standard index chunks
+    indexes: Vec<(u64, u32, u32)>,
+    frames: u32,
+    max_frame_size: u32,
+}
+
+impl<W: Write + Seek> AviWriter<W> {
+    /// Write headers of the video with the given frame dimensions
+    pub fn new(
+        mut writer: W, width: usize, height: usize, rate: FrameRate,
+    ) -> io::Result<Self> {
+        if width == 0 || height == 0 {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "frame is empty"))?
+        }
+        if width > i32::MAX as usize || height > i32::MAX as usize {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame dimensions are too large for AVI",
+            ))?
+        }
+        if rate.num == 0 || rate.den == 0 {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput, "frame rate must not be zero",
+            ))?
+        }
+        let pos = writer.stream_position()?;
+        let segment = Segment { riff_pos: pos, movi_pos: 0, frames: vec![] };
+        let mut avi = Self {
+            writer, pos, width, height, rate,
+            header_pos: pos + 12,
+            segment,
+            first_frames: Vec::new(),
+            indexes: Vec::new(),
+            frames: 0,
+            max_frame_size: 0,
+        };
+        let header = avi.header();
+        avi.start_segment(b"AVI ", &header)?;
+        Ok(avi)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.writer.write_all(buf)?;
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Write value at the given position and return to the end of file
+    fn patch_u32(&mut self, pos: u64, v: u32) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(pos))?;
+        self.writer.write_all(&v.to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(self.pos))?;
+        Ok(())
+    }
+
+    /// `hdrl` list with the main AVI header and the stream header, sizes
+    /// and frame counts are updated by `finish`
+    fn header(&self) -> Vec<u8> {
+        let (width, height) = (self.width as u32, self.height as u32);
+        let (num, den) = (self.rate.num as u64, self.rate.den as u64);
+        let frame_duration = (1_000_000*den + num/2)/num;
+        let buffer_size = self.max_frame_size + 8;
+        let first_frames = self.first_frames.len() as u32;
+
+        let mut avih = Vec::with_capacity(56);
+        put_u32(&mut avih, frame_duration.min(u32::MAX as u64) as u32);
+        put_u32(&mut avih, 0);
+        put_u32(&mut avih, 0);
+        put_u32(&mut avih, AVIF_HASINDEX | AVIF_ISINTERLEAVED);
+        put_u32(&mut avih, first_frames);
+        put_u32(&mut avih, 0);
+        put_u32(&mut avih, 1);
+        put_u32(&mut avih, buffer_size);
+        put_u32(&mut avih, width);
+        put_u32(&mut avih, height);
+        avih.extend_from_slice(&[0; 16]);
+
+        let mut strh = Vec::with_capacity(56);
+        strh.extend_from_slice(b"vids");
+        strh.extend_from_slice(b"MJPG");
+        put_u32(&mut strh, 0);
+        put_u32(&mut strh, 0);
+        put_u32(&mut strh, 0);
+        put_u32(&mut strh, self.rate.den);
+        put_u32(&mut strh, self.rate.num);
+        put_u32(&mut strh, 0);
+        put_u32(&mut strh, self.frames);
+        put_u32(&mut strh, buffer_size);
+        put_u32(&mut strh, u32::MAX);
+        put_u32(&mut strh, 0);
+        put_u16(&mut strh, 0);
+        put_u16(&mut strh, 0);
+        put_u16(&mut strh, width.min(u16::MAX as u32) as u16);
+        put_u16(&mut strh, height.min(u16::MAX as u32) as u16);
+
+        // BITMAPINFOHEADER
+        let mut strf = Vec::with_capacity(40);
+        put_u32(&mut strf, 40);
+        put_u32(&mut strf, width);
+        put_u32(&mut strf, height);
+        put_u16(&mut strf, 1);
+        put_u16(&mut strf, 24);
+        strf.extend_from_slice(b"MJPG");
+        let image_size = (3*self.width*self.height).min(u32::MAX as usize);
+        put_u32(&mut strf, image_size as u32);
+        strf.extend_from_slice(&[0; 16]);
+
+        let mut entries = Vec::with_capacity(16*MAX_RIFF_CHUNKS);
+        for &(pos, size, duration) in self.indexes.iter() {
+            put_u64(&mut entries, pos);
+            put_u32(&mut entries, size);
+            put_u32(&mut entries, duration);
+        }
+        entries.resize(16*MAX_RIFF_CHUNKS, 0);
+        let indx = index_chunk(
+            b"indx", 4, AVI_INDEX_OF_INDEXES, self.indexes.len(), None,
+            &entries,
+        );
+
+        let mut strl = Vec::new();
+        put_chunk(&mut strl, b"strh", &strh);
+        put_chunk(&mut strl, b"strf", &strf);
+        strl.extend_from_slice(&indx);
+
+        let mut dmlh = vec![0; DMLH_SIZE];
+        dmlh[..4].copy_from_slice(&self.frames.to_le_bytes());
+        let mut odml = Vec::new();
+        put_chunk(&mut odml, b"dmlh", &dmlh);
+
+        let mut hdrl = Vec::new();
+        put_chunk(&mut hdrl, b"avih", &avih);
+        put_list(&mut hdrl, b"strl", &strl);
+        put_list(&mut hdrl, b"odml", &odml);
+        let mut buf = Vec::new();
+        put_list(&mut buf, b"hdrl", &hdrl);
+        buf
+    }
+
+    /// Start RIFF chunk of the given type and its `movi` list, sizes are
+    /// written by `finish_segment`
+    fn start_segment(
+        &mut self, kind: &[u8; 4], header: &[u8],
+    ) -> io::Result<()> {
+        if self.indexes.len() == MAX_RIFF_CHUNKS {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput, "video is too large for AVI",
+            ))?
+        }
+        self.segment.riff_pos = self.pos;
+        let mut buf = Vec::with_capacity(24 + header.len());
+        buf.extend_from_slice(b"RIFF");
+        put_u32(&mut buf, 0);
+        buf.extend_from_slice(kind);
+        buf.extend_from_slice(header);
+        self.segment.movi_pos = self.pos + buf.len() as u64;
+        buf.extend_from_slice(b"LIST");
+        put_u32(&mut buf, 0);
+        buf.extend_from_slice(b"movi");
+        self.write_all(&buf)
+    }
+
+    /// Write standard index of the current RIFF chunk (and `idx1` for the
+    /// first one) and sizes of its lists
+    fn finish_segment(&mut self) -> io::Result<()> {
+        let frames = std::mem::take(&mut self.segment.frames);
+        let mut entries = Vec::with_capacity(8*frames.len());
+        for &(offset, size) in frames.iter() {
+            // offsets point to the frame data
+            put_u32(&mut entries, offset + 8);
+            put_u32(&mut entries, size);
+        }
+        let base = self.segment.movi_pos + 8;
+        let ix = index_chunk(
+            b"ix00", 2, AVI_INDEX_OF_CHUNKS, frames.len(), Some(base),
+            &entries,
+        );
+        self.indexes.push((self.pos, ix.len() as u32, frames.len() as u32));
+        self.write_all(&ix)?;
+        let movi_size = self.pos - self.segment.movi_pos - 8;
+        self.patch_u32(self.segment.movi_pos + 4, movi_size as u32)?;
+
+        if self.indexes.len() == 1 {
+            let mut idx1 = Vec::with_capacity(16*frames.len());
+            for &(offset, size) in frames.iter() {
+                idx1.extend_from_slice(FRAME_CHUNK_ID);
+                put_u32(&mut idx1, AVIIF_KEYFRAME);
+                put_u32(&mut idx1, offset);
+                put_u32(&mut idx1, size);
+            }
+            let mut buf = Vec::with_capacity(8 + idx1.len());
+            put_chunk(&mut buf, b"idx1", &idx1);
+            self.write_all(&buf)?;
+            self.first_frames = frames;
+        }
+        let riff_size = self.pos - self.segment.riff_pos - 8;
+        self.patch_u32(self.segment.riff_pos + 4, riff_size as u32)
+    }
+
+    /// Write JPEG image as the next frame
+    pub fn write_frame(&mut self, jpeg: &[u8]) -> io::Result<()> {
+        let size = jpeg.len() as u64;
+        if size >= MAX_RIFF_SIZE {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput, "frame is too large for AVI",
+            ))?
+        }
+        if !self.segment.frames.is_empty()
+            && self.pos + size - self.segment.riff_pos > MAX_RIFF_SIZE
+        {
+            self.finish_segment()?;
+            self.start_segment(b"AVIX", &[])?;
+        }
+        let offset = self.pos - self.segment.movi_pos - 8;
+        self.segment.frames.push((offset as u32, size as u32));
+        self.frames += 1;
+        self.max_frame_size = self.max_frame_size.max(size as u32);
+
+        let mut buf = Vec::with_capacity(8);
+        buf.extend_from_slice(FRAME_CHUNK_ID);
+        put_u32(&mut buf, size as u32);
+        self.write_all(&buf)?;
+        self.write_all(jpeg)?;
+        if size % 2 == 1 { self.write_all(&[0])?; }
+        Ok(())
+    }
+
+    /// Write indexes, update headers and return the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.finish_segment()?;
+        let header = self.header();
+        self.writer.seek(SeekFrom::Start(self.header_pos))?;
+        self.writer.write_all(&header)?;
+        self.writer.seek(SeekFrom::Start(self.pos))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
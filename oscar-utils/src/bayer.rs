@@ -1,9 +1,154 @@
+use std::str::FromStr;
+
+use super::{CfaPattern, Geometry, Sample};
+use super::simd::{self, SimdLevel};
+
+mod ahd;
+mod mhc;
+
+pub use self::ahd::ahd_bayer;
+pub use self::mhc::mhc_bayer;
+
+/// Demosaicing algorithm
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DemosaicAlgorithm {
+    /// Bi-linear interpolation, see `bilinear_bayer`
+    Bilinear,
+    /// Malvar-He-Cutler gradient-corrected interpolation, see `mhc_bayer`
+    Mhc,
+    /// Adaptive homogeneity-directed interpolation, see `ahd_bayer`
+    Ahd,
+    /// Conversion of each 2x2 quad into a single pixel, see
+    /// `superpixel_bayer`
+    Superpixel,
+}
+
+impl FromStr for DemosaicAlgorithm {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bilinear" => Ok(DemosaicAlgorithm::Bilinear),
+            "mhc" => Ok(DemosaicAlgorithm::Mhc),
+            "ahd" => Ok(DemosaicAlgorithm::Ahd),
+            "superpixel" => Ok(DemosaicAlgorithm::Superpixel),
+            _ => Err("unexpected demosaicing algorithm"),
+        }
+    }
+}
+
+/// Demosaic image into RGB using the given algorithm
+///
+/// Note that superpixel demosaicing produces image with halved dimensions.
+pub fn demosaic<T: Sample>(
+    data: &[T], geom: Geometry, algo: DemosaicAlgorithm,
+) -> Box<[T]> {
+    match algo {
+        DemosaicAlgorithm::Bilinear => bilinear_bayer(data, geom),
+        DemosaicAlgorithm::Mhc => mhc_bayer(data, geom),
+        DemosaicAlgorithm::Ahd => ahd_bayer(data, geom),
+        DemosaicAlgorithm::Superpixel => superpixel_bayer(data, geom),
+    }
+}
+
+/// Demosaic image by converting each 2x2 quad into a single RGB pixel with
+/// averaged green values. Resulting image has halved dimensions.
+pub fn superpixel_bayer<T: Sample>(data: &[T], geom: Geometry) -> Box<[T]> {
+    let (w, h) = (geom.width, geom.height);
+    assert_eq!(data.len(), w*h);
+    let mut buf = vec![T::default(); 3*w*h/4].into_boxed_slice();
+    let [r, g1, g2, b] = geom.cfa.positions();
+    let get = |x: usize, y: usize, (dx, dy): (usize, usize)| {
+        data[(2*y + dy)*w + 2*x + dx]
+    };
+    for (y, row) in buf.chunks_exact_mut(3*w/2).enumerate() {
+        for (x, pixel) in row.chunks_exact_mut(3).enumerate() {
+            let g = get(x, y, g1).to_u32() + get(x, y, g2).to_u32();
+            pixel[0] = get(x, y, r);
+            pixel[1] = T::from_u32((g + 1) >> 1);
+            pixel[2] = get(x, y, b);
+        }
+    }
+    buf
+}
+
+/// Demosaic image using bi-linear approach, CFA pattern is taken from `geom`
+///
+/// Other patterns are reduced to BGGR by mirroring image and by swapping red
+/// and blue channels.
+pub fn bilinear_bayer<T: Sample>(data: &[T], geom: Geometry) -> Box<[T]> {
+    let (w, h) = (geom.width, geom.height);
+    assert_eq!(data.len(), w*h);
+    match geom.cfa {
+        CfaPattern::Bggr => bggr_bayer(data, w, h),
+        CfaPattern::Rggb => {
+            let mut buf = bggr_bayer(data, w, h);
+            swap_rb(&mut buf);
+            buf
+        },
+        CfaPattern::Gbrg | CfaPattern::Grbg => {
+            let mut mirrored = data.to_vec();
+            mirror_rows(&mut mirrored, w, 1);
+            let geom = Geometry { cfa: geom.cfa.mirrored(), ..geom };
+            let mut buf = bilinear_bayer(&mirrored, geom);
+            mirror_rows(&mut buf, w, 3);
+            buf
+        },
+    }
+}
+
+/// Mirror coordinate across the frame border. Resulting coordinate has the
+/// same parity, so it points to the same colour of the CFA.
+fn reflect(mut v: isize, n: usize) -> usize {
+    let n = n as isize;
+    loop {
+        if v < 0 {
+            v = -v;
+        } else if v >= n {
+            v = 2*(n - 1) - v;
+        } else {
+            return v as usize;
+        }
+    }
+}
+
+/// Swap red and blue channels of RGB image
+fn swap_rb<T>(buf: &mut [T]) {
+    for pixel in buf.chunks_exact_mut(3) {
+        pixel.swap(0, 2);
+    }
+}
+
+/// Mirror image with `n` channels along the vertical axis
+fn mirror_rows<T>(buf: &mut [T], width: usize, n: usize) {
+    for row in buf.chunks_exact_mut(n*width) {
+        for x in 0..width/2 {
+            let (a, b) = (n*x, n*(width - x - 1));
+            for c in 0..n {
+                row.swap(a + c, b + c);
+            }
+        }
+    }
+}
+
 /// Demosaic image using bi-linear approach assuming BGGR pattern
-pub fn bggr_bayer(data: &[u8], width: usize, height: usize) -> Box<[u8]> {
+///
+/// 8-bit images are processed using SIMD instructions.
+pub fn bggr_bayer<T: Sample>(
+    data: &[T], width: usize, height: usize,
+) -> Box<[T]> {
+    bggr_bayer_with(SimdLevel::detect(), data, width, height)
+}
+
+/// Version of `bggr_bayer` which uses the given SIMD instruction set for
+/// 8-bit images
+pub(crate) fn bggr_bayer_with<T: Sample>(
+    level: SimdLevel, data: &[T], width: usize, height: usize,
+) -> Box<[T]> {
     assert_eq!(data.len(), width*height);
     assert_eq!(width % 2, 0);
     assert_eq!(height % 2, 0);
-    let buf = vec![0u8; 3*width*height];
+    let buf = vec![T::default(); 3*width*height];
     let mut buf = buf.into_boxed_slice();
 
     unsafe {
@@ -15,7 +160,15 @@ pub fn bggr_bayer(data: &[u8], width: usize, height: usize) -> Box<[u8]> {
 
         for y in (1..height/2 - 1).map(|v| 2*v) {
             first_column(&mut buf, data, y, width, height);
-            for x in (1..width/2 - 1).map(|v| 2*v) {
+            // interior blocks before `start` are processed by the SIMD code
+            let bytes = (T::as_u8_slice(data), T::as_u8_slice_mut(&mut buf));
+            let start = match bytes {
+                (Some(data), Some(buf)) => {
+                    simd::bggr_core(level, buf, data, y, width)
+                },
+                _ => 2,
+            };
+            for x in (start/2..width/2 - 1).map(|v| 2*v) {
                 core(&mut buf, data, x, y, width, height);
             }
             last_column(&mut buf, data, y, width, height);
@@ -41,7 +194,7 @@ pub fn bggr_bayer(data: &[u8], width: usize, height: usize) -> Box<[u8]> {
 // g  b  g  b
 // r  g  r  g
 //    b  g  b
-unsafe fn core(buf: &mut [u8], data: &[u8], x: usize, y: usize, w: usize, h: usize) {
+unsafe fn core<T: Sample>(buf: &mut [T], data: &[T], x: usize, y: usize, w: usize, h: usize) {
     debug_assert!(buf.len() == 3*w*h);
     debug_assert!(data.len() == w*h);
     debug_assert!(x > 0);
@@ -88,22 +241,24 @@ fn get_idx(x: usize, y: usize, width: usize) -> usize {
 }
 
 #[inline(always)]
-unsafe fn get(data: &[u8], x: usize, y: usize, width: usize) -> u16 {
+unsafe fn get<T: Sample>(data: &[T], x: usize, y: usize, width: usize) -> u32 {
     let idx = get_idx(x, y, width);
-    debug_assert!(data.len() < idx);
+    debug_assert!(idx < data.len());
     debug_assert!(x < width);
-    *data.get_unchecked(idx) as u16
+    data.get_unchecked(idx).to_u32()
 }
 
 #[inline(always)]
-unsafe fn set(data: &mut [u8], x: usize, y: usize, col: u8, width: usize, val: u16) {
+unsafe fn set<T: Sample>(
+    data: &mut [T], x: usize, y: usize, col: u8, width: usize, val: u32,
+) {
     let idx = 3*get_idx(x, y, width) + col as usize;
-    debug_assert!(data.len() < idx);
+    debug_assert!(idx < data.len());
     debug_assert!(x < width);
-    *(data.get_unchecked_mut(idx)) = val as u8;
+    *(data.get_unchecked_mut(idx)) = T::from_u32(val);
 }
 
-unsafe fn first_row(buf: &mut [u8], data: &[u8], x: usize, w: usize, h: usize) {
+unsafe fn first_row<T: Sample>(buf: &mut [T], data: &[T], x: usize, w: usize, h: usize) {
     debug_assert!(buf.len() == 3*w*h);
     debug_assert!(data.len() == w*h);
     debug_assert!(x < w);
@@ -140,7 +295,7 @@ unsafe fn first_row(buf: &mut [u8], data: &[u8], x: usize, w: usize, h: usize) {
     set(buf, x+1, y+1, 2, w, (b4+b6+b11+b13)/4);
 }
 
-unsafe fn last_row(buf: &mut [u8], data: &[u8], x: usize, w: usize, h: usize) {
+unsafe fn last_row<T: Sample>(buf: &mut [T], data: &[T], x: usize, w: usize, h: usize) {
     debug_assert!(buf.len() == 3*w*h);
     debug_assert!(data.len() == w*h);
     debug_assert!(x < w);
@@ -177,7 +332,7 @@ unsafe fn last_row(buf: &mut [u8], data: &[u8], x: usize, w: usize, h: usize) {
     set(buf, x+1, y+1, 2, w, (b4+b6)/2);
 }
 
-unsafe fn first_column(buf: &mut [u8], data: &[u8], y: usize, w: usize, h: usize) {
+unsafe fn first_column<T: Sample>(buf: &mut [T], data: &[T], y: usize, w: usize, h: usize) {
     debug_assert!(buf.len() == 3*w*h);
     debug_assert!(data.len() == w*h);
     debug_assert!(y < h);
@@ -214,7 +369,7 @@ unsafe fn first_column(buf: &mut [u8], data: &[u8], y: usize, w: usize, h: usize
     set(buf, x+1, y+1, 2, w, (b4+b6+b11+b13)/4);
 }
 
-unsafe fn last_column(buf: &mut [u8], data: &[u8], y: usize, w: usize, h: usize) {
+unsafe fn last_column<T: Sample>(buf: &mut [T], data: &[T], y: usize, w: usize, h: usize) {
     debug_assert!(buf.len() == 3*w*h);
     debug_assert!(data.len() == w*h);
     debug_assert!(y < h);
@@ -251,7 +406,7 @@ unsafe fn last_column(buf: &mut [u8], data: &[u8], y: usize, w: usize, h: usize)
     set(buf, x+1, y+1, 2, w, (b4+b11)/2);
 }
 
-unsafe fn top_left_corner(buf: &mut [u8], data: &[u8], w: usize, h: usize) {
+unsafe fn top_left_corner<T: Sample>(buf: &mut [T], data: &[T], w: usize, h: usize) {
     debug_assert!(buf.len() == 3*w*h);
     debug_assert!(data.len() == w*h);
 
@@ -285,7 +440,7 @@ unsafe fn top_left_corner(buf: &mut [u8], data: &[u8], w: usize, h: usize) {
     set(buf, x+1, y+1, 2, w, (b4+b6+b11+b13)/4);
 }
 
-unsafe fn top_right_corner(buf: &mut [u8], data: &[u8], w: usize, h: usize) {
+unsafe fn top_right_corner<T: Sample>(buf: &mut [T], data: &[T], w: usize, h: usize) {
     debug_assert!(buf.len() == 3*w*h);
     debug_assert!(data.len() == w*h);
 
@@ -318,7 +473,7 @@ unsafe fn top_right_corner(buf: &mut [u8], data: &[u8], w: usize, h: usize) {
     set(buf, x+1, y+1, 2, w, (b4+b11)/2);
 }
 
-unsafe fn bottom_left_corner(buf: &mut [u8], data: &[u8], w: usize, h: usize) {
+unsafe fn bottom_left_corner<T: Sample>(buf: &mut [T], data: &[T], w: usize, h: usize) {
     debug_assert!(buf.len() == 3*w*h);
     debug_assert!(data.len() == w*h);
 
@@ -352,7 +507,7 @@ unsafe fn bottom_left_corner(buf: &mut [u8], data: &[u8], w: usize, h: usize) {
     set(buf, x+1, y+1, 2, w, (b4+b6)/2);
 }
 
-unsafe fn bottom_right_corner(buf: &mut [u8], data: &[u8], w: usize, h: usize) {
+unsafe fn bottom_right_corner<T: Sample>(buf: &mut [T], data: &[T], w: usize, h: usize) {
     debug_assert!(buf.len() == 3*w*h);
     debug_assert!(data.len() == w*h);
 
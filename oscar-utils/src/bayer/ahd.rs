@@ -0,0 +1,212 @@
+//! Adaptive homogeneity-directed demosaicing described in K. Hirakawa,
+//! T. W. Parks, "Adaptive homogeneity-directed demosaicing algorithm",
+//! IEEE Transactions on Image Processing, 2005.
+use rayon::prelude::*;
+
+use crate::{Geometry, Sample};
+use super::reflect;
+
+/// Number of output rows processed by a single task
+const STRIPE: usize = 32;
+/// Number of additional pixels around a stripe needed for computation of its
+/// pixels. Must be even to keep the CFA pattern unchanged.
+const MARGIN: usize = 6;
+
+/// Horizontal and vertical directions of interpolation
+const DIRS: usize = 2;
+
+/// Demosaic image using adaptive homogeneity-directed interpolation
+///
+/// Image is processed in parallel by horizontal stripes. Near the frame
+/// borders missing pixels are mirrored across the edge.
+pub fn ahd_bayer<T: Sample>(data: &[T], geom: Geometry) -> Box<[T]> {
+    let (w, h) = (geom.width, geom.height);
+    assert_eq!(data.len(), w*h);
+    let mut buf = vec![T::default(); 3*w*h].into_boxed_slice();
+    buf.par_chunks_mut(3*w*STRIPE)
+        .enumerate()
+        .for_each(|(i, out)| {
+            let stripe = Stripe::new(data, geom, i*STRIPE, out.len()/(3*w));
+            stripe.demosaic(out);
+        });
+    buf
+}
+
+/// Part of the raw image extended by `MARGIN` pixels on each side, values
+/// are normalized to the 0..1 range
+struct Stripe {
+    geom: Geometry,
+    /// Stride of the extended image
+    width: usize,
+    height: usize,
+    raw: Vec<f32>,
+}
+
+impl Stripe {
+    fn new<T: Sample>(data: &[T], geom: Geometry, y0: usize, rows: usize) -> Self {
+        let scale = 1.0/T::MAX as f32;
+        let m = MARGIN as isize;
+        let width = geom.width + 2*MARGIN;
+        let height = rows + 2*MARGIN;
+        let mut raw = Vec::with_capacity(width*height);
+        for y in 0..height {
+            let sy = reflect((y0 + y) as isize - m, geom.height);
+            let row = &data[sy*geom.width..(sy + 1)*geom.width];
+            for x in 0..width {
+                let v = row[reflect(x as isize - m, geom.width)];
+                raw.push(scale*v.to_u32() as f32);
+            }
+        }
+        Self { geom, width, height, raw }
+    }
+
+    fn color(&self, x: usize, y: usize) -> usize {
+        self.geom.cfa.color(x, y)
+    }
+
+    /// Interpolate green channel using horizontal and vertical
+    /// gradient-corrected filters
+    fn green(&self) -> [Vec<f32>; DIRS] {
+        let (w, raw) = (self.width, &self.raw);
+        let mut green = [raw.clone(), raw.clone()];
+        for y in 2..self.height - 2 {
+            for x in 2..w - 2 {
+                if self.color(x, y) == 1 { continue; }
+                let i = y*w + x;
+                let c = 2.0*raw[i];
+                for (d, &s) in [1, w].iter().enumerate() {
+                    let (a, b) = (raw[i - s], raw[i + s]);
+                    let g = (a + b)/2.0 + (c - raw[i - 2*s] - raw[i + 2*s])/4.0;
+                    green[d][i] = g.clamp(a.min(b), a.max(b));
+                }
+            }
+        }
+        green
+    }
+
+    /// Interpolate red and blue channels using colour differences with the
+    /// interpolated green channel
+    fn rgb(&self, green: &[f32]) -> Vec<[f32; 3]> {
+        let (w, raw) = (self.width, &self.raw);
+        let diff = |i: usize| raw[i] - green[i];
+        let mut res = vec![[0f32; 3]; w*self.height];
+        for y in 1..self.height - 1 {
+            for x in 1..w - 1 {
+                let i = y*w + x;
+                let c = raw[i];
+                let rgb = match self.color(x, y) {
+                    1 => {
+                        let hor = c + (diff(i - 1) + diff(i + 1))/2.0;
+                        let ver = c + (diff(i - w) + diff(i + w))/2.0;
+                        if self.color(x + 1, y) == 0 {
+                            [hor, c, ver]
+                        } else {
+                            [ver, c, hor]
+                        }
+                    },
+                    color => {
+                        let g = green[i];
+                        let opposite = g + (
+                            diff(i - w - 1) + diff(i - w + 1) +
+                            diff(i + w - 1) + diff(i + w + 1)
+                        )/4.0;
+                        if color == 0 { [c, g, opposite] } else { [opposite, g, c] }
+                    },
+                };
+                res[i] = [clamp(rgb[0]), clamp(rgb[1]), clamp(rgb[2])];
+            }
+        }
+        res
+    }
+
+    /// Number of neighbours of each pixel which are close to it in both
+    /// luminance and chrominance
+    fn homogeneity(&self, lab: &[Vec<[f32; 3]>; DIRS]) -> [Vec<u8>; DIRS] {
+        let w = self.width;
+        let mut res = [vec![0u8; w*self.height], vec![0u8; w*self.height]];
+        for y in 2..self.height - 2 {
+            for x in 2..w - 2 {
+                let i = y*w + x;
+                let nbs = [i - 1, i + 1, i - w, i + w];
+                let mut ldiff = [[0f32; 4]; DIRS];
+                let mut cdiff = [[0f32; 4]; DIRS];
+                for d in 0..DIRS {
+                    let p = lab[d][i];
+                    for (k, &j) in nbs.iter().enumerate() {
+                        let q = lab[d][j];
+                        ldiff[d][k] = (p[0] - q[0]).abs();
+                        cdiff[d][k] = (p[1] - q[1]).powi(2) + (p[2] - q[2]).powi(2);
+                    }
+                }
+                let leps = ldiff[0][0].max(ldiff[0][1])
+                    .min(ldiff[1][2].max(ldiff[1][3]));
+                let ceps = cdiff[0][0].max(cdiff[0][1])
+                    .min(cdiff[1][2].max(cdiff[1][3]));
+                for d in 0..DIRS {
+                    res[d][i] = (0..4)
+                        .filter(|&k| ldiff[d][k] <= leps && cdiff[d][k] <= ceps)
+                        .count() as u8;
+                }
+            }
+        }
+        res
+    }
+
+    fn demosaic<T: Sample>(&self, out: &mut [T]) {
+        let w = self.width;
+        let green = self.green();
+        let rgb = [self.rgb(&green[0]), self.rgb(&green[1])];
+        let lab = [
+            rgb[0].iter().map(to_lab).collect(),
+            rgb[1].iter().map(to_lab).collect(),
+        ];
+        let homo = self.homogeneity(&lab);
+
+        let rows = self.height - 2*MARGIN;
+        let out_rows = out.chunks_exact_mut(3*self.geom.width);
+        for (y, out_row) in (MARGIN..MARGIN + rows).zip(out_rows) {
+            let pixels = out_row.chunks_exact_mut(3);
+            for (x, pixel) in (MARGIN..MARGIN + self.geom.width).zip(pixels) {
+                let i = y*w + x;
+                let mut score = [0u32; DIRS];
+                for (d, s) in score.iter_mut().enumerate() {
+                    for j in &[i - w, i, i + w] {
+                        *s += (homo[d][j - 1] + homo[d][*j] + homo[d][j + 1]) as u32;
+                    }
+                }
+                let (h, v) = (rgb[0][i], rgb[1][i]);
+                for c in 0..3 {
+                    let val = if score[0] > score[1] {
+                        h[c]
+                    } else if score[1] > score[0] {
+                        v[c]
+                    } else {
+                        (h[c] + v[c])/2.0
+                    };
+                    pixel[c] = T::from_u32((T::MAX as f32*val + 0.5) as u32);
+                }
+            }
+        }
+    }
+}
+
+#[inline(always)]
+fn clamp(v: f32) -> f32 {
+    v.clamp(0.0, 1.0)
+}
+
+/// Convert normalized camera RGB to CIELab, RGB is assumed to be linear with
+/// sRGB primaries and D65 white point
+fn to_lab(rgb: &[f32; 3]) -> [f32; 3] {
+    const M: [[f32; 3]; 3] = [
+        [0.412453/0.950456, 0.357580/0.950456, 0.180423/0.950456],
+        [0.212671, 0.715160, 0.072169],
+        [0.019334/1.088754, 0.119193/1.088754, 0.950227/1.088754],
+    ];
+    let f = |row: &[f32; 3]| {
+        let t = row[0]*rgb[0] + row[1]*rgb[1] + row[2]*rgb[2];
+        if t > 0.008856 { t.cbrt() } else { 7.787*t + 16.0/116.0 }
+    };
+    let (fx, fy, fz) = (f(&M[0]), f(&M[1]), f(&M[2]));
+    [116.0*fy - 16.0, 500.0*(fx - fy), 200.0*(fy - fz)]
+}
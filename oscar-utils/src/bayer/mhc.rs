@@ -0,0 +1,82 @@
+//! Gradient-corrected linear interpolation described in H. S. Malvar, L. He,
+//! R. Cutler, "High-quality linear interpolation for demosaicing of
+//! Bayer-patterned color images", ICASSP 2004.
+use crate::{CfaPattern, Geometry, Sample};
+use super::reflect;
+
+/// Demosaic image using Malvar-He-Cutler 5x5 gradient-corrected filters
+///
+/// Near the frame borders missing pixels are mirrored across the edge, which
+/// preserves colour of the CFA cells.
+pub fn mhc_bayer<T: Sample>(data: &[T], geom: Geometry) -> Box<[T]> {
+    let (w, h) = (geom.width, geom.height);
+    assert_eq!(data.len(), w*h);
+    let mut buf = vec![T::default(); 3*w*h].into_boxed_slice();
+
+    for (y, row) in buf.chunks_exact_mut(3*w).enumerate() {
+        let inner_row = y >= 2 && y + 2 < h;
+        for (x, pixel) in row.chunks_exact_mut(3).enumerate() {
+            let rgb = if inner_row && x >= 2 && x + 2 < w {
+                interpolate(geom.cfa, x, y, |dx, dy| {
+                    let idx = (y as isize + dy) as usize*w
+                        + (x as isize + dx) as usize;
+                    unsafe { data.get_unchecked(idx).to_u32() as i32 }
+                })
+            } else {
+                interpolate(geom.cfa, x, y, |dx, dy| {
+                    let xr = reflect(x as isize + dx, w);
+                    let yr = reflect(y as isize + dy, h);
+                    data[yr*w + xr].to_u32() as i32
+                })
+            };
+            pixel.copy_from_slice(&rgb);
+        }
+    }
+    buf
+}
+
+/// Scale down value multiplied by 16 and clamp it to the sample range
+#[inline(always)]
+fn clip<T: Sample>(v: i32) -> T {
+    let v = (v + 8) >> 4;
+    T::from_u32(if v < 0 { 0 } else { (v as u32).min(T::MAX) })
+}
+
+/// Compute RGB value of pixel `(x, y)`, `p` returns raw value of the pixel
+/// with the given offset from the current one
+#[inline(always)]
+fn interpolate<T, F>(cfa: CfaPattern, x: usize, y: usize, p: F) -> [T; 3]
+    where T: Sample, F: Fn(isize, isize) -> i32
+{
+    let c = p(0, 0);
+    let hor = p(-1, 0) + p(1, 0);
+    let ver = p(0, -1) + p(0, 1);
+    let hor2 = p(-2, 0) + p(2, 0);
+    let ver2 = p(0, -2) + p(0, 2);
+    let diag = p(-1, -1) + p(1, -1) + p(-1, 1) + p(1, 1);
+
+    // filter coefficients from the paper are multiplied by 16
+    match cfa.color(x, y) {
+        1 => {
+            // R or B with the same colour neighbours in the current row
+            let row = 10*c + 8*hor - 2*hor2 - 2*diag + ver2;
+            // R or B with the same colour neighbours in the current column
+            let col = 10*c + 8*ver - 2*ver2 - 2*diag + hor2;
+            let g = T::from_u32(c as u32);
+            if cfa.color(x + 1, y) == 0 {
+                [clip(row), g, clip(col)]
+            } else {
+                [clip(col), g, clip(row)]
+            }
+        },
+        color => {
+            let g = 8*c + 4*(hor + ver) - 2*(hor2 + ver2);
+            let opposite = 12*c + 4*diag - 3*(hor2 + ver2);
+            if color == 0 {
+                [T::from_u32(c as u32), clip(g), clip(opposite)]
+            } else {
+                [clip(opposite), clip(g), T::from_u32(c as u32)]
+            }
+        },
+    }
+}
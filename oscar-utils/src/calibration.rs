@@ -0,0 +1,231 @@
+use std::path::Path;
+
+use super::{
+    AnyPackedFrame, AnyRawFrame, CfaPattern, Error, Geometry, PackedFrame,
+    RawFrame, Sample,
+};
+use super::conversions::{rgba2raw, raw_flip};
+use super::frame::{pack_quad, unpack_quad};
+use super::load_frames::{is_raw_pnm, load_flif_packed, load_raw_pnm};
+
+/// Minimal dark subtracted value of the flat frame, darker samples are not
+/// corrected to avoid extreme gains
+const MIN_FLAT: f32 = 1e-3;
+
+/// Master calibration frame in the packed layout (see `PackedFrame`), which
+/// stores R, G1, B and G2 samples of each quad normalized to the 0..=1 range
+#[derive(Debug, Clone)]
+pub struct MasterFrame {
+    pub geometry: Geometry,
+    pub data: Box<[f32]>,
+}
+
+impl MasterFrame {
+    pub fn from_packed<T: Sample>(frame: &PackedFrame<T>) -> Self {
+        let scale = 1.0/T::MAX as f32;
+        let mut data = Vec::with_capacity(frame.data.len());
+        for p in frame.data.chunks_exact(4) {
+            data.extend(unpack_quad(p).iter().map(|v| v.to_u32() as f32*scale));
+        }
+        Self { geometry: frame.geometry, data: data.into_boxed_slice() }
+    }
+
+    /// Load master frame from the raw PNM file in the sensor orientation
+    /// (e.g. saved from `to_raw` output) or from the packed FLIF file
+    ///
+    /// `cfa` is the CFA pattern of the sensor, i.e. before flipping.
+    pub fn load(path: &Path, cfa: CfaPattern) -> Result<Self, Error> {
+        Ok(if is_raw_pnm(path) {
+            match load_raw_pnm(path, cfa)? {
+                AnyRawFrame::U8(f) => Self::from_packed(&f.pack_flipped()),
+                AnyRawFrame::U16(f) => Self::from_packed(&f.pack_flipped()),
+            }
+        } else {
+            match load_flif_packed(path, cfa.flipped())? {
+                AnyPackedFrame::U8(f) => Self::from_packed(&f),
+                AnyPackedFrame::U16(f) => Self::from_packed(&f),
+            }
+        })
+    }
+
+    /// Convert into 16-bit raw frame in the sensor orientation
+    pub fn to_raw(&self) -> RawFrame<u16> {
+        let mut packed = vec![0u16; self.data.len()];
+        let quads = packed.chunks_exact_mut(4).zip(self.data.chunks_exact(4));
+        for (p, m) in quads {
+            let mut q = [0u16; 4];
+            for (v, m) in q.iter_mut().zip(m.iter()) {
+                *v = (m.clamp(0.0, 1.0)*u16::MAX as f32 + 0.5) as u16;
+            }
+            pack_quad(p, q);
+        }
+        let mut frame = RawFrame::empty(self.geometry);
+        rgba2raw(&packed, &mut frame.data, self.geometry);
+        raw_flip(&mut frame.data, self.geometry);
+        frame.geometry = self.geometry.flipped();
+        frame
+    }
+}
+
+/// Sum of packed frames used for building master frames by averaging
+///
+/// Samples are accumulated with 16-bit precision.
+#[derive(Debug, Clone, Default)]
+pub struct FrameSum {
+    geometry: Option<Geometry>,
+    sum: Vec<u64>,
+    frames: usize,
+}
+
+impl FrameSum {
+    fn check_geometry(&mut self, geom: Geometry) -> Result<(), Error> {
+        match self.geometry {
+            None => self.geometry = Some(geom),
+            Some(g) if g == geom => (),
+            Some(_) => {
+                let (width, height) = (geom.width, geom.height);
+                Err(Error::InvalidDimensions { width, height })?
+            },
+        }
+        Ok(())
+    }
+
+    /// Add frame to the sum, all frames must have the same geometry
+    pub fn add<T: Sample>(
+        &mut self, frame: &PackedFrame<T>,
+    ) -> Result<(), Error> {
+        self.check_geometry(frame.geometry)?;
+        if self.sum.is_empty() {
+            self.sum = vec![0; frame.data.len()];
+        }
+        let scale = (u16::MAX as u32/T::MAX) as u64;
+        let quads = self.sum.chunks_exact_mut(4)
+            .zip(frame.data.chunks_exact(4));
+        for (s, p) in quads {
+            for (s, v) in s.iter_mut().zip(unpack_quad(p).iter()) {
+                *s += v.to_u32() as u64*scale;
+            }
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Merge sums of two sets of frames
+    pub fn merge(mut self, other: Self) -> Result<Self, Error> {
+        let geom = match (self.geometry, other.geometry) {
+            (_, None) => return Ok(self),
+            (None, _) => return Ok(other),
+            (_, Some(geom)) => geom,
+        };
+        self.check_geometry(geom)?;
+        for (a, b) in self.sum.iter_mut().zip(other.sum.iter()) {
+            *a += b;
+        }
+        self.frames += other.frames;
+        Ok(self)
+    }
+
+    /// Number of summed frames
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Average of the summed frames, `None` if sum is empty
+    pub fn mean(&self) -> Option<MasterFrame> {
+        let geometry = self.geometry?;
+        let scale = 1.0/(self.frames as f64*u16::MAX as f64);
+        let data = self.sum.iter()
+            .map(|&s| (s as f64*scale) as f32)
+            .collect();
+        Some(MasterFrame { geometry, data })
+    }
+}
+
+/// Dark frame subtraction and flat-field correction of packed frames
+///
+/// Flat-field gains are normalized separately for each colour channel, so
+/// the correction does not change white balance of the frames.
+#[derive(Debug, Clone)]
+pub struct Calibration {
+    geometry: Geometry,
+    dark: Box<[f32]>,
+    gain: Box<[f32]>,
+}
+
+impl Calibration {
+    /// Create calibration from the master frames, at least one of which must
+    /// be provided. Dark frame gets subtracted from the flat frame.
+    pub fn new(
+        dark: Option<MasterFrame>, flat: Option<MasterFrame>,
+    ) -> Result<Self, Error> {
+        let geometry = match (&dark, &flat) {
+            (Some(d), Some(f)) if d.geometry != f.geometry => {
+                let (width, height) = (f.geometry.width, f.geometry.height);
+                Err(Error::InvalidDimensions { width, height })?
+            },
+            (Some(d), _) => d.geometry,
+            (None, Some(f)) => f.geometry,
+            (None, None) => panic!("no master frames"),
+        };
+        let n = geometry.pixels();
+        let dark = dark
+            .map(|d| d.data)
+            .unwrap_or_else(|| vec![0.0; n].into_boxed_slice());
+        let gain = match flat {
+            Some(flat) => {
+                let mut gain: Vec<f32> = flat.data.iter()
+                    .zip(dark.iter())
+                    .map(|(f, d)| f - d)
+                    .collect();
+                let mut means = [0f64; 4];
+                for q in gain.chunks_exact(4) {
+                    for (m, v) in means.iter_mut().zip(q.iter()) {
+                        *m += *v as f64;
+                    }
+                }
+                for m in means.iter_mut() {
+                    *m /= (n/4) as f64;
+                }
+                for q in gain.chunks_exact_mut(4) {
+                    for (v, m) in q.iter_mut().zip(means.iter()) {
+                        *v = if *v < MIN_FLAT { 1.0 } else { *m as f32/ *v };
+                    }
+                }
+                gain.into_boxed_slice()
+            },
+            None => vec![1.0; n].into_boxed_slice(),
+        };
+        Ok(Self { geometry, dark, gain })
+    }
+
+    /// Correct frame, its geometry must be equal to the geometry of master
+    /// frames
+    pub fn apply(&self, frame: &mut AnyPackedFrame) -> Result<(), Error> {
+        match frame {
+            AnyPackedFrame::U8(f) => self.apply_typed(f),
+            AnyPackedFrame::U16(f) => self.apply_typed(f),
+        }
+    }
+
+    fn apply_typed<T: Sample>(
+        &self, frame: &mut PackedFrame<T>,
+    ) -> Result<(), Error> {
+        if frame.geometry != self.geometry {
+            let (width, height) = (frame.geometry.width, frame.geometry.height);
+            Err(Error::InvalidDimensions { width, height })?
+        }
+        let max = T::MAX as f32;
+        let iter = frame.data.chunks_exact_mut(4)
+            .zip(self.dark.chunks_exact(4))
+            .zip(self.gain.chunks_exact(4));
+        for ((p, dark), gain) in iter {
+            let mut q = unpack_quad(p);
+            for ((v, d), g) in q.iter_mut().zip(dark).zip(gain) {
+                let res = (v.to_u32() as f32 - d*max)*g;
+                *v = T::from_u32((res + 0.5).clamp(0.0, max) as u32);
+            }
+            pack_quad(p, q);
+        }
+        Ok(())
+    }
+}
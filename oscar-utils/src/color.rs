@@ -0,0 +1,118 @@
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::{fs, io};
+
+use super::Sample;
+
+/// Additional bits of precision of the linear values used for lookup of the
+/// sRGB encoded samples
+const LINEAR_EXTRA_BITS: u32 = 4;
+
+/// Matrix which converts white balanced camera RGB into linear sRGB
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ColorMatrix(pub [[f32; 3]; 3]);
+
+impl Default for ColorMatrix {
+    /// Identity matrix
+    fn default() -> Self {
+        ColorMatrix([[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]])
+    }
+}
+
+/// Parses 9 row-major matrix coefficients separated by whitespace or commas,
+/// text after `#` is ignored till the end of line
+impl FromStr for ColorMatrix {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let vals = s.lines()
+            .map(|line| line.split('#').next().unwrap_or(""))
+            .flat_map(|line| {
+                line.split(|c: char| c == ',' || c.is_whitespace())
+            })
+            .filter(|v| !v.is_empty())
+            .map(|v| v.parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|_| "invalid matrix coefficient")?;
+        if vals.len() != 9 {
+            Err("colour matrix must contain 9 coefficients")?
+        }
+        let mut m = [[0f32; 3]; 3];
+        for (row, chunk) in m.iter_mut().zip(vals.chunks(3)) {
+            row.copy_from_slice(chunk);
+        }
+        Ok(ColorMatrix(m))
+    }
+}
+
+impl ColorMatrix {
+    /// Load matrix from the camera calibration file (see `FromStr`)
+    pub fn load(path: &Path) -> io::Result<Self> {
+        fs::read_to_string(path)?
+            .parse()
+            .map_err(|err| io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), err),
+            ))
+    }
+}
+
+/// sRGB transfer function applied to the linear value in the 0..=1 range
+pub fn srgb_encode(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        12.92*v
+    } else {
+        1.055*v.powf(1.0/2.4) - 0.055
+    }
+}
+
+/// sRGB encoded samples indexed by linear values, built once for 8-bit and
+/// 16-bit samples
+static SRGB_LUTS: [OnceLock<Box<[u16]>>; 2] = [
+    OnceLock::new(), OnceLock::new(),
+];
+
+fn srgb_lut<T: Sample>() -> &'static [u16] {
+    let lut_max = (1u32 << (T::BITS + LINEAR_EXTRA_BITS)) - 1;
+    SRGB_LUTS[(T::BITS > 8) as usize].get_or_init(|| {
+        (0..=lut_max)
+            .map(|i| {
+                let v = srgb_encode(i as f32/lut_max as f32);
+                (v*T::MAX as f32 + 0.5) as u16
+            })
+            .collect()
+    })
+}
+
+/// Convert interleaved RGB image from the linear camera colour space into
+/// sRGB
+///
+/// Matrix is applied in linear light using floating point arithmetic, the
+/// result is quantized with `LINEAR_EXTRA_BITS` bits over the sample
+/// precision before applying the sRGB transfer curve.
+pub fn camera_to_srgb<T: Sample>(data: &mut [T], matrix: &ColorMatrix) {
+    assert_eq!(data.len() % 3, 0);
+    let lut = srgb_lut::<T>();
+    let lut_max = (lut.len() - 1) as u32;
+
+    // fold sample to LUT index scaling into the matrix
+    let scale = lut_max as f32/T::MAX as f32;
+    let mut m = matrix.0;
+    for v in m.iter_mut().flat_map(|row| row.iter_mut()) {
+        *v *= scale;
+    }
+
+    for pixel in data.chunks_exact_mut(3) {
+        let rgb = [
+            pixel[0].to_u32() as f32,
+            pixel[1].to_u32() as f32,
+            pixel[2].to_u32() as f32,
+        ];
+        for (val, row) in pixel.iter_mut().zip(m.iter()) {
+            let v = row[0]*rgb[0] + row[1]*rgb[1] + row[2]*rgb[2];
+            let idx = (v + 0.5).clamp(0.0, lut_max as f32) as usize;
+            *val = T::from_u32(lut[idx] as u32);
+        }
+    }
+}
@@ -1,62 +1,143 @@
-use super::{WIDTH, HEIGHT};
+use super::{Geometry, Sample};
+use super::simd::{self, SimdLevel};
+
+/// Offsets of R, G1, G2 and B samples relative to the top-left pixel of
+/// 2x2 quad
+fn quad_offsets(geom: Geometry) -> [usize; 4] {
+    let mut res = [0; 4];
+    for (r, (x, y)) in res.iter_mut().zip(geom.cfa.positions().iter()) {
+        *r = y*geom.width + x;
+    }
+    res
+}
 
 /// Flips frame and converts from raw Bayer to RGBA fromat
-pub fn raw2rgba_flip(src: &[u8], dst: &mut [u8]) {
-    assert_eq!(src.len(), WIDTH*HEIGHT);
-    assert_eq!(dst.len(), WIDTH*HEIGHT);
+///
+/// CFA pattern of the source frame is taken from `geom`.
+///
+/// 8-bit frames are converted using the best SIMD instruction set supported
+/// by the CPU (see `simd::SimdLevel::detect`).
+pub fn raw2rgba_flip<T: Sample>(src: &[T], dst: &mut [T], geom: Geometry) {
+    match (T::as_u8_slice(src), T::as_u8_slice_mut(dst)) {
+        (Some(src), Some(dst)) => {
+            simd::raw2rgba_flip(SimdLevel::detect(), src, dst, geom)
+        },
+        _ => raw2rgba_flip_quads(src, dst, geom, 0),
+    }
+}
+
+/// Scalar version of `raw2rgba_flip` which converts only quads starting from
+/// the `start` one in each row of the RGBA frame
+pub(crate) fn raw2rgba_flip_quads<T: Sample>(
+    src: &[T], dst: &mut [T], geom: Geometry, start: usize,
+) {
+    let (w, h) = (geom.width, geom.height);
+    assert_eq!(src.len(), w*h);
+    assert_eq!(dst.len(), w*h);
+    // after the flip pixels inside of a quad also swap places
+    let [r_off, g1_off, g2_off, b_off] = quad_offsets(geom.flipped());
 
-    for y in 0..HEIGHT/2 {
-        for x in 0..WIDTH/2 {
-            let rgba_pos = 4*(y*WIDTH/2 + x);
-            let raw_pos = (HEIGHT - 2*y - 1)*WIDTH + (WIDTH - 2*x - 1);
+    for y in 0..h/2 {
+        for x in start..w/2 {
+            let rgba_pos = 4*(y*w/2 + x);
+            let raw_pos = (h - 2*y - 1)*w + (w - 2*x - 1);
             unsafe {
-                let b = *src.get_unchecked(raw_pos);
-                let g1 = *src.get_unchecked(raw_pos - 1);
-                let g2 = *src.get_unchecked(raw_pos - WIDTH);
-                let r = *src.get_unchecked(raw_pos - 1 - WIDTH);
+                let b = *src.get_unchecked(raw_pos - b_off);
+                let g1 = *src.get_unchecked(raw_pos - g1_off);
+                let g2 = *src.get_unchecked(raw_pos - g2_off);
+                let r = *src.get_unchecked(raw_pos - r_off);
                 *dst.get_unchecked_mut(rgba_pos + 0) = r;
                 *dst.get_unchecked_mut(rgba_pos + 1) = g1;
                 *dst.get_unchecked_mut(rgba_pos + 2) = b;
                 *dst.get_unchecked_mut(rgba_pos + 3) =
-                    g2.wrapping_sub(g1).wrapping_add(0x80);
+                    g2.wrapping_sub(g1).wrapping_add(T::HALF);
             }
         }
     }
 }
 
 /// Converts frame from RGBA to raw Bayer fromat (but does no perform flipping!)
-pub fn rgba2raw(src: &[u8], dst: &mut [u8]) {
-    assert_eq!(src.len(), WIDTH*HEIGHT);
-    assert_eq!(dst.len(), WIDTH*HEIGHT);
+///
+/// CFA pattern of the resulting frame is taken from `geom`.
+///
+/// 8-bit frames are converted using SIMD instructions.
+pub fn rgba2raw<T: Sample>(src: &[T], dst: &mut [T], geom: Geometry) {
+    match (T::as_u8_slice(src), T::as_u8_slice_mut(dst)) {
+        (Some(src), Some(dst)) => {
+            simd::rgba2raw(SimdLevel::detect(), src, dst, geom)
+        },
+        _ => rgba2raw_quads(src, dst, geom, 0),
+    }
+}
 
-    for y in 0..HEIGHT/2 {
-        for x in 0..WIDTH/2 {
-            let rgba_pos = 4*(y*WIDTH/2 + x);
-            let raw_pos = 2*WIDTH*y + 2*x;
+/// Scalar version of `rgba2raw` which converts only quads starting from the
+/// `start` one in each row of the RGBA frame
+pub(crate) fn rgba2raw_quads<T: Sample>(
+    src: &[T], dst: &mut [T], geom: Geometry, start: usize,
+) {
+    let (w, h) = (geom.width, geom.height);
+    assert_eq!(src.len(), w*h);
+    assert_eq!(dst.len(), w*h);
+    let [r_off, g1_off, g2_off, b_off] = quad_offsets(geom);
+
+    for y in 0..h/2 {
+        for x in start..w/2 {
+            let rgba_pos = 4*(y*w/2 + x);
+            let raw_pos = 2*w*y + 2*x;
             unsafe {
                 let r = *src.get_unchecked(rgba_pos + 0);
                 let g1 = *src.get_unchecked(rgba_pos + 1);
                 let b = *src.get_unchecked(rgba_pos + 2);
                 let delta = *src.get_unchecked(rgba_pos + 3);
-                let g2 = delta.wrapping_add(g1).wrapping_sub(0x80);
+                let g2 = delta.wrapping_add(g1).wrapping_sub(T::HALF);
 
-                *dst.get_unchecked_mut(raw_pos + 0) = b;
-                *dst.get_unchecked_mut(raw_pos + 1) = g1;
-                *dst.get_unchecked_mut(raw_pos + WIDTH) = g2;
-                *dst.get_unchecked_mut(raw_pos + WIDTH + 1) = r;
+                *dst.get_unchecked_mut(raw_pos + b_off) = b;
+                *dst.get_unchecked_mut(raw_pos + g1_off) = g1;
+                *dst.get_unchecked_mut(raw_pos + g2_off) = g2;
+                *dst.get_unchecked_mut(raw_pos + r_off) = r;
             }
         }
     }
 }
 
+/// Converts frame from RGBA to RGB image with halved dimensions by averaging
+/// G1 and G2 values of each pixel (i.e. performs superpixel demosaicing)
+pub fn rgba2rgb<T: Sample>(src: &[T], dst: &mut [T], geom: Geometry) {
+    assert_eq!(src.len(), geom.pixels());
+    assert_eq!(dst.len(), 3*geom.pixels()/4);
+
+    for (rgba, rgb) in src.chunks_exact(4).zip(dst.chunks_exact_mut(3)) {
+        let g1 = rgba[1];
+        let g2 = rgba[3].wrapping_add(g1).wrapping_sub(T::HALF);
+        rgb[0] = rgba[0];
+        rgb[1] = T::from_u32((g1.to_u32() + g2.to_u32() + 1) >> 1);
+        rgb[2] = rgba[2];
+    }
+}
+
 /// Performs in-place horizontal flip of raw Bayer image
-pub fn raw_flip(buf: &mut [u8]) {
-    assert_eq!(buf.len(), WIDTH*HEIGHT);
-    assert_eq!(HEIGHT % 2, 0);
-    for y in 0..HEIGHT/2 {
-        for x in 0..WIDTH {
-            let pos1 = y*WIDTH + x;
-            let pos2 = (HEIGHT - y - 1)*WIDTH + (WIDTH - x - 1);
+///
+/// Note that CFA pattern of the flipped image is equal to
+/// `geom.cfa.flipped()`. 8-bit frames are flipped using SIMD instructions.
+pub fn raw_flip<T: Sample>(buf: &mut [T], geom: Geometry) {
+    match T::as_u8_slice_mut(buf) {
+        Some(buf) => simd::raw_flip(SimdLevel::detect(), buf, geom),
+        None => raw_flip_columns(buf, geom, 0),
+    }
+}
+
+/// Scalar version of `raw_flip` which swaps only pixels starting from the
+/// `start` column in the top half of the frame
+pub(crate) fn raw_flip_columns<T: Copy>(
+    buf: &mut [T], geom: Geometry, start: usize,
+) {
+    let (w, h) = (geom.width, geom.height);
+    assert_eq!(buf.len(), w*h);
+    assert_eq!(h % 2, 0);
+    for y in 0..h/2 {
+        for x in start..w {
+            let pos1 = y*w + x;
+            let pos2 = (h - y - 1)*w + (w - x - 1);
             unsafe {
                 let t = *buf.get_unchecked(pos1);
                 *buf.get_unchecked_mut(pos1) = *buf.get_unchecked(pos2);
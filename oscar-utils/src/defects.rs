@@ -0,0 +1,260 @@
+use std::path::Path;
+use std::str::FromStr;
+use std::{fmt, fs, io};
+
+use super::{AnyPackedFrame, Error, FrameSum, Geometry, PackedFrame, Sample};
+use super::frame::{pack_quad, unpack_quad};
+
+/// Minimal fraction of frames in which pixel must be saturated or zero to
+/// be classified as stuck
+const STUCK_RATIO: f32 = 0.99;
+
+/// Index of the R, G1, G2 and B samples inside of the unpacked quad
+/// (see `frame::unpack_quad`)
+const QUAD_CHANNELS: [usize; 4] = [0, 1, 3, 2];
+
+/// Position of the raw frame pixel in the packed frame
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct PackedPos {
+    x: usize,
+    y: usize,
+    /// Channel index in the unpacked quad
+    ch: usize,
+}
+
+/// Convert coordinates of the pixel in the sensor orientation into position
+/// in the packed frame with the given geometry (i.e. after flipping)
+fn to_packed(geom: Geometry, x: usize, y: usize) -> PackedPos {
+    let (fx, fy) = (geom.width - 1 - x, geom.height - 1 - y);
+    let pos = (fx & 1, fy & 1);
+    let i = geom.cfa.positions().iter().position(|p| *p == pos).unwrap();
+    PackedPos { x: fx/2, y: fy/2, ch: QUAD_CHANNELS[i] }
+}
+
+/// Inverse of `to_packed`
+fn from_packed(geom: Geometry, p: PackedPos) -> (usize, usize) {
+    let i = QUAD_CHANNELS.iter().position(|&ch| ch == p.ch).unwrap();
+    let (dx, dy) = geom.cfa.positions()[i];
+    (geom.width - 1 - (2*p.x + dx), geom.height - 1 - (2*p.y + dy))
+}
+
+/// Median of the same colour neighbours of the packed sample, `skip` is
+/// used for exclusion of neighbours which are defective themselves
+fn neighbours_median<F, S>(
+    p: PackedPos, width: usize, height: usize, get: F, skip: S,
+) -> Option<f32>
+    where F: Fn(PackedPos) -> f32, S: Fn(PackedPos) -> bool
+{
+    let mut vals = Vec::with_capacity(8);
+    for dy in -1isize..=1 {
+        for dx in -1isize..=1 {
+            let (x, y) = (p.x as isize + dx, p.y as isize + dy);
+            if (dx == 0 && dy == 0) || x < 0 || y < 0 { continue; }
+            let (x, y) = (x as usize, y as usize);
+            if x >= width || y >= height { continue; }
+            let n = PackedPos { x, y, ch: p.ch };
+            if !skip(n) { vals.push(get(n)); }
+        }
+    }
+    if vals.is_empty() { return None; }
+    vals.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+    let n = vals.len();
+    Some(if n % 2 == 1 { vals[n/2] } else { (vals[n/2 - 1] + vals[n/2])/2.0 })
+}
+
+/// Map of defective pixels of the sensor
+///
+/// Coordinates are stored in the sensor orientation (i.e. the same as in the
+/// raw PNM frames before flipping).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DefectMap {
+    pub width: usize,
+    pub height: usize,
+    /// `(x, y)` coordinates sorted by rows, i.e. by `y` and then by `x`
+    pub pixels: Vec<(usize, usize)>,
+}
+
+/// Parses text with frame width and height on the first line followed by
+/// `x y` coordinates of defective pixels, one per line. Text after `#` is
+/// ignored till the end of line.
+impl FromStr for DefectMap {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s.lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let mut vals = line.split_whitespace().map(|v| v.parse());
+                match (vals.next(), vals.next(), vals.next()) {
+                    (Some(Ok(a)), Some(Ok(b)), None) => Ok((a, b)),
+                    _ => Err("expected two numbers per line"),
+                }
+            });
+        let (width, height) = lines.next().ok_or("empty defect map")??;
+        let mut pixels = lines.collect::<Result<Vec<(usize, usize)>, _>>()?;
+        if pixels.iter().any(|&(x, y)| x >= width || y >= height) {
+            Err("defect coordinates are out of the frame")?
+        }
+        pixels.sort_unstable_by_key(|&(x, y)| (y, x));
+        pixels.dedup();
+        Ok(Self { width, height, pixels })
+    }
+}
+
+impl fmt::Display for DefectMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# frame width and height")?;
+        writeln!(f, "{} {}", self.width, self.height)?;
+        writeln!(f, "# x y coordinates of defective pixels")?;
+        for (x, y) in self.pixels.iter() {
+            writeln!(f, "{} {}", x, y)?;
+        }
+        Ok(())
+    }
+}
+
+impl DefectMap {
+    /// Load defect map from the text file (see `FromStr`)
+    pub fn load(path: &Path) -> io::Result<Self> {
+        fs::read_to_string(path)?
+            .parse()
+            .map_err(|err| io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), err),
+            ))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    fn contains(&self, x: usize, y: usize) -> bool {
+        self.pixels.binary_search_by_key(&(y, x), |&(x, y)| (y, x)).is_ok()
+    }
+
+    /// Replace defective pixels of the frame with median of their same
+    /// colour neighbours which are not defective
+    pub fn correct(&self, frame: &mut AnyPackedFrame) -> Result<(), Error> {
+        match frame {
+            AnyPackedFrame::U8(f) => self.correct_typed(f),
+            AnyPackedFrame::U16(f) => self.correct_typed(f),
+        }
+    }
+
+    fn correct_typed<T: Sample>(
+        &self, frame: &mut PackedFrame<T>,
+    ) -> Result<(), Error> {
+        let geom = frame.geometry;
+        if (geom.width, geom.height) != (self.width, self.height) {
+            let (width, height) = (geom.width, geom.height);
+            Err(Error::InvalidDimensions { width, height })?
+        }
+        let (w, h) = geom.packed();
+        let data = &frame.data;
+        let get = |p: PackedPos| {
+            unpack_quad(&data[4*(p.y*w + p.x)..])[p.ch].to_u32() as f32
+        };
+        let skip = |n| {
+            let (x, y) = from_packed(geom, n);
+            self.contains(x, y)
+        };
+        let vals: Vec<(PackedPos, f32)> = self.pixels.iter()
+            .filter_map(|&(x, y)| {
+                let p = to_packed(geom, x, y);
+                neighbours_median(p, w, h, get, skip).map(|v| (p, v))
+            })
+            .collect();
+        for (p, v) in vals {
+            let pixel = &mut frame.data[4*(p.y*w + p.x)..][..4];
+            let mut q = unpack_quad(pixel);
+            q[p.ch] = T::from_u32((v + 0.5) as u32);
+            pack_quad(pixel, q);
+        }
+        Ok(())
+    }
+}
+
+/// Per-sample statistics of a recording used for detection of defective
+/// pixels
+#[derive(Debug, Clone, Default)]
+pub struct DefectStats {
+    sum: FrameSum,
+    saturated: Vec<u32>,
+    zero: Vec<u32>,
+}
+
+impl DefectStats {
+    /// Add frame to the statistics, all frames must have the same geometry
+    pub fn add<T: Sample>(
+        &mut self, frame: &PackedFrame<T>,
+    ) -> Result<(), Error> {
+        self.sum.add(frame)?;
+        if self.saturated.is_empty() {
+            self.saturated = vec![0; frame.data.len()];
+            self.zero = vec![0; frame.data.len()];
+        }
+        let iter = frame.data.chunks_exact(4)
+            .zip(self.saturated.chunks_exact_mut(4))
+            .zip(self.zero.chunks_exact_mut(4));
+        for ((p, sat), zero) in iter {
+            let q = unpack_quad(p);
+            for ((v, s), z) in q.iter().zip(sat).zip(zero) {
+                let v = v.to_u32();
+                if v == T::MAX { *s = s.saturating_add(1); }
+                if v == 0 { *z = z.saturating_add(1); }
+            }
+        }
+        Ok(())
+    }
+
+    /// Merge statistics of two sets of frames
+    pub fn merge(mut self, other: Self) -> Result<Self, Error> {
+        if other.frames() == 0 { return Ok(self); }
+        if self.frames() == 0 { return Ok(other); }
+        self.sum = self.sum.merge(other.sum)?;
+        let iter = self.saturated.iter_mut().zip(other.saturated)
+            .chain(self.zero.iter_mut().zip(other.zero));
+        for (a, b) in iter {
+            *a = a.saturating_add(b);
+        }
+        Ok(self)
+    }
+
+    /// Number of frames added to the statistics
+    pub fn frames(&self) -> usize {
+        self.sum.frames()
+    }
+
+    /// Detect pixels which are saturated or zero in almost all frames or
+    /// whose average value deviates from the median of their same colour
+    /// neighbours by more than `threshold` (fraction of the full range).
+    /// Returns `None` if no frames were added.
+    pub fn detect(&self, threshold: f32) -> Option<DefectMap> {
+        let mean = self.sum.mean()?;
+        let geom = mean.geometry;
+        let (w, h) = geom.packed();
+        let stuck = (self.frames() as f32*STUCK_RATIO).ceil() as u32;
+        let get = |p: PackedPos| mean.data[4*(p.y*w + p.x) + p.ch];
+
+        let mut pixels = Vec::new();
+        for y in 0..h {
+            for x in 0..w {
+                for ch in 0..4 {
+                    let p = PackedPos { x, y, ch };
+                    let i = 4*(y*w + x) + ch;
+                    let is_stuck = self.saturated[i] >= stuck
+                        || self.zero[i] >= stuck;
+                    let is_outlier = neighbours_median(p, w, h, get, |_| false)
+                        .map(|m| (mean.data[i] - m).abs() > threshold)
+                        .unwrap_or(false);
+                    if is_stuck || is_outlier {
+                        pixels.push(from_packed(geom, p));
+                    }
+                }
+            }
+        }
+        pixels.sort_unstable_by_key(|&(x, y)| (y, x));
+        Some(DefectMap { width: geom.width, height: geom.height, pixels })
+    }
+}
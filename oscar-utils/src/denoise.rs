@@ -0,0 +1,80 @@
+use super::{AnyPackedFrame, PackedFrame, Sample};
+use super::frame::{pack_quad, unpack_quad};
+
+/// Temporal denoising, which averages each sample of the frame with the same
+/// samples of the neighbouring frames
+///
+/// Neighbouring samples which differ from the sample of the denoised frame by
+/// more than `threshold` (fraction of the full range) are considered to be
+/// caused by motion and are excluded from averaging, so moving objects do not
+/// leave trails.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TemporalDenoise {
+    /// Number of preceding and following frames used for averaging
+    pub radius: usize,
+    pub threshold: f32,
+}
+
+impl TemporalDenoise {
+    /// Denoise frame using its neighbours, neighbours with different
+    /// geometry or bit depth are ignored
+    pub fn apply(
+        &self, frame: &AnyPackedFrame, neighbours: &[&AnyPackedFrame],
+    ) -> AnyPackedFrame {
+        match frame {
+            AnyPackedFrame::U8(f) => {
+                let neighbours: Vec<&PackedFrame<u8>> = neighbours.iter()
+                    .filter_map(|n| match n {
+                        AnyPackedFrame::U8(n) => Some(n),
+                        AnyPackedFrame::U16(_) => None,
+                    })
+                    .collect();
+                AnyPackedFrame::U8(self.apply_typed(f, &neighbours))
+            },
+            AnyPackedFrame::U16(f) => {
+                let neighbours: Vec<&PackedFrame<u16>> = neighbours.iter()
+                    .filter_map(|n| match n {
+                        AnyPackedFrame::U16(n) => Some(n),
+                        AnyPackedFrame::U8(_) => None,
+                    })
+                    .collect();
+                AnyPackedFrame::U16(self.apply_typed(f, &neighbours))
+            },
+        }
+    }
+
+    fn apply_typed<T: Sample>(
+        &self, frame: &PackedFrame<T>, neighbours: &[&PackedFrame<T>],
+    ) -> PackedFrame<T> {
+        let neighbours: Vec<&[T]> = neighbours.iter()
+            .filter(|n| n.geometry == frame.geometry)
+            .map(|n| &n.data[..])
+            .collect();
+        let threshold = (self.threshold*T::MAX as f32) as u32;
+        let mut res = frame.clone();
+        for (i, pixel) in res.data.chunks_exact_mut(4).enumerate() {
+            let q = unpack_quad(pixel);
+            let mut sums = [0u32; 4];
+            let mut counts = [1u32; 4];
+            for (s, v) in sums.iter_mut().zip(q.iter()) {
+                *s = v.to_u32();
+            }
+            for n in neighbours.iter() {
+                let nq = unpack_quad(&n[4*i..]);
+                for ch in 0..4 {
+                    let nv = nq[ch].to_u32();
+                    if q[ch].to_u32().abs_diff(nv) <= threshold {
+                        sums[ch] += nv;
+                        counts[ch] += 1;
+                    }
+                }
+            }
+            let mut out = q;
+            for ch in 0..4 {
+                out[ch] = T::from_u32((sums[ch] + counts[ch]/2)/counts[ch]);
+            }
+            pack_quad(pixel, out);
+        }
+        res
+    }
+}
@@ -0,0 +1,173 @@
+//! Encoder of raw CFA images into DNG files
+//!
+//! DNG is a TIFF file with additional tags, so the image is written by
+//! `tiff_encoder` as an uncompressed single channel image split into strips
+//! of about 64 KiB with the `CFA` photometric interpretation.
+use std::io::{self, Write};
+
+use super::{ColorMatrix, RawFrame, Sample, WbGains};
+use super::tiff_encoder::{
+    encode_tiff, Tag, TagValue, TiffCompression, PHOTOMETRIC_INTERPRETATION,
+};
+
+pub const MAKE: u16 = 271;
+pub const MODEL: u16 = 272;
+pub const ORIENTATION: u16 = 274;
+pub const CFA_REPEAT_PATTERN_DIM: u16 = 33421;
+pub const CFA_PATTERN: u16 = 33422;
+pub const DNG_VERSION: u16 = 50706;
+pub const DNG_BACKWARD_VERSION: u16 = 50707;
+pub const UNIQUE_CAMERA_MODEL: u16 = 50708;
+pub const CFA_PLANE_COLOR: u16 = 50710;
+pub const CFA_LAYOUT: u16 = 50711;
+pub const BLACK_LEVEL: u16 = 50714;
+pub const WHITE_LEVEL: u16 = 50717;
+pub const COLOR_MATRIX_1: u16 = 50721;
+pub const AS_SHOT_NEUTRAL: u16 = 50728;
+pub const CALIBRATION_ILLUMINANT_1: u16 = 50778;
+
+/// Value of the `PhotometricInterpretation` tag for CFA images
+const PHOTOMETRIC_CFA: u16 = 32803;
+/// Value of the `CalibrationIlluminant1` tag for D65
+const ILLUMINANT_D65: u16 = 21;
+/// Denominator of the rational tag values
+const DENOM: i32 = 10_000;
+
+type Matrix = [[f64; 3]; 3];
+
+/// Linear sRGB into CIE XYZ for the D65 white point
+const SRGB_TO_XYZ: Matrix = [
+    [0.412_456_4, 0.357_576_1, 0.180_437_5],
+    [0.212_672_9, 0.715_152_2, 0.072_175_0],
+    [0.019_333_9, 0.119_192_0, 0.950_304_1],
+];
+/// CIE XYZ of the D65 white point
+const D65: [f64; 3] = [0.950_47, 1.0, 1.088_83];
+
+fn mul(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut res = [[0.0; 3]; 3];
+    for (i, row) in res.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (0..3).map(|k| a[i][k]*b[k][j]).sum();
+        }
+    }
+    res
+}
+
+fn inverse(m: &Matrix) -> Option<Matrix> {
+    let cofactor = |i: usize, j: usize| {
+        let (i1, i2) = ((i + 1) % 3, (i + 2) % 3);
+        let (j1, j2) = ((j + 1) % 3, (j + 2) % 3);
+        m[i1][j1]*m[i2][j2] - m[i1][j2]*m[i2][j1]
+    };
+    let det: f64 = (0..3).map(|j| m[0][j]*cofactor(0, j)).sum();
+    if det.abs() < 1e-12 { return None; }
+    let mut res = [[0.0; 3]; 3];
+    for (i, row) in res.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = cofactor(j, i)/det;
+        }
+    }
+    Some(res)
+}
+
+/// Camera and capture metadata of the DNG file
+#[derive(Debug, Clone)]
+pub struct DngMetadata {
+    /// Unique non-localized name of the camera
+    pub camera: String,
+    /// Sample value of the black pixels
+    pub black_level: u32,
+    /// Matrix which converts white balanced camera RGB into linear sRGB
+    pub color_matrix: ColorMatrix,
+    /// White balance gains of the frame, saved as the as shot neutral
+    pub gains: WbGains,
+    /// Additional tags (e.g. capture time), they can't replace tags
+    /// describing the image data
+    pub tags: Vec<Tag>,
+}
+
+impl DngMetadata {
+    /// `ColorMatrix1` (CIE XYZ into camera RGB) for D65, normalized so the
+    /// largest camera channel of the D65 white is equal to one
+    fn xyz_to_camera(&self) -> io::Result<Matrix> {
+        let mut ccm = [[0.0; 3]; 3];
+        for (row, src) in ccm.iter_mut().zip(self.color_matrix.0.iter()) {
+            for (v, s) in row.iter_mut().zip(src.iter()) {
+                *v = *s as f64;
+            }
+        }
+        let (r, b) = (self.gains.red as f64, self.gains.blue as f64);
+        let gains = [[r, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, b]];
+        let camera_to_xyz = mul(&SRGB_TO_XYZ, &mul(&ccm, &gains));
+        let mut m = inverse(&camera_to_xyz).ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput, "colour matrix is not invertible",
+        ))?;
+        let max = m.iter()
+            .map(|row| row.iter().zip(D65.iter()).map(|(a, b)| a*b).sum())
+            .fold(0f64, f64::max);
+        if max > 0.0 {
+            for v in m.iter_mut().flat_map(|row| row.iter_mut()) {
+                *v /= max;
+            }
+        }
+        Ok(m)
+    }
+}
+
+/// Encode raw frame (in the output orientation, e.g. unpacked
+/// `PackedFrame`) into DNG file
+///
+/// Samples are written as is, white balance gains are saved only as
+/// metadata, so raw processors can apply them.
+pub fn encode_dng<T: Sample, W: Write>(
+    frame: &RawFrame<T>, meta: &DngMetadata, writer: W,
+) -> io::Result<()> {
+    if meta.black_level >= T::MAX {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "black level must be lower than the maximum sample value",
+        ))?
+    }
+    let geometry = frame.geometry;
+    let pattern = [(0, 0), (1, 0), (0, 1), (1, 1)].iter()
+        .map(|&(x, y)| geometry.cfa.color(x, y) as u8)
+        .collect();
+    let rational = |v: f64| ((v*DENOM as f64).round() as i32, DENOM);
+    let matrix = meta.xyz_to_camera()?.iter()
+        .flat_map(|row| row.iter())
+        .map(|&v| rational(v))
+        .collect();
+    let neutral = [1.0/meta.gains.red, 1.0, 1.0/meta.gains.blue].iter()
+        .map(|&v| rational(v as f64))
+        .map(|(n, d)| (n.max(0) as u32, d as u32))
+        .collect();
+
+    let mut tags = meta.tags.clone();
+    tags.extend_from_slice(&[
+        Tag::new(PHOTOMETRIC_INTERPRETATION, TagValue::Short(
+            vec![PHOTOMETRIC_CFA],
+        )),
+        Tag::new(MAKE, TagValue::Ascii(meta.camera.clone())),
+        Tag::new(MODEL, TagValue::Ascii(meta.camera.clone())),
+        Tag::new(ORIENTATION, TagValue::Short(vec![1])),
+        Tag::new(CFA_REPEAT_PATTERN_DIM, TagValue::Short(vec![2, 2])),
+        Tag::new(CFA_PATTERN, TagValue::Byte(pattern)),
+        Tag::new(DNG_VERSION, TagValue::Byte(vec![1, 4, 0, 0])),
+        Tag::new(DNG_BACKWARD_VERSION, TagValue::Byte(vec![1, 1, 0, 0])),
+        Tag::new(UNIQUE_CAMERA_MODEL, TagValue::Ascii(meta.camera.clone())),
+        Tag::new(CFA_PLANE_COLOR, TagValue::Byte(vec![0, 1, 2])),
+        Tag::new(CFA_LAYOUT, TagValue::Short(vec![1])),
+        Tag::new(BLACK_LEVEL, TagValue::Long(vec![meta.black_level])),
+        Tag::new(WHITE_LEVEL, TagValue::Long(vec![T::MAX])),
+        Tag::new(COLOR_MATRIX_1, TagValue::SRational(matrix)),
+        Tag::new(AS_SHOT_NEUTRAL, TagValue::Rational(neutral)),
+        Tag::new(CALIBRATION_ILLUMINANT_1, TagValue::Short(
+            vec![ILLUMINANT_D65],
+        )),
+    ]);
+    encode_tiff(
+        &frame.data, geometry.width, geometry.height, 1,
+        TiffCompression::None, &tags, writer,
+    )
+}
@@ -0,0 +1,166 @@
+use super::Sample;
+
+/// Maximum number of histogram bins, 16-bit samples are binned and mapped
+/// with linear interpolation inside of the bins
+const MAX_BINS_BITS: u32 = 12;
+
+/// Luminance of the interleaved RGB pixel
+fn luma<T: Sample>(pixel: &[T]) -> u32 {
+    let (r, g, b) = (pixel[0].to_u32(), pixel[1].to_u32(), pixel[2].to_u32());
+    (r + 2*g + b)/4
+}
+
+/// Histogram equalization of the image luminance
+///
+/// Colour images are equalized by scaling all channels of the pixel by the
+/// same factor, so hue and saturation are preserved. Without tiles the
+/// whole image uses one mapping (global equalization), otherwise mappings
+/// are computed for each tile and bilinearly interpolated between tile
+/// centres (CLAHE).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Equalization {
+    /// Tile size in pixels, `None` for the global equalization
+    pub tile: Option<usize>,
+    /// Maximum height of the histogram bins relative to their average
+    /// height, `None` disables contrast limiting
+    pub clip_limit: Option<f32>,
+}
+
+impl Equalization {
+    /// Global equalization without contrast limiting
+    pub fn global() -> Self {
+        Self { tile: None, clip_limit: None }
+    }
+
+    /// Contrast limited adaptive histogram equalization
+    pub fn clahe(tile: usize, clip_limit: f32) -> Self {
+        assert!(tile > 0 && clip_limit > 0.0);
+        Self { tile: Some(tile), clip_limit: Some(clip_limit) }
+    }
+
+    /// Equalize grayscale (`channels == 1`) or interleaved RGB
+    /// (`channels == 3`) image
+    pub fn apply<T: Sample>(
+        &self, data: &mut [T], width: usize, height: usize, channels: usize,
+    ) {
+        assert!(channels == 1 || channels == 3);
+        assert_eq!(data.len(), channels*width*height);
+        if data.is_empty() { return; }
+        let luma: Vec<u32> = data.chunks_exact(channels)
+            .map(|p| match p {
+                [v] => v.to_u32(),
+                _ => luma(p),
+            })
+            .collect();
+        let tile = self.tile.unwrap_or(usize::MAX);
+        let clip_limit = self.clip_limit;
+        let tiles = Tiles::new::<T>(&luma, width, height, tile, clip_limit);
+
+        for (i, (pixel, &y)) in data.chunks_exact_mut(channels)
+            .zip(luma.iter())
+            .enumerate()
+        {
+            let new = tiles.map(i % width, i/width, y);
+            if channels == 1 {
+                pixel[0] = T::from_u32(new);
+            } else if y == 0 {
+                for v in pixel.iter_mut() { *v = T::from_u32(new); }
+            } else {
+                let k = new as f32/y as f32;
+                for v in pixel.iter_mut() {
+                    let v2 = (v.to_u32() as f32*k + 0.5).min(T::MAX as f32);
+                    *v = T::from_u32(v2 as u32);
+                }
+            }
+        }
+    }
+}
+
+/// Equalization mappings of the image tiles
+struct Tiles {
+    tile: usize,
+    nx: usize,
+    ny: usize,
+    /// Number of low sample bits dropped for binning
+    shift: u32,
+    max: u32,
+    /// Normalized cumulative histograms of tiles, `bins + 1` values per tile
+    cdfs: Vec<f32>,
+}
+
+impl Tiles {
+    fn new<T: Sample>(
+        luma: &[u32], width: usize, height: usize, tile: usize,
+        clip_limit: Option<f32>,
+    ) -> Self {
+        let tile = tile.min(width.max(height));
+        let (nx, ny) = (width.div_ceil(tile), height.div_ceil(tile));
+        let shift = T::BITS.saturating_sub(MAX_BINS_BITS);
+        let bins = (T::MAX >> shift) as usize + 1;
+        let mut cdfs = Vec::with_capacity(nx*ny*(bins + 1));
+        let mut hist = vec![0f32; bins];
+        for ty in 0..ny {
+            for tx in 0..nx {
+                for v in hist.iter_mut() { *v = 0.0; }
+                let (x0, y0) = (tx*tile, ty*tile);
+                let x1 = (x0 + tile).min(width);
+                let y1 = (y0 + tile).min(height);
+                for row in luma[y0*width..y1*width].chunks_exact(width) {
+                    for &y in row[x0..x1].iter() {
+                        hist[(y >> shift) as usize] += 1.0;
+                    }
+                }
+                let total = ((x1 - x0)*(y1 - y0)) as f32;
+                if let Some(clip) = clip_limit {
+                    let limit = (clip*total/bins as f32).max(1.0);
+                    let mut excess = 0.0;
+                    for v in hist.iter_mut() {
+                        if *v > limit {
+                            excess += *v - limit;
+                            *v = limit;
+                        }
+                    }
+                    // clipped counts are redistributed evenly
+                    let add = excess/bins as f32;
+                    for v in hist.iter_mut() { *v += add; }
+                }
+                let mut sum = 0.0;
+                cdfs.push(0.0);
+                for v in hist.iter() {
+                    sum += v;
+                    cdfs.push(sum/total);
+                }
+            }
+        }
+        Self { tile, nx, ny, shift, max: T::MAX, cdfs }
+    }
+
+    /// Mapping of the tile, interpolated inside of the histogram bin
+    fn tile_map(&self, tx: usize, ty: usize, y: u32) -> f32 {
+        let bins = (self.max >> self.shift) as usize + 1;
+        let cdf = &self.cdfs[(ty*self.nx + tx)*(bins + 1)..];
+        let bin = (y >> self.shift) as usize;
+        let frac = ((y & ((1 << self.shift) - 1)) as f32 + 0.5)
+            /(1u32 << self.shift) as f32;
+        cdf[bin] + frac*(cdf[bin + 1] - cdf[bin])
+    }
+
+    /// New luminance of the pixel, mappings of four nearest tiles are
+    /// bilinearly interpolated
+    fn map(&self, x: usize, y: usize, luma: u32) -> u32 {
+        let coord = |pos: usize, n: usize| {
+            let f = (pos as f32 + 0.5)/self.tile as f32 - 0.5;
+            let t0 = (f.max(0.0) as usize).min(n - 1);
+            let t1 = (t0 + 1).min(n - 1);
+            (t0, t1, (f - t0 as f32).clamp(0.0, 1.0))
+        };
+        let (tx0, tx1, wx) = coord(x, self.nx);
+        let (ty0, ty1, wy) = coord(y, self.ny);
+        let top = self.tile_map(tx0, ty0, luma)*(1.0 - wx)
+            + self.tile_map(tx1, ty0, luma)*wx;
+        let bottom = self.tile_map(tx0, ty1, luma)*(1.0 - wx)
+            + self.tile_map(tx1, ty1, luma)*wx;
+        let res = (top*(1.0 - wy) + bottom*wy)*self.max as f32;
+        (res + 0.5).min(self.max as f32) as u32
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{error, fmt, io};
+
+/// Error of frame loading and decoding
+#[derive(Debug)]
+pub enum Error {
+    /// I/O error, `path` is set for errors of file operations
+    Io { path: Option<PathBuf>, err: io::Error },
+    /// Malformed or unsupported PNM header
+    InvalidHeader(&'static str),
+    /// Image data is shorter than specified by the header
+    TruncatedData,
+    /// Image dimensions which can not be used for raw frames
+    InvalidDimensions { width: usize, height: usize },
+    /// Image channels do not match the frame type (e.g. RGB PNM instead of
+    /// the single channel one or non-RGBA FLIF)
+    UnsupportedChannels(String),
+    /// Interlaced or animated FLIF image
+    UnsupportedFlif { interlaced: bool, num_frames: u32 },
+    /// Error reported by the FLIF decoder
+    Flif(flif::Error),
+}
+
+/// Classification of errors, can be used for counting failures
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum ErrorKind {
+    Io,
+    InvalidHeader,
+    TruncatedData,
+    InvalidDimensions,
+    UnsupportedChannels,
+    UnsupportedFlif,
+    Flif,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Io { .. } => ErrorKind::Io,
+            Error::InvalidHeader(_) => ErrorKind::InvalidHeader,
+            Error::TruncatedData => ErrorKind::TruncatedData,
+            Error::InvalidDimensions { .. } => ErrorKind::InvalidDimensions,
+            Error::UnsupportedChannels(_) => ErrorKind::UnsupportedChannels,
+            Error::UnsupportedFlif { .. } => ErrorKind::UnsupportedFlif,
+            Error::Flif(_) => ErrorKind::Flif,
+        }
+    }
+
+    /// Check if error is caused by the content of a frame, so processing of
+    /// other frames can continue, while I/O errors usually affect all frames
+    /// (e.g. full disk or missing permissions)
+    pub fn is_invalid_frame(&self) -> bool {
+        self.kind() != ErrorKind::Io
+    }
+
+    /// Attach path to the I/O error which does not have it
+    pub fn with_path(self, path: &Path) -> Self {
+        match self {
+            Error::Io { path: None, err } => {
+                Error::Io { path: Some(path.to_path_buf()), err }
+            },
+            err => err,
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorKind::Io => "I/O error",
+            ErrorKind::InvalidHeader => "invalid header",
+            ErrorKind::TruncatedData => "truncated data",
+            ErrorKind::InvalidDimensions => "invalid dimensions",
+            ErrorKind::UnsupportedChannels => "unsupported channels",
+            ErrorKind::UnsupportedFlif => "unsupported FLIF image",
+            ErrorKind::Flif => "FLIF decoding error",
+        })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path: Some(path), err } => {
+                write!(f, "{}: {}", path.display(), err)
+            },
+            Error::Io { path: None, err } => write!(f, "{}", err),
+            Error::InvalidHeader(msg) => {
+                write!(f, "invalid PNM header: {}", msg)
+            },
+            Error::TruncatedData => write!(f, "truncated image data"),
+            Error::InvalidDimensions { width, height } => {
+                write!(f, "invalid frame dimensions: {}x{}", width, height)
+            },
+            Error::UnsupportedChannels(msg) => {
+                write!(f, "unsupported channels: {}", msg)
+            },
+            Error::UnsupportedFlif { interlaced, num_frames } => write!(f,
+                "unsupported FLIF image: interlaced {}, number of frames {}",
+                interlaced, num_frames,
+            ),
+            Error::Flif(err) => write!(f, "FLIF decoding error: {}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io { err, .. } => Some(err),
+            Error::Flif(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io { path: None, err }
+    }
+}
+
+/// FLIF decoder reads frames from memory, so its I/O errors are caused by
+/// the frame data and not by the file system
+impl From<flif::Error> for Error {
+    fn from(err: flif::Error) -> Self {
+        match err {
+            flif::Error::Io(ref io_err)
+                if io_err.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                Error::TruncatedData
+            },
+            err => Error::Flif(err),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io { path: None, err } => err,
+            err => {
+                let kind = match err {
+                    Error::Io { ref err, .. } => err.kind(),
+                    _ => io::ErrorKind::InvalidData,
+                };
+                io::Error::new(kind, err)
+            },
+        }
+    }
+}
+
+/// Thread-safe counter of frames which failed to process, grouped by the
+/// error kind
+///
+/// Invalid frames are skipped, while the first I/O error aborts processing.
+#[derive(Default)]
+pub struct ErrorStats {
+    counts: Mutex<BTreeMap<ErrorKind, usize>>,
+    fatal: Mutex<Option<Error>>,
+}
+
+impl ErrorStats {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Print and count error of the given frame
+    pub fn report(&self, frame: &dyn fmt::Display, err: Error) {
+        eprintln!("Error: {} {}", frame, err);
+        *self.counts.lock().unwrap().entry(err.kind()).or_insert(0) += 1;
+        if !err.is_invalid_frame() {
+            let mut fatal = self.fatal.lock().unwrap();
+            if fatal.is_none() {
+                *fatal = Some(err);
+            }
+        }
+    }
+
+    /// Check if processing of the remaining frames should be stopped
+    pub fn is_aborted(&self) -> bool {
+        self.fatal.lock().unwrap().is_some()
+    }
+
+    /// Number of failed frames for each error kind
+    pub fn counts(&self) -> BTreeMap<ErrorKind, usize> {
+        self.counts.lock().unwrap().clone()
+    }
+
+    /// Print number of failed frames and return the error which aborted
+    /// processing
+    pub fn finish(self) -> Result<(), Error> {
+        let counts = self.counts.into_inner().unwrap();
+        if !counts.is_empty() {
+            let total: usize = counts.values().sum();
+            let kinds: Vec<String> = counts.iter()
+                .map(|(kind, n)| format!("{} {}", kind, n))
+                .collect();
+            eprintln!("Failed frames: {} ({})", total, kinds.join(", "));
+        }
+        match self.fatal.into_inner().unwrap() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! Lossless encoder of RGBA FLIF images
+//!
+//! The encoder produces non-interlaced images with the YCoCg transformation
+//! and without bounds and channel compaction transformations (same as
+//! `flif -N -B -C`), so they can be decoded by `load_frames::decode_flif`.
+//!
+//! Only 8-bit frames are supported: the `flif` crate used for decoding can't
+//! read 16-bit images, so their bit compatibility couldn't be verified.
+//! 16-bit frames have to be encoded with the external `flif` tool.
+use std::io::{self, Write};
+
+use super::PackedFrame;
+use self::maniac::{Pvec, Sample, Tree, MAX_PROPS};
+use self::rac::{RacOutput, UpdateTable};
+
+mod maniac;
+mod rac;
+
+/// Maximum number of samples per channel used for the tree learning
+const LEARN_SAMPLES: usize = 1 << 18;
+
+/// Channels in the order of coding
+const CHANNEL_ORDER: [usize; 4] = [A, Y, CO, CG];
+const Y: usize = 0;
+const CO: usize = 1;
+const CG: usize = 2;
+const A: usize = 3;
+/// Maximum value of the Y channel and maximum absolute value of Co and Cg
+const YCOCG_MAX: i32 = 255;
+
+/// Image planes after the YCoCg transformation
+struct Planes {
+    width: usize,
+    data: [Vec<i16>; 4],
+}
+
+impl Planes {
+    fn new(rgba: &[u8], width: usize) -> Self {
+        let n = rgba.len()/4;
+        let mut data = [
+            Vec::with_capacity(n), Vec::with_capacity(n),
+            Vec::with_capacity(n), Vec::with_capacity(n),
+        ];
+        for pixel in rgba.chunks_exact(4) {
+            let (r, g, b) = (pixel[0] as i16, pixel[1] as i16, pixel[2] as i16);
+            let co = r - b;
+            let t = b + (co >> 1);
+            let cg = g - t;
+            data[Y].push(t + (cg >> 1));
+            data[CO].push(co);
+            data[CG].push(cg);
+            data[A].push(pixel[3] as i16);
+        }
+        Self { width, data }
+    }
+
+    /// Range of the channel values
+    fn range(chan: usize) -> (i32, i32) {
+        match chan {
+            CO | CG => (-YCOCG_MAX, YCOCG_MAX),
+            _ => (0, 255),
+        }
+    }
+
+    /// Range of the channel values at the given pixel, it depends on values
+    /// of the previously decoded channels
+    fn crange(&self, chan: usize, pos: usize) -> (i32, i32) {
+        let max = YCOCG_MAX;
+        let origmax4 = (max + 1)/4;
+        let y = self.data[Y][pos] as i32;
+        match chan {
+            CO => if y < origmax4 - 1 {
+                (-3 - 4*y, 3 + 4*y)
+            } else if y > 3*origmax4 - 1 {
+                (4*(y - max), 4*origmax4 - 4*(1 + y - 3*origmax4))
+            } else {
+                (-max, max)
+            },
+            CG => {
+                let co = (self.data[CO][pos] as i32).abs();
+                if y < origmax4 - 1 {
+                    (-(2*y + 1), 1 + 2*y - (co/2)*2)
+                } else if y > 3*origmax4 - 1 {
+                    (
+                        -(2*(4*origmax4 - 1 - y) - ((1 + co)/2)*2),
+                        2*(4*origmax4 - 1 - y),
+                    )
+                } else {
+                    (
+                        -(2*origmax4 - 1 + (y - origmax4 + 1)*2)
+                            .min(2*origmax4 + (3*origmax4 - 1 - y)*2
+                                - ((1 + co)/2)*2),
+                        -(-4*origmax4 + (1 + y - 2*origmax4)*2)
+                            .max(-2*origmax4 - (y - origmax4)*2 - 1
+                                + (co/2)*2),
+                    )
+                }
+            },
+            _ => Self::range(chan),
+        }
+    }
+
+    /// Ranges of the properties used for coding of the channel
+    fn prange(chan: usize) -> Vec<(i32, i32)> {
+        let mut res = Vec::with_capacity(MAX_PROPS);
+        if chan == CO || chan == CG { res.push(Self::range(Y)); }
+        if chan == CG { res.push(Self::range(CO)); }
+        if chan != A { res.push(Self::range(A)); }
+        let (min, max) = Self::range(chan);
+        res.push((min, max));
+        res.push((0, 2));
+        for _ in 0..5 {
+            res.push((min - max, max - min));
+        }
+        res
+    }
+
+    /// Compute the snapped prediction, property vector and range of the value
+    /// at the given pixel
+    fn predict(&self, chan: usize, x: usize, y: usize) -> (i32, Pvec, (i32, i32)) {
+        let w = self.width;
+        let pos = y*w + x;
+        let plane = &self.data[chan];
+        let get = |cond: bool, p: usize| {
+            if cond { Some(plane[p] as i32) } else { None }
+        };
+        let left = get(x > 0, pos.wrapping_sub(1));
+        let left2 = get(x > 1, pos.wrapping_sub(2));
+        let top = get(y > 0, pos.wrapping_sub(w));
+        let top2 = get(y > 1, pos.wrapping_sub(2*w));
+        let top_left = get(x > 0 && y > 0, pos.wrapping_sub(w + 1));
+        let top_right = get(y > 0 && x + 1 < w, (pos + 1).wrapping_sub(w));
+
+        let l = left.or(top).unwrap_or(Self::range(chan).0);
+        let t = top.unwrap_or(l);
+        let tl = top_left.or(top).unwrap_or(l);
+        let guess = median3(l + t - tl, l, t);
+
+        let range = self.crange(chan, pos);
+        let pred = guess.max(range.0).min(range.1);
+
+        let mut pvec = [0i16; MAX_PROPS];
+        let mut i = 0;
+        let mut push = |v: i32| { pvec[i] = v as i16; i += 1; };
+        if chan == CO || chan == CG { push(self.data[Y][pos] as i32); }
+        if chan == CG { push(self.data[CO][pos] as i32); }
+        if chan != A { push(self.data[A][pos] as i32); }
+        push(pred);
+        push(match (left, top, top_left) {
+            (Some(l), Some(t), Some(tl)) if pred == l + t - tl => 0,
+            (Some(l), Some(_), Some(_)) if pred == l => 1,
+            (Some(_), Some(t), Some(_)) if pred == t => 2,
+            _ => 0,
+        });
+        let diff = |a: Option<i32>, b: Option<i32>| match (a, b) {
+            (Some(a), Some(b)) => a - b,
+            _ => 0,
+        };
+        push(diff(left, top_left));
+        push(diff(top_left, top));
+        push(diff(top, top_right));
+        push(diff(top2, top));
+        push(diff(left2, left));
+
+        (pred, pvec, range)
+    }
+}
+
+fn median3(a: i32, b: i32, c: i32) -> i32 {
+    a.min(b).max(a.max(b).min(c))
+}
+
+fn write_varint<W: Write>(mut w: W, val: u32) -> io::Result<()> {
+    let mut buf = [0u8; 5];
+    let mut n = buf.len() - 1;
+    let mut val = val;
+    buf[n] = (val & 0x7F) as u8;
+    while val > 0x7F {
+        val >>= 7;
+        n -= 1;
+        buf[n] = 0x80 | (val & 0x7F) as u8;
+    }
+    w.write_all(&buf[n..])
+}
+
+/// Encode packed 8-bit frame into lossless RGBA FLIF image
+pub fn encode_flif_packed<W: Write>(
+    frame: &PackedFrame<u8>, mut writer: W,
+) -> io::Result<()> {
+    let (width, height) = frame.geometry.packed();
+    let planes = Planes::new(&frame.data, width);
+
+    // non-interlaced RGBA image with 8-bit channels without metadata
+    writer.write_all(b"FLIF\x341")?;
+    write_varint(&mut writer, width as u32 - 1)?;
+    write_varint(&mut writer, height as u32 - 1)?;
+    writer.write_all(&[0])?;
+
+    let mut rac = RacOutput::new(writer, UpdateTable::new(19, 2));
+    // alpha zero and custom cutoff flags
+    rac.write_bit(false)?;
+    rac.write_bit(false)?;
+    // YCoCg transformation
+    rac.write_bit(true)?;
+    rac.write_val(0, 13, 1)?;
+    rac.write_bit(false)?;
+
+    let step = (width*height).div_ceil(LEARN_SAMPLES);
+    let mut trees: Vec<Tree> = Vec::with_capacity(4);
+    for chan in 0..4 {
+        let mut samples = Vec::with_capacity(width*height/step + 1);
+        for pos in (0..width*height).step_by(step) {
+            let (x, y) = (pos % width, pos / width);
+            let (pred, pvec, (min, max)) = planes.predict(chan, x, y);
+            if min == max { continue; }
+            let residual = (planes.data[chan][pos] as i32 - pred) as i16;
+            samples.push(Sample { pvec, residual });
+        }
+        let tree = if samples.is_empty() {
+            Tree::single()
+        } else {
+            Tree::learn(&samples, &Planes::prange(chan))
+        };
+        tree.write(&mut rac, &Planes::prange(chan))?;
+        trees.push(tree);
+    }
+
+    for &chan in CHANNEL_ORDER.iter() {
+        let mut coder = trees[chan].coder();
+        for y in 0..height {
+            for x in 0..width {
+                let (pred, pvec, (min, max)) = planes.predict(chan, x, y);
+                if min == max { continue; }
+                let val = planes.data[chan][y*width + x] as i32;
+                coder.write(&mut rac, &pvec, min - pred, max - pred, val - pred)?;
+            }
+        }
+    }
+    // no checksum
+    rac.write_bit(false)?;
+    rac.finish()?.flush()
+}
@@ -0,0 +1,274 @@
+//! MANIAC context trees: learning, serialization and coding of values
+use std::io::{self, Write};
+
+use super::rac::{ChanceTable, RacOutput};
+
+/// Maximum number of properties in the property vector
+pub(super) const MAX_PROPS: usize = 10;
+pub(super) type Pvec = [i16; MAX_PROPS];
+
+/// Number of values coded with the parent context before the split
+const SPLIT_COUNTER: i32 = 32;
+const MAX_DEPTH: usize = 16;
+const MAX_NODES: usize = 4096;
+/// Minimum number of samples on each side of a split
+const MIN_SAMPLES: u32 = 64;
+/// Minimum estimated gain of a split in bits
+const MIN_GAIN: f64 = 128.0;
+
+/// Number of residual classes: zero and (sign, exponent) pairs
+const CLASSES: usize = 1 + 2*16;
+
+#[derive(Debug, Clone)]
+enum Node {
+    Leaf,
+    Split { prop: usize, value: i32, left: usize, right: usize },
+}
+
+/// Residual of a value coded with the given property vector
+pub(super) struct Sample {
+    pub pvec: Pvec,
+    pub residual: i16,
+}
+
+fn class(residual: i16) -> usize {
+    if residual == 0 { return 0; }
+    let exp = 15 - (residual.unsigned_abs()).leading_zeros() as usize;
+    1 + exp + if residual > 0 { 16 } else { 0 }
+}
+
+type Stats = [u32; CLASSES];
+
+/// Estimation of the number of bits required for coding residuals with the
+/// given class counts by the near-zero coder
+fn cost(stats: &Stats, xlogx: &[f64]) -> f64 {
+    let entropy = |counts: &[u32]| {
+        let total: u32 = counts.iter().sum();
+        xlogx[total as usize] - counts.iter()
+            .map(|&c| xlogx[c as usize])
+            .sum::<f64>()
+    };
+    let nonzero: u32 = stats[1..].iter().sum();
+    let (neg, pos) = stats[1..].split_at(16);
+    let signs = [neg.iter().sum(), pos.iter().sum()];
+    let mantissa: u32 = neg.iter().chain(pos.iter())
+        .enumerate()
+        .map(|(i, &c)| (i as u32 % 16)*c)
+        .sum();
+    entropy(&[stats[0], nonzero]) + entropy(&signs)
+        + entropy(neg) + entropy(pos) + mantissa as f64
+}
+
+/// Context tree of a single channel
+pub(super) struct Tree {
+    nodes: Vec<Node>,
+}
+
+impl Tree {
+    /// Build tree which splits the given samples into contexts with the
+    /// lowest estimated coding cost. `prange` contains ranges of properties.
+    pub fn learn(samples: &[Sample], prange: &[(i32, i32)]) -> Self {
+        let mut xlogx = vec![0f64; samples.len() + 1];
+        for (n, v) in xlogx.iter_mut().enumerate().skip(1) {
+            *v = n as f64*(n as f64).log2();
+        }
+        let mut learner = Learner {
+            samples, xlogx,
+            root_prange: prange,
+            nodes: vec![Node::Leaf],
+            hist: Vec::new(),
+        };
+        let mut indices: Vec<u32> = (0..samples.len() as u32).collect();
+        let mut stack = vec![(0, 0, indices.len(), prange.to_vec(), 0)];
+        while let Some((node, start, end, prange, depth)) = stack.pop() {
+            if depth >= MAX_DEPTH || learner.nodes.len() + 2 > MAX_NODES {
+                continue;
+            }
+            let idx = &mut indices[start..end];
+            let (prop, value) = match learner.best_split(idx, &prange) {
+                Some(v) => v,
+                None => continue,
+            };
+            // move samples with property bigger than `value` to the front
+            let mut mid = 0;
+            for i in 0..idx.len() {
+                if samples[idx[i] as usize].pvec[prop] as i32 > value {
+                    idx.swap(i, mid);
+                    mid += 1;
+                }
+            }
+            let left = learner.nodes.len();
+            learner.nodes.push(Node::Leaf);
+            learner.nodes.push(Node::Leaf);
+            learner.nodes[node] = Node::Split {
+                prop, value, left, right: left + 1,
+            };
+
+            let mut left_prange = prange.clone();
+            left_prange[prop].0 = value + 1;
+            let mut right_prange = prange;
+            right_prange[prop].1 = value;
+            stack.push((left + 1, start + mid, end, right_prange, depth + 1));
+            stack.push((left, start, start + mid, left_prange, depth + 1));
+        }
+        Self { nodes: learner.nodes }
+    }
+
+    /// Tree with a single context
+    pub fn single() -> Self {
+        Self { nodes: vec![Node::Leaf] }
+    }
+
+    /// Write tree in the pre-order traversal
+    pub fn write<W: Write>(
+        &self, rac: &mut RacOutput<W>, prange: &[(i32, i32)],
+    ) -> io::Result<()> {
+        let mut tables = [ChanceTable::new(), ChanceTable::new(), ChanceTable::new()];
+        let n = prange.len() as i32;
+        let mut stack = vec![(0, prange.to_vec())];
+        while let Some((node, prange)) = stack.pop() {
+            match self.nodes[node] {
+                Node::Leaf => rac.write_near_zero(&mut tables[0], 0, n, 0)?,
+                Node::Split { prop, value, left, right } => {
+                    let (min, max) = prange[prop];
+                    rac.write_near_zero(&mut tables[0], 0, n, prop as i32 + 1)?;
+                    rac.write_near_zero(&mut tables[1], 1, 512, SPLIT_COUNTER)?;
+                    rac.write_near_zero(&mut tables[2], min, max - 1, value)?;
+
+                    let mut left_prange = prange.clone();
+                    left_prange[prop].0 = value + 1;
+                    let mut right_prange = prange;
+                    right_prange[prop].1 = value;
+                    stack.push((right, right_prange));
+                    stack.push((left, left_prange));
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Create coder which uses the tree in the same way as decoders do
+    pub fn coder(&self) -> TreeCoder<'_> {
+        let mut states = vec![State::Inactive; self.nodes.len()];
+        states[0] = State::Active(ChanceTable::new(), SPLIT_COUNTER);
+        TreeCoder { tree: self, states }
+    }
+}
+
+struct Learner<'a> {
+    samples: &'a [Sample],
+    xlogx: Vec<f64>,
+    root_prange: &'a [(i32, i32)],
+    nodes: Vec<Node>,
+    /// Class counts for each value of the property
+    hist: Vec<Stats>,
+}
+
+impl<'a> Learner<'a> {
+    /// Find split `(property, value)` with the biggest gain
+    fn best_split(
+        &mut self, idx: &[u32], prange: &[(i32, i32)],
+    ) -> Option<(usize, i32)> {
+        if (idx.len() as u32) < 2*MIN_SAMPLES { return None; }
+        let mut total = [0u32; CLASSES];
+        for &i in idx {
+            total[class(self.samples[i as usize].residual)] += 1;
+        }
+        let base = cost(&total, &self.xlogx);
+
+        let mut best = None;
+        let mut best_cost = base - MIN_GAIN;
+        for (prop, &(min, max)) in prange.iter().enumerate() {
+            if min >= max { continue; }
+            let offset = self.root_prange[prop].0;
+            let len = (max - offset + 1) as usize;
+            self.hist.clear();
+            self.hist.resize(len, [0; CLASSES]);
+            for &i in idx {
+                let s = &self.samples[i as usize];
+                let v = (s.pvec[prop] as i32 - offset) as usize;
+                self.hist[v][class(s.residual)] += 1;
+            }
+
+            // `left` accumulates samples with property bigger than `value`
+            let mut left = [0u32; CLASSES];
+            let mut left_n = 0;
+            for value in (min..max).rev() {
+                let h = &self.hist[(value + 1 - offset) as usize];
+                for (l, c) in left.iter_mut().zip(h.iter()) {
+                    *l += c;
+                    left_n += c;
+                }
+                if left_n < MIN_SAMPLES { continue; }
+                if idx.len() as u32 - left_n < MIN_SAMPLES { break; }
+                let mut right = total;
+                for (r, l) in right.iter_mut().zip(left.iter()) {
+                    *r -= l;
+                }
+                let c = cost(&left, &self.xlogx) + cost(&right, &self.xlogx);
+                if c < best_cost {
+                    best_cost = c;
+                    best = Some((prop, value));
+                }
+            }
+        }
+        best
+    }
+}
+
+#[derive(Clone)]
+enum State {
+    Inactive,
+    /// Node codes values with its own context, when counter reaches zero
+    /// split nodes pass the context to their children
+    Active(ChanceTable, i32),
+    /// Split node with active children
+    Passed,
+}
+
+/// Coder of values which tracks state of the tree contexts
+pub(super) struct TreeCoder<'a> {
+    tree: &'a Tree,
+    states: Vec<State>,
+}
+
+impl<'a> TreeCoder<'a> {
+    pub fn write<W: Write>(
+        &mut self, rac: &mut RacOutput<W>, pvec: &Pvec,
+        min: i32, max: i32, val: i32,
+    ) -> io::Result<()> {
+        let mut node = 0;
+        loop {
+            let (prop, value, left, right) = match self.tree.nodes[node] {
+                Node::Leaf => match &mut self.states[node] {
+                    State::Active(table, _) => {
+                        return rac.write_near_zero(table, min, max, val);
+                    },
+                    _ => unreachable!("inactive leaf"),
+                },
+                Node::Split { prop, value, left, right } => {
+                    (prop, value, left, right)
+                },
+            };
+            let next = if pvec[prop] as i32 > value { left } else { right };
+            match &mut self.states[node] {
+                State::Passed => node = next,
+                State::Active(table, counter) if *counter > 0 => {
+                    *counter -= 1;
+                    return rac.write_near_zero(table, min, max, val);
+                },
+                State::Active(table, _) => {
+                    let mut tables = [table.clone(), table.clone()];
+                    let t = if next == left { 0 } else { 1 };
+                    rac.write_near_zero(&mut tables[t], min, max, val)?;
+                    let [left_table, right_table] = tables;
+                    self.states[left] = State::Active(left_table, SPLIT_COUNTER);
+                    self.states[right] = State::Active(right_table, SPLIT_COUNTER);
+                    self.states[node] = State::Passed;
+                    return Ok(());
+                },
+                State::Inactive => unreachable!("inactive node"),
+            }
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! Range coder and adaptive bit chances compatible with FLIF decoders
+use std::io::{self, Write};
+
+const MIN_RANGE_BITS: u32 = 16;
+const MIN_RANGE: u32 = 1 << MIN_RANGE_BITS;
+const MAX_RANGE: u32 = 1 << 24;
+
+/// Table of chance updates after coding a bit (see `UpdateTable::new` in the
+/// `flif` crate)
+pub(super) struct UpdateTable {
+    updates: Box<[u16]>,
+}
+
+impl UpdateTable {
+    pub fn new(alpha_divisor: u8, cutoff: u8) -> Self {
+        let mut updates = vec![0u16; 4096];
+        let max_chance = 4096 - cutoff as u16;
+        let mut old_chance = 0u16;
+        let mut acc: u64 = 1 << 31;
+        for _ in 0..2048 {
+            let mut new_chance = (acc >> 20) as u16
+                + if acc & 0x8_0000 != 0 { 1 } else { 0 };
+            if new_chance <= old_chance {
+                new_chance = old_chance + 1;
+            }
+            if old_chance != 0 && (old_chance as usize) < updates.len()
+                && new_chance <= max_chance
+            {
+                updates[old_chance as usize] = new_chance;
+            }
+            acc += Self::step(acc, alpha_divisor);
+            old_chance = new_chance;
+        }
+
+        const ONE: u64 = 1 << 32;
+        for old_chance in cutoff as u16..=max_chance {
+            if updates[old_chance as usize] != 0 { continue; }
+            let mut new_chance = (old_chance as u64*ONE + 2048)/4096;
+            new_chance += Self::step(new_chance, alpha_divisor);
+            new_chance = (4096*new_chance + ONE/2) >> 32;
+            new_chance = new_chance
+                .max(old_chance as u64 + 1)
+                .min(max_chance as u64);
+            updates[old_chance as usize] = new_chance as u16;
+        }
+        Self { updates: updates.into_boxed_slice() }
+    }
+
+    fn step(old: u64, alpha_divisor: u8) -> u64 {
+        const MAX: u64 = u32::MAX as u64;
+        let v = (MAX - old + 1)*(MAX/alpha_divisor as u64);
+        if v & 0xFFFF_FFFF != 0 { (v + 1) >> 32 } else { v >> 32 }
+    }
+
+    #[inline(always)]
+    fn next(&self, bit: bool, chance: u16) -> u16 {
+        if bit {
+            self.updates[chance as usize]
+        } else {
+            4096 - self.updates[(4096 - chance) as usize]
+        }
+    }
+}
+
+const EXP_TABLE: [u16; 8] = [1000, 1200, 1500, 1750, 2000, 2300, 2800, 2400];
+const MANT_TABLE: [u16; 8] = [1900, 1850, 1800, 1750, 1650, 1600, 1600, 2048];
+
+const ZERO: usize = 0;
+const SIGN: usize = 1;
+const EXP: usize = 2;
+const MANT: usize = 18;
+
+/// Adaptive chances of the near-zero integer coder for 8-bit images
+#[derive(Clone)]
+pub(super) struct ChanceTable {
+    chances: [u16; 26],
+}
+
+impl ChanceTable {
+    pub fn new() -> Self {
+        let mut chances = [0; 26];
+        chances[ZERO] = 1000;
+        chances[SIGN] = 2048;
+        chances[EXP..EXP + 8].copy_from_slice(&EXP_TABLE);
+        chances[EXP + 8..EXP + 16].copy_from_slice(&EXP_TABLE);
+        chances[MANT..].copy_from_slice(&MANT_TABLE);
+        Self { chances }
+    }
+}
+
+/// Range encoder which outputs bytes with the carry propagation
+pub(super) struct RacOutput<W: Write> {
+    writer: W,
+    updates: UpdateTable,
+    range: u32,
+    low: u32,
+    delayed_byte: Option<u8>,
+    delayed_count: usize,
+}
+
+impl<W: Write> RacOutput<W> {
+    pub fn new(writer: W, updates: UpdateTable) -> Self {
+        Self {
+            writer, updates,
+            range: MAX_RANGE,
+            low: 0,
+            delayed_byte: None,
+            delayed_count: 0,
+        }
+    }
+
+    fn write_delayed(&mut self, byte: u8, fill: u8) -> io::Result<()> {
+        self.writer.write_all(&[byte])?;
+        for _ in 0..self.delayed_count {
+            self.writer.write_all(&[fill])?;
+        }
+        self.delayed_count = 0;
+        Ok(())
+    }
+
+    fn output(&mut self) -> io::Result<()> {
+        while self.range <= MIN_RANGE {
+            let byte = self.low >> MIN_RANGE_BITS;
+            match self.delayed_byte {
+                None => self.delayed_byte = Some(byte as u8),
+                // carry can not reach the delayed byte anymore
+                Some(d) if (self.low + self.range) >> 8 < MIN_RANGE => {
+                    self.write_delayed(d, 0xFF)?;
+                    self.delayed_byte = Some(byte as u8);
+                },
+                // carry has reached the delayed byte
+                Some(d) if self.low >> 8 >= MIN_RANGE => {
+                    self.write_delayed(d.wrapping_add(1), 0x00)?;
+                    self.delayed_byte = Some(byte as u8);
+                },
+                // the byte is 0xFF and may still overflow
+                Some(_) => self.delayed_count += 1,
+            }
+            self.low = (self.low & (MIN_RANGE - 1)) << 8;
+            self.range <<= 8;
+        }
+        Ok(())
+    }
+
+    fn put(&mut self, chance: u32, bit: bool) -> io::Result<()> {
+        debug_assert!(chance > 0 && chance < self.range);
+        if bit {
+            self.low += self.range - chance;
+            self.range = chance;
+        } else {
+            self.range -= chance;
+        }
+        self.output()
+    }
+
+    /// Write bit with 50% chance
+    pub fn write_bit(&mut self, bit: bool) -> io::Result<()> {
+        let chance = self.range >> 1;
+        self.put(chance, bit)
+    }
+
+    fn write_chance(
+        &mut self, table: &mut ChanceTable, idx: usize, bit: bool,
+    ) -> io::Result<()> {
+        let chance = table.chances[idx] as u32;
+        let range = self.range;
+        let scaled = (range >> 12)*chance + ((range & 0xFFF)*chance + 2048)/4096;
+        self.put(scaled, bit)?;
+        table.chances[idx] = self.updates.next(bit, table.chances[idx]);
+        Ok(())
+    }
+
+    /// Write value from the range `min..=max` with the uniform distribution
+    pub fn write_val(&mut self, mut min: i32, mut max: i32, val: i32) -> io::Result<()> {
+        debug_assert!(min <= val && val <= max);
+        while min != max {
+            let mid = min + ((max - min) >> 1);
+            if val > mid {
+                self.write_bit(true)?;
+                min = mid + 1;
+            } else {
+                self.write_bit(false)?;
+                max = mid;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write value from the range `min..=max` using its zero flag, sign,
+    /// exponent and mantissa bits
+    pub fn write_near_zero(
+        &mut self, table: &mut ChanceTable, min: i32, max: i32, val: i32,
+    ) -> io::Result<()> {
+        debug_assert!(min <= val && val <= max);
+        let delta = max.min(min.max(0));
+        let (min, max, val) = (min - delta, max - delta, val - delta);
+        if min == max { return Ok(()); }
+
+        self.write_chance(table, ZERO, val == 0)?;
+        if val == 0 { return Ok(()); }
+
+        let sign = if min < 0 && max > 0 {
+            self.write_chance(table, SIGN, val > 0)?;
+            val > 0
+        } else {
+            min >= 0
+        };
+        let abs_max = if sign { max } else { -min } as u32;
+        let abs = val.unsigned_abs();
+        let max_exp = 31 - abs_max.leading_zeros();
+        let exp = 31 - abs.leading_zeros();
+
+        let exp_base = EXP + if sign { 8 } else { 0 };
+        for i in 0..exp {
+            self.write_chance(table, exp_base + i as usize, false)?;
+        }
+        if exp != max_exp {
+            self.write_chance(table, exp_base + exp as usize, true)?;
+        }
+
+        let mut have = 1 << exp;
+        for pos in (0..exp).rev() {
+            let one = have | (1 << pos);
+            // skip bits which would push value above maximum
+            if one > abs_max { continue; }
+            let bit = abs & (1 << pos) != 0;
+            self.write_chance(table, MANT + pos as usize, bit)?;
+            if bit { have = one; }
+        }
+        Ok(())
+    }
+
+    /// Write the remaining state and return the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        // any value from `low..low + range` can be used, the last one
+        // remains valid with the 0xFF padding which decoders use after EOF
+        self.low += self.range - 1;
+        self.range = 1;
+        self.output()?;
+        if let Some(d) = self.delayed_byte.take() {
+            self.write_delayed(d, 0xFF)?;
+        }
+        Ok(self.writer)
+    }
+}
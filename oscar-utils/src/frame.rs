@@ -0,0 +1,243 @@
+use std::str::FromStr;
+
+use super::Sample;
+use super::transform::CropRect;
+
+/// Colour filter array pattern, named after colours of the top-left 2x2 quad
+/// in the row-major order
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum CfaPattern {
+    Bggr,
+    Rggb,
+    Grbg,
+    Gbrg,
+}
+
+impl CfaPattern {
+    /// Pattern of the frame rotated by 180 degrees (see `raw_flip`)
+    pub fn flipped(self) -> Self {
+        match self {
+            CfaPattern::Bggr => CfaPattern::Rggb,
+            CfaPattern::Rggb => CfaPattern::Bggr,
+            CfaPattern::Grbg => CfaPattern::Gbrg,
+            CfaPattern::Gbrg => CfaPattern::Grbg,
+        }
+    }
+
+    /// Pattern of the frame mirrored along the vertical axis
+    pub fn mirrored(self) -> Self {
+        match self {
+            CfaPattern::Bggr => CfaPattern::Gbrg,
+            CfaPattern::Rggb => CfaPattern::Grbg,
+            CfaPattern::Grbg => CfaPattern::Rggb,
+            CfaPattern::Gbrg => CfaPattern::Bggr,
+        }
+    }
+
+    /// Positions `(x, y)` of R, G1, G2 and B samples inside 2x2 quad, where
+    /// G1 shares row with B and G2 shares row with R
+    pub fn positions(self) -> [(usize, usize); 4] {
+        match self {
+            CfaPattern::Bggr => [(1, 1), (1, 0), (0, 1), (0, 0)],
+            CfaPattern::Rggb => [(0, 0), (0, 1), (1, 0), (1, 1)],
+            CfaPattern::Grbg => [(1, 0), (1, 1), (0, 0), (0, 1)],
+            CfaPattern::Gbrg => [(0, 1), (0, 0), (1, 1), (1, 0)],
+        }
+    }
+
+    /// Colour channel (0 for R, 1 for G and 2 for B) of the pixel with the
+    /// given coordinates
+    #[inline(always)]
+    pub fn color(self, x: usize, y: usize) -> usize {
+        let pos = (x & 1, y & 1);
+        let [r, _, _, b] = self.positions();
+        if pos == r { 0 } else if pos == b { 2 } else { 1 }
+    }
+}
+
+impl Default for CfaPattern {
+    /// Pattern of the OS:Car cameras
+    fn default() -> Self {
+        CfaPattern::Rggb
+    }
+}
+
+impl FromStr for CfaPattern {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bggr" => Ok(CfaPattern::Bggr),
+            "rggb" => Ok(CfaPattern::Rggb),
+            "grbg" => Ok(CfaPattern::Grbg),
+            "gbrg" => Ok(CfaPattern::Gbrg),
+            _ => Err("unexpected CFA pattern"),
+        }
+    }
+}
+
+/// Geometry of a raw Bayer frame
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Geometry {
+    /// Frame width in pixels
+    pub width: usize,
+    /// Frame height in pixels
+    pub height: usize,
+    /// Layout of the colour filter array
+    pub cfa: CfaPattern,
+}
+
+impl Geometry {
+    /// Create new frame geometry. Both dimensions must be non-zero and even,
+    /// so the frame consists of whole 2x2 Bayer quads.
+    pub fn new(width: usize, height: usize, cfa: CfaPattern) -> Self {
+        assert!(width != 0 && height != 0, "empty frame geometry");
+        assert_eq!(width % 2, 0, "frame width must be even");
+        assert_eq!(height % 2, 0, "frame height must be even");
+        Self { width, height, cfa }
+    }
+
+    /// Create raw frame geometry from dimensions of the half-resolution
+    /// RGBA image which packs one 2x2 Bayer quad into a single pixel
+    pub fn from_packed(width: usize, height: usize, cfa: CfaPattern) -> Self {
+        Self::new(2*width, 2*height, cfa)
+    }
+
+    /// Dimensions of the packed RGBA image
+    pub fn packed(&self) -> (usize, usize) {
+        (self.width/2, self.height/2)
+    }
+
+    /// Number of pixels in the raw frame
+    pub fn pixels(&self) -> usize {
+        self.width*self.height
+    }
+
+    /// Geometry of the frame rotated by 180 degrees
+    pub fn flipped(&self) -> Self {
+        Self { cfa: self.cfa.flipped(), ..*self }
+    }
+}
+
+/// Raw Bayer frame
+#[derive(Debug, Clone)]
+pub struct RawFrame<T: Sample = u8> {
+    pub geometry: Geometry,
+    pub data: Box<[T]>,
+}
+
+impl<T: Sample> RawFrame<T> {
+    /// Create black frame with the given geometry
+    pub fn empty(geometry: Geometry) -> Self {
+        let data = vec![T::default(); geometry.pixels()].into_boxed_slice();
+        Self { geometry, data }
+    }
+
+    /// Flip frame and pack it into RGBA (see `conversions::raw2rgba_flip`)
+    pub fn pack_flipped(&self) -> PackedFrame<T> {
+        let mut data = vec![T::default(); self.geometry.pixels()];
+        crate::conversions::raw2rgba_flip(&self.data, &mut data, self.geometry);
+        PackedFrame {
+            geometry: self.geometry.flipped(),
+            data: data.into_boxed_slice(),
+        }
+    }
+}
+
+/// Split packed pixel into R, G1, B and G2 samples
+#[inline(always)]
+pub(crate) fn unpack_quad<T: Sample>(p: &[T]) -> [T; 4] {
+    [p[0], p[1], p[2], p[3].wrapping_add(p[1]).wrapping_sub(T::HALF)]
+}
+
+/// Store R, G1, B and G2 samples as packed pixel
+#[inline(always)]
+pub(crate) fn pack_quad<T: Sample>(p: &mut [T], q: [T; 4]) {
+    p[..3].copy_from_slice(&q[..3]);
+    p[3] = q[3].wrapping_sub(q[1]).wrapping_add(T::HALF);
+}
+
+/// Raw Bayer frame packed into half-resolution RGBA image, each pixel of which
+/// stores R, G1, B and G2 - G1 + `T::HALF` values of a single 2x2 quad
+/// (see `conversions::raw2rgba_flip`)
+#[derive(Debug, Clone)]
+pub struct PackedFrame<T: Sample = u8> {
+    /// Geometry of the raw frame after unpacking
+    pub geometry: Geometry,
+    pub data: Box<[T]>,
+}
+
+impl<T: Sample> PackedFrame<T> {
+    /// Create packed black frame with the given geometry
+    pub fn empty(geometry: Geometry) -> Self {
+        let mut data = vec![T::default(); geometry.pixels()].into_boxed_slice();
+        for pixel in data.chunks_exact_mut(4) {
+            pixel[3] = T::HALF;
+        }
+        Self { geometry, data }
+    }
+
+    /// Crop frame, rectangle must be aligned to the 2x2 Bayer quads and fit
+    /// into the frame
+    pub fn crop(&self, rect: CropRect) -> Self {
+        assert!(rect.is_cfa_aligned());
+        let (w, _) = self.geometry.packed();
+        let packed = CropRect {
+            x: rect.x/2,
+            y: rect.y/2,
+            width: rect.width/2,
+            height: rect.height/2,
+        };
+        let data = crate::transform::crop(&self.data, w, 4, packed);
+        let cfa = self.geometry.cfa;
+        let geometry = Geometry::new(rect.width, rect.height, cfa);
+        Self { geometry, data }
+    }
+
+    /// Unpack frame into raw Bayer frame
+    pub fn unpack(&self) -> RawFrame<T> {
+        let mut frame = RawFrame::empty(self.geometry);
+        crate::conversions::rgba2raw(&self.data, &mut frame.data, self.geometry);
+        frame
+    }
+}
+
+/// Raw frame with 8-bit or 16-bit samples
+#[derive(Debug, Clone)]
+pub enum AnyRawFrame {
+    U8(RawFrame<u8>),
+    U16(RawFrame<u16>),
+}
+
+impl AnyRawFrame {
+    pub fn geometry(&self) -> Geometry {
+        match self {
+            AnyRawFrame::U8(f) => f.geometry,
+            AnyRawFrame::U16(f) => f.geometry,
+        }
+    }
+}
+
+/// Packed frame with 8-bit or 16-bit samples
+#[derive(Debug, Clone)]
+pub enum AnyPackedFrame {
+    U8(PackedFrame<u8>),
+    U16(PackedFrame<u16>),
+}
+
+impl AnyPackedFrame {
+    pub fn geometry(&self) -> Geometry {
+        match self {
+            AnyPackedFrame::U8(f) => f.geometry,
+            AnyPackedFrame::U16(f) => f.geometry,
+        }
+    }
+
+    /// Unpack frame into raw Bayer frame
+    pub fn unpack(&self) -> AnyRawFrame {
+        match self {
+            AnyPackedFrame::U8(f) => AnyRawFrame::U8(f.unpack()),
+            AnyPackedFrame::U16(f) => AnyRawFrame::U16(f.unpack()),
+        }
+    }
+}
@@ -1,14 +1,50 @@
+pub mod avi_encoder;
 pub mod conversions;
+pub mod dng_encoder;
 pub mod load_frames;
+pub mod flif_encoder;
+pub mod simd;
+pub mod tiff_encoder;
+pub mod webp_encoder;
+pub mod y4m_encoder;
 mod bayer;
+mod calibration;
+mod color;
+mod defects;
+mod denoise;
+mod equalize;
+mod error;
+mod frame;
+mod rectify;
+mod resample;
+mod sample;
+mod transform;
+mod undistort;
+mod white_balance;
 
-pub use self::bayer::bggr_bayer;
-
-pub const WIDTH: usize = 2448;
-pub const HEIGHT: usize = 2048;
+pub use self::bayer::{
+    bggr_bayer, bilinear_bayer, mhc_bayer, ahd_bayer, superpixel_bayer,
+    demosaic, DemosaicAlgorithm,
+};
+pub use self::calibration::{Calibration, FrameSum, MasterFrame};
+pub use self::color::{ColorMatrix, camera_to_srgb, srgb_encode};
+pub use self::defects::{DefectMap, DefectStats};
+pub use self::denoise::TemporalDenoise;
+pub use self::equalize::Equalization;
+pub use self::error::{Error, ErrorKind, ErrorStats};
+pub use self::frame::{
+    CfaPattern, Geometry, RawFrame, PackedFrame, AnyRawFrame, AnyPackedFrame,
+};
+pub use self::rectify::{
+    Rectification, Rectifier, StereoCamera, StereoModel,
+};
+pub use self::resample::{resample, ResampleFilter, ResampleSize};
+pub use self::sample::Sample;
+pub use self::transform::{crop, flip, rotate, CropRect, Rotation};
+pub use self::undistort::{CameraModel, RemapTable, Undistorter};
+pub use self::white_balance::{WhiteBalance, WbGains, smooth_gains};
 
 pub const PBAR_TEMPLATE: &str = "\
     {wide_bar} {percent:>3}% {pos:>7}/{len} \
     Elapsed: {elapsed_precise} ETA: {eta_precise}\
 ";
-
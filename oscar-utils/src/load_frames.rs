@@ -1,49 +1,344 @@
 use std::path::Path;
 use std::fs::File;
-use std::io;
 
-use super::{WIDTH, HEIGHT};
+use super::{
+    CfaPattern, Geometry, RawFrame, PackedFrame, AnyRawFrame, AnyPackedFrame,
+    Sample, Error,
+};
 
-const PNM_HEADER: &[u8] = b"P5\n2448 2048\n255\n";
+/// Netpbm format of the image
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PnmFormat {
+    /// Binary graymap (`P5`)
+    Gray,
+    /// Binary pixmap (`P6`)
+    Rgb,
+    /// Portable arbitrary map (`P7`)
+    Pam,
+}
+
+/// Parsed header of a binary PNM or PAM image
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PnmHeader {
+    pub format: PnmFormat,
+    pub width: usize,
+    pub height: usize,
+    /// Number of channels
+    pub depth: usize,
+    /// Maximum sample value, samples bigger than 255 use two bytes stored
+    /// in the big-endian order
+    pub maxval: u32,
+    /// Value of the `TUPLTYPE` PAM field, `None` for P5 and P6 images and
+    /// for PAM images without it
+    pub tupltype: Option<String>,
+}
+
+impl PnmHeader {
+    /// Number of bytes used by a single sample
+    pub fn bytes_per_sample(&self) -> usize {
+        if self.maxval > 0xFF { 2 } else { 1 }
+    }
+
+    /// Length of the image data in bytes, `None` if it overflows `usize`
+    pub fn image_len(&self) -> Option<usize> {
+        self.width.checked_mul(self.height)?
+            .checked_mul(self.depth)?
+            .checked_mul(self.bytes_per_sample())
+    }
+}
+
+/// Reader of the whitespace separated header tokens
+struct Tokens<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Tokens<'a> {
+    /// Skip whitespace and comments which last until the end of line
+    fn skip_whitespace(&mut self) {
+        while let Some(&c) = self.data.get(self.pos) {
+            if c == b'#' {
+                self.line();
+            } else if c.is_ascii_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn token(&mut self) -> Result<&'a [u8], Error> {
+        self.skip_whitespace();
+        let start = self.pos;
+        while self.pos < self.data.len()
+            && !self.data[self.pos].is_ascii_whitespace()
+        {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            Err(Error::InvalidHeader("unexpected end of header"))?
+        }
+        Ok(&self.data[start..self.pos])
+    }
+
+    /// Rest of the current line without the leading and trailing whitespace
+    fn line(&mut self) -> &'a [u8] {
+        while self.pos < self.data.len()
+            && self.data[self.pos] != b'\n'
+            && self.data[self.pos].is_ascii_whitespace()
+        {
+            self.pos += 1;
+        }
+        let start = self.pos;
+        while self.pos < self.data.len() && self.data[self.pos] != b'\n' {
+            self.pos += 1;
+        }
+        let mut end = self.pos;
+        while end > start && self.data[end - 1].is_ascii_whitespace() {
+            end -= 1;
+        }
+        &self.data[start..end]
+    }
+
+    fn number(&mut self, err: &'static str) -> Result<usize, Error> {
+        std::str::from_utf8(self.token()?).ok()
+            .and_then(|v| v.parse().ok())
+            .ok_or(Error::InvalidHeader(err))
+    }
+
+    fn maxval(&mut self) -> Result<u32, Error> {
+        match self.number("invalid maxval")? {
+            v @ 1..=0xFFFF => Ok(v as u32),
+            _ => Err(Error::InvalidHeader("invalid maxval")),
+        }
+    }
+
+    /// Consume the single whitespace character which ends the header
+    fn end(&mut self) -> Result<usize, Error> {
+        match self.data.get(self.pos) {
+            Some(c) if c.is_ascii_whitespace() => Ok(self.pos + 1),
+            _ => Err(Error::InvalidHeader("unexpected end of header")),
+        }
+    }
+}
+
+fn parse_pam_header(tokens: &mut Tokens<'_>) -> Result<PnmHeader, Error> {
+    let (mut width, mut height) = (None, None);
+    let (mut depth, mut maxval) = (None, None);
+    let mut tupltype: Option<String> = None;
+    loop {
+        match tokens.token()? {
+            b"ENDHDR" => break,
+            b"WIDTH" => width = Some(tokens.number("invalid width")?),
+            b"HEIGHT" => height = Some(tokens.number("invalid height")?),
+            b"DEPTH" => depth = Some(tokens.number("invalid depth")?),
+            b"MAXVAL" => maxval = Some(tokens.maxval()?),
+            b"TUPLTYPE" => {
+                let val = String::from_utf8_lossy(tokens.line());
+                // multiple TUPLTYPE lines are concatenated
+                tupltype = Some(match tupltype {
+                    Some(t) => format!("{} {}", t, val),
+                    None => val.into_owned(),
+                });
+            },
+            _ => Err(Error::InvalidHeader("unexpected PAM header field"))?,
+        }
+    }
+    match (width, height, depth, maxval) {
+        (Some(width), Some(height), Some(depth), Some(maxval)) => {
+            let format = PnmFormat::Pam;
+            Ok(PnmHeader { format, width, height, depth, maxval, tupltype })
+        },
+        _ => Err(Error::InvalidHeader("missing PAM header field")),
+    }
+}
+
+/// Parse binary PNM (P5, P6) or PAM (P7) image and return its header and
+/// image data
+///
+/// Comments and arbitrary whitespace between header fields are allowed.
+/// Data following the image (e.g. next image in the stream) is ignored.
+pub fn parse_pnm(data: &[u8]) -> Result<(PnmHeader, &[u8]), Error> {
+    let mut tokens = Tokens { data, pos: 0 };
+    let format = match tokens.token()? {
+        b"P5" => PnmFormat::Gray,
+        b"P6" => PnmFormat::Rgb,
+        b"P7" => PnmFormat::Pam,
+        _ => Err(Error::InvalidHeader("unsupported magic number"))?,
+    };
+    let header = match format {
+        PnmFormat::Pam => parse_pam_header(&mut tokens)?,
+        _ => {
+            let width = tokens.number("invalid width")?;
+            let height = tokens.number("invalid height")?;
+            let maxval = tokens.maxval()?;
+            let depth = if format == PnmFormat::Rgb { 3 } else { 1 };
+            PnmHeader { format, width, height, depth, maxval, tupltype: None }
+        },
+    };
+    let header_len = tokens.end()?;
 
-pub fn load_raw_pnm(path: &Path) -> io::Result<Box<[u8]>> {
-    let mmap = unsafe { memmap::Mmap::map(&File::open(path)?)? };
-    let (header, image) = mmap.split_at(17);
+    if header.width == 0 || header.height == 0 {
+        let (width, height) = (header.width, header.height);
+        Err(Error::InvalidDimensions { width, height })?
+    }
+    if header.depth == 0 {
+        Err(Error::InvalidHeader("zero depth"))?
+    }
+    let image = &data[header_len..];
+    let image_len = header.image_len()
+        .ok_or(Error::InvalidHeader("image is too large"))?;
+    if image.len() < image_len {
+        Err(Error::TruncatedData)?
+    }
+    Ok((header, &image[..image_len]))
+}
+
+/// Memory map file, errors are reported with the file path
+fn map_file(path: &Path) -> Result<memmap::Mmap, Error> {
+    let map = || unsafe { memmap::Mmap::map(&File::open(path)?) };
+    map().map_err(|err| Error::Io { path: Some(path.to_path_buf()), err })
+}
+
+/// Shift samples with the given maximum value to use the full range of `T`
+fn align_samples<T: Sample>(data: &mut [T], maxval: u32) {
+    let bits = 32 - maxval.leading_zeros();
+    let shift = T::BITS - bits;
+    if shift != 0 {
+        for v in data.iter_mut() {
+            *v = T::from_u32(v.to_u32() << shift);
+        }
+    }
+}
+
+/// Check if the path has extension of raw PNM frames (`pnm` or `pgm`)
+pub fn is_raw_pnm(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext == "pnm" || ext == "pgm")
+        .unwrap_or(false)
+}
+
+/// Load raw frame recorded with a sensor which uses the given CFA pattern
+///
+/// Frames with maximum value bigger than 255 are loaded as 16-bit frames.
+/// Samples are shifted to the most significant bits, e.g. 12-bit value
+/// `0xABC` is loaded as `0xABC0`.
+pub fn load_raw_pnm(
+    path: &Path, cfa: CfaPattern,
+) -> Result<AnyRawFrame, Error> {
+    let mmap = map_file(path)?;
+    decode_raw_pnm(&mmap, cfa)
+}
+
+/// Decode raw PNM frame, see `load_raw_pnm`
+pub fn decode_raw_pnm(
+    data: &[u8], cfa: CfaPattern,
+) -> Result<AnyRawFrame, Error> {
+    let (header, image) = parse_pnm(data)?;
+    if header.format == PnmFormat::Rgb || header.depth != 1 {
+        Err(Error::UnsupportedChannels(format!(
+            "raw frame must have a single channel, found {}", header.depth,
+        )))?
+    }
+    let even = |v: usize| v % 2 == 0;
+    if !even(header.width) || !even(header.height) {
+        let (width, height) = (header.width, header.height);
+        Err(Error::InvalidDimensions { width, height })?
+    }
+    let geometry = Geometry::new(header.width, header.height, cfa);
+    let maxval = header.maxval;
 
-    if header != PNM_HEADER || image.len() != WIDTH*HEIGHT {
-        Err(io::Error::new(io::ErrorKind::InvalidData,
-            "invalid PNM frame".to_string()))
+    if header.bytes_per_sample() == 1 {
+        let mut data = u8::decode_be(image);
+        align_samples(&mut data, maxval);
+        Ok(AnyRawFrame::U8(RawFrame { geometry, data }))
     } else {
-        Ok(image.to_vec().into_boxed_slice())
+        let mut data = u16::decode_be(image);
+        align_samples(&mut data, maxval);
+        Ok(AnyRawFrame::U16(RawFrame { geometry, data }))
     }
 }
 
-pub fn load_flif(path: &Path) -> io::Result<Box<[u8]>> {
-    let mmap = unsafe { memmap::Mmap::map(&File::open(path)?)? };
-    decode_flif(mmap.as_ref())
+/// Load RGBA FLIF frame and unpack it into raw frame with the given CFA
+/// pattern
+pub fn load_flif(
+    path: &Path, cfa: CfaPattern,
+) -> Result<AnyRawFrame, Error> {
+    let mmap = map_file(path)?;
+    decode_flif(mmap.as_ref(), cfa).map_err(|err| err.with_path(path))
+}
+
+pub fn decode_flif(
+    data: &[u8], cfa: CfaPattern,
+) -> Result<AnyRawFrame, Error> {
+    decode_flif_packed(data, cfa).map(|frame| frame.unpack())
+}
+
+/// Load RGBA FLIF frame without unpacking it, `cfa` is used as CFA pattern of
+/// the raw frame after unpacking
+pub fn load_flif_packed(
+    path: &Path, cfa: CfaPattern,
+) -> Result<AnyPackedFrame, Error> {
+    let mmap = map_file(path)?;
+    decode_flif_packed(mmap.as_ref(), cfa).map_err(|err| err.with_path(path))
 }
 
-pub fn decode_flif(data: &[u8]) -> io::Result<Box<[u8]>> {
-    let image = flif::Flif::decode(data)
-        .map_err(|err| match err {
-            flif::Error::Io(err) => err,
-            err => io::Error::new(io::ErrorKind::InvalidData, err)
-        })?;
+/// Decode RGBA FLIF frame without unpacking it
+///
+/// The `flif` crate doesn't support 16-bit images yet, they are reported as
+/// `Error::Flif`, so 16-bit frames have to be loaded from raw PNM files.
+pub fn decode_flif_packed(
+    data: &[u8], cfa: CfaPattern,
+) -> Result<AnyPackedFrame, Error> {
+    use flif::components::{BytesPerChannel, ColorSpace};
+
+    let image = flif::Flif::decode(data)?;
     let header = image.info().header;
 
-    let wt = (WIDTH as u32)/2;
-    let ht = (HEIGHT as u32)/2;
-    let rgba = match header {
-        flif::components::Header {
-            width, height,
-            num_frames: 1, interlaced: false,
-            bytes_per_channel: flif::components::BytesPerChannel::One,
-            channels: flif::components::ColorSpace::RGBA,
-        } if width == wt && height == ht => image.into_raw(),
-        _ => Err(io::Error::new(io::ErrorKind::InvalidData,
-            format!("unexpected image properites: {:?}", header)))?,
-    };
-    let mut raw = vec![0u8; WIDTH*HEIGHT].into_boxed_slice();
-    crate::conversions::rgba2raw(&rgba, &mut raw);
-    Ok(raw)
+    if header.interlaced || header.num_frames != 1 {
+        let (interlaced, num_frames) = (header.interlaced, header.num_frames);
+        Err(Error::UnsupportedFlif { interlaced, num_frames })?
+    }
+    if header.channels != ColorSpace::RGBA {
+        Err(Error::UnsupportedChannels(format!(
+            "expected RGBA FLIF image, found {:?}", header.channels,
+        )))?
+    }
+    let (width, height) = (header.width as usize, header.height as usize);
+    if width == 0 || height == 0 {
+        Err(Error::InvalidDimensions { width, height })?
+    }
+    let geometry = Geometry::from_packed(width, height, cfa);
+    let rgba = image.into_raw();
+    match header.bytes_per_channel {
+        BytesPerChannel::One => {
+            Ok(AnyPackedFrame::U8(PackedFrame { geometry, data: rgba }))
+        },
+        bpc => Err(Error::UnsupportedChannels(format!(
+            "unsupported FLIF bytes per channel: {:?}", bpc,
+        ))),
+    }
+}
+
+/// Load packed frame from the RGBA FLIF file or from the raw PNM file (see
+/// `is_raw_pnm`), which gets flipped and packed the same way as by pnm2flif.
+/// `cfa` is used as CFA pattern of the raw frame after unpacking (i.e. after
+/// flipping).
+pub fn load_packed(
+    path: &Path, cfa: CfaPattern,
+) -> Result<AnyPackedFrame, Error> {
+    let mmap = map_file(path)?;
+    decode_packed(mmap.as_ref(), is_raw_pnm(path), cfa)
+        .map_err(|err| err.with_path(path))
+}
+
+/// Decode packed frame from the raw PNM or RGBA FLIF data, see `load_packed`
+pub fn decode_packed(
+    data: &[u8], is_pnm: bool, cfa: CfaPattern,
+) -> Result<AnyPackedFrame, Error> {
+    if !is_pnm { return decode_flif_packed(data, cfa); }
+    Ok(match decode_raw_pnm(data, cfa.flipped())? {
+        AnyRawFrame::U8(f) => AnyPackedFrame::U8(f.pack_flipped()),
+        AnyRawFrame::U16(f) => AnyPackedFrame::U16(f.pack_flipped()),
+    })
 }
@@ -0,0 +1,325 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::{fs, io};
+
+use super::{CameraModel, RemapTable, Sample};
+use super::undistort::{yaml_entry, yaml_lines, yaml_matrix, yaml_scalar};
+
+type Matrix = [[f64; 3]; 3];
+type Vector = [f64; 3];
+
+const IDENTITY: Matrix = [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]];
+
+fn mul(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut res = [[0.0; 3]; 3];
+    for (i, row) in res.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (0..3).map(|k| a[i][k]*b[k][j]).sum();
+        }
+    }
+    res
+}
+
+fn mul_vec(a: &Matrix, v: &Vector) -> Vector {
+    let mut res = [0.0; 3];
+    for (r, row) in res.iter_mut().zip(a.iter()) {
+        *r = row[0]*v[0] + row[1]*v[1] + row[2]*v[2];
+    }
+    res
+}
+
+fn transpose(a: &Matrix) -> Matrix {
+    let mut res = [[0.0; 3]; 3];
+    for (i, row) in res.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = a[j][i];
+        }
+    }
+    res
+}
+
+fn norm(v: &Vector) -> f64 {
+    (v[0]*v[0] + v[1]*v[1] + v[2]*v[2]).sqrt()
+}
+
+/// Rotation matrix of the rotation vector (Rodrigues formula)
+fn rotation(v: &Vector) -> Matrix {
+    let theta = norm(v);
+    if theta < 1e-12 { return IDENTITY; }
+    let k = [v[0]/theta, v[1]/theta, v[2]/theta];
+    let (sin, cos) = theta.sin_cos();
+    let cross = [[0.0, -k[2], k[1]], [k[2], 0.0, -k[0]], [-k[1], k[0], 0.0]];
+    let mut res = [[0.0; 3]; 3];
+    for (i, row) in res.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (1.0 - cos)*k[i]*k[j] + sin*cross[i][j] + IDENTITY[i][j]*cos;
+        }
+    }
+    res
+}
+
+/// Rotation vector of the rotation matrix, inverse of `rotation`
+fn rotation_vector(m: &Matrix) -> Vector {
+    let cos = ((m[0][0] + m[1][1] + m[2][2] - 1.0)/2.0).clamp(-1.0, 1.0);
+    let theta = cos.acos();
+    if theta < 1e-12 { return [0.0; 3]; }
+    let s = theta/(2.0*theta.sin());
+    [s*(m[2][1] - m[1][2]), s*(m[0][2] - m[2][0]), s*(m[1][0] - m[0][1])]
+}
+
+/// Left or right camera of the stereo pair
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StereoCamera {
+    Left,
+    Right,
+}
+
+/// Calibration of the stereo camera pair, as produced by OpenCV
+/// `stereoCalibrate`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StereoModel {
+    pub left: CameraModel,
+    pub right: CameraModel,
+    /// Rotation from the left camera coordinate system into the right one
+    pub rotation: Matrix,
+    /// Translation from the left camera coordinate system into the right one
+    pub translation: Vector,
+}
+
+/// Parses OpenCV YAML file with `M1`, `D1`, `M2`, `D2`, `R` and `T` entries
+/// (as written by the OpenCV stereo calibration sample into `intrinsics.yml`
+/// and `extrinsics.yml`). Optional `image_width` and `image_height` entries
+/// specify size of the calibration images, it's set to zero if they are
+/// missing (see `StereoModel::with_default_size`).
+impl FromStr for StereoModel {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lines = yaml_lines(s);
+        let (width, height) = match yaml_entry(&lines, "image_width") {
+            Some(_) => (
+                yaml_scalar(&lines, "image_width")?,
+                yaml_scalar(&lines, "image_height")?,
+            ),
+            None => (0, 0),
+        };
+        let camera = |m, d| -> Result<CameraModel, &'static str> {
+            let m = yaml_matrix(&lines, m)?;
+            let d = yaml_matrix(&lines, d)?;
+            CameraModel::from_matrices(width, height, &m, &d)
+        };
+        let left = camera("M1", "D1")?;
+        let right = camera("M2", "D2")?;
+        let r = yaml_matrix(&lines, "R")?;
+        let t = yaml_matrix(&lines, "T")?;
+        if r.len() != 9 || t.len() != 3 {
+            Err("invalid stereo extrinsics")?
+        }
+        let mut rotation = [[0.0; 3]; 3];
+        for (row, chunk) in rotation.iter_mut().zip(r.chunks(3)) {
+            row.copy_from_slice(chunk);
+        }
+        let translation = [t[0], t[1], t[2]];
+        if norm(&translation) == 0.0 {
+            Err("zero stereo baseline")?
+        }
+        Ok(Self { left, right, rotation, translation })
+    }
+}
+
+impl StereoModel {
+    /// Load stereo model from one or more OpenCV YAML files, whose entries
+    /// are merged (e.g. separate intrinsics and extrinsics files)
+    pub fn load(paths: &[PathBuf]) -> io::Result<Self> {
+        let mut text = String::new();
+        for path in paths {
+            text.push_str(&fs::read_to_string(path)?);
+            text.push('\n');
+        }
+        text.parse().map_err(|err| {
+            let names: Vec<String> = paths.iter()
+                .map(|p| p.display().to_string())
+                .collect();
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", names.join(", "), err),
+            )
+        })
+    }
+
+    /// Set size of the calibration images if it was not specified
+    pub fn with_default_size(mut self, width: usize, height: usize) -> Self {
+        for camera in [&mut self.left, &mut self.right].iter_mut() {
+            if camera.width == 0 || camera.height == 0 {
+                camera.width = width;
+                camera.height = height;
+            }
+        }
+        self
+    }
+
+    pub fn camera(&self, camera: StereoCamera) -> &CameraModel {
+        match camera {
+            StereoCamera::Left => &self.left,
+            StereoCamera::Right => &self.right,
+        }
+    }
+}
+
+/// Rectification of the stereo pair, which makes epipolar lines horizontal
+/// and maps both images onto the same virtual camera
+///
+/// Transforms are computed with the Bouguet's algorithm the same way as
+/// OpenCV `stereoRectify` with `CALIB_ZERO_DISPARITY` flag and without
+/// scaling (`alpha = -1`).
+#[derive(Debug, Clone)]
+pub struct Rectification {
+    model: StereoModel,
+    /// Rectifying rotations of the left and right cameras
+    rotations: [Matrix; 2],
+    /// Focal length of the rectified images
+    focal: f64,
+    /// Principal point of the rectified images
+    center: (f64, f64),
+}
+
+impl Rectification {
+    pub fn new(model: &StereoModel) -> Self {
+        let model = *model;
+        // split rotation between cameras, so they become parallel
+        let mut om = rotation_vector(&model.rotation);
+        for v in om.iter_mut() { *v *= -0.5; }
+        let r_r = rotation(&om);
+        let t = mul_vec(&r_r, &model.translation);
+
+        // rotate cameras so the baseline becomes parallel to the X axis
+        let horizontal = t[0].abs() > t[1].abs();
+        let idx = if horizontal { 0 } else { 1 };
+        let mut uu = [0.0; 3];
+        uu[idx] = if t[idx] > 0.0 { 1.0 } else { -1.0 };
+        let mut ww = [
+            t[1]*uu[2] - t[2]*uu[1],
+            t[2]*uu[0] - t[0]*uu[2],
+            t[0]*uu[1] - t[1]*uu[0],
+        ];
+        let nw = norm(&ww);
+        if nw > 0.0 {
+            let angle = (t[idx].abs()/norm(&t)).acos();
+            for v in ww.iter_mut() { *v *= angle/nw; }
+        }
+        let w_r = rotation(&ww);
+        let rotations = [mul(&w_r, &transpose(&r_r)), mul(&w_r, &r_r)];
+
+        let (l, r) = (&model.left, &model.right);
+        let focal = if horizontal {
+            (l.fy + r.fy)/2.0
+        } else {
+            (l.fx + r.fx)/2.0
+        };
+
+        // principal point keeps centre of the image corners in the centre
+        let mut center = (0.0, 0.0);
+        for (camera, rot) in [l, r].iter().zip(rotations.iter()) {
+            let w = (camera.width - 1) as f64;
+            let h = (camera.height - 1) as f64;
+            let corners = [(0.0, 0.0), (w, 0.0), (0.0, h), (w, h)];
+            let (mut sx, mut sy) = (0.0, 0.0);
+            for &(u, v) in corners.iter() {
+                let (x, y) = camera.undistort_normalized(u, v);
+                let p = mul_vec(rot, &[x, y, 1.0]);
+                sx += focal*p[0]/p[2];
+                sy += focal*p[1]/p[2];
+            }
+            center.0 += (w/2.0 - sx/4.0)/2.0;
+            center.1 += (h/2.0 - sy/4.0)/2.0;
+        }
+        Self { model, rotations, focal, center }
+    }
+
+    fn rotation(&self, camera: StereoCamera) -> &Matrix {
+        match camera {
+            StereoCamera::Left => &self.rotations[0],
+            StereoCamera::Right => &self.rotations[1],
+        }
+    }
+
+    /// Position in the rectified image of the source image point
+    pub fn rectify_point(
+        &self, camera: StereoCamera, u: f64, v: f64,
+    ) -> (f64, f64) {
+        let (x, y) = self.model.camera(camera).undistort_normalized(u, v);
+        let p = mul_vec(self.rotation(camera), &[x, y, 1.0]);
+        let (cx, cy) = self.center;
+        (self.focal*p[0]/p[2] + cx, self.focal*p[1]/p[2] + cy)
+    }
+
+    /// Position in the source image of the rectified image point, inverse
+    /// of `rectify_point`
+    pub fn source_point(
+        &self, camera: StereoCamera, u: f64, v: f64,
+    ) -> (f64, f64) {
+        let (cx, cy) = self.center;
+        let p = [(u - cx)/self.focal, (v - cy)/self.focal, 1.0];
+        let p = mul_vec(&transpose(self.rotation(camera)), &p);
+        self.model.camera(camera).distort_normalized(p[0]/p[2], p[1]/p[2])
+    }
+
+    /// Remap table of the camera for images of the given size
+    pub fn table(
+        &self, camera: StereoCamera, width: usize, height: usize,
+    ) -> RemapTable {
+        let model = self.model.camera(camera);
+        let calib_size = (model.width, model.height);
+        RemapTable::from_fn(width, height, calib_size, |u, v| {
+            self.source_point(camera, u, v)
+        })
+    }
+}
+
+type TablePair = (Arc<RemapTable>, Arc<RemapTable>);
+
+/// Stereo rectification, which computes remap tables on the first use and
+/// reuses them for all pairs of the same size
+#[derive(Debug)]
+pub struct Rectifier {
+    rectification: Rectification,
+    tables: Mutex<Option<TablePair>>,
+}
+
+impl Rectifier {
+    pub fn new(model: &StereoModel) -> Self {
+        let rectification = Rectification::new(model);
+        Self { rectification, tables: Mutex::new(None) }
+    }
+
+    /// Remap tables of the left and right cameras for images of the given
+    /// size
+    pub fn tables(&self, width: usize, height: usize) -> TablePair {
+        // lock is held while tables are built, so other threads wait for it
+        let mut tables = self.tables.lock().unwrap();
+        match &*tables {
+            Some((l, r)) if l.size() == (width, height) => {
+                (l.clone(), r.clone())
+            },
+            _ => {
+                let table = |camera| {
+                    Arc::new(self.rectification.table(camera, width, height))
+                };
+                let l = table(StereoCamera::Left);
+                let r = table(StereoCamera::Right);
+                *tables = Some((l.clone(), r.clone()));
+                (l, r)
+            },
+        }
+    }
+
+    /// Rectify left and right images with interleaved channels
+    pub fn rectify<T: Sample>(
+        &self, left: &[T], right: &[T], width: usize, height: usize,
+        channels: usize,
+    ) -> (Box<[T]>, Box<[T]>) {
+        let (l, r) = self.tables(width, height);
+        (l.remap(left, channels), r.remap(right, channels))
+    }
+}
@@ -0,0 +1,204 @@
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+use super::Sample;
+
+/// Parameter of the Keys cubic convolution kernel
+const CUBIC_A: f32 = -0.5;
+/// Number of lobes of the Lanczos kernel
+const LANCZOS_LOBES: f32 = 3.0;
+
+/// Interpolation filter used for resampling
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ResampleFilter {
+    /// Triangle filter, bilinear interpolation on upscaling
+    Bilinear,
+    /// Keys cubic convolution with `a = -0.5` (Catmull-Rom)
+    Bicubic,
+    /// Three lobe Lanczos filter
+    Lanczos,
+}
+
+impl FromStr for ResampleFilter {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bilinear" => Ok(ResampleFilter::Bilinear),
+            "bicubic" => Ok(ResampleFilter::Bicubic),
+            "lanczos" => Ok(ResampleFilter::Lanczos),
+            _ => Err("unexpected resampling filter"),
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 { 1.0 } else { (PI*x).sin()/(PI*x) }
+}
+
+impl ResampleFilter {
+    /// Radius of the filter kernel in source pixels (without downscaling)
+    fn support(self) -> f32 {
+        match self {
+            ResampleFilter::Bilinear => 1.0,
+            ResampleFilter::Bicubic => 2.0,
+            ResampleFilter::Lanczos => LANCZOS_LOBES,
+        }
+    }
+
+    fn weight(self, x: f32) -> f32 {
+        let x = x.abs();
+        match self {
+            ResampleFilter::Bilinear => (1.0 - x).max(0.0),
+            ResampleFilter::Bicubic => {
+                let a = CUBIC_A;
+                if x < 1.0 {
+                    ((a + 2.0)*x - (a + 3.0))*x*x + 1.0
+                } else if x < 2.0 {
+                    ((a*x - 5.0*a)*x + 8.0*a)*x - 4.0*a
+                } else {
+                    0.0
+                }
+            },
+            ResampleFilter::Lanczos if x < LANCZOS_LOBES => {
+                sinc(x)*sinc(x/LANCZOS_LOBES)
+            },
+            ResampleFilter::Lanczos => 0.0,
+        }
+    }
+}
+
+/// Output size of the resampling: either exact dimensions or scale factor
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ResampleSize {
+    Size(usize, usize),
+    Scale(f32),
+}
+
+/// Parses `WIDTHxHEIGHT` (e.g. `1280x720`) or positive scale factor (e.g.
+/// `0.5`)
+impl FromStr for ResampleSize {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let res = match s.find('x') {
+            Some(i) => {
+                let w = s[..i].parse().map_err(|_| "invalid width")?;
+                let h = s[i + 1..].parse().map_err(|_| "invalid height")?;
+                ResampleSize::Size(w, h)
+            },
+            None => ResampleSize::Scale(
+                s.parse().map_err(|_| "invalid scale factor")?
+            ),
+        };
+        match res {
+            ResampleSize::Size(w, h) if w == 0 || h == 0 => {
+                Err("size must be positive")
+            },
+            ResampleSize::Scale(s) if s <= 0.0 || !s.is_finite() => {
+                Err("scale factor must be positive")
+            },
+            res => Ok(res),
+        }
+    }
+}
+
+impl ResampleSize {
+    /// Output dimensions for the image with the given size
+    pub fn dimensions(self, width: usize, height: usize) -> (usize, usize) {
+        match self {
+            ResampleSize::Size(w, h) => (w, h),
+            ResampleSize::Scale(s) => {
+                let scale = |v: usize| ((v as f32*s).round() as usize).max(1);
+                (scale(width), scale(height))
+            },
+        }
+    }
+}
+
+/// Source pixels and their weights used for one output pixel
+struct Contribution {
+    start: usize,
+    weights: Vec<f32>,
+}
+
+/// Contributions of the source pixels along one axis, the filter is
+/// stretched on downscaling to avoid aliasing
+fn contributions(
+    src: usize, dst: usize, filter: ResampleFilter,
+) -> Vec<Contribution> {
+    let scale = src as f32/dst as f32;
+    let filter_scale = scale.max(1.0);
+    let support = filter.support()*filter_scale;
+    (0..dst)
+        .map(|i| {
+            let center = (i as f32 + 0.5)*scale;
+            let start = (center - support).floor().max(0.0) as usize;
+            let end = ((center + support).ceil() as usize).min(src);
+            let mut weights: Vec<f32> = (start..end)
+                .map(|j| {
+                    filter.weight((j as f32 + 0.5 - center)/filter_scale)
+                })
+                .collect();
+            let sum: f32 = weights.iter().sum();
+            if sum.abs() > 1e-6 {
+                for w in weights.iter_mut() { *w /= sum; }
+                Contribution { start, weights }
+            } else {
+                // fallback to the nearest pixel
+                let start = (center as usize).min(src - 1);
+                Contribution { start, weights: vec![1.0] }
+            }
+        })
+        .collect()
+}
+
+/// Resample image with interleaved channels to the new size using the
+/// separable filter
+///
+/// Samples are expected to be linear (i.e. image must be resampled before
+/// applying the sRGB transfer curve), results are rounded and clamped to
+/// the sample range.
+pub fn resample<T: Sample>(
+    data: &[T], width: usize, height: usize, channels: usize,
+    new_width: usize, new_height: usize, filter: ResampleFilter,
+) -> Box<[T]> {
+    assert_eq!(data.len(), channels*width*height);
+    assert!(width > 0 && height > 0 && new_width > 0 && new_height > 0);
+    let c = channels;
+    let horizontal = contributions(width, new_width, filter);
+    let vertical = contributions(height, new_height, filter);
+
+    // horizontal pass
+    let mut tmp = vec![0f32; c*new_width*height];
+    for (src, dst) in data.chunks_exact(c*width)
+        .zip(tmp.chunks_exact_mut(c*new_width))
+    {
+        for (contrib, out) in horizontal.iter().zip(dst.chunks_exact_mut(c)) {
+            let pixels = src[c*contrib.start..].chunks_exact(c);
+            for (w, pixel) in contrib.weights.iter().zip(pixels) {
+                for (o, v) in out.iter_mut().zip(pixel) {
+                    *o += w*v.to_u32() as f32;
+                }
+            }
+        }
+    }
+
+    // vertical pass
+    let row_len = c*new_width;
+    let mut res = Vec::with_capacity(row_len*new_height);
+    let mut row = vec![0f32; row_len];
+    for contrib in vertical.iter() {
+        for v in row.iter_mut() { *v = 0.0; }
+        let rows = tmp[row_len*contrib.start..].chunks_exact(row_len);
+        for (w, src) in contrib.weights.iter().zip(rows) {
+            for (o, v) in row.iter_mut().zip(src) {
+                *o += w*v;
+            }
+        }
+        res.extend(row.iter().map(|&v| {
+            T::from_u32((v + 0.5).clamp(0.0, T::MAX as f32) as u32)
+        }));
+    }
+    res.into_boxed_slice()
+}
@@ -0,0 +1,96 @@
+use std::borrow::Cow;
+use std::fmt::Debug;
+
+/// Type of raw frame samples, implemented for `u8` and `u16`
+///
+/// Samples always use the full range of the type, so data with a lower bit
+/// depth (e.g. 12-bit) is stored in the most significant bits of `u16`.
+pub trait Sample: Copy + Default + Eq + Ord + Debug + Send + Sync + 'static {
+    /// Number of bits in the sample
+    const BITS: u32;
+    /// Maximum sample value
+    const MAX: u32;
+    /// Offset added to G2 - G1 difference in packed frames
+    const HALF: Self;
+
+    fn to_u32(self) -> u32;
+    /// Convert value which must not be bigger than `MAX`
+    fn from_u32(val: u32) -> Self;
+    fn wrapping_add(self, other: Self) -> Self;
+    fn wrapping_sub(self, other: Self) -> Self;
+
+    /// Reduce sample to 8 bits
+    #[inline(always)]
+    fn to_u8(self) -> u8 {
+        (self.to_u32() >> (Self::BITS - 8)) as u8
+    }
+
+    /// Serialize samples into big-endian bytes (as used by PNM and PNG)
+    fn encode_be(data: &[Self]) -> Cow<'_, [u8]>;
+    /// Deserialize samples from big-endian bytes
+    fn decode_be(data: &[u8]) -> Box<[Self]>;
+
+    /// View samples as bytes if they are 8-bit (used for selection of the
+    /// SIMD code paths)
+    fn as_u8_slice(data: &[Self]) -> Option<&[u8]>;
+    fn as_u8_slice_mut(data: &mut [Self]) -> Option<&mut [u8]>;
+}
+
+impl Sample for u8 {
+    const BITS: u32 = 8;
+    const MAX: u32 = 0xFF;
+    const HALF: Self = 0x80;
+
+    #[inline(always)]
+    fn to_u32(self) -> u32 { self as u32 }
+    #[inline(always)]
+    fn from_u32(val: u32) -> Self { val as u8 }
+    #[inline(always)]
+    fn wrapping_add(self, other: Self) -> Self { u8::wrapping_add(self, other) }
+    #[inline(always)]
+    fn wrapping_sub(self, other: Self) -> Self { u8::wrapping_sub(self, other) }
+
+    fn encode_be(data: &[Self]) -> Cow<'_, [u8]> {
+        Cow::Borrowed(data)
+    }
+
+    fn decode_be(data: &[u8]) -> Box<[Self]> {
+        data.to_vec().into_boxed_slice()
+    }
+
+    fn as_u8_slice(data: &[Self]) -> Option<&[u8]> { Some(data) }
+    fn as_u8_slice_mut(data: &mut [Self]) -> Option<&mut [u8]> { Some(data) }
+}
+
+impl Sample for u16 {
+    const BITS: u32 = 16;
+    const MAX: u32 = 0xFFFF;
+    const HALF: Self = 0x8000;
+
+    #[inline(always)]
+    fn to_u32(self) -> u32 { self as u32 }
+    #[inline(always)]
+    fn from_u32(val: u32) -> Self { val as u16 }
+    #[inline(always)]
+    fn wrapping_add(self, other: Self) -> Self { u16::wrapping_add(self, other) }
+    #[inline(always)]
+    fn wrapping_sub(self, other: Self) -> Self { u16::wrapping_sub(self, other) }
+
+    fn encode_be(data: &[Self]) -> Cow<'_, [u8]> {
+        let mut buf = Vec::with_capacity(2*data.len());
+        for v in data {
+            buf.extend_from_slice(&v.to_be_bytes());
+        }
+        Cow::Owned(buf)
+    }
+
+    fn decode_be(data: &[u8]) -> Box<[Self]> {
+        assert_eq!(data.len() % 2, 0);
+        data.chunks_exact(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .collect()
+    }
+
+    fn as_u8_slice(_: &[Self]) -> Option<&[u8]> { None }
+    fn as_u8_slice_mut(_: &mut [Self]) -> Option<&mut [u8]> { None }
+}
@@ -0,0 +1,171 @@
+//! SIMD implementations of the hot conversion and demosaicing loops for
+//! 8-bit frames
+//!
+//! Instruction set is selected at runtime, SIMD kernels process the bulk of
+//! each row and the remaining pixels are handled by the scalar code, so
+//! results are bit-exact for all levels.
+use super::Geometry;
+use super::bayer::bggr_bayer_with;
+use super::conversions::{
+    raw2rgba_flip_quads, rgba2raw_quads, raw_flip_columns,
+};
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86;
+#[cfg(target_arch = "aarch64")]
+mod neon;
+
+/// SIMD instruction set used for processing of 8-bit frames
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SimdLevel {
+    /// Plain scalar code
+    Scalar,
+    Sse2,
+    Avx2,
+    Neon,
+}
+
+impl SimdLevel {
+    /// Best instruction set supported by the CPU
+    pub fn detect() -> Self {
+        [SimdLevel::Avx2, SimdLevel::Neon, SimdLevel::Sse2].iter()
+            .cloned()
+            .find(|level| level.is_supported())
+            .unwrap_or(SimdLevel::Scalar)
+    }
+
+    /// Check if instruction set is supported by the CPU
+    pub fn is_supported(self) -> bool {
+        match self {
+            SimdLevel::Scalar => true,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            SimdLevel::Sse2 => is_x86_feature_detected!("sse2"),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            SimdLevel::Avx2 => is_x86_feature_detected!("avx2"),
+            // NEON is a mandatory part of AArch64
+            #[cfg(target_arch = "aarch64")]
+            SimdLevel::Neon => true,
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    /// All instruction sets supported by the CPU including `Scalar`
+    pub fn supported() -> Vec<Self> {
+        [SimdLevel::Scalar, SimdLevel::Sse2, SimdLevel::Avx2, SimdLevel::Neon]
+            .iter()
+            .cloned()
+            .filter(|level| level.is_supported())
+            .collect()
+    }
+}
+
+fn check_level(level: SimdLevel) {
+    assert!(level.is_supported(), "{:?} is not supported by CPU", level);
+}
+
+/// Version of `conversions::raw2rgba_flip` for 8-bit frames which uses the
+/// given instruction set
+pub fn raw2rgba_flip(
+    level: SimdLevel, src: &[u8], dst: &mut [u8], geom: Geometry,
+) {
+    let (w, h) = (geom.width, geom.height);
+    assert_eq!(src.len(), w*h);
+    assert_eq!(dst.len(), w*h);
+    check_level(level);
+    let start = unsafe { match level {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        SimdLevel::Sse2 => x86::raw2rgba_flip_sse2(src, dst, geom),
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        SimdLevel::Avx2 => x86::raw2rgba_flip_avx2(src, dst, geom),
+        #[cfg(target_arch = "aarch64")]
+        SimdLevel::Neon => neon::raw2rgba_flip(src, dst, geom),
+        _ => 0,
+    } };
+    raw2rgba_flip_quads(src, dst, geom, start);
+}
+
+/// Version of `conversions::rgba2raw` for 8-bit frames which uses the given
+/// instruction set
+pub fn rgba2raw(
+    level: SimdLevel, src: &[u8], dst: &mut [u8], geom: Geometry,
+) {
+    let (w, h) = (geom.width, geom.height);
+    assert_eq!(src.len(), w*h);
+    assert_eq!(dst.len(), w*h);
+    check_level(level);
+    let start = unsafe { match level {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        SimdLevel::Sse2 => x86::rgba2raw_sse2(src, dst, geom),
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        SimdLevel::Avx2 => x86::rgba2raw_avx2(src, dst, geom),
+        #[cfg(target_arch = "aarch64")]
+        SimdLevel::Neon => neon::rgba2raw(src, dst, geom),
+        _ => 0,
+    } };
+    rgba2raw_quads(src, dst, geom, start);
+}
+
+/// Version of `conversions::raw_flip` for 8-bit frames which uses the given
+/// instruction set
+pub fn raw_flip(level: SimdLevel, buf: &mut [u8], geom: Geometry) {
+    let (w, h) = (geom.width, geom.height);
+    assert_eq!(buf.len(), w*h);
+    assert_eq!(h % 2, 0);
+    check_level(level);
+    let start = unsafe { match level {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        SimdLevel::Sse2 => x86::raw_flip_sse2(buf, geom),
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        SimdLevel::Avx2 => x86::raw_flip_avx2(buf, geom),
+        #[cfg(target_arch = "aarch64")]
+        SimdLevel::Neon => neon::raw_flip(buf, geom),
+        _ => 0,
+    } };
+    raw_flip_columns(buf, geom, start);
+}
+
+/// Version of `bggr_bayer` for 8-bit frames which uses the given instruction
+/// set
+pub fn bggr_bayer(
+    level: SimdLevel, data: &[u8], width: usize, height: usize,
+) -> Box<[u8]> {
+    check_level(level);
+    bggr_bayer_with(level, data, width, height)
+}
+
+/// Demosaic interior 2x2 blocks of rows `y` and `y + 1` (`y` is even and
+/// not on the border) starting from the column 2 and return column of the
+/// first block which was not processed
+pub(crate) fn bggr_core(
+    level: SimdLevel, buf: &mut [u8], data: &[u8], y: usize, width: usize,
+) -> usize {
+    let height = data.len()/width;
+    assert_eq!(buf.len(), 3*data.len());
+    assert!(y >= 2 && y + 3 < height && y % 2 == 0);
+    // loads use columns from `x - 1` to `x + 16` for a 16 columns chunk
+    let end = 2 + 16*(width.saturating_sub(4)/16);
+    if end == 2 { return 2; }
+    unsafe {
+        match level {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            SimdLevel::Sse2 => x86::bggr_core_sse2(buf, data, y, width, end),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            SimdLevel::Avx2 => x86::bggr_core_avx2(buf, data, y, width, end),
+            #[cfg(target_arch = "aarch64")]
+            SimdLevel::Neon => neon::bggr_core(buf, data, y, width, end),
+            _ => return 2,
+        }
+    }
+    end
+}
+
+/// Indices `2*dy + dx` of R, G1, G2 and B samples inside 2x2 quad, used by
+/// the kernels to select planes of the quad samples
+fn plane_indices(geom: Geometry) -> [usize; 4] {
+    let mut res = [0; 4];
+    for (r, &(dx, dy)) in res.iter_mut().zip(geom.cfa.positions().iter()) {
+        *r = 2*dy + dx;
+    }
+    res
+}
@@ -0,0 +1,149 @@
+//! NEON kernels
+//!
+//! Kernels return the number of quads (or columns for `raw_flip`) processed
+//! in each row, the rest is left for the scalar code.
+use std::arch::aarch64::*;
+
+use crate::Geometry;
+use super::plane_indices;
+
+/// Reverse order of bytes
+#[inline(always)]
+unsafe fn reverse(v: uint8x16_t) -> uint8x16_t {
+    let v = vrev64q_u8(v);
+    vextq_u8(v, v, 8)
+}
+
+pub(super) unsafe fn raw_flip(buf: &mut [u8], geom: Geometry) -> usize {
+    let (w, h) = (geom.width, geom.height);
+    let end = w - w % 16;
+    let p = buf.as_mut_ptr();
+    for y in 0..h/2 {
+        let top = p.add(y*w);
+        let bottom = p.add((h - y - 1)*w);
+        for x in (0..end).step_by(16) {
+            let (a, b) = (top.add(x), bottom.add(w - x - 16));
+            let (va, vb) = (vld1q_u8(a), vld1q_u8(b));
+            vst1q_u8(a, reverse(vb));
+            vst1q_u8(b, reverse(va));
+        }
+    }
+    end
+}
+
+pub(super) unsafe fn raw2rgba_flip(
+    src: &[u8], dst: &mut [u8], geom: Geometry,
+) -> usize {
+    let (w, h) = (geom.width, geom.height);
+    let end = w/2 - (w/2) % 8;
+    let [r, g1, g2, b] = plane_indices(geom.flipped());
+    let half = vdup_n_u8(0x80);
+    for y in 0..h/2 {
+        let row0 = src.as_ptr().add((h - 2*y - 1)*w);
+        let row1 = src.as_ptr().add((h - 2*y - 2)*w);
+        let out = dst.as_mut_ptr().add(4*y*(w/2));
+        for x in (0..end).step_by(8) {
+            let col = w - 2*x - 16;
+            let v0 = vld2_u8(row0.add(col));
+            let v1 = vld2_u8(row1.add(col));
+            // planes are indexed by `2*dy + dx`, columns are loaded in the
+            // reversed order, so odd bytes have `dx` equal to zero
+            let p = [
+                vrev64_u8(v0.1), vrev64_u8(v0.0),
+                vrev64_u8(v1.1), vrev64_u8(v1.0),
+            ];
+            let delta = vadd_u8(vsub_u8(p[g2], p[g1]), half);
+            vst4_u8(out.add(4*x), uint8x8x4_t(p[r], p[g1], p[b], delta));
+        }
+    }
+    end
+}
+
+pub(super) unsafe fn rgba2raw(
+    src: &[u8], dst: &mut [u8], geom: Geometry,
+) -> usize {
+    let (w, h) = (geom.width, geom.height);
+    let end = w/2 - (w/2) % 8;
+    let [r, g1, g2, b] = plane_indices(geom);
+    let half = vdup_n_u8(0x80);
+    for y in 0..h/2 {
+        let inp = src.as_ptr().add(4*y*(w/2));
+        let row0 = dst.as_mut_ptr().add(2*y*w);
+        let row1 = row0.add(w);
+        for x in (0..end).step_by(8) {
+            let v = vld4_u8(inp.add(4*x));
+            let mut p = [vdup_n_u8(0); 4];
+            p[r] = v.0;
+            p[g1] = v.1;
+            p[b] = v.2;
+            p[g2] = vsub_u8(vadd_u8(v.3, v.1), half);
+            vst2_u8(row0.add(2*x), uint8x8x2_t(p[0], p[1]));
+            vst2_u8(row1.add(2*x), uint8x8x2_t(p[2], p[3]));
+        }
+    }
+    end
+}
+
+#[inline(always)]
+unsafe fn widen_low(v: uint8x16_t) -> uint16x8_t {
+    vmovl_u8(vget_low_u8(v))
+}
+
+#[inline(always)]
+unsafe fn widen_high(v: uint8x16_t) -> uint16x8_t {
+    vmovl_high_u8(v)
+}
+
+/// Demosaic 8 pixels of the central row `m` with even lanes at the B or R
+/// sites (depending on `b_row`), rows contain values shifted by -1, 0 and 1
+/// columns and are widened using `widen`
+#[inline(always)]
+unsafe fn bggr_pixels(
+    u: &[uint8x16_t; 3], m: &[uint8x16_t; 3], d: &[uint8x16_t; 3],
+    widen: unsafe fn(uint8x16_t) -> uint16x8_t, b_row: bool,
+) -> [uint16x8_t; 3] {
+    let add = |a, b| vaddq_u16(a, b);
+    let c = widen(m[1]);
+    let h = add(widen(m[0]), widen(m[2]));
+    let v = add(widen(u[1]), widen(d[1]));
+    let diag = add(
+        add(widen(u[0]), widen(u[2])), add(widen(d[0]), widen(d[2])),
+    );
+
+    let even = vreinterpretq_u16_u32(vdupq_n_u32(0xFFFF));
+    let sel = |a, b| vbslq_u16(even, a, b);
+    let x4 = vshrq_n_u16(add(h, v), 2);
+    let d4 = vshrq_n_u16(diag, 2);
+    let (h2, v2) = (vshrq_n_u16(h, 1), vshrq_n_u16(v, 1));
+    if b_row {
+        [sel(d4, v2), sel(x4, c), sel(c, h2)]
+    } else {
+        [sel(h2, c), sel(c, x4), sel(v2, d4)]
+    }
+}
+
+pub(super) unsafe fn bggr_core(
+    buf: &mut [u8], data: &[u8], y: usize, w: usize, end: usize,
+) {
+    for &(row, b_row) in [(y, true), (y + 1, false)].iter() {
+        let up = data.as_ptr().add((row - 1)*w);
+        let mid = data.as_ptr().add(row*w);
+        let down = data.as_ptr().add((row + 1)*w);
+        let out = buf.as_mut_ptr().add(3*row*w);
+        for x in (2..end).step_by(16) {
+            let load = |p: *const u8| [
+                vld1q_u8(p.add(x - 1)),
+                vld1q_u8(p.add(x)),
+                vld1q_u8(p.add(x + 1)),
+            ];
+            let (u, m, d) = (load(up), load(mid), load(down));
+            let lo = bggr_pixels(&u, &m, &d, widen_low, b_row);
+            let hi = bggr_pixels(&u, &m, &d, widen_high, b_row);
+            let narrow = |c: usize| {
+                vcombine_u8(vmovn_u16(lo[c]), vmovn_u16(hi[c]))
+            };
+            let rgb = uint8x16x3_t(narrow(0), narrow(1), narrow(2));
+            vst3q_u8(out.add(3*x), rgb);
+        }
+    }
+}
@@ -0,0 +1,416 @@
+//! SSE2 and AVX2 kernels
+//!
+//! Kernels return the number of quads (or columns for `raw_flip`) processed
+//! in each row, the rest is left for the scalar code.
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+use crate::Geometry;
+use super::plane_indices;
+
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn load(p: *const u8) -> __m128i {
+    _mm_loadu_si128(p as *const __m128i)
+}
+
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn store(p: *mut u8, v: __m128i) {
+    _mm_storeu_si128(p as *mut __m128i, v)
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn load256(p: *const u8) -> __m256i {
+    _mm256_loadu_si256(p as *const __m256i)
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn store256(p: *mut u8, v: __m256i) {
+    _mm256_storeu_si256(p as *mut __m256i, v)
+}
+
+/// Reverse order of bytes
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn reverse_sse2(v: __m128i) -> __m128i {
+    // swap bytes in 16-bit words and then reverse order of the words
+    let v = _mm_or_si128(_mm_slli_epi16(v, 8), _mm_srli_epi16(v, 8));
+    let v = _mm_shufflelo_epi16(v, 0x1B);
+    let v = _mm_shufflehi_epi16(v, 0x1B);
+    _mm_shuffle_epi32(v, 0x4E)
+}
+
+/// Reverse order of bytes
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn reverse_avx2(v: __m256i) -> __m256i {
+    let mask = _mm256_setr_epi8(
+        15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+        15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    );
+    let v = _mm256_shuffle_epi8(v, mask);
+    _mm256_permute2x128_si256(v, v, 0x01)
+}
+
+#[target_feature(enable = "sse2")]
+pub(super) unsafe fn raw_flip_sse2(buf: &mut [u8], geom: Geometry) -> usize {
+    let (w, h) = (geom.width, geom.height);
+    let end = w - w % 16;
+    let p = buf.as_mut_ptr();
+    for y in 0..h/2 {
+        let top = p.add(y*w);
+        let bottom = p.add((h - y - 1)*w);
+        for x in (0..end).step_by(16) {
+            let (a, b) = (top.add(x), bottom.add(w - x - 16));
+            let (va, vb) = (load(a), load(b));
+            store(a, reverse_sse2(vb));
+            store(b, reverse_sse2(va));
+        }
+    }
+    end
+}
+
+#[target_feature(enable = "avx2")]
+pub(super) unsafe fn raw_flip_avx2(buf: &mut [u8], geom: Geometry) -> usize {
+    let (w, h) = (geom.width, geom.height);
+    let end = w - w % 32;
+    let p = buf.as_mut_ptr();
+    for y in 0..h/2 {
+        let top = p.add(y*w);
+        let bottom = p.add((h - y - 1)*w);
+        for x in (0..end).step_by(32) {
+            let (a, b) = (top.add(x), bottom.add(w - x - 32));
+            let (va, vb) = (load256(a), load256(b));
+            store256(a, reverse_avx2(vb));
+            store256(b, reverse_avx2(va));
+        }
+    }
+    end
+}
+
+#[target_feature(enable = "sse2")]
+pub(super) unsafe fn raw2rgba_flip_sse2(
+    src: &[u8], dst: &mut [u8], geom: Geometry,
+) -> usize {
+    let (w, h) = (geom.width, geom.height);
+    let end = w/2 - (w/2) % 8;
+    let [r, g1, g2, b] = plane_indices(geom.flipped());
+    let mask = _mm_set1_epi16(0xFF);
+    let half = _mm_set1_epi16(0x80);
+    for y in 0..h/2 {
+        let row0 = src.as_ptr().add((h - 2*y - 1)*w);
+        let row1 = src.as_ptr().add((h - 2*y - 2)*w);
+        let out = dst.as_mut_ptr().add(4*y*(w/2));
+        for x in (0..end).step_by(8) {
+            let col = w - 2*x - 16;
+            let v0 = reverse_sse2(load(row0.add(col)));
+            let v1 = reverse_sse2(load(row1.add(col)));
+            // planes are indexed by `2*dy + dx`, after the reversal even
+            // bytes have `dx` equal to zero
+            let p = [
+                _mm_and_si128(v0, mask), _mm_srli_epi16(v0, 8),
+                _mm_and_si128(v1, mask), _mm_srli_epi16(v1, 8),
+            ];
+            let delta = _mm_add_epi16(_mm_sub_epi16(p[g2], p[g1]), half);
+            let delta = _mm_and_si128(delta, mask);
+            let rg = _mm_or_si128(p[r], _mm_slli_epi16(p[g1], 8));
+            let ba = _mm_or_si128(p[b], _mm_slli_epi16(delta, 8));
+            store(out.add(4*x), _mm_unpacklo_epi16(rg, ba));
+            store(out.add(4*x + 16), _mm_unpackhi_epi16(rg, ba));
+        }
+    }
+    end
+}
+
+#[target_feature(enable = "avx2")]
+pub(super) unsafe fn raw2rgba_flip_avx2(
+    src: &[u8], dst: &mut [u8], geom: Geometry,
+) -> usize {
+    let (w, h) = (geom.width, geom.height);
+    let end = w/2 - (w/2) % 16;
+    let [r, g1, g2, b] = plane_indices(geom.flipped());
+    let mask = _mm256_set1_epi16(0xFF);
+    let half = _mm256_set1_epi16(0x80);
+    for y in 0..h/2 {
+        let row0 = src.as_ptr().add((h - 2*y - 1)*w);
+        let row1 = src.as_ptr().add((h - 2*y - 2)*w);
+        let out = dst.as_mut_ptr().add(4*y*(w/2));
+        for x in (0..end).step_by(16) {
+            let col = w - 2*x - 32;
+            let v0 = reverse_avx2(load256(row0.add(col)));
+            let v1 = reverse_avx2(load256(row1.add(col)));
+            let p = [
+                _mm256_and_si256(v0, mask), _mm256_srli_epi16(v0, 8),
+                _mm256_and_si256(v1, mask), _mm256_srli_epi16(v1, 8),
+            ];
+            let delta = _mm256_sub_epi16(p[g2], p[g1]);
+            let delta = _mm256_add_epi16(delta, half);
+            let delta = _mm256_and_si256(delta, mask);
+            let rg = _mm256_or_si256(p[r], _mm256_slli_epi16(p[g1], 8));
+            let ba = _mm256_or_si256(p[b], _mm256_slli_epi16(delta, 8));
+            // unpacking works inside of 128-bit lanes
+            let lo = _mm256_unpacklo_epi16(rg, ba);
+            let hi = _mm256_unpackhi_epi16(rg, ba);
+            let out = out.add(4*x);
+            store256(out, _mm256_permute2x128_si256(lo, hi, 0x20));
+            store256(out.add(32), _mm256_permute2x128_si256(lo, hi, 0x31));
+        }
+    }
+    end
+}
+
+/// Extract RGBA channel with the given shift into 32-bit lanes
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn channel_sse2(v: __m128i, shift: i32) -> __m128i {
+    let mask = _mm_set1_epi32(0xFF);
+    match shift {
+        0 => _mm_and_si128(v, mask),
+        8 => _mm_and_si128(_mm_srli_epi32(v, 8), mask),
+        16 => _mm_and_si128(_mm_srli_epi32(v, 16), mask),
+        _ => _mm_srli_epi32(v, 24),
+    }
+}
+
+/// Extract RGBA channel with the given shift into 32-bit lanes
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn channel_avx2(v: __m256i, shift: i32) -> __m256i {
+    let mask = _mm256_set1_epi32(0xFF);
+    match shift {
+        0 => _mm256_and_si256(v, mask),
+        8 => _mm256_and_si256(_mm256_srli_epi32(v, 8), mask),
+        16 => _mm256_and_si256(_mm256_srli_epi32(v, 16), mask),
+        _ => _mm256_srli_epi32(v, 24),
+    }
+}
+
+#[target_feature(enable = "sse2")]
+pub(super) unsafe fn rgba2raw_sse2(
+    src: &[u8], dst: &mut [u8], geom: Geometry,
+) -> usize {
+    let (w, h) = (geom.width, geom.height);
+    let end = w/2 - (w/2) % 8;
+    let [r, g1, g2, b] = plane_indices(geom);
+    let mask = _mm_set1_epi16(0xFF);
+    let half = _mm_set1_epi16(0x80);
+    for y in 0..h/2 {
+        let inp = src.as_ptr().add(4*y*(w/2));
+        let row0 = dst.as_mut_ptr().add(2*y*w);
+        let row1 = row0.add(w);
+        for x in (0..end).step_by(8) {
+            let v0 = load(inp.add(4*x));
+            let v1 = load(inp.add(4*x + 16));
+            let chan = |shift| _mm_packs_epi32(
+                channel_sse2(v0, shift), channel_sse2(v1, shift),
+            );
+            let mut p = [_mm_setzero_si128(); 4];
+            p[r] = chan(0);
+            p[g1] = chan(8);
+            p[b] = chan(16);
+            let g2_val = _mm_sub_epi16(_mm_add_epi16(chan(24), p[g1]), half);
+            p[g2] = _mm_and_si128(g2_val, mask);
+            store(row0.add(2*x), _mm_or_si128(p[0], _mm_slli_epi16(p[1], 8)));
+            store(row1.add(2*x), _mm_or_si128(p[2], _mm_slli_epi16(p[3], 8)));
+        }
+    }
+    end
+}
+
+#[target_feature(enable = "avx2")]
+pub(super) unsafe fn rgba2raw_avx2(
+    src: &[u8], dst: &mut [u8], geom: Geometry,
+) -> usize {
+    let (w, h) = (geom.width, geom.height);
+    let end = w/2 - (w/2) % 16;
+    let [r, g1, g2, b] = plane_indices(geom);
+    let mask = _mm256_set1_epi16(0xFF);
+    let half = _mm256_set1_epi16(0x80);
+    for y in 0..h/2 {
+        let inp = src.as_ptr().add(4*y*(w/2));
+        let row0 = dst.as_mut_ptr().add(2*y*w);
+        let row1 = row0.add(w);
+        for x in (0..end).step_by(16) {
+            let v0 = load256(inp.add(4*x));
+            let v1 = load256(inp.add(4*x + 32));
+            // packing works inside of 128-bit lanes, so the resulting quad
+            // order is 0-3, 8-11, 4-7, 12-15
+            let chan = |shift| _mm256_packs_epi32(
+                channel_avx2(v0, shift), channel_avx2(v1, shift),
+            );
+            let mut p = [_mm256_setzero_si256(); 4];
+            p[r] = chan(0);
+            p[g1] = chan(8);
+            p[b] = chan(16);
+            let g2_val = _mm256_add_epi16(chan(24), p[g1]);
+            let g2_val = _mm256_sub_epi16(g2_val, half);
+            p[g2] = _mm256_and_si256(g2_val, mask);
+            let top = _mm256_or_si256(p[0], _mm256_slli_epi16(p[1], 8));
+            let bottom = _mm256_or_si256(p[2], _mm256_slli_epi16(p[3], 8));
+            store256(row0.add(2*x), _mm256_permute4x64_epi64(top, 0xD8));
+            store256(row1.add(2*x), _mm256_permute4x64_epi64(bottom, 0xD8));
+        }
+    }
+    end
+}
+
+/// Neighbourhood of 16-bit pixels: sums of horizontal, vertical and diagonal
+/// neighbours and the central value
+struct Sums<V> {
+    c: V,
+    h: V,
+    v: V,
+    d: V,
+}
+
+/// Select RGB values of 16-bit pixels, even lanes are taken from `a` and
+/// odd ones from `b`
+macro_rules! bggr_select {
+    ($sel:ident, $shr:ident, $add:ident, $s:expr, $b_row:expr) => {{
+        let s = $s;
+        let x4 = $shr($add(s.h, s.v), 2);
+        if $b_row {
+            [
+                $sel($shr(s.d, 2), $shr(s.v, 1)),
+                $sel(x4, s.c),
+                $sel(s.c, $shr(s.h, 1)),
+            ]
+        } else {
+            [
+                $sel($shr(s.h, 1), s.c),
+                $sel(s.c, x4),
+                $sel($shr(s.v, 1), $shr(s.d, 2)),
+            ]
+        }
+    }};
+}
+
+#[target_feature(enable = "sse2")]
+pub(super) unsafe fn bggr_core_sse2(
+    buf: &mut [u8], data: &[u8], y: usize, w: usize, end: usize,
+) {
+    let zero = _mm_setzero_si128();
+    let even = _mm_set1_epi32(0xFFFF);
+    let sel = |a, b| _mm_or_si128(
+        _mm_and_si128(even, a), _mm_andnot_si128(even, b),
+    );
+    let shr = |v, n| match n {
+        1 => _mm_srli_epi16(v, 1),
+        _ => _mm_srli_epi16(v, 2),
+    };
+    let add = |a, b| _mm_add_epi16(a, b);
+    let mut rgb = [[0u8; 16]; 3];
+    for (row, b_row) in [(y, true), (y + 1, false)].iter().cloned() {
+        let up = data.as_ptr().add((row - 1)*w);
+        let mid = data.as_ptr().add(row*w);
+        let down = data.as_ptr().add((row + 1)*w);
+        let out = buf.as_mut_ptr().add(3*row*w);
+        for x in (2..end).step_by(16) {
+            let load3 = |p: *const u8| {
+                [load(p.add(x - 1)), load(p.add(x)), load(p.add(x + 1))]
+            };
+            let (u, m, d) = (load3(up), load3(mid), load3(down));
+            let pixels = |widen: &dyn Fn(__m128i) -> __m128i| {
+                let s = Sums {
+                    c: widen(m[1]),
+                    h: add(widen(m[0]), widen(m[2])),
+                    v: add(widen(u[1]), widen(d[1])),
+                    d: add(
+                        add(widen(u[0]), widen(u[2])),
+                        add(widen(d[0]), widen(d[2])),
+                    ),
+                };
+                bggr_select!(sel, shr, add, s, b_row)
+            };
+            let lo = pixels(&|a| _mm_unpacklo_epi8(a, zero));
+            let hi = pixels(&|a| _mm_unpackhi_epi8(a, zero));
+            for (c, chan) in rgb.iter_mut().enumerate() {
+                store(chan.as_mut_ptr(), _mm_packus_epi16(lo[c], hi[c]));
+            }
+            // SSE2 has no byte shuffles, so channels are interleaved by
+            // the scalar code
+            let out = out.add(3*x);
+            let channels = rgb[0].iter().zip(rgb[1].iter()).zip(rgb[2].iter());
+            for (i, ((&r, &g), &b)) in channels.enumerate() {
+                *out.add(3*i) = r;
+                *out.add(3*i + 1) = g;
+                *out.add(3*i + 2) = b;
+            }
+        }
+    }
+}
+
+/// Shuffle masks which interleave three 16-byte channel vectors into three
+/// 16-byte RGB vectors, indexed by output vector and channel
+fn rgb_masks() -> [[[i8; 16]; 3]; 3] {
+    let mut masks = [[[-128i8; 16]; 3]; 3];
+    for (j, out) in masks.iter_mut().enumerate() {
+        for pos in 16*j..16*(j + 1) {
+            out[pos % 3][pos - 16*j] = (pos / 3) as i8;
+        }
+    }
+    masks
+}
+
+#[target_feature(enable = "avx2")]
+pub(super) unsafe fn bggr_core_avx2(
+    buf: &mut [u8], data: &[u8], y: usize, w: usize, end: usize,
+) {
+    let even = _mm256_set1_epi32(0xFFFF);
+    let sel = |a, b| _mm256_blendv_epi8(b, a, even);
+    let shr = |v, n| match n {
+        1 => _mm256_srli_epi16(v, 1),
+        _ => _mm256_srli_epi16(v, 2),
+    };
+    let add = |a, b| _mm256_add_epi16(a, b);
+    let masks = rgb_masks();
+    let mut shuffles = [[_mm_setzero_si128(); 3]; 3];
+    for (s, m) in shuffles.iter_mut().zip(masks.iter()) {
+        for c in 0..3 {
+            s[c] = load(m[c].as_ptr() as *const u8);
+        }
+    }
+    for (row, b_row) in [(y, true), (y + 1, false)].iter().cloned() {
+        let up = data.as_ptr().add((row - 1)*w);
+        let mid = data.as_ptr().add(row*w);
+        let down = data.as_ptr().add((row + 1)*w);
+        let out = buf.as_mut_ptr().add(3*row*w);
+        for x in (2..end).step_by(16) {
+            let get = |p: *const u8, dx: usize| {
+                _mm256_cvtepu8_epi16(load(p.add(x + dx - 1)))
+            };
+            let s = Sums {
+                c: get(mid, 1),
+                h: add(get(mid, 0), get(mid, 2)),
+                v: add(get(up, 1), get(down, 1)),
+                d: add(
+                    add(get(up, 0), get(up, 2)),
+                    add(get(down, 0), get(down, 2)),
+                ),
+            };
+            let px = bggr_select!(sel, shr, add, s, b_row);
+            let mut rgb = [_mm_setzero_si128(); 3];
+            for c in 0..3 {
+                let lo = _mm256_castsi256_si128(px[c]);
+                let hi = _mm256_extracti128_si256(px[c], 1);
+                rgb[c] = _mm_packus_epi16(lo, hi);
+            }
+            let out = out.add(3*x);
+            for (j, s) in shuffles.iter().enumerate() {
+                let v = _mm_or_si128(
+                    _mm_or_si128(
+                        _mm_shuffle_epi8(rgb[0], s[0]),
+                        _mm_shuffle_epi8(rgb[1], s[1]),
+                    ),
+                    _mm_shuffle_epi8(rgb[2], s[2]),
+                );
+                store(out.add(16*j), v);
+            }
+        }
+    }
+}
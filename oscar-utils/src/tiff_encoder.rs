@@ -0,0 +1,382 @@
+//! Baseline TIFF encoder of 8- and 16-bit grayscale and RGB images
+//!
+//! Images are written in little-endian byte order with one IFD and chunky
+//! (interleaved) samples split into strips. Compressed strips use the
+//! horizontal differencing predictor.
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use deflate::deflate_bytes_zlib;
+
+use super::Sample;
+
+/// Approximate size of the uncompressed strip in bytes
+const STRIP_SIZE: usize = 1 << 16;
+
+pub const NEW_SUBFILE_TYPE: u16 = 254;
+pub const IMAGE_WIDTH: u16 = 256;
+pub const IMAGE_LENGTH: u16 = 257;
+pub const BITS_PER_SAMPLE: u16 = 258;
+pub const COMPRESSION: u16 = 259;
+pub const PHOTOMETRIC_INTERPRETATION: u16 = 262;
+pub const IMAGE_DESCRIPTION: u16 = 270;
+pub const STRIP_OFFSETS: u16 = 273;
+pub const SAMPLES_PER_PIXEL: u16 = 277;
+pub const ROWS_PER_STRIP: u16 = 278;
+pub const STRIP_BYTE_COUNTS: u16 = 279;
+pub const PLANAR_CONFIGURATION: u16 = 284;
+pub const SOFTWARE: u16 = 305;
+pub const DATE_TIME: u16 = 306;
+pub const PREDICTOR: u16 = 317;
+pub const EXIF_IFD: u16 = 34665;
+pub const DATE_TIME_ORIGINAL: u16 = 36867;
+
+/// Compression of the image strips
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TiffCompression {
+    None,
+    Lzw,
+    /// zlib stream (Adobe deflate)
+    Deflate,
+}
+
+impl FromStr for TiffCompression {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(TiffCompression::None),
+            "lzw" => Ok(TiffCompression::Lzw),
+            "deflate" => Ok(TiffCompression::Deflate),
+            _ => Err("unexpected TIFF compression"),
+        }
+    }
+}
+
+impl TiffCompression {
+    /// Value of the `Compression` tag
+    fn code(self) -> u16 {
+        match self {
+            TiffCompression::None => 1,
+            TiffCompression::Lzw => 5,
+            TiffCompression::Deflate => 8,
+        }
+    }
+
+    fn compress(self, data: Vec<u8>) -> Vec<u8> {
+        match self {
+            TiffCompression::None => data,
+            TiffCompression::Lzw => lzw_encode(&data),
+            TiffCompression::Deflate => deflate_bytes_zlib(&data),
+        }
+    }
+}
+
+/// Value of the TIFF tag
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagValue {
+    Byte(Vec<u8>),
+    /// ASCII string, terminating NUL is added on writing
+    Ascii(String),
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    Rational(Vec<(u32, u32)>),
+    SRational(Vec<(i32, i32)>),
+    /// Sub-IFD with the given tags (e.g. `EXIF_IFD`), written as offset
+    Ifd(Vec<Tag>),
+}
+
+impl TagValue {
+    fn field_type(&self) -> u16 {
+        match self {
+            TagValue::Byte(_) => 1,
+            TagValue::Ascii(_) => 2,
+            TagValue::Short(_) => 3,
+            TagValue::Long(_) => 4,
+            TagValue::Rational(_) => 5,
+            TagValue::SRational(_) => 10,
+            TagValue::Ifd(_) => 4,
+        }
+    }
+
+    fn count(&self) -> usize {
+        match self {
+            TagValue::Byte(v) => v.len(),
+            TagValue::Ascii(v) => v.len() + 1,
+            TagValue::Short(v) => v.len(),
+            TagValue::Long(v) => v.len(),
+            TagValue::Rational(v) => v.len(),
+            TagValue::SRational(v) => v.len(),
+            TagValue::Ifd(_) => 1,
+        }
+    }
+
+    /// Little-endian representation of the value placed at `pos`
+    fn to_bytes(&self, pos: usize) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        match self {
+            TagValue::Byte(v) => buf.extend_from_slice(v),
+            TagValue::Ascii(v) => {
+                buf.extend_from_slice(v.as_bytes());
+                buf.push(0);
+            },
+            TagValue::Short(v) => for x in v {
+                buf.extend_from_slice(&x.to_le_bytes());
+            },
+            TagValue::Long(v) => for x in v {
+                buf.extend_from_slice(&x.to_le_bytes());
+            },
+            TagValue::Rational(v) => for (n, d) in v {
+                buf.extend_from_slice(&n.to_le_bytes());
+                buf.extend_from_slice(&d.to_le_bytes());
+            },
+            TagValue::SRational(v) => for (n, d) in v {
+                buf.extend_from_slice(&n.to_le_bytes());
+                buf.extend_from_slice(&d.to_le_bytes());
+            },
+            TagValue::Ifd(tags) => {
+                let entries = tags.iter()
+                    .map(|tag| (tag.id, tag.value.clone()))
+                    .collect();
+                buf = encode_ifd(&entries, pos)?;
+            },
+        }
+        Ok(buf)
+    }
+}
+
+/// TIFF tag with its numeric identifier
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tag {
+    pub id: u16,
+    pub value: TagValue,
+}
+
+impl Tag {
+    pub fn new(id: u16, value: TagValue) -> Self {
+        Self { id, value }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn offset(pos: usize) -> io::Result<u32> {
+    if pos > u32::MAX as usize {
+        Err(invalid_data("TIFF file exceeds 4 GiB"))?
+    }
+    Ok(pos as u32)
+}
+
+/// Encode grayscale (`channels == 1`) or interleaved RGB (`channels == 3`)
+/// image
+///
+/// Tags written by the encoder describe the image layout, `tags` can add
+/// other tags (e.g. `DATE_TIME`) or replace default ones (e.g.
+/// `PHOTOMETRIC_INTERPRETATION`), but tags describing strips are always
+/// computed by the encoder.
+pub fn encode_tiff<T: Sample, W: Write>(
+    data: &[T], width: usize, height: usize, channels: usize,
+    compression: TiffCompression, tags: &[Tag], mut writer: W,
+) -> io::Result<()> {
+    assert!(channels == 1 || channels == 3);
+    assert_eq!(data.len(), channels*width*height);
+    if width == 0 || height == 0 {
+        Err(invalid_data("TIFF image must not be empty"))?
+    }
+    let row_len = channels*width;
+    let row_bytes = row_len*(T::BITS as usize/8);
+    let rows_per_strip = (STRIP_SIZE/row_bytes).clamp(1, height);
+
+    // strips are placed right after the header, each one at a word boundary
+    let mut strips = Vec::with_capacity(height.div_ceil(rows_per_strip));
+    let mut offsets = Vec::with_capacity(strips.capacity());
+    let mut byte_counts = Vec::with_capacity(strips.capacity());
+    let mut pos = 8;
+    for rows in data.chunks(rows_per_strip*row_len) {
+        let buf = match compression {
+            TiffCompression::None => encode_le(rows),
+            _ => {
+                let mut rows = rows.to_vec();
+                for row in rows.chunks_exact_mut(row_len) {
+                    predict(row, channels);
+                }
+                encode_le(&rows)
+            },
+        };
+        let buf = compression.compress(buf);
+        offsets.push(offset(pos)?);
+        byte_counts.push(offset(buf.len())?);
+        pos += buf.len() + (buf.len() & 1);
+        strips.push(buf);
+    }
+
+    let mut entries = BTreeMap::new();
+    let mut set = |id, value| { entries.insert(id, value); };
+    set(NEW_SUBFILE_TYPE, TagValue::Long(vec![0]));
+    set(IMAGE_WIDTH, TagValue::Long(vec![offset(width)?]));
+    set(IMAGE_LENGTH, TagValue::Long(vec![offset(height)?]));
+    set(BITS_PER_SAMPLE, TagValue::Short(vec![T::BITS as u16; channels]));
+    set(COMPRESSION, TagValue::Short(vec![compression.code()]));
+    let photometric = if channels == 3 { 2 } else { 1 };
+    set(PHOTOMETRIC_INTERPRETATION, TagValue::Short(vec![photometric]));
+    set(SAMPLES_PER_PIXEL, TagValue::Short(vec![channels as u16]));
+    set(ROWS_PER_STRIP, TagValue::Long(vec![rows_per_strip as u32]));
+    set(PLANAR_CONFIGURATION, TagValue::Short(vec![1]));
+    if compression != TiffCompression::None {
+        set(PREDICTOR, TagValue::Short(vec![2]));
+    }
+    for tag in tags {
+        set(tag.id, tag.value.clone());
+    }
+    set(STRIP_OFFSETS, TagValue::Long(offsets));
+    set(STRIP_BYTE_COUNTS, TagValue::Long(byte_counts));
+
+    let ifd_pos = pos;
+    let ifd = encode_ifd(&entries, ifd_pos)?;
+
+    writer.write_all(b"II\x2A\x00")?;
+    writer.write_all(&offset(ifd_pos)?.to_le_bytes())?;
+    for strip in strips.iter() {
+        writer.write_all(strip)?;
+        if strip.len() & 1 == 1 { writer.write_all(&[0])?; }
+    }
+    writer.write_all(&ifd)?;
+    Ok(())
+}
+
+/// Encode IFD placed at `pos`, values which don't fit into the entry
+/// (including sub-IFDs) follow the IFD
+fn encode_ifd(
+    entries: &BTreeMap<u16, TagValue>, pos: usize,
+) -> io::Result<Vec<u8>> {
+    let mut values_pos = pos + 2 + 12*entries.len() + 4;
+    let mut ifd = Vec::with_capacity(values_pos - pos);
+    let mut values = Vec::new();
+    ifd.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    for (id, value) in entries.iter() {
+        let mut bytes = value.to_bytes(values_pos)?;
+        ifd.extend_from_slice(&id.to_le_bytes());
+        ifd.extend_from_slice(&value.field_type().to_le_bytes());
+        ifd.extend_from_slice(&offset(value.count())?.to_le_bytes());
+        if bytes.len() <= 4 {
+            bytes.resize(4, 0);
+            ifd.extend_from_slice(&bytes);
+        } else {
+            ifd.extend_from_slice(&offset(values_pos)?.to_le_bytes());
+            if bytes.len() & 1 == 1 { bytes.push(0); }
+            values_pos += bytes.len();
+            values.extend_from_slice(&bytes);
+        }
+    }
+    offset(values_pos)?;
+    ifd.extend_from_slice(&[0; 4]);
+    ifd.extend_from_slice(&values);
+    Ok(ifd)
+}
+
+fn encode_le<T: Sample>(data: &[T]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(data.len()*(T::BITS as usize/8));
+    for v in data {
+        let v = v.to_u32();
+        buf.push(v as u8);
+        if T::BITS == 16 { buf.push((v >> 8) as u8); }
+    }
+    buf
+}
+
+/// Horizontal differencing predictor applied to one row
+fn predict<T: Sample>(row: &mut [T], channels: usize) {
+    for i in (channels..row.len()).rev() {
+        row[i] = row[i].wrapping_sub(row[i - channels]);
+    }
+}
+
+const LZW_CLEAR: u16 = 256;
+const LZW_EOI: u16 = 257;
+const LZW_FIRST: u16 = 258;
+/// Table is reset before codes become longer than 12 bits
+const LZW_MAX: u16 = 4094;
+
+/// Writer of the MSB-first variable length codes
+struct BitWriter {
+    buf: Vec<u8>,
+    acc: u32,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, len: u32) {
+        self.acc = (self.acc << len) | code as u32;
+        self.bits += len;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.buf.push((self.acc >> self.bits) as u8);
+        }
+        self.acc &= (1 << self.bits) - 1;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits != 0 {
+            self.buf.push((self.acc << (8 - self.bits)) as u8);
+        }
+        self.buf
+    }
+}
+
+/// LZW compression as defined by the TIFF specification
+///
+/// Code width is increased one code earlier than in GIF, the same way as
+/// libtiff does it.
+fn lzw_encode(data: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter { buf: Vec::new(), acc: 0, bits: 0 };
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut len = 9;
+    let mut next = LZW_FIRST;
+    w.write(LZW_CLEAR, len);
+    let mut iter = data.iter();
+    let mut prefix = match iter.next() {
+        Some(&b) => b as u16,
+        None => {
+            w.write(LZW_EOI, len);
+            return w.finish();
+        },
+    };
+    // adds new code and returns width of the following codes
+    let add = |next: &mut u16, len: u32| {
+        *next += 1;
+        if *next == LZW_MAX {
+            (true, 9)
+        } else if *next as u32 > (1 << len) - 1 {
+            (false, len + 1)
+        } else {
+            (false, len)
+        }
+    };
+    for &b in iter {
+        if let Some(&code) = table.get(&(prefix, b)) {
+            prefix = code;
+            continue;
+        }
+        w.write(prefix, len);
+        table.insert((prefix, b), next);
+        let (reset, new_len) = add(&mut next, len);
+        if reset {
+            w.write(LZW_CLEAR, len);
+            table.clear();
+            next = LZW_FIRST;
+        }
+        len = new_len;
+        prefix = b as u16;
+    }
+    w.write(prefix, len);
+    // decoder adds table entry after reading the last code
+    let (reset, new_len) = add(&mut next, len);
+    if reset {
+        w.write(LZW_CLEAR, len);
+    }
+    w.write(LZW_EOI, new_len);
+    w.finish()
+}
@@ -0,0 +1,132 @@
+use std::str::FromStr;
+
+/// Rectangle used for cropping, in pixels
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CropRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Parses `X,Y,WIDTH,HEIGHT`
+impl FromStr for CropRect {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let vals = s.split(',')
+            .map(|v| v.trim().parse::<usize>())
+            .collect::<Result<Vec<usize>, _>>()
+            .map_err(|_| "invalid crop rectangle value")?;
+        match vals[..] {
+            [x, y, width, height] if width != 0 && height != 0 => {
+                Ok(Self { x, y, width, height })
+            },
+            [_, _, _, _] => Err("crop rectangle must not be empty"),
+            _ => Err("crop rectangle must be specified as X,Y,WIDTH,HEIGHT"),
+        }
+    }
+}
+
+impl CropRect {
+    /// Check if rectangle is inside of the image with the given size
+    pub fn fits(&self, width: usize, height: usize) -> bool {
+        self.x + self.width <= width && self.y + self.height <= height
+    }
+
+    /// Check if rectangle consists of whole 2x2 Bayer quads
+    pub fn is_cfa_aligned(&self) -> bool {
+        (self.x | self.y | self.width | self.height) & 1 == 0
+    }
+}
+
+/// Crop image with interleaved channels, rectangle must fit into the image
+pub fn crop<T: Copy>(
+    data: &[T], width: usize, channels: usize, rect: CropRect,
+) -> Box<[T]> {
+    let c = channels;
+    assert_eq!(data.len() % (c*width), 0);
+    assert!(rect.fits(width, data.len()/(c*width)));
+    let mut res = Vec::with_capacity(c*rect.width*rect.height);
+    for row in data.chunks_exact(c*width).skip(rect.y).take(rect.height) {
+        res.extend_from_slice(&row[c*rect.x..c*(rect.x + rect.width)]);
+    }
+    res.into_boxed_slice()
+}
+
+/// Clockwise rotation by a multiple of 90 degrees
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Rotation {
+    None,
+    Cw90,
+    Cw180,
+    Cw270,
+}
+
+impl FromStr for Rotation {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(Rotation::None),
+            "90" => Ok(Rotation::Cw90),
+            "180" => Ok(Rotation::Cw180),
+            "270" => Ok(Rotation::Cw270),
+            _ => Err("rotation angle must be 0, 90, 180 or 270"),
+        }
+    }
+}
+
+/// Rotate image with interleaved channels, returns rotated image with its
+/// width and height
+pub fn rotate<T: Copy>(
+    data: &[T], width: usize, height: usize, channels: usize,
+    rotation: Rotation,
+) -> (Box<[T]>, usize, usize) {
+    let c = channels;
+    assert_eq!(data.len(), c*width*height);
+    let (w, h) = match rotation {
+        Rotation::None | Rotation::Cw180 => (width, height),
+        Rotation::Cw90 | Rotation::Cw270 => (height, width),
+    };
+    let mut res = Vec::with_capacity(data.len());
+    for y in 0..h {
+        for x in 0..w {
+            // position of the output pixel in the source image
+            let (sx, sy) = match rotation {
+                Rotation::None => (x, y),
+                Rotation::Cw90 => (y, height - 1 - x),
+                Rotation::Cw180 => (width - 1 - x, height - 1 - y),
+                Rotation::Cw270 => (width - 1 - y, x),
+            };
+            let i = c*(sy*width + sx);
+            res.extend_from_slice(&data[i..i + c]);
+        }
+    }
+    (res.into_boxed_slice(), w, h)
+}
+
+/// Flip image with interleaved channels in place
+pub fn flip<T: Copy>(
+    data: &mut [T], width: usize, channels: usize,
+    horizontal: bool, vertical: bool,
+) {
+    let c = channels;
+    assert_eq!(data.len() % (c*width), 0);
+    if horizontal {
+        for row in data.chunks_exact_mut(c*width) {
+            for x in 0..width/2 {
+                let (l, r) = row.split_at_mut(c*(width - 1 - x));
+                l[c*x..c*x + c].swap_with_slice(&mut r[..c]);
+            }
+        }
+    }
+    if vertical {
+        let height = data.len()/(c*width);
+        for y in 0..height/2 {
+            let (top, bottom) = data.split_at_mut(c*width*(height - 1 - y));
+            top[c*width*y..c*width*(y + 1)]
+                .swap_with_slice(&mut bottom[..c*width]);
+        }
+    }
+}
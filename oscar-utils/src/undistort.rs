@@ -0,0 +1,300 @@
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::{fs, io};
+
+use super::Sample;
+
+/// Number of iterations used for inversion of the distortion model
+const UNDISTORT_ITERATIONS: usize = 20;
+
+/// Pinhole camera model with radial and tangential (Brown-Conrady)
+/// distortion, as used by OpenCV (including its rational model)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CameraModel {
+    /// Size of the calibration images
+    pub width: usize,
+    pub height: usize,
+    pub fx: f64,
+    pub fy: f64,
+    pub cx: f64,
+    pub cy: f64,
+    /// Radial distortion coefficients, `k4..k6` are used only by the
+    /// rational model and are zero otherwise
+    pub k: [f64; 6],
+    /// Tangential distortion coefficients
+    pub p: [f64; 2],
+}
+
+/// Lines of the OpenCV YAML file without comments and directives
+pub(crate) fn yaml_lines(s: &str) -> Vec<&str> {
+    s.lines()
+        .filter(|line| {
+            let line = line.trim();
+            !(line.is_empty() || line.starts_with('#')
+                || line.starts_with('%') || line == "---")
+        })
+        .collect()
+}
+
+/// Find top-level entry of the OpenCV YAML file and return its text
+pub(crate) fn yaml_entry<'a>(
+    lines: &[&'a str], key: &str,
+) -> Option<Vec<&'a str>> {
+    let start = lines.iter().position(|line| {
+        line.starts_with(key) && line[key.len()..].trim_start().starts_with(':')
+    })?;
+    let mut res = vec![&lines[start][key.len()..]];
+    res.extend(lines[start + 1..].iter()
+        .take_while(|line| line.starts_with(char::is_whitespace)));
+    Some(res)
+}
+
+pub(crate) fn yaml_scalar(
+    lines: &[&str], key: &str,
+) -> Result<usize, &'static str> {
+    let entry = yaml_entry(lines, key).ok_or("image size is not specified")?;
+    entry[0].trim_start()[1..].trim().parse()
+        .map_err(|_| "invalid image size")
+}
+
+/// Parse `data` field of the `!!opencv-matrix` entry
+pub(crate) fn yaml_matrix(
+    lines: &[&str], key: &str,
+) -> Result<Vec<f64>, &'static str> {
+    let entry = yaml_entry(lines, key).ok_or("matrix is not found")?;
+    let text = entry.join(" ");
+    let start = text.find("data:").ok_or("matrix data is not found")?;
+    let text = &text[start + 5..];
+    let (start, end) = match (text.find('['), text.find(']')) {
+        (Some(s), Some(e)) if s < e => (s, e),
+        _ => Err("invalid matrix data")?,
+    };
+    text[start + 1..end]
+        .split(',')
+        .map(|v| v.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|_| "invalid matrix value")
+}
+
+/// Parses OpenCV YAML file with `image_width`, `image_height`,
+/// `camera_matrix` and `distortion_coefficients` entries (as written by the
+/// OpenCV camera calibration sample). Distortion coefficients are expected
+/// in the `k1, k2, p1, p2[, k3[, k4, k5, k6]]` order.
+impl FromStr for CameraModel {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lines = yaml_lines(s);
+        let width = yaml_scalar(&lines, "image_width")?;
+        let height = yaml_scalar(&lines, "image_height")?;
+        let m = yaml_matrix(&lines, "camera_matrix")?;
+        let d = yaml_matrix(&lines, "distortion_coefficients")?;
+        Self::from_matrices(width, height, &m, &d)
+    }
+}
+
+impl CameraModel {
+    /// Construct model from the row-major camera matrix and distortion
+    /// coefficients in the OpenCV order
+    pub fn from_matrices(
+        width: usize, height: usize, m: &[f64], d: &[f64],
+    ) -> Result<Self, &'static str> {
+        let is_valid = m.len() == 9 && m[1] == 0.0 && m[3] == 0.0
+            && m[6..] == [0., 0., 1.];
+        if !is_valid {
+            Err("invalid camera matrix")?
+        }
+        let mut k = [0.0; 6];
+        match d.len() {
+            4 => (),
+            5 => k[2] = d[4],
+            8 => k[2..].copy_from_slice(&d[4..]),
+            _ => Err("unsupported number of distortion coefficients")?,
+        }
+        k[..2].copy_from_slice(&d[..2]);
+        Ok(Self {
+            width, height,
+            fx: m[0], fy: m[4], cx: m[2], cy: m[5],
+            k,
+            p: [d[2], d[3]],
+        })
+    }
+
+    /// Load camera model from the OpenCV YAML file (see `FromStr`)
+    pub fn load(path: &Path) -> io::Result<Self> {
+        fs::read_to_string(path)?
+            .parse()
+            .map_err(|err| io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), err),
+            ))
+    }
+
+    /// Position in the distorted image of the undistorted image point, both
+    /// are in pixels of the image with the calibration size
+    pub fn distort(&self, u: f64, v: f64) -> (f64, f64) {
+        self.distort_normalized((u - self.cx)/self.fx, (v - self.cy)/self.fy)
+    }
+
+    /// Position in pixels of the distorted image of the point with the given
+    /// normalized (i.e. `X/Z`, `Y/Z`) coordinates
+    pub fn distort_normalized(&self, x: f64, y: f64) -> (f64, f64) {
+        let [p1, p2] = self.p;
+        let r2 = x*x + y*y;
+        let radial = self.radial(r2);
+        let xd = x*radial + 2.0*p1*x*y + p2*(r2 + 2.0*x*x);
+        let yd = y*radial + p1*(r2 + 2.0*y*y) + 2.0*p2*x*y;
+        (self.fx*xd + self.cx, self.fy*yd + self.cy)
+    }
+
+    /// Normalized coordinates of the distorted image point, inverse of
+    /// `distort_normalized` computed iteratively the same way as by OpenCV
+    /// `undistortPoints`
+    pub fn undistort_normalized(&self, u: f64, v: f64) -> (f64, f64) {
+        let [p1, p2] = self.p;
+        let (x0, y0) = ((u - self.cx)/self.fx, (v - self.cy)/self.fy);
+        let (mut x, mut y) = (x0, y0);
+        for _ in 0..UNDISTORT_ITERATIONS {
+            let r2 = x*x + y*y;
+            let dx = 2.0*p1*x*y + p2*(r2 + 2.0*x*x);
+            let dy = p1*(r2 + 2.0*y*y) + 2.0*p2*x*y;
+            let radial = self.radial(r2);
+            x = (x0 - dx)/radial;
+            y = (y0 - dy)/radial;
+        }
+        (x, y)
+    }
+
+    fn radial(&self, r2: f64) -> f64 {
+        let [k1, k2, k3, k4, k5, k6] = self.k;
+        (1.0 + r2*(k1 + r2*(k2 + r2*k3)))/(1.0 + r2*(k4 + r2*(k5 + r2*k6)))
+    }
+}
+
+/// Source of the output pixel, `idx` is index of the top-left pixel of the
+/// bilinear interpolation neighbourhood or `u32::MAX` for pixels outside of
+/// the source image
+#[derive(Debug, Copy, Clone)]
+struct RemapEntry {
+    idx: u32,
+    wx: f32,
+    wy: f32,
+}
+
+/// Precomputed undistortion or rectification map for images of the given size
+#[derive(Debug, Clone)]
+pub struct RemapTable {
+    width: usize,
+    height: usize,
+    entries: Box<[RemapEntry]>,
+}
+
+impl RemapTable {
+    /// Build table for images with the given size, camera model is scaled
+    /// if the size differs from the calibration one (e.g. for downscaled
+    /// images)
+    pub fn new(model: &CameraModel, width: usize, height: usize) -> Self {
+        let calib_size = (model.width, model.height);
+        Self::from_fn(width, height, calib_size, |u, v| model.distort(u, v))
+    }
+
+    /// Build table for images with the given size from the function, which
+    /// maps output pixel positions into source ones, both in pixels of the
+    /// image with the calibration size
+    pub fn from_fn<F>(
+        width: usize, height: usize, calib_size: (usize, usize), f: F,
+    ) -> Self
+        where F: Fn(f64, f64) -> (f64, f64)
+    {
+        assert!(width >= 2 && height >= 2);
+        assert!((width*height) < u32::MAX as usize);
+        let sx = calib_size.0 as f64/width as f64;
+        let sy = calib_size.1 as f64/height as f64;
+        let (w, h) = (width as f64, height as f64);
+        let mut entries = Vec::with_capacity(width*height);
+        for y in 0..height {
+            for x in 0..width {
+                // pixel centres of the scaled image
+                let u = (x as f64 + 0.5)*sx - 0.5;
+                let v = (y as f64 + 0.5)*sy - 0.5;
+                let (u, v) = f(u, v);
+                let (u, v) = ((u + 0.5)/sx - 0.5, (v + 0.5)/sy - 0.5);
+                let outside = u < 0.0 || v < 0.0 || u > w - 1.0 || v > h - 1.0;
+                let entry = if outside {
+                    RemapEntry { idx: u32::MAX, wx: 0.0, wy: 0.0 }
+                } else {
+                    let x0 = (u.floor() as usize).min(width - 2);
+                    let y0 = (v.floor() as usize).min(height - 2);
+                    RemapEntry {
+                        idx: (y0*width + x0) as u32,
+                        wx: (u - x0 as f64) as f32,
+                        wy: (v - y0 as f64) as f32,
+                    }
+                };
+                entries.push(entry);
+            }
+        }
+        Self { width, height, entries: entries.into_boxed_slice() }
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Remap image with interleaved channels using bilinear interpolation,
+    /// pixels outside of the source image are set to zero
+    pub fn remap<T: Sample>(&self, src: &[T], channels: usize) -> Box<[T]> {
+        let (w, c) = (self.width, channels);
+        assert_eq!(src.len(), c*w*self.height);
+        let mut dst = vec![T::default(); src.len()];
+        for (e, out) in self.entries.iter().zip(dst.chunks_exact_mut(c)) {
+            if e.idx == u32::MAX { continue; }
+            let i = c*e.idx as usize;
+            let (wx, wy) = (e.wx, e.wy);
+            for (ch, out) in out.iter_mut().enumerate() {
+                let v = |off: usize| src[i + off + ch].to_u32() as f32;
+                let top = v(0)*(1.0 - wx) + v(c)*wx;
+                let bottom = v(c*w)*(1.0 - wx) + v(c*w + c)*wx;
+                let res = top*(1.0 - wy) + bottom*wy;
+                *out = T::from_u32((res + 0.5) as u32);
+            }
+        }
+        dst.into_boxed_slice()
+    }
+}
+
+/// Lens undistortion, which computes remap table on the first use and
+/// reuses it for all images of the same size
+#[derive(Debug)]
+pub struct Undistorter {
+    model: CameraModel,
+    table: Mutex<Option<Arc<RemapTable>>>,
+}
+
+impl Undistorter {
+    pub fn new(model: CameraModel) -> Self {
+        Self { model, table: Mutex::new(None) }
+    }
+
+    /// Remap table for images of the given size
+    pub fn table(&self, width: usize, height: usize) -> Arc<RemapTable> {
+        // lock is held while table is built, so other threads wait for it
+        let mut table = self.table.lock().unwrap();
+        match &*table {
+            Some(t) if t.size() == (width, height) => t.clone(),
+            _ => {
+                let t = Arc::new(RemapTable::new(&self.model, width, height));
+                *table = Some(t.clone());
+                t
+            },
+        }
+    }
+
+    /// Undistort image with interleaved channels
+    pub fn undistort<T: Sample>(
+        &self, src: &[T], width: usize, height: usize, channels: usize,
+    ) -> Box<[T]> {
+        self.table(width, height).remap(src, channels)
+    }
+}
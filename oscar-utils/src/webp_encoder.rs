@@ -0,0 +1,62 @@
+//! Encoder of 8-bit lossless (VP8L) and lossy (VP8) WebP images
+//!
+//! Images are written in the simple file format (one image chunk without
+//! metadata and alpha), grayscale images are stored as RGB.
+use std::io::{self, Write};
+
+mod lossless;
+mod lossy;
+mod tables;
+
+/// Maximum width and height of WebP images
+pub const MAX_DIMENSION: usize = 16383;
+
+/// Compression of the image data
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WebpMode {
+    Lossless,
+    /// VP8 compression with the given quality (from 0 to 100)
+    Lossy(u8),
+}
+
+/// Encode 8-bit grayscale or RGB image into WebP file
+pub fn encode_webp<W: Write>(
+    data: &[u8], width: usize, height: usize, channels: usize,
+    mode: WebpMode, mut writer: W,
+) -> io::Result<()> {
+    assert!(channels == 1 || channels == 3);
+    assert_eq!(data.len(), channels*width*height);
+    if width == 0 || height == 0 {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "image is empty"))?
+    }
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "image dimensions are too large for WebP",
+        ))?
+    }
+    let (fourcc, chunk) = match mode {
+        WebpMode::Lossless => {
+            (b"VP8L", lossless::encode(data, width, height, channels))
+        },
+        WebpMode::Lossy(quality) => {
+            (b"VP8 ", lossy::encode(data, width, height, channels, quality)?)
+        },
+    };
+    // chunks are padded to even size
+    let pad = chunk.len() & 1;
+    let riff_size = 4 + 8 + chunk.len() + pad;
+    if riff_size > u32::MAX as usize {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput, "image is too large for WebP",
+        ))?
+    }
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(riff_size as u32).to_le_bytes())?;
+    writer.write_all(b"WEBP")?;
+    writer.write_all(fourcc)?;
+    writer.write_all(&(chunk.len() as u32).to_le_bytes())?;
+    writer.write_all(&chunk)?;
+    writer.write_all(&[0u8][..pad])?;
+    Ok(())
+}
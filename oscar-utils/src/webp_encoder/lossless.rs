@@ -0,0 +1,516 @@
+//! Lossless VP8L bitstream encoder
+//!
+//! Images are coded with the subtract green and predictor transforms, LZ77
+//! backward references and the colour cache, using one group of prefix codes
+//! for the whole image.
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// Signature byte of the VP8L bitstream
+const SIGNATURE: u32 = 0x2f;
+const PREDICTOR_TRANSFORM: u32 = 0;
+const SUBTRACT_GREEN_TRANSFORM: u32 = 2;
+/// Log2 of the block size of the predictor transform
+const PREDICTOR_BITS: u32 = 4;
+const NUM_PREDICTORS: u32 = 14;
+/// Log2 of the colour cache size
+const CACHE_BITS: u32 = 10;
+const NUM_LITERALS: usize = 256;
+const NUM_LENGTH_CODES: usize = 24;
+const NUM_DISTANCE_CODES: usize = 40;
+const MIN_LENGTH: usize = 3;
+const MAX_LENGTH: usize = 4096;
+const MAX_CODE_LENGTH: u8 = 15;
+const MAX_CODE_LENGTH_CODE_LENGTH: u8 = 7;
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    17, 18, 0, 1, 2, 3, 4, 5, 16, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+/// Writer of the bitstream, bits are packed starting from the least
+/// significant one
+struct BitWriter {
+    buf: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self { buf: Vec::new(), acc: 0, bits: 0 }
+    }
+
+    fn write(&mut self, val: u32, len: u32) {
+        debug_assert!(len == 32 || val >> len == 0);
+        self.acc |= (val as u64) << self.bits;
+        self.bits += len;
+        while self.bits >= 8 {
+            self.buf.push(self.acc as u8);
+            self.acc >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits != 0 {
+            self.buf.push(self.acc as u8);
+        }
+        self.buf
+    }
+}
+
+/// Lengths of the Huffman code for the given symbol counts, lengths are
+/// limited by flattening the counts until the code fits
+fn code_lengths(counts: &[u32], max_len: u8) -> Vec<u8> {
+    let mut lengths = vec![0u8; counts.len()];
+    let used: Vec<usize> = (0..counts.len()).filter(|&s| counts[s] != 0)
+        .collect();
+    match used.len() {
+        0 => return lengths,
+        1 => {
+            lengths[used[0]] = 1;
+            return lengths;
+        },
+        _ => (),
+    }
+    let n = used.len();
+    let mut min_count = 1u64;
+    loop {
+        // leaves are nodes `0..n`, internal nodes are added after them
+        let mut heap: BinaryHeap<_> = used.iter().enumerate()
+            .map(|(i, &s)| Reverse(((counts[s] as u64).max(min_count), i)))
+            .collect();
+        let mut parents = vec![0usize; 2*n - 1];
+        let mut next = n;
+        while let (Some(Reverse((w1, n1))), Some(Reverse((w2, n2))))
+            = (heap.pop(), heap.pop())
+        {
+            parents[n1] = next;
+            parents[n2] = next;
+            heap.push(Reverse((w1 + w2, next)));
+            next += 1;
+        }
+        // parents always have larger indices than their children
+        let mut depths = vec![0u8; next];
+        for i in (0..next - 1).rev() {
+            depths[i] = depths[parents[i]] + 1;
+        }
+        if depths[..n].iter().all(|&d| d <= max_len) {
+            for (&s, &d) in used.iter().zip(depths.iter()) {
+                lengths[s] = d;
+            }
+            return lengths;
+        }
+        min_count *= 2;
+    }
+}
+
+/// Canonical prefix code
+struct PrefixCode {
+    lengths: Vec<u8>,
+    /// Bit reversed codes, so they can be written starting from the least
+    /// significant bit
+    codes: Vec<u16>,
+    /// Code consisting of one symbol is decoded without reading any bits
+    is_single: bool,
+}
+
+impl PrefixCode {
+    fn new(counts: &[u32], max_len: u8) -> Self {
+        let lengths = code_lengths(counts, max_len);
+        let mut codes = vec![0u16; lengths.len()];
+        let is_single = lengths.iter().filter(|&&l| l != 0).count() <= 1;
+        if !is_single {
+            let mut next = [0u16; MAX_CODE_LENGTH as usize + 2];
+            for &l in lengths.iter().filter(|&&l| l != 0) {
+                next[l as usize + 1] += 1;
+            }
+            for l in 1..next.len() {
+                next[l] = (next[l] + next[l - 1]) << 1;
+            }
+            for (code, &l) in codes.iter_mut().zip(lengths.iter()) {
+                if l == 0 { continue; }
+                let c = next[l as usize];
+                next[l as usize] += 1;
+                *code = c.reverse_bits() >> (16 - l);
+            }
+        }
+        Self { lengths, codes, is_single }
+    }
+
+    fn write_symbol(&self, w: &mut BitWriter, symbol: usize) {
+        if !self.is_single {
+            w.write(self.codes[symbol] as u32, self.lengths[symbol] as u32);
+        }
+    }
+
+    /// Write code lengths of the code
+    fn write(&self, w: &mut BitWriter) {
+        let used: Vec<usize> = (0..self.lengths.len())
+            .filter(|&s| self.lengths[s] != 0)
+            .collect();
+        if used.len() <= 2 && used.iter().all(|&s| s < 256) {
+            // simple code, unused codes are written as a code of symbol 0
+            let first = used.first().cloned().unwrap_or(0) as u32;
+            w.write(1, 1);
+            w.write(used.len().max(1) as u32 - 1, 1);
+            if first < 2 {
+                w.write(0, 1);
+                w.write(first, 1);
+            } else {
+                w.write(1, 1);
+                w.write(first, 8);
+            }
+            if let Some(&second) = used.get(1) {
+                w.write(second as u32, 8);
+            }
+            return;
+        }
+
+        let tokens = length_tokens(&self.lengths);
+        let mut counts = [0u32; 19];
+        for &(symbol, _) in tokens.iter() {
+            counts[symbol as usize] += 1;
+        }
+        let code = PrefixCode::new(&counts, MAX_CODE_LENGTH_CODE_LENGTH);
+        let num = CODE_LENGTH_ORDER.iter()
+            .rposition(|&s| code.lengths[s] != 0)
+            .map_or(4, |i| (i + 1).max(4));
+        w.write(0, 1);
+        w.write(num as u32 - 4, 4);
+        for &s in CODE_LENGTH_ORDER[..num].iter() {
+            w.write(code.lengths[s] as u32, 3);
+        }
+        // lengths of all symbols are written
+        w.write(0, 1);
+        for &(symbol, extra) in tokens.iter() {
+            code.write_symbol(w, symbol as usize);
+            match symbol {
+                16 => w.write(extra, 2),
+                17 => w.write(extra, 3),
+                18 => w.write(extra, 7),
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Run length coding of the code lengths, returns symbols of the code
+/// length code with values of their extra bits
+fn length_tokens(lengths: &[u8]) -> Vec<(u8, u32)> {
+    let mut res = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let l = lengths[i];
+        let mut run = lengths[i..].iter().take_while(|&&v| v == l).count();
+        i += run;
+        if l == 0 {
+            while run >= 11 {
+                let n = run.min(138);
+                res.push((18, (n - 11) as u32));
+                run -= n;
+            }
+            if run >= 3 {
+                res.push((17, (run - 3) as u32));
+                run = 0;
+            }
+        } else {
+            res.push((l, 0));
+            run -= 1;
+            // repeats previous non-zero length
+            while run >= 3 {
+                let n = run.min(6);
+                res.push((16, (n - 3) as u32));
+                run -= n;
+            }
+        }
+        res.extend((0..run).map(|_| (l, 0)));
+    }
+    res
+}
+
+/// Prefix symbol, number of extra bits and their value of the length or
+/// distance code
+fn prefix_encode(val: usize) -> (usize, u32, u32) {
+    debug_assert!(val > 0);
+    if val <= 4 { return (val - 1, 0, 0); }
+    let d = (val - 1) as u32;
+    let high = 31 - d.leading_zeros();
+    let extra_bits = high - 1;
+    let prefix = 2*high + ((d >> extra_bits) & 1);
+    (prefix as usize, extra_bits, d & ((1 << extra_bits) - 1))
+}
+
+/// Symbol of the entropy coded image
+#[derive(Copy, Clone)]
+enum Symbol {
+    Literal(u32),
+    Cache(u32),
+    Copy { length: usize, distance: usize },
+}
+
+fn cache_key(argb: u32) -> usize {
+    (argb.wrapping_mul(0x1e35_a7bd) >> (32 - CACHE_BITS)) as usize
+}
+
+/// Find backward references to the left and top pixels, pixels which are
+/// not copied are coded either as literals or as colour cache entries
+///
+/// Matches found at other distances (e.g. by hash chains) are mostly short
+/// on photos and their distance codes are expensive, so they make the
+/// output larger than the colour cache.
+fn backward_refs(argb: &[u32], width: usize) -> Vec<Symbol> {
+    let n = argb.len();
+    let match_len = |i: usize, j: usize, max: usize| {
+        argb[i..i + max].iter().zip(argb[j..].iter())
+            .take_while(|(a, b)| a == b)
+            .count()
+    };
+
+    let mut cache = vec![0u32; 1 << CACHE_BITS];
+    let mut res = Vec::new();
+    let mut i = 0;
+    while i < n {
+        let max = MAX_LENGTH.min(n - i);
+        let left = if i >= 1 { match_len(i, i - 1, max) } else { 0 };
+        let top = if i >= width { match_len(i, i - width, max) } else { 0 };
+        let length = left.max(top);
+        if length >= MIN_LENGTH {
+            // codes 1 and 2 of the distance map are the top and left pixels
+            let distance = if top >= left { 1 } else { 2 };
+            res.push(Symbol::Copy { length, distance });
+            for &p in argb[i..i + length].iter() {
+                cache[cache_key(p)] = p;
+            }
+            i += length;
+        } else {
+            let p = argb[i];
+            let key = cache_key(p);
+            if cache[key] == p {
+                res.push(Symbol::Cache(key as u32));
+            } else {
+                res.push(Symbol::Literal(p));
+                cache[key] = p;
+            }
+            i += 1;
+        }
+    }
+    res
+}
+
+/// Write entropy coded image, the main image additionally has the meta
+/// prefix codes flag
+fn write_image(w: &mut BitWriter, argb: &[u32], width: usize, is_main: bool) {
+    let symbols = backward_refs(argb, width);
+    let green_size = NUM_LITERALS + NUM_LENGTH_CODES + (1 << CACHE_BITS);
+    let mut counts = [
+        vec![0u32; green_size], vec![0; NUM_LITERALS], vec![0; NUM_LITERALS],
+        vec![0; NUM_LITERALS], vec![0; NUM_DISTANCE_CODES],
+    ];
+    for symbol in symbols.iter() {
+        match *symbol {
+            Symbol::Literal(p) => {
+                counts[0][(p >> 8) as usize & 0xff] += 1;
+                counts[1][(p >> 16) as usize & 0xff] += 1;
+                counts[2][p as usize & 0xff] += 1;
+                counts[3][(p >> 24) as usize] += 1;
+            },
+            Symbol::Cache(key) => {
+                counts[0][NUM_LITERALS + NUM_LENGTH_CODES + key as usize] += 1;
+            },
+            Symbol::Copy { length, distance } => {
+                counts[0][NUM_LITERALS + prefix_encode(length).0] += 1;
+                counts[4][prefix_encode(distance).0] += 1;
+            },
+        }
+    }
+    let codes: Vec<PrefixCode> = counts.iter()
+        .map(|c| PrefixCode::new(c, MAX_CODE_LENGTH))
+        .collect();
+
+    w.write(1, 1);
+    w.write(CACHE_BITS, 4);
+    if is_main {
+        w.write(0, 1);
+    }
+    for code in codes.iter() {
+        code.write(w);
+    }
+    for symbol in symbols {
+        match symbol {
+            Symbol::Literal(p) => {
+                codes[0].write_symbol(w, (p >> 8) as usize & 0xff);
+                codes[1].write_symbol(w, (p >> 16) as usize & 0xff);
+                codes[2].write_symbol(w, p as usize & 0xff);
+                codes[3].write_symbol(w, (p >> 24) as usize);
+            },
+            Symbol::Cache(key) => {
+                let s = NUM_LITERALS + NUM_LENGTH_CODES + key as usize;
+                codes[0].write_symbol(w, s);
+            },
+            Symbol::Copy { length, distance } => {
+                let (prefix, bits, extra) = prefix_encode(length);
+                codes[0].write_symbol(w, NUM_LITERALS + prefix);
+                w.write(extra, bits);
+                let (prefix, bits, extra) = prefix_encode(distance);
+                codes[4].write_symbol(w, prefix);
+                w.write(extra, bits);
+            },
+        }
+    }
+}
+
+/// Per channel average of two pixels
+fn average2(a: u32, b: u32) -> u32 {
+    (((a ^ b) & 0xfefe_fefe) >> 1) + (a & b)
+}
+
+/// Per channel difference of two pixels
+fn sub_pixels(a: u32, b: u32) -> u32 {
+    let alpha_green = 0x00ff_00ffu32
+        .wrapping_add(a & 0xff00_ff00)
+        .wrapping_sub(b & 0xff00_ff00);
+    let red_blue = 0xff00_ff00u32
+        .wrapping_add(a & 0x00ff_00ff)
+        .wrapping_sub(b & 0x00ff_00ff);
+    (alpha_green & 0xff00_ff00) | (red_blue & 0x00ff_00ff)
+}
+
+/// Per channel function of three pixels
+fn map_channels<F>(a: u32, b: u32, c: u32, f: F) -> u32
+    where F: Fn(i32, i32, i32) -> i32
+{
+    let (a, b, c) = (a.to_le_bytes(), b.to_le_bytes(), c.to_le_bytes());
+    let mut res = [0u8; 4];
+    for i in 0..4 {
+        res[i] = f(a[i] as i32, b[i] as i32, c[i] as i32).clamp(0, 255) as u8;
+    }
+    u32::from_le_bytes(res)
+}
+
+/// Sum of per channel absolute differences of two pixels
+fn manhattan(a: u32, b: u32) -> i32 {
+    let (a, b) = (a.to_le_bytes(), b.to_le_bytes());
+    (0..4).map(|i| (a[i] as i32 - b[i] as i32).abs()).sum()
+}
+
+/// Prediction of the pixel which is not on the top row or the left column
+fn predict_pixel(mode: u32, argb: &[u32], i: usize, width: usize) -> u32 {
+    let l = argb[i - 1];
+    let t = argb[i - width];
+    let tl = argb[i - width - 1];
+    // for the rightmost column it's the leftmost pixel of the current row
+    let tr = argb[i - width + 1];
+    match mode {
+        0 => 0xff00_0000,
+        1 => l,
+        2 => t,
+        3 => tr,
+        4 => tl,
+        5 => average2(average2(l, tr), t),
+        6 => average2(l, tl),
+        7 => average2(l, t),
+        8 => average2(tl, t),
+        9 => average2(t, tr),
+        10 => average2(average2(l, tl), average2(t, tr)),
+        11 => if manhattan(t, tl) < manhattan(l, tl) { l } else { t },
+        12 => map_channels(l, t, tl, |l, t, tl| l + t - tl),
+        _ => map_channels(average2(l, t), tl, 0, |a, tl, _| a + (a - tl)/2),
+    }
+}
+
+/// Prediction of the pixel using the transform rules for the image borders
+fn predict(mode: u32, argb: &[u32], x: usize, y: usize, width: usize) -> u32 {
+    let i = y*width + x;
+    match (x, y) {
+        (0, 0) => 0xff00_0000,
+        (_, 0) => argb[i - 1],
+        (0, _) => argb[i - width],
+        _ => predict_pixel(mode, argb, i, width),
+    }
+}
+
+/// Choose predictor modes of the blocks by the smallest sum of absolute
+/// residuals, returns residuals and the sub-image of modes
+fn predictor_transform(
+    argb: &[u32], width: usize, height: usize,
+) -> (Vec<u32>, Vec<u32>) {
+    let size = 1 << PREDICTOR_BITS;
+    let blocks_w = (width + size - 1) >> PREDICTOR_BITS;
+    let blocks_h = (height + size - 1) >> PREDICTOR_BITS;
+    // alpha is always predicted exactly
+    let cost = |res: u32| -> u32 {
+        res.to_le_bytes()[..3].iter()
+            .map(|&v| (v as i8).unsigned_abs() as u32)
+            .sum()
+    };
+    let mut modes = Vec::with_capacity(blocks_w*blocks_h);
+    let mut residuals = vec![0u32; argb.len()];
+    for by in 0..blocks_h {
+        let ys = by*size..((by + 1)*size).min(height);
+        for bx in 0..blocks_w {
+            let xs = bx*size..((bx + 1)*size).min(width);
+            let mode = (0..NUM_PREDICTORS)
+                .min_by_key(|&mode| {
+                    let mut sum = 0;
+                    for y in ys.clone().filter(|&y| y != 0).step_by(2) {
+                        for x in xs.clone().filter(|&x| x != 0) {
+                            let i = y*width + x;
+                            let p = predict_pixel(mode, argb, i, width);
+                            sum += cost(sub_pixels(argb[i], p));
+                        }
+                    }
+                    sum
+                })
+                .unwrap_or(0);
+            for y in ys.clone() {
+                for x in xs.clone() {
+                    let i = y*width + x;
+                    let p = predict(mode, argb, x, y, width);
+                    residuals[i] = sub_pixels(argb[i], p);
+                }
+            }
+            // mode is stored in the green channel
+            modes.push(0xff00_0000 | (mode << 8));
+        }
+    }
+    (residuals, modes)
+}
+
+/// Encode 8-bit grayscale or RGB image into VP8L bitstream
+pub(super) fn encode(
+    data: &[u8], width: usize, height: usize, channels: usize,
+) -> Vec<u8> {
+    // opaque ARGB pixels after the subtract green transform
+    let argb: Vec<u32> = data.chunks_exact(channels)
+        .map(|p| match *p {
+            [v] => 0xff00_0000 | (v as u32) << 8,
+            [r, g, b] => {
+                let r = r.wrapping_sub(g) as u32;
+                let b = b.wrapping_sub(g) as u32;
+                0xff00_0000 | (r << 16) | (g as u32) << 8 | b
+            },
+            _ => unreachable!(),
+        })
+        .collect();
+
+    let mut w = BitWriter::new();
+    w.write(SIGNATURE, 8);
+    w.write(width as u32 - 1, 14);
+    w.write(height as u32 - 1, 14);
+    // alpha is not used, version 0
+    w.write(0, 1);
+    w.write(0, 3);
+
+    w.write(1, 1);
+    w.write(SUBTRACT_GREEN_TRANSFORM, 2);
+    let (residuals, modes) = predictor_transform(&argb, width, height);
+    w.write(1, 1);
+    w.write(PREDICTOR_TRANSFORM, 2);
+    w.write(PREDICTOR_BITS - 2, 3);
+    let blocks_w = (width + (1 << PREDICTOR_BITS) - 1) >> PREDICTOR_BITS;
+    write_image(&mut w, &modes, blocks_w, false);
+    w.write(0, 1);
+
+    write_image(&mut w, &residuals, width, true);
+    w.finish()
+}
@@ -0,0 +1,760 @@
+//! Lossy VP8 key frame encoder
+//!
+//! Macroblocks are predicted only with the whole block luma and chroma modes
+//! chosen by the smallest sum of absolute differences, coefficients are
+//! coded with the default token probabilities in one partition.
+use std::io;
+
+use super::tables::{AC_QUANT, COEFF_PROBS, COEFF_UPDATE_PROBS, DC_QUANT};
+
+const START_CODE: [u8; 3] = [0x9d, 0x01, 0x2a];
+/// Maximum size of the first partition
+const MAX_PARTITION_SIZE: usize = (1 << 19) - 1;
+const MAX_LEVEL: i32 = 2047;
+/// Loop filter level is equal to the quantizer index divided by this value
+const FILTER_DIVISOR: usize = 3;
+
+/// Bands of the coefficients in the zigzag order
+const COEFF_BANDS: [usize; 16] = [
+    0, 1, 2, 3, 6, 4, 5, 6, 6, 6, 6, 6, 6, 6, 6, 7,
+];
+/// Raster positions of the coefficients in the zigzag order
+const ZIGZAG: [usize; 16] = [
+    0, 1, 4, 8, 5, 2, 3, 6, 9, 12, 13, 10, 7, 11, 14, 15,
+];
+/// Base values and probabilities of the extra bits of the token categories
+const CATEGORIES: [(i32, &[u8]); 6] = [
+    (5, &[159]),
+    (7, &[165, 145]),
+    (11, &[173, 148, 140]),
+    (19, &[176, 155, 140, 135]),
+    (35, &[180, 157, 141, 134, 130]),
+    (67, &[254, 254, 243, 230, 196, 177, 153, 140, 133, 130, 129]),
+];
+
+/// Block types used for selection of the token probabilities
+const TYPE_Y_AFTER_Y2: usize = 0;
+const TYPE_Y2: usize = 1;
+const TYPE_CHROMA: usize = 2;
+
+/// Whole block intra prediction modes
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Mode {
+    Dc,
+    V,
+    H,
+    Tm,
+}
+
+const MODES: [Mode; 4] = [Mode::Dc, Mode::V, Mode::H, Mode::Tm];
+
+/// Boolean entropy encoder (see RFC 6386, section 7)
+struct BoolEncoder {
+    buf: Vec<u8>,
+    range: u32,
+    bottom: u32,
+    bit_count: i32,
+}
+
+impl BoolEncoder {
+    fn new() -> Self {
+        Self { buf: Vec::new(), range: 255, bottom: 0, bit_count: 24 }
+    }
+
+    fn add_one(&mut self) {
+        for b in self.buf.iter_mut().rev() {
+            if *b == 255 {
+                *b = 0;
+            } else {
+                *b += 1;
+                break;
+            }
+        }
+    }
+
+    fn write_bool(&mut self, prob: u8, bit: bool) {
+        let split = 1 + (((self.range - 1)*prob as u32) >> 8);
+        if bit {
+            self.bottom += split;
+            self.range -= split;
+        } else {
+            self.range = split;
+        }
+        while self.range < 128 {
+            self.range <<= 1;
+            if self.bottom & (1 << 31) != 0 {
+                self.add_one();
+            }
+            self.bottom <<= 1;
+            self.bit_count -= 1;
+            if self.bit_count == 0 {
+                self.buf.push((self.bottom >> 24) as u8);
+                self.bottom &= (1 << 24) - 1;
+                self.bit_count = 8;
+            }
+        }
+    }
+
+    /// Write unsigned value starting from the most significant bit
+    fn write_literal(&mut self, val: u32, bits: u32) {
+        for i in (0..bits).rev() {
+            self.write_bool(128, (val >> i) & 1 != 0);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let mut c = self.bit_count;
+        let mut v = self.bottom;
+        if v & (1 << (32 - c)) != 0 {
+            self.add_one();
+        }
+        v <<= c & 7;
+        c >>= 3;
+        for _ in 0..c {
+            v <<= 8;
+        }
+        for _ in 0..4 {
+            self.buf.push((v >> 24) as u8);
+            v <<= 8;
+        }
+        self.buf
+    }
+}
+
+/// Forward DCT of the 4x4 block of residuals (the same as in libvpx)
+fn fdct(input: &[i32; 16]) -> [i32; 16] {
+    let mut tmp = [0i32; 16];
+    for i in 0..4 {
+        let ip = &input[4*i..4*i + 4];
+        let a1 = (ip[0] + ip[3])*8;
+        let b1 = (ip[1] + ip[2])*8;
+        let c1 = (ip[1] - ip[2])*8;
+        let d1 = (ip[0] - ip[3])*8;
+        tmp[4*i] = a1 + b1;
+        tmp[4*i + 2] = a1 - b1;
+        tmp[4*i + 1] = (c1*2217 + d1*5352 + 14500) >> 12;
+        tmp[4*i + 3] = (d1*2217 - c1*5352 + 7500) >> 12;
+    }
+    let mut out = [0i32; 16];
+    for i in 0..4 {
+        let a1 = tmp[i] + tmp[12 + i];
+        let b1 = tmp[4 + i] + tmp[8 + i];
+        let c1 = tmp[4 + i] - tmp[8 + i];
+        let d1 = tmp[i] - tmp[12 + i];
+        out[i] = (a1 + b1 + 7) >> 4;
+        out[8 + i] = (a1 - b1 + 7) >> 4;
+        out[4 + i] = ((c1*2217 + d1*5352 + 12000) >> 16)
+            + if d1 != 0 { 1 } else { 0 };
+        out[12 + i] = (d1*2217 - c1*5352 + 51000) >> 16;
+    }
+    out
+}
+
+/// Inverse DCT, must match the decoder exactly
+fn idct(block: &mut [i32; 16]) {
+    const C1: i64 = 20091;
+    const C2: i64 = 35468;
+    let mut tmp = [0i64; 16];
+    for i in 0..4 {
+        let (b0, b1) = (block[i] as i64, block[4 + i] as i64);
+        let (b2, b3) = (block[8 + i] as i64, block[12 + i] as i64);
+        let a = b0 + b2;
+        let b = b0 - b2;
+        let c = ((b1*C2) >> 16) - (b3 + ((b3*C1) >> 16));
+        let d = (b1 + ((b1*C1) >> 16)) + ((b3*C2) >> 16);
+        tmp[i] = a + d;
+        tmp[4 + i] = b + c;
+        tmp[8 + i] = b - c;
+        tmp[12 + i] = a - d;
+    }
+    for i in 0..4 {
+        let t = &tmp[4*i..4*i + 4];
+        let a = t[0] + t[2];
+        let b = t[0] - t[2];
+        let c = ((t[1]*C2) >> 16) - (t[3] + ((t[3]*C1) >> 16));
+        let d = (t[1] + ((t[1]*C1) >> 16)) + ((t[3]*C2) >> 16);
+        block[4*i] = ((a + d + 4) >> 3) as i32;
+        block[4*i + 1] = ((b + c + 4) >> 3) as i32;
+        block[4*i + 2] = ((b - c + 4) >> 3) as i32;
+        block[4*i + 3] = ((a - d + 4) >> 3) as i32;
+    }
+}
+
+/// Forward Walsh-Hadamard transform of the luma DC coefficients (the same as
+/// in libvpx)
+fn fwht(input: &[i32; 16]) -> [i32; 16] {
+    let mut tmp = [0i32; 16];
+    for i in 0..4 {
+        let ip = &input[4*i..4*i + 4];
+        let a1 = (ip[0] + ip[2])*4;
+        let d1 = (ip[1] + ip[3])*4;
+        let c1 = (ip[1] - ip[3])*4;
+        let b1 = (ip[0] - ip[2])*4;
+        tmp[4*i] = a1 + d1 + if a1 != 0 { 1 } else { 0 };
+        tmp[4*i + 1] = b1 + c1;
+        tmp[4*i + 2] = b1 - c1;
+        tmp[4*i + 3] = a1 - d1;
+    }
+    let mut out = [0i32; 16];
+    for i in 0..4 {
+        let a1 = tmp[i] + tmp[8 + i];
+        let d1 = tmp[4 + i] + tmp[12 + i];
+        let c1 = tmp[4 + i] - tmp[12 + i];
+        let b1 = tmp[i] - tmp[8 + i];
+        let round = |v: i32| (v + if v < 0 { 1 } else { 0 } + 3) >> 3;
+        out[i] = round(a1 + d1);
+        out[4 + i] = round(b1 + c1);
+        out[8 + i] = round(b1 - c1);
+        out[12 + i] = round(a1 - d1);
+    }
+    out
+}
+
+/// Inverse Walsh-Hadamard transform, must match the decoder exactly
+fn iwht(block: &mut [i32; 16]) {
+    let mut tmp = [0i32; 16];
+    for i in 0..4 {
+        let a1 = block[i] + block[12 + i];
+        let b1 = block[4 + i] + block[8 + i];
+        let c1 = block[4 + i] - block[8 + i];
+        let d1 = block[i] - block[12 + i];
+        tmp[i] = a1 + b1;
+        tmp[4 + i] = c1 + d1;
+        tmp[8 + i] = a1 - b1;
+        tmp[12 + i] = d1 - c1;
+    }
+    for i in 0..4 {
+        let t = &tmp[4*i..4*i + 4];
+        let a1 = t[0] + t[3];
+        let b1 = t[1] + t[2];
+        let c1 = t[1] - t[2];
+        let d1 = t[0] - t[3];
+        block[4*i] = (a1 + b1 + 3) >> 3;
+        block[4*i + 1] = (c1 + d1 + 3) >> 3;
+        block[4*i + 2] = (a1 - b1 + 3) >> 3;
+        block[4*i + 3] = (d1 - c1 + 3) >> 3;
+    }
+}
+
+fn quantize(coeff: i32, q: i32) -> i32 {
+    let level = ((coeff.abs() + q/2)/q).min(MAX_LEVEL);
+    if coeff < 0 { -level } else { level }
+}
+
+/// Dequantization factors of DC and AC coefficients
+#[derive(Copy, Clone)]
+struct Quant {
+    dc: i32,
+    ac: i32,
+}
+
+/// Image plane padded to whole macroblocks
+struct Plane {
+    data: Vec<u8>,
+    stride: usize,
+}
+
+impl Plane {
+    fn new(width: usize, height: usize) -> Self {
+        Self { data: vec![0; width*height], stride: width }
+    }
+
+    fn get(&self, x: usize, y: usize) -> u8 {
+        self.data[y*self.stride + x]
+    }
+
+    fn set(&mut self, x: usize, y: usize, v: u8) {
+        self.data[y*self.stride + x] = v;
+    }
+}
+
+/// Pixels bordering the predicted block of the reconstructed image
+struct Border {
+    above: Vec<u8>,
+    left: Vec<u8>,
+    corner: u8,
+    has_above: bool,
+    has_left: bool,
+}
+
+impl Border {
+    /// Border of the block, outside of the image the above row is equal to
+    /// 127 and the left column to 129
+    fn new(plane: &Plane, size: usize, mbx: usize, mby: usize) -> Self {
+        let (x0, y0) = (mbx*size, mby*size);
+        let above = match mby {
+            0 => vec![127; size],
+            _ => (0..size).map(|i| plane.get(x0 + i, y0 - 1)).collect(),
+        };
+        let left = match mbx {
+            0 => vec![129; size],
+            _ => (0..size).map(|i| plane.get(x0 - 1, y0 + i)).collect(),
+        };
+        let corner = match (mbx, mby) {
+            (_, 0) => 127,
+            (0, _) => 129,
+            _ => plane.get(x0 - 1, y0 - 1),
+        };
+        Self { above, left, corner, has_above: mby != 0, has_left: mbx != 0 }
+    }
+
+    /// Prediction of the block in raster order
+    fn predict(&self, mode: Mode) -> Vec<u8> {
+        let size = self.above.len();
+        let mut res = Vec::with_capacity(size*size);
+        match mode {
+            Mode::Dc => {
+                let mut sum = 0u32;
+                let mut shift = if size == 16 { 3 } else { 2 };
+                if self.has_above {
+                    sum += self.above.iter().map(|&v| v as u32).sum::<u32>();
+                    shift += 1;
+                }
+                if self.has_left {
+                    sum += self.left.iter().map(|&v| v as u32).sum::<u32>();
+                    shift += 1;
+                }
+                let dc = match self.has_above || self.has_left {
+                    true => (sum + (1 << (shift - 1))) >> shift,
+                    false => 128,
+                };
+                res.resize(size*size, dc as u8);
+            },
+            Mode::V => {
+                for _ in 0..size {
+                    res.extend_from_slice(&self.above);
+                }
+            },
+            Mode::H => {
+                for &l in self.left.iter() {
+                    res.extend((0..size).map(|_| l));
+                }
+            },
+            Mode::Tm => {
+                for &l in self.left.iter() {
+                    res.extend(self.above.iter().map(|&a| {
+                        (l as i32 + a as i32 - self.corner as i32)
+                            .clamp(0, 255) as u8
+                    }));
+                }
+            },
+        }
+        res
+    }
+}
+
+/// Sum of absolute differences between the block of the plane and its
+/// prediction
+fn sad(plane: &Plane, x0: usize, y0: usize, pred: &[u8], size: usize) -> u32 {
+    pred.chunks_exact(size).enumerate()
+        .map(|(y, row)| {
+            row.iter().enumerate()
+                .map(|(x, &p)| {
+                    (plane.get(x0 + x, y0 + y) as i32 - p as i32).unsigned_abs()
+                })
+                .sum::<u32>()
+        })
+        .sum()
+}
+
+/// Position of the pixel `i` of the 4x4 sub-block `n` inside of the block
+/// with the given size
+fn sub_block_pos(size: usize, n: usize, i: usize) -> (usize, usize) {
+    let blocks = size/4;
+    (4*(n % blocks) + i % 4, 4*(n/blocks) + i/4)
+}
+
+/// Transform residuals of the 4x4 sub-block `n` of the block at `(x0, y0)`
+fn residual_dct(
+    plane: &Plane, pred: &[u8], size: usize, (x0, y0): (usize, usize),
+    n: usize,
+) -> [i32; 16] {
+    let mut res = [0i32; 16];
+    for (i, r) in res.iter_mut().enumerate() {
+        let (x, y) = sub_block_pos(size, n, i);
+        *r = plane.get(x0 + x, y0 + y) as i32 - pred[y*size + x] as i32;
+    }
+    fdct(&res)
+}
+
+/// Add inverse transformed residuals to the prediction of the 4x4 sub-block
+/// and store the result in the reconstructed plane
+fn reconstruct(
+    plane: &mut Plane, pred: &[u8], size: usize, (x0, y0): (usize, usize),
+    n: usize, mut block: [i32; 16],
+) {
+    idct(&mut block);
+    for (i, r) in block.iter().enumerate() {
+        let (x, y) = sub_block_pos(size, n, i);
+        let v = (pred[y*size + x] as i32 + r).clamp(0, 255);
+        plane.set(x0 + x, y0 + y, v as u8);
+    }
+}
+
+/// Quantized coefficients of the macroblock in the zigzag order
+struct MacroblockCoeffs {
+    y2: [i32; 16],
+    y: [[i32; 16]; 16],
+    u: [[i32; 16]; 4],
+    v: [[i32; 16]; 4],
+}
+
+impl MacroblockCoeffs {
+    fn is_empty(&self) -> bool {
+        let zero = |b: &[i32; 16]| b.iter().all(|&v| v == 0);
+        zero(&self.y2) && self.y.iter().all(zero)
+            && self.u.iter().all(zero) && self.v.iter().all(zero)
+    }
+}
+
+/// Encoder state of one frame
+struct Encoder {
+    src: [Plane; 3],
+    rec: [Plane; 3],
+    y1: Quant,
+    y2: Quant,
+    uv: Quant,
+}
+
+impl Encoder {
+    /// Choose luma mode, quantize its coefficients and reconstruct the
+    /// macroblock
+    fn encode_luma(
+        &mut self, mbx: usize, mby: usize, coeffs: &mut MacroblockCoeffs,
+    ) -> Mode {
+        let (x0, y0) = (16*mbx, 16*mby);
+        let border = Border::new(&self.rec[0], 16, mbx, mby);
+        let (mode, pred) = MODES.iter()
+            .map(|&m| (m, border.predict(m)))
+            .min_by_key(|(_, pred)| sad(&self.src[0], x0, y0, pred, 16))
+            .unwrap();
+
+        let mut dct = [[0i32; 16]; 16];
+        let mut dc = [0i32; 16];
+        for (i, block) in dct.iter_mut().enumerate() {
+            *block = residual_dct(&self.src[0], &pred, 16, (x0, y0), i);
+            dc[i] = block[0];
+        }
+        let wht = fwht(&dc);
+        let mut y2 = [0i32; 16];
+        for (i, &z) in ZIGZAG.iter().enumerate() {
+            let q = if z == 0 { self.y2.dc } else { self.y2.ac };
+            coeffs.y2[i] = quantize(wht[z], q);
+            y2[z] = coeffs.y2[i]*q;
+        }
+        iwht(&mut y2);
+
+        for (i, block) in dct.iter().enumerate() {
+            let mut deq = [0i32; 16];
+            deq[0] = y2[i];
+            for (j, &z) in ZIGZAG.iter().enumerate().skip(1) {
+                coeffs.y[i][j] = quantize(block[z], self.y1.ac);
+                deq[z] = coeffs.y[i][j]*self.y1.ac;
+            }
+            reconstruct(&mut self.rec[0], &pred, 16, (x0, y0), i, deq);
+        }
+        mode
+    }
+
+    /// Choose chroma mode, quantize its coefficients and reconstruct the
+    /// macroblock
+    fn encode_chroma(
+        &mut self, mbx: usize, mby: usize, coeffs: &mut MacroblockCoeffs,
+    ) -> Mode {
+        let (x0, y0) = (8*mbx, 8*mby);
+        let borders = [
+            Border::new(&self.rec[1], 8, mbx, mby),
+            Border::new(&self.rec[2], 8, mbx, mby),
+        ];
+        let mode = *MODES.iter()
+            .min_by_key(|&&m| {
+                sad(&self.src[1], x0, y0, &borders[0].predict(m), 8)
+                    + sad(&self.src[2], x0, y0, &borders[1].predict(m), 8)
+            })
+            .unwrap();
+
+        for (p, border) in borders.iter().enumerate() {
+            let pred = border.predict(mode);
+            let levels = match p {
+                0 => &mut coeffs.u,
+                _ => &mut coeffs.v,
+            };
+            for (i, levels) in levels.iter_mut().enumerate() {
+                let src = &self.src[p + 1];
+                let block = residual_dct(src, &pred, 8, (x0, y0), i);
+                let mut deq = [0i32; 16];
+                for (j, &z) in ZIGZAG.iter().enumerate() {
+                    let q = if z == 0 { self.uv.dc } else { self.uv.ac };
+                    levels[j] = quantize(block[z], q);
+                    deq[z] = levels[j]*q;
+                }
+                reconstruct(&mut self.rec[p + 1], &pred, 8, (x0, y0), i, deq);
+            }
+        }
+        mode
+    }
+}
+
+/// Write tokens of the block starting from the coefficient `first`, returns
+/// whether the block has non-zero coefficients
+fn write_tokens(
+    e: &mut BoolEncoder, block_type: usize, first: usize, ctx: usize,
+    levels: &[i32; 16],
+) -> bool {
+    let probs = &COEFF_PROBS[block_type];
+    let last = match (first..16).rev().find(|&i| levels[i] != 0) {
+        Some(last) => last,
+        None => {
+            e.write_bool(probs[COEFF_BANDS[first]][ctx][0], false);
+            return false;
+        },
+    };
+    let mut ctx = ctx;
+    let mut after_zero = false;
+    for (i, &level) in levels.iter().enumerate().take(last + 1).skip(first) {
+        let p = &probs[COEFF_BANDS[i]][ctx];
+        // end of block can't follow zero
+        if !after_zero {
+            e.write_bool(p[0], true);
+        }
+        let v = level.abs();
+        e.write_bool(p[1], v != 0);
+        if v == 0 {
+            after_zero = true;
+            ctx = 0;
+            continue;
+        }
+        e.write_bool(p[2], v > 1);
+        if v > 1 {
+            if v <= 4 {
+                e.write_bool(p[3], false);
+                e.write_bool(p[4], v > 2);
+                if v > 2 {
+                    e.write_bool(p[5], v == 4);
+                }
+            } else {
+                e.write_bool(p[3], true);
+                let cat = match v {
+                    5..=6 => 0,
+                    7..=10 => 1,
+                    11..=18 => 2,
+                    19..=34 => 3,
+                    35..=66 => 4,
+                    _ => 5,
+                };
+                e.write_bool(p[6], cat > 1);
+                match cat {
+                    0 | 1 => e.write_bool(p[7], cat == 1),
+                    2 | 3 => {
+                        e.write_bool(p[8], false);
+                        e.write_bool(p[9], cat == 3);
+                    },
+                    _ => {
+                        e.write_bool(p[8], true);
+                        e.write_bool(p[10], cat == 5);
+                    },
+                }
+                let (base, extra_probs) = CATEGORIES[cat];
+                let extra = v - base;
+                for (j, &prob) in extra_probs.iter().enumerate() {
+                    let shift = extra_probs.len() - 1 - j;
+                    e.write_bool(prob, (extra >> shift) & 1 != 0);
+                }
+            }
+        }
+        e.write_bool(128, level < 0);
+        ctx = if v == 1 { 1 } else { 2 };
+        after_zero = false;
+    }
+    if last < 15 {
+        e.write_bool(probs[COEFF_BANDS[last + 1]][ctx][0], false);
+    }
+    true
+}
+
+/// Non-zero flags of the neighbouring blocks used as token contexts: Y2,
+/// four luma, two U and two V blocks
+type Contexts = [bool; 9];
+
+fn write_macroblock_tokens(
+    e: &mut BoolEncoder, coeffs: &MacroblockCoeffs,
+    top: &mut Contexts, left: &mut Contexts,
+) {
+    let ctx = |a: bool, b: bool| a as usize + b as usize;
+    let nz = write_tokens(e, TYPE_Y2, 0, ctx(top[0], left[0]), &coeffs.y2);
+    top[0] = nz;
+    left[0] = nz;
+    for (i, block) in coeffs.y.iter().enumerate() {
+        let (x, y) = (1 + i % 4, 1 + i/4);
+        let c = ctx(top[x], left[y]);
+        let nz = write_tokens(e, TYPE_Y_AFTER_Y2, 1, c, block);
+        top[x] = nz;
+        left[y] = nz;
+    }
+    for (offset, blocks) in [(5, &coeffs.u), (7, &coeffs.v)].iter() {
+        for (i, block) in blocks.iter().enumerate() {
+            let (x, y) = (offset + i % 2, offset + i/2);
+            let c = ctx(top[x], left[y]);
+            let nz = write_tokens(e, TYPE_CHROMA, 0, c, block);
+            top[x] = nz;
+            left[y] = nz;
+        }
+    }
+}
+
+fn write_mode(e: &mut BoolEncoder, mode: Mode, is_luma: bool) {
+    let bits: &[(u8, bool)] = match (is_luma, mode) {
+        (true, Mode::Dc) => &[(145, true), (156, false), (163, false)],
+        (true, Mode::V) => &[(145, true), (156, false), (163, true)],
+        (true, Mode::H) => &[(145, true), (156, true), (128, false)],
+        (true, Mode::Tm) => &[(145, true), (156, true), (128, true)],
+        (false, Mode::Dc) => &[(142, false)],
+        (false, Mode::V) => &[(142, true), (114, false)],
+        (false, Mode::H) => &[(142, true), (114, true), (183, false)],
+        (false, Mode::Tm) => &[(142, true), (114, true), (183, true)],
+    };
+    for &(prob, bit) in bits {
+        e.write_bool(prob, bit);
+    }
+}
+
+/// Convert image into YUV 4:2:0 planes (BT.601, limited range) padded to
+/// whole macroblocks by replicating the edge pixels
+fn yuv_planes(
+    data: &[u8], width: usize, height: usize, channels: usize,
+    mb_w: usize, mb_h: usize,
+) -> [Plane; 3] {
+    let rgb = |x: usize, y: usize| {
+        let i = channels*(y.min(height - 1)*width + x.min(width - 1));
+        match channels {
+            1 => [data[i] as i32; 3],
+            _ => [data[i] as i32, data[i + 1] as i32, data[i + 2] as i32],
+        }
+    };
+    let mut y_plane = Plane::new(16*mb_w, 16*mb_h);
+    for y in 0..16*mb_h {
+        for x in 0..16*mb_w {
+            let [r, g, b] = rgb(x, y);
+            let v = (16839*r + 33059*g + 6420*b + (16 << 16) + (1 << 15)) >> 16;
+            y_plane.set(x, y, v as u8);
+        }
+    }
+    let mut u_plane = Plane::new(8*mb_w, 8*mb_h);
+    let mut v_plane = Plane::new(8*mb_w, 8*mb_h);
+    for y in 0..8*mb_h {
+        for x in 0..8*mb_w {
+            let mut sum = [0i32; 3];
+            for &(dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+                let p = rgb(2*x + dx, 2*y + dy);
+                for (s, v) in sum.iter_mut().zip(p.iter()) {
+                    *s += v;
+                }
+            }
+            // sums of four pixels, so the shift is larger by two
+            let [r, g, b] = sum;
+            let round = (128 << 18) + (1 << 17);
+            let u = (-9719*r - 19081*g + 28800*b + round) >> 18;
+            let v = (28800*r - 24116*g - 4684*b + round) >> 18;
+            u_plane.set(x, y, u.clamp(0, 255) as u8);
+            v_plane.set(x, y, v.clamp(0, 255) as u8);
+        }
+    }
+    [y_plane, u_plane, v_plane]
+}
+
+/// Encode 8-bit grayscale or RGB image into VP8 key frame, quality is in
+/// the range from 0 to 100
+pub(super) fn encode(
+    data: &[u8], width: usize, height: usize, channels: usize, quality: u8,
+) -> io::Result<Vec<u8>> {
+    let mb_w = width.div_ceil(16);
+    let mb_h = height.div_ceil(16);
+    let qi = (127*(100 - quality.min(100) as usize) + 50)/100;
+    let (dc_q, ac_q) = (DC_QUANT[qi], AC_QUANT[qi]);
+    let src = yuv_planes(data, width, height, channels, mb_w, mb_h);
+    let rec = [
+        Plane::new(16*mb_w, 16*mb_h),
+        Plane::new(8*mb_w, 8*mb_h),
+        Plane::new(8*mb_w, 8*mb_h),
+    ];
+    let mut enc = Encoder {
+        src,
+        rec,
+        y1: Quant { dc: dc_q, ac: ac_q },
+        y2: Quant { dc: 2*dc_q, ac: (ac_q*155/100).max(8) },
+        uv: Quant { dc: dc_q.min(132), ac: ac_q },
+    };
+
+    let mut tokens = BoolEncoder::new();
+    let mut modes = Vec::with_capacity(mb_w*mb_h);
+    let mut top = vec![[false; 9]; mb_w];
+    for mby in 0..mb_h {
+        let mut left = [false; 9];
+        for (mbx, top) in top.iter_mut().enumerate() {
+            let mut coeffs = MacroblockCoeffs {
+                y2: [0; 16], y: [[0; 16]; 16], u: [[0; 16]; 4], v: [[0; 16]; 4],
+            };
+            let luma = enc.encode_luma(mbx, mby, &mut coeffs);
+            let chroma = enc.encode_chroma(mbx, mby, &mut coeffs);
+            let skip = coeffs.is_empty();
+            if skip {
+                *top = [false; 9];
+                left = [false; 9];
+            } else {
+                write_macroblock_tokens(&mut tokens, &coeffs, top, &mut left);
+            }
+            modes.push((luma, chroma, skip));
+        }
+    }
+
+    let mut e = BoolEncoder::new();
+    // colour space and clamping type
+    e.write_literal(0, 1);
+    e.write_literal(0, 1);
+    // segmentation is disabled
+    e.write_literal(0, 1);
+    // normal loop filter without sharpness and adjustments
+    e.write_literal(0, 1);
+    e.write_literal((qi/FILTER_DIVISOR) as u32, 6);
+    e.write_literal(0, 3);
+    e.write_literal(0, 1);
+    // one token partition
+    e.write_literal(0, 2);
+    // quantizer index without deltas
+    e.write_literal(qi as u32, 7);
+    for _ in 0..5 {
+        e.write_literal(0, 1);
+    }
+    // refresh entropy probabilities
+    e.write_literal(0, 1);
+    for p in COEFF_UPDATE_PROBS.iter().flatten().flatten().flatten() {
+        e.write_bool(*p, false);
+    }
+    let skipped = modes.iter().filter(|m| m.2).count();
+    let prob_skip = 255*(modes.len() - skipped)/modes.len();
+    let prob_skip = prob_skip.clamp(1, 254) as u8;
+    e.write_literal(1, 1);
+    e.write_literal(prob_skip as u32, 8);
+    for &(luma, chroma, skip) in modes.iter() {
+        e.write_bool(prob_skip, skip);
+        write_mode(&mut e, luma, true);
+        write_mode(&mut e, chroma, false);
+    }
+    let first = e.finish();
+    if first.len() > MAX_PARTITION_SIZE {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput, "image is too large for VP8",
+        ))?
+    }
+
+    let mut res = Vec::with_capacity(10 + first.len());
+    // key frame, version 0, shown frame
+    let tag = (1 << 4) | (first.len() as u32) << 5;
+    res.extend_from_slice(&tag.to_le_bytes()[..3]);
+    res.extend_from_slice(&START_CODE);
+    res.extend_from_slice(&(width as u16).to_le_bytes());
+    res.extend_from_slice(&(height as u16).to_le_bytes());
+    res.extend_from_slice(&first);
+    res.extend_from_slice(&tokens.finish());
+    Ok(res)
+}
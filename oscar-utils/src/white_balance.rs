@@ -0,0 +1,203 @@
+use std::str::FromStr;
+
+use super::{PackedFrame, Sample};
+
+/// Range to which estimated gains are clamped, protects against extreme
+/// corrections of nearly monochrome frames
+const MIN_GAIN: f32 = 0.125;
+const MAX_GAIN: f32 = 8.0;
+/// Percentile of channel values used as the white point by `WhitePatch`
+const WHITE_PERCENTILE: f64 = 0.99;
+
+/// White balance mode
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WhiteBalance {
+    /// Keep raw sensor gains
+    None,
+    /// Apply fixed per-channel gains
+    Fixed,
+    /// Estimate gains assuming that average colour of the scene is gray
+    GrayWorld,
+    /// Estimate gains assuming that the brightest parts of the scene are
+    /// white, uses 99th percentile of each channel instead of maximum
+    WhitePatch,
+}
+
+impl WhiteBalance {
+    /// Check if gains are estimated from the frame content
+    pub fn is_adaptive(self) -> bool {
+        match self {
+            WhiteBalance::GrayWorld | WhiteBalance::WhitePatch => true,
+            WhiteBalance::None | WhiteBalance::Fixed => false,
+        }
+    }
+}
+
+impl Default for WhiteBalance {
+    fn default() -> Self {
+        WhiteBalance::None
+    }
+}
+
+impl FromStr for WhiteBalance {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(WhiteBalance::None),
+            "fixed" => Ok(WhiteBalance::Fixed),
+            "gray_world" => Ok(WhiteBalance::GrayWorld),
+            "white_patch" => Ok(WhiteBalance::WhitePatch),
+            _ => Err("unexpected white balance mode"),
+        }
+    }
+}
+
+/// Gains of red and blue channels relative to the green one
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WbGains {
+    pub red: f32,
+    pub blue: f32,
+}
+
+impl Default for WbGains {
+    fn default() -> Self {
+        Self { red: 1.0, blue: 1.0 }
+    }
+}
+
+/// Parses `R,G,B` gains, which get normalized by the green gain
+impl FromStr for WbGains {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let gains = s.split(',')
+            .map(|v| v.trim().parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|_| "invalid gain value")?;
+        match gains[..] {
+            [r, g, b] if r > 0.0 && g > 0.0 && b > 0.0 => {
+                Ok(Self { red: r/g, blue: b/g })
+            },
+            [_, _, _] => Err("gains must be positive"),
+            _ => Err("expected gains in the R,G,B format"),
+        }
+    }
+}
+
+impl WbGains {
+    /// Compute gains from the reference values of each channel, which should
+    /// become equal after white balancing
+    fn from_reference(r: f64, g: f64, b: f64) -> Self {
+        let gain = |v: f64| if v > 0.0 {
+            ((g/v) as f32).clamp(MIN_GAIN, MAX_GAIN)
+        } else {
+            1.0
+        };
+        Self { red: gain(r), blue: gain(b) }
+    }
+
+    /// Estimate gains of the packed frame, returns `None` for modes which do
+    /// not estimate gains
+    pub fn estimate<T: Sample>(
+        frame: &PackedFrame<T>, mode: WhiteBalance,
+    ) -> Option<Self> {
+        match mode {
+            WhiteBalance::GrayWorld => Some(gray_world(&frame.data)),
+            WhiteBalance::WhitePatch => Some(white_patch(&frame.data)),
+            WhiteBalance::None | WhiteBalance::Fixed => None,
+        }
+    }
+
+    /// Geometric mean of the gains, returns `None` for empty iterator
+    pub fn average(gains: impl Iterator<Item=Self>) -> Option<Self> {
+        let (mut n, mut red, mut blue) = (0, 0f64, 0f64);
+        for g in gains {
+            n += 1;
+            red += (g.red as f64).ln();
+            blue += (g.blue as f64).ln();
+        }
+        if n == 0 { return None; }
+        let n = n as f64;
+        Some(Self {
+            red: (red/n).exp() as f32,
+            blue: (blue/n).exp() as f32,
+        })
+    }
+
+    /// Apply gains to the red and blue samples of the packed frame, values
+    /// are clipped to the maximum sample value
+    pub fn apply<T: Sample>(&self, frame: &mut PackedFrame<T>) {
+        let lut = |gain: f32| -> Vec<T> {
+            (0..=T::MAX)
+                .map(|v| {
+                    let v = (v as f32*gain + 0.5) as u32;
+                    T::from_u32(v.min(T::MAX))
+                })
+                .collect()
+        };
+        let (red, blue) = (lut(self.red), lut(self.blue));
+        for pixel in frame.data.chunks_exact_mut(4) {
+            pixel[0] = red[pixel[0].to_u32() as usize];
+            pixel[2] = blue[pixel[2].to_u32() as usize];
+        }
+    }
+}
+
+/// Iterate over R, G and B values of packed pixels which are not clipped,
+/// G1 is used as the green value
+fn unclipped<T: Sample>(data: &[T]) -> impl Iterator<Item=[u32; 3]> + '_ {
+    data.chunks_exact(4)
+        .map(|p| [p[0].to_u32(), p[1].to_u32(), p[2].to_u32()])
+        .filter(|p| p.iter().all(|&v| v < T::MAX))
+}
+
+fn gray_world<T: Sample>(data: &[T]) -> WbGains {
+    let mut sums = [0u64; 3];
+    for pixel in unclipped(data) {
+        for (s, v) in sums.iter_mut().zip(pixel.iter()) {
+            *s += *v as u64;
+        }
+    }
+    WbGains::from_reference(sums[0] as f64, sums[1] as f64, sums[2] as f64)
+}
+
+fn white_patch<T: Sample>(data: &[T]) -> WbGains {
+    let mut hists = vec![vec![0u64; T::MAX as usize + 1]; 3];
+    let mut n = 0u64;
+    for pixel in unclipped(data) {
+        n += 1;
+        for (hist, v) in hists.iter_mut().zip(pixel.iter()) {
+            hist[*v as usize] += 1;
+        }
+    }
+    let target = (n as f64*WHITE_PERCENTILE) as u64;
+    let percentile = |hist: &[u64]| -> f64 {
+        let mut sum = 0;
+        for (v, count) in hist.iter().enumerate() {
+            sum += count;
+            if sum > target { return v as f64; }
+        }
+        0.0
+    };
+    WbGains::from_reference(
+        percentile(&hists[0]), percentile(&hists[1]), percentile(&hists[2]),
+    )
+}
+
+/// Smooth gains of consecutive frames using moving geometric mean over the
+/// centered window of `window` frames, `None` values (e.g. missing frames)
+/// are skipped and replaced by the average of their neighbours. `window`
+/// must be odd.
+pub fn smooth_gains(gains: &[Option<WbGains>], window: usize) -> Vec<WbGains> {
+    assert!(window % 2 == 1, "smoothing window must be odd: {}", window);
+    let half = window/2;
+    (0..gains.len())
+        .map(|i| {
+            let start = i.saturating_sub(half);
+            let end = (i + half + 1).min(gains.len());
+            WbGains::average(gains[start..end].iter().filter_map(|g| *g))
+                .unwrap_or_default()
+        })
+        .collect()
+}
@@ -0,0 +1,104 @@
+//! Writer of uncompressed YUV4MPEG2 (Y4M) video streams
+//!
+//! Grayscale frames are written as the `mono` colour space, RGB frames are
+//! converted into BT.601 limited range Y'CbCr without chroma subsampling.
+use std::io::{self, Write};
+
+/// Frame rate of the video stream as a ratio
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FrameRate {
+    pub num: u32,
+    pub den: u32,
+}
+
+impl Default for FrameRate {
+    fn default() -> Self {
+        Self { num: 30, den: 1 }
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+impl FrameRate {
+    /// Average frame rate of frames with the given sorted timestamps in
+    /// microseconds, rounded to 0.001 FPS. `None` if there is less than two
+    /// distinct timestamps.
+    pub fn from_timestamps(ts: &[u64]) -> Option<Self> {
+        let (first, last) = (*ts.first()?, *ts.last()?);
+        if last <= first { return None; }
+        let den = 1000;
+        let num = ((ts.len() as u64 - 1)*1_000_000_000 + (last - first)/2)
+            / (last - first);
+        if num == 0 || num > u32::MAX as u64 { return None; }
+        let d = gcd(num, den);
+        Some(Self { num: (num/d) as u32, den: (den/d) as u32 })
+    }
+}
+
+/// Convert 8-bit grayscale or RGB image into planes of the Y4M frame
+pub fn y4m_planes(data: &[u8], channels: usize) -> Vec<u8> {
+    assert!(channels == 1 || channels == 3);
+    assert_eq!(data.len() % channels, 0);
+    if channels == 1 { return data.to_vec(); }
+    let n = data.len()/3;
+    let mut res = vec![0u8; 3*n];
+    let (y, chroma) = res.split_at_mut(n);
+    let (cb, cr) = chroma.split_at_mut(n);
+    for (i, pix) in data.chunks(3).enumerate() {
+        let (r, g, b) = (pix[0] as i32, pix[1] as i32, pix[2] as i32);
+        y[i] = (((66*r + 129*g + 25*b + 128) >> 8) + 16) as u8;
+        cb[i] = (((-38*r - 74*g + 112*b + 128) >> 8) + 128) as u8;
+        cr[i] = (((112*r - 94*g - 18*b + 128) >> 8) + 128) as u8;
+    }
+    res
+}
+
+/// Writer of Y4M stream with frames of the fixed size
+pub struct Y4mWriter<W: Write> {
+    writer: W,
+    frame_len: usize,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    /// Write header of the stream of 8-bit grayscale or RGB frames
+    pub fn new(
+        mut writer: W, width: usize, height: usize, channels: usize,
+        rate: FrameRate,
+    ) -> io::Result<Self> {
+        assert!(channels == 1 || channels == 3);
+        if width == 0 || height == 0 {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "frame is empty"))?
+        }
+        if rate.num == 0 || rate.den == 0 {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput, "frame rate must not be zero",
+            ))?
+        }
+        let colorspace = if channels == 1 { "mono" } else { "444" };
+        writeln!(
+            writer, "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C{}",
+            width, height, rate.num, rate.den, colorspace,
+        )?;
+        Ok(Self { writer, frame_len: channels*width*height })
+    }
+
+    /// Write frame planes converted by `y4m_planes`
+    pub fn write_frame(&mut self, planes: &[u8]) -> io::Result<()> {
+        if planes.len() != self.frame_len {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame size doesn't match the stream header",
+            ))?
+        }
+        self.writer.write_all(b"FRAME\n")?;
+        self.writer.write_all(planes)
+    }
+
+    /// Flush the stream and return the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
@@ -1,28 +1,1237 @@
+use std::io;
 
-use oscar_utils::{WIDTH, HEIGHT};
-use oscar_utils::conversions::{rgba2raw, raw2rgba_flip, raw_flip};
+use png::HasParameters;
 
-fn test_image() -> Vec<u8> {
-    (0..WIDTH*HEIGHT).map(|n| (n % 256) as u8).collect()
+use oscar_utils::{
+    demosaic, CfaPattern, DemosaicAlgorithm, Geometry, PackedFrame,
+    AnyPackedFrame, ErrorKind, WhiteBalance, WbGains, ColorMatrix,
+    smooth_gains, camera_to_srgb, Calibration, FrameSum, RawFrame, DefectMap,
+    DefectStats, CameraModel, RemapTable, Rectification, StereoCamera,
+    StereoModel, Equalization, resample, ResampleFilter, ResampleSize,
+    crop, flip, rotate, CropRect, Rotation, TemporalDenoise,
+};
+use oscar_utils::conversions::{rgba2raw, rgba2rgb, raw2rgba_flip, raw_flip};
+use oscar_utils::load_frames::{
+    parse_pnm, decode_flif_packed, decode_packed, PnmHeader, PnmFormat,
+};
+use oscar_utils::avi_encoder::AviWriter;
+use oscar_utils::dng_encoder::{encode_dng, DngMetadata};
+use oscar_utils::flif_encoder::encode_flif_packed;
+use oscar_utils::tiff_encoder::{encode_tiff, Tag, TagValue, TiffCompression};
+use oscar_utils::webp_encoder::{encode_webp, WebpMode, MAX_DIMENSION};
+use oscar_utils::y4m_encoder::{y4m_planes, FrameRate, Y4mWriter};
+use oscar_utils::simd::{self, SimdLevel};
+
+const PATTERNS: [CfaPattern; 4] = [
+    CfaPattern::Bggr, CfaPattern::Rggb, CfaPattern::Grbg, CfaPattern::Gbrg,
+];
+
+fn test_geometry() -> Geometry {
+    Geometry::new(2448, 2048, CfaPattern::Rggb)
+}
+
+fn test_image(geom: Geometry) -> Vec<u8> {
+    (0..geom.pixels()).map(|n| (n % 256) as u8).collect()
+}
+
+/// Mosaic image filled with a single colour
+fn flat_mosaic(geom: Geometry, rgb: [u8; 3]) -> Vec<u8> {
+    (0..geom.pixels())
+        .map(|n| rgb[geom.cfa.color(n % geom.width, n / geom.width)])
+        .collect()
 }
 
 #[test]
 fn test_flip() {
-    let orig = test_image();
+    let geom = test_geometry();
+    let orig = test_image(geom);
     let mut buf = orig.clone();
-    raw_flip(&mut buf);
+    raw_flip(&mut buf, geom);
     assert!(buf != orig);
-    raw_flip(&mut buf);
+    raw_flip(&mut buf, geom);
     assert!(buf == orig);
 }
 
 #[test]
 fn test_conversions() {
-    let orig = test_image();
-    let mut buf1 = vec![0u8; WIDTH*HEIGHT];
-    let mut buf2 = vec![0u8; WIDTH*HEIGHT];
-    raw2rgba_flip(&orig, &mut buf1);
-    rgba2raw(&buf1, &mut buf2);
-    raw_flip(&mut buf2);
+    let geoms = [
+        test_geometry(),
+        Geometry::new(1920, 1200, CfaPattern::Grbg),
+        Geometry::new(2, 2, CfaPattern::Gbrg),
+    ];
+    for &geom in geoms.iter() {
+        let orig = test_image(geom);
+        let mut buf1 = vec![0u8; geom.pixels()];
+        let mut buf2 = vec![0u8; geom.pixels()];
+        raw2rgba_flip(&orig, &mut buf1, geom);
+        rgba2raw(&buf1, &mut buf2, geom.flipped());
+        raw_flip(&mut buf2, geom.flipped());
+        assert_eq!(orig, buf2);
+    }
+}
+
+#[test]
+fn test_rgba_channels() {
+    let rgb = [10, 20, 30];
+    for &cfa in PATTERNS.iter() {
+        let geom = Geometry::new(8, 6, cfa);
+        let mut rgba = vec![0u8; geom.pixels()];
+        raw2rgba_flip(&flat_mosaic(geom, rgb), &mut rgba, geom);
+        for pixel in rgba.chunks(4) {
+            assert_eq!(pixel, &[10, 20, 30, 0x80]);
+        }
+    }
+}
+
+#[test]
+fn test_demosaic_patterns() {
+    let rgb = [10, 20, 30];
+    let algos = [
+        DemosaicAlgorithm::Bilinear, DemosaicAlgorithm::Mhc,
+        DemosaicAlgorithm::Ahd, DemosaicAlgorithm::Superpixel,
+    ];
+    for &algo in algos.iter() {
+        for &cfa in PATTERNS.iter() {
+            for &(w, h) in [(16, 12), (4, 4)].iter() {
+                let geom = Geometry::new(w, h, cfa);
+                let res = demosaic(&flat_mosaic(geom, rgb), geom, algo);
+                for pixel in res.chunks(3) {
+                    assert_eq!(pixel, &rgb, "{:?} {:?}", algo, cfa);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_gray_ramp() {
+    // algorithms and widths of the frame border affected by mirroring
+    let algos = [(DemosaicAlgorithm::Mhc, 2), (DemosaicAlgorithm::Ahd, 3)];
+    let ramp = |x: usize, y: usize| (x + 2*y) as u8;
+    for &(algo, border) in algos.iter() {
+        for &cfa in PATTERNS.iter() {
+            // AHD processes image by stripes, so use frame with several of them
+            let geom = Geometry::new(64, 80, cfa);
+            let data: Vec<u8> = (0..geom.pixels())
+                .map(|n| ramp(n % geom.width, n / geom.width))
+                .collect();
+            let res = demosaic(&data, geom, algo);
+            for y in border..geom.height - border {
+                for x in border..geom.width - border {
+                    let v = ramp(x, y);
+                    let pos = 3*(y*geom.width + x);
+                    assert_eq!(&res[pos..pos + 3], &[v, v, v], "{:?} {:?}", algo, cfa);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_superpixel() {
+    for &cfa in PATTERNS.iter() {
+        let geom = Geometry::new(16, 12, cfa);
+        let raw = test_image(geom);
+        let mut rgba = vec![0u8; geom.pixels()];
+        let mut rgb = vec![0u8; 3*geom.pixels()/4];
+        raw2rgba_flip(&raw, &mut rgba, geom);
+        rgba2rgb(&rgba, &mut rgb, geom.flipped());
+
+        let mut flipped = raw.clone();
+        raw_flip(&mut flipped, geom);
+        let res = demosaic(&flipped, geom.flipped(), DemosaicAlgorithm::Superpixel);
+        assert_eq!(&rgb[..], &res[..]);
+    }
+}
+
+#[test]
+fn test_16bit() {
+    let geom = Geometry::new(16, 12, CfaPattern::Rggb);
+    let orig: Vec<u16> = (0..geom.pixels())
+        .map(|n| (n as u16).wrapping_mul(1031))
+        .collect();
+    let mut buf1 = vec![0u16; geom.pixels()];
+    let mut buf2 = vec![0u16; geom.pixels()];
+    raw2rgba_flip(&orig, &mut buf1, geom);
+    rgba2raw(&buf1, &mut buf2, geom.flipped());
+    raw_flip(&mut buf2, geom.flipped());
     assert_eq!(orig, buf2);
+
+    let rgb = [0x0ABC, 0x8000, 0xFFF0];
+    let algos = [
+        DemosaicAlgorithm::Bilinear, DemosaicAlgorithm::Mhc,
+        DemosaicAlgorithm::Ahd, DemosaicAlgorithm::Superpixel,
+    ];
+    for &algo in algos.iter() {
+        for &cfa in PATTERNS.iter() {
+            let geom = Geometry::new(16, 12, cfa);
+            let mosaic: Vec<u16> = (0..geom.pixels())
+                .map(|n| rgb[geom.cfa.color(n % geom.width, n / geom.width)])
+                .collect();
+            let res = demosaic(&mosaic, geom, algo);
+            for pixel in res.chunks(3) {
+                assert_eq!(pixel, &rgb, "{:?} {:?}", algo, cfa);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse_pnm() {
+    let data = b"P5 # comment\n4\t2\n# another comment\n255\n\x01\x02\x03\x04\x05\x06\x07\x08\x09";
+    let (header, image) = parse_pnm(data).unwrap();
+    assert_eq!(header, PnmHeader {
+        format: PnmFormat::Gray, width: 4, height: 2, depth: 1, maxval: 255,
+        tupltype: None,
+    });
+    assert_eq!(image, &data[data.len() - 9..data.len() - 1]);
+
+    let data = b"P7\nWIDTH 1\nHEIGHT 1\n# comment\nDEPTH 4\nMAXVAL 65535\n\
+        TUPLTYPE RGB_ALPHA\nENDHDR\n\x00\x01\x00\x02\x00\x03\x00\x04";
+    let (header, image) = parse_pnm(data).unwrap();
+    assert_eq!(header, PnmHeader {
+        format: PnmFormat::Pam, width: 1, height: 1, depth: 4, maxval: 65535,
+        tupltype: Some("RGB_ALPHA".to_string()),
+    });
+    assert_eq!(image.len(), 8);
+
+    assert!(parse_pnm(b"P6\n2 2\n255\n\x00\x00\x00").is_err());
+    assert!(parse_pnm(b"P5\n2 2\n65536\n\x00\x00\x00\x00").is_err());
+    assert!(parse_pnm(b"P7\nWIDTH 1\nHEIGHT 1\nENDHDR\n\x00").is_err());
+    // dimensions whose product overflows
+    let err = parse_pnm(b"P5 4294967296 4294967296 255\n\x00").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidHeader);
+    let data = b"P7\nWIDTH 4294967296\nHEIGHT 4294967296\nDEPTH 4\n\
+        MAXVAL 255\nENDHDR\n\x00";
+    assert_eq!(parse_pnm(data).unwrap_err().kind(), ErrorKind::InvalidHeader);
+}
+
+#[test]
+fn test_white_balance() {
+    // packed frame with a green cast and a few clipped pixels
+    let geom = Geometry::new(64, 64, CfaPattern::Bggr);
+    let mut data: Vec<u8> = (0..geom.pixels()/4)
+        .flat_map(|i| {
+            let v = (i % 50) as u8;
+            vec![v + 50, 2*v + 100, v + 50, 128]
+        })
+        .collect();
+    data[..8].copy_from_slice(&[255, 255, 0, 128, 0, 255, 255, 128]);
+    let mut frame = PackedFrame { geometry: geom, data: data.into() };
+
+    for &mode in &[WhiteBalance::GrayWorld, WhiteBalance::WhitePatch] {
+        let gains = WbGains::estimate(&frame, mode).unwrap();
+        assert!(gains.red > 1.5 && gains.red < 2.5, "{:?}", gains);
+        assert!((gains.red - gains.blue).abs() < 1e-6);
+    }
+    assert_eq!(WbGains::estimate(&frame, WhiteBalance::None), None);
+
+    let gains: WbGains = "3,1.5,1".parse().unwrap();
+    assert_eq!(gains, WbGains { red: 2.0, blue: 2.0/3.0 });
+    assert!("1,0,1".parse::<WbGains>().is_err());
+    assert!("1,1".parse::<WbGains>().is_err());
+    gains.apply(&mut frame);
+    assert_eq!(&frame.data[..8], &[255, 255, 0, 128, 0, 255, 170, 128]);
+    assert_eq!(&frame.data[8..12], &[104, 104, 35, 128]);
+
+    let g = |red| Some(WbGains { red, blue: 1.0 });
+    let smoothed = smooth_gains(&[g(1.0), g(4.0), None, g(1.0)], 3);
+    let red: Vec<f32> = smoothed.iter().map(|g| g.red).collect();
+    for (a, b) in red.iter().zip(&[2.0, 2.0, 2.0, 1.0]) {
+        assert!((a - b).abs() < 1e-5, "{:?}", red);
+    }
+}
+
+#[test]
+#[should_panic(expected = "smoothing window must be odd")]
+fn test_smooth_gains_even() {
+    smooth_gains(&[None, None], 2);
+}
+
+#[test]
+fn test_srgb() {
+    let mut data = [0u8, 128, 255, 255, 0, 3];
+    camera_to_srgb(&mut data, &ColorMatrix::default());
+    assert_eq!(data, [0, 188, 255, 255, 0, 28]);
+
+    let mut data = [0u16, 0x8000, 0xFFFF];
+    camera_to_srgb(&mut data, &ColorMatrix::default());
+    assert_eq!(data, [0, 0xBC40, 0xFFFF]);
+
+    let m: ColorMatrix = "# calibration\n1.5, -0.5, 0\n0 1 0 # green\n\
+        0 -0.25 1.25\n".parse().unwrap();
+    assert_eq!(m, ColorMatrix([
+        [1.5, -0.5, 0.0], [0.0, 1.0, 0.0], [0.0, -0.25, 1.25],
+    ]));
+    assert!("1 0 0 0 1 0 0 0".parse::<ColorMatrix>().is_err());
+    assert!("1 0 0 0 1 0 0 0 x".parse::<ColorMatrix>().is_err());
+
+    // gray stays gray, saturated colours get clipped
+    let mut data = [100u8, 100, 100, 200, 100, 0];
+    camera_to_srgb(&mut data, &m);
+    assert_eq!(data, [168, 168, 168, 253, 168, 0]);
+}
+
+#[test]
+fn test_calibration() {
+    let geom = Geometry::new(8, 4, CfaPattern::Rggb);
+    let raw = |f: &dyn Fn(usize) -> u8| RawFrame {
+        geometry: geom,
+        data: (0..geom.pixels()).map(f).collect(),
+    };
+
+    // average of two dark frames survives round trip through raw frame
+    let mut sum = FrameSum::default();
+    sum.add(&raw(&|i| (i % 7) as u8).pack_flipped()).unwrap();
+    sum.add(&raw(&|i| (i % 7) as u8 + 2).pack_flipped()).unwrap();
+    let other = Geometry::new(8, 8, CfaPattern::Rggb);
+    assert!(sum.add(&PackedFrame::<u8>::empty(other)).is_err());
+    assert_eq!(sum.frames(), 2);
+    let dark = sum.mean().unwrap();
+    let dark_raw = dark.to_raw();
+    assert_eq!(dark_raw.geometry, geom);
+    for (i, v) in dark_raw.data.iter().enumerate() {
+        assert_eq!(*v, 257*((i % 7) as u16 + 1));
+    }
+
+    // vignetted flat frame, left half is two times darker than right one
+    let flat = raw(&|i| if i % 8 < 4 { 101 } else { 201 }).pack_flipped();
+    let mut sum = FrameSum::default();
+    sum.add(&flat).unwrap();
+    let flat = sum.mean().unwrap();
+    let dark = raw(&|_| 1).pack_flipped();
+    let mut sum = FrameSum::default();
+    sum.add(&dark).unwrap();
+    let calib = Calibration::new(sum.mean(), Some(flat)).unwrap();
+
+    let mut frame = AnyPackedFrame::U8(
+        raw(&|i| if i % 8 < 4 { 51 } else { 101 }).pack_flipped()
+    );
+    calib.apply(&mut frame).unwrap();
+    match frame {
+        AnyPackedFrame::U8(f) => {
+            assert!(f.unpack().data.iter().all(|&v| v == 75));
+        },
+        AnyPackedFrame::U16(_) => unreachable!(),
+    }
+    let mut frame = AnyPackedFrame::U8(PackedFrame::empty(other));
+    assert!(calib.apply(&mut frame).is_err());
+}
+
+#[test]
+fn test_frame_sum_limit() {
+    // sums aren't limited to 65536 frames of the full range
+    let geom = Geometry::new(2, 2, CfaPattern::Rggb);
+    let white = RawFrame { geometry: geom, data: vec![255u8; 4].into() };
+    let white = white.pack_flipped();
+    let mut sum = FrameSum::default();
+    for _ in 0..40_000 {
+        sum.add(&white).unwrap();
+    }
+    let other = sum.clone();
+    let sum = sum.merge(other).unwrap();
+    assert_eq!(sum.frames(), 80_000);
+    assert!(sum.mean().unwrap().data.iter().all(|&v| v == 1.0));
+}
+
+#[test]
+fn test_defects() {
+    let geom = Geometry::new(16, 8, CfaPattern::Grbg);
+    let frame = |k: u8, broken: bool| {
+        let data = (0..geom.pixels())
+            .map(|i| {
+                let (x, y) = (i % 16, i / 16);
+                let v = [60, 100, 40][geom.cfa.color(x, y)] + k;
+                match (x, y) {
+                    (5, 3) | (10, 6) if broken => [255, 0][x/6],
+                    (2, 2) if broken => v + 60,
+                    _ => v,
+                }
+            })
+            .collect();
+        RawFrame { geometry: geom, data }
+    };
+
+    let mut stats = DefectStats::default();
+    for k in 0..10 {
+        stats.add(&frame(5*k, true).pack_flipped()).unwrap();
+    }
+    let map = stats.detect(0.1).unwrap();
+    assert_eq!(map, DefectMap {
+        width: 16, height: 8, pixels: vec![(2, 2), (5, 3), (10, 6)],
+    });
+    assert_eq!(map.to_string().parse::<DefectMap>().unwrap(), map);
+    // coordinates are sorted on parsing
+    assert_eq!("16 8\n10 6\n5 3\n2 2\n".parse::<DefectMap>().unwrap(), map);
+    assert!("16 8\n16 0\n".parse::<DefectMap>().is_err());
+    assert!("16 8\n1 2 3\n".parse::<DefectMap>().is_err());
+
+    let mut packed = AnyPackedFrame::U8(frame(7, true).pack_flipped());
+    map.correct(&mut packed).unwrap();
+    let expected = frame(7, false).pack_flipped().unpack().data;
+    match packed {
+        AnyPackedFrame::U8(f) => assert_eq!(f.unpack().data, expected),
+        AnyPackedFrame::U16(_) => unreachable!(),
+    }
+}
+
+const CAMERA_YAML: &str = "%YAML:1.0
+---
+calibration_time: \"Mon Jan 1 00:00:00 2019\"
+image_width: 64
+image_height: 48
+camera_matrix: !!opencv-matrix
+   rows: 3
+   cols: 3
+   dt: d
+   data: [ 50., 0., 31.5, 0., 50., 23.5,
+       0., 0., 1. ]
+distortion_coefficients: !!opencv-matrix
+   rows: 1
+   cols: 5
+   dt: d
+   data: [ -0.2, 0.05, 0.001, -0.002, 0. ]
+";
+
+#[test]
+fn test_undistort() {
+    let model: CameraModel = CAMERA_YAML.parse().unwrap();
+    assert_eq!(model, CameraModel {
+        width: 64, height: 48, fx: 50.0, fy: 50.0, cx: 31.5, cy: 23.5,
+        k: [-0.2, 0.05, 0.0, 0.0, 0.0, 0.0], p: [0.001, -0.002],
+    });
+    assert!(CAMERA_YAML.replace("0.001, -0.002, 0.", "0.001")
+        .parse::<CameraModel>().is_err());
+    assert!(CAMERA_YAML.replace("image_width: 64\n", "")
+        .parse::<CameraModel>().is_err());
+
+    // principal point stays in place, corners move towards it
+    assert_eq!(model.distort(31.5, 23.5), (31.5, 23.5));
+    let (u, v) = model.distort(0.0, 0.0);
+    assert!(u > 0.0 && v > 0.0);
+
+    // without distortion remapping does not change the image
+    let ideal = CameraModel { k: [0.0; 6], p: [0.0; 2], ..model };
+    let img: Vec<u16> = (0..3*32*24).map(|i| (i*37 % 65536) as u16).collect();
+    let res = RemapTable::new(&ideal, 32, 24).remap(&img, 3);
+    assert_eq!(&res[..], &img[..]);
+
+    // horizontal gradient gets interpolated linearly
+    let img: Vec<u8> = (0..64*48).map(|i| (i % 64) as u8).collect();
+    let res = RemapTable::new(&model, 64, 48).remap(&img, 1);
+    for y in 0..48 {
+        for x in 0..64 {
+            let (u, v) = model.distort(x as f64, y as f64);
+            let expected = if u < 0.0 || v < 0.0 || u > 63.0 || v > 47.0 {
+                0
+            } else {
+                (u + 0.5) as u8
+            };
+            assert!((res[64*y + x] as i32 - expected as i32).abs() <= 1);
+        }
+    }
+}
+
+const STEREO_YAML: &str = "%YAML:1.0
+M1: !!opencv-matrix
+   rows: 3
+   cols: 3
+   dt: d
+   data: [ 52., 0., 30.5, 0., 51., 24.5, 0., 0., 1. ]
+D1: !!opencv-matrix
+   rows: 1
+   cols: 5
+   dt: d
+   data: [ -0.1, 0.02, 0.001, 0., 0. ]
+M2: !!opencv-matrix
+   rows: 3
+   cols: 3
+   dt: d
+   data: [ 50., 0., 32.5, 0., 50., 22.5, 0., 0., 1. ]
+D2: !!opencv-matrix
+   rows: 1
+   cols: 8
+   dt: d
+   data: [ -0.15, 0.03, 0., -0.001, 0., 0.01, 0., 0. ]
+%YAML:1.0
+---
+R: !!opencv-matrix
+   rows: 3
+   cols: 3
+   dt: d
+   data: [ 0.9998000066665778, 0., 0.019998666693333080, 0., 1., 0.,
+       -0.019998666693333080, 0., 0.9998000066665778 ]
+T: !!opencv-matrix
+   rows: 3
+   cols: 1
+   dt: d
+   data: [ -0.1, 0.004, 0.002 ]
+";
+
+#[test]
+fn test_rectify() {
+    let model: StereoModel = STEREO_YAML.parse().unwrap();
+    assert_eq!((model.left.width, model.right.fx), (0, 50.0));
+    assert_eq!(model.right.k, [-0.15, 0.03, 0.0, 0.01, 0.0, 0.0]);
+    assert!(STEREO_YAML.replace("T:", "X:").parse::<StereoModel>().is_err());
+    let model = model.with_default_size(64, 48);
+    assert_eq!((model.left.width, model.right.height), (64, 48));
+
+    let rect = Rectification::new(&model);
+    let project = |camera: &CameraModel, p: [f64; 3]| {
+        camera.distort_normalized(p[0]/p[2], p[1]/p[2])
+    };
+    for &(x, y, z) in [(0.0, 0.0, 1.0), (0.3, -0.2, 2.0), (-0.5, 0.4, 1.5),
+        (0.1, 0.3, 0.8)].iter()
+    {
+        let r = model.rotation;
+        let t = model.translation;
+        let pr = [
+            r[0][0]*x + r[0][1]*y + r[0][2]*z + t[0],
+            r[1][0]*x + r[1][1]*y + r[1][2]*z + t[1],
+            r[2][0]*x + r[2][1]*y + r[2][2]*z + t[2],
+        ];
+        let (ul, vl) = project(&model.left, [x, y, z]);
+        let (ur, vr) = project(&model.right, pr);
+
+        // epipolar lines are horizontal and disparity is positive
+        let (ul2, vl2) = rect.rectify_point(StereoCamera::Left, ul, vl);
+        let (ur2, vr2) = rect.rectify_point(StereoCamera::Right, ur, vr);
+        assert!((vl2 - vr2).abs() < 1e-6, "{} {}", vl2, vr2);
+        assert!(ul2 > ur2);
+
+        // mapping back gives the source point
+        let (u, v) = rect.source_point(StereoCamera::Right, ur2, vr2);
+        assert!((u - ur).abs() < 1e-6 && (v - vr).abs() < 1e-6);
+    }
+
+    // image centre stays close to the centre of the rectified image
+    let (u, v) = rect.rectify_point(StereoCamera::Left, 31.5, 23.5);
+    assert!((u - 31.5).abs() < 3.0 && (v - 23.5).abs() < 3.0);
+    let table = rect.table(StereoCamera::Left, 32, 24);
+    assert_eq!(table.size(), (32, 24));
+}
+
+#[test]
+fn test_equalize() {
+    // dark ramp gets spread over the full range
+    let mut img: Vec<u8> = (0..64*4).map(|i| (i % 64) as u8).collect();
+    Equalization::global().apply(&mut img, 64, 4, 1);
+    assert_eq!((img[0], img[63]), (2, 253));
+    assert!(img[..64].windows(2).all(|w| w[0] < w[1]));
+
+    // channels are scaled by the same factor
+    let mut img: Vec<u16> = vec![1000, 1200, 800, 4000, 4800, 3200];
+    Equalization::global().apply(&mut img, 2, 1, 3);
+    assert!(img[1] > 1200 && img[4] > 4800);
+    for p in img.chunks(3) {
+        let (r, g, b) = (p[0] as f32, p[1] as f32, p[2] as f32);
+        assert!((r/g - 0.833).abs() < 0.01 && (b/g - 0.667).abs() < 0.01);
+    }
+
+    // tiny and empty images are supported
+    let mut img = [100u8];
+    Equalization::clahe(8, 2.0).apply(&mut img, 1, 1, 1);
+    assert_eq!(img, [128]);
+    Equalization::global().apply(&mut [0u8; 0], 0, 0, 3);
+
+    // CLAHE adapts to the local brightness, while the clip limit prevents
+    // amplification of the flat regions
+    let mut img: Vec<u8> = (0..64*32)
+        .map(|i| if i % 64 < 32 { 20 + (i % 2) as u8 } else { 200 })
+        .collect();
+    let mut global = img.clone();
+    Equalization::global().apply(&mut global, 64, 32, 1);
+    Equalization::clahe(16, 2.0).apply(&mut img, 64, 32, 1);
+    let diff = |img: &[u8]| img[1] as i32 - img[0] as i32;
+    assert!(diff(&img) > 0 && diff(&img) < diff(&global));
+    assert!(img[40] > img[0]);
+}
+
+#[test]
+fn test_resample() {
+    use ResampleFilter::{Bilinear, Bicubic, Lanczos};
+
+    assert_eq!("1280x720".parse(), Ok(ResampleSize::Size(1280, 720)));
+    assert_eq!("0.5".parse(), Ok(ResampleSize::Scale(0.5)));
+    assert!("0x720".parse::<ResampleSize>().is_err());
+    assert!("-1".parse::<ResampleSize>().is_err());
+    assert_eq!(ResampleSize::Scale(0.4).dimensions(2448, 2048), (979, 819));
+
+    let img: Vec<u16> = (0..3*40*30).map(|i| (i*37 % 65536) as u16).collect();
+    for &filter in [Bilinear, Bicubic, Lanczos].iter() {
+        // same size does not change the image
+        let res = resample(&img, 40, 30, 3, 40, 30, filter);
+        assert_eq!(&res[..], &img[..]);
+
+        // constant image stays constant for any size
+        let flat = vec![200u8; 40*30];
+        for &(w, h) in [(17, 9), (64, 48), (1, 1), (40, 7)].iter() {
+            let res = resample(&flat, 40, 30, 1, w, h, filter);
+            assert_eq!(res.len(), w*h);
+            assert!(res.iter().all(|&v| v == 200));
+        }
+    }
+
+    // downscaling averages pixels
+    let ramp: Vec<u8> = (0..8).map(|i| 10*i as u8).collect();
+    let res = resample(&ramp, 8, 1, 1, 4, 1, Bilinear);
+    assert_eq!(&res[..], &[7, 25, 45, 63]);
+}
+
+#[test]
+fn test_transform() {
+    let rect = CropRect { x: 2, y: 4, width: 6, height: 8 };
+    assert_eq!("2,4,6,8".parse(), Ok(rect));
+    assert!("2,4,0,8".parse::<CropRect>().is_err());
+    assert!("2,4,6".parse::<CropRect>().is_err());
+    assert!(!"1,4,6,8".parse::<CropRect>().unwrap().is_cfa_aligned());
+    assert_eq!("270".parse(), Ok(Rotation::Cw270));
+    assert!("45".parse::<Rotation>().is_err());
+
+    // 3x2 image with 2 channels
+    let img = [1, 10, 2, 20, 3, 30, 4, 40, 5, 50, 6, 60];
+    let rect = CropRect { x: 1, y: 0, width: 2, height: 2 };
+    assert!(!rect.fits(2, 2));
+    assert_eq!(&crop(&img, 3, 2, rect)[..], &[2, 20, 3, 30, 5, 50, 6, 60]);
+
+    let (res, w, h) = rotate(&img, 3, 2, 2, Rotation::Cw90);
+    assert_eq!((w, h), (2, 3));
+    assert_eq!(&res[..], &[4, 40, 1, 10, 5, 50, 2, 20, 6, 60, 3, 30]);
+    let (res, ..) = rotate(&img, 3, 2, 2, Rotation::Cw270);
+    assert_eq!(&res[..], &[3, 30, 6, 60, 2, 20, 5, 50, 1, 10, 4, 40]);
+
+    // rotation by 180 degrees is the same as both flips
+    let (res, ..) = rotate(&img, 3, 2, 2, Rotation::Cw180);
+    let mut flipped = img;
+    flip(&mut flipped, 3, 2, true, true);
+    assert_eq!(&res[..], &flipped[..]);
+    let mut flipped = img;
+    flip(&mut flipped, 3, 2, false, true);
+    assert_eq!(flipped, [4, 40, 5, 50, 6, 60, 1, 10, 2, 20, 3, 30]);
+
+    // raw crop keeps CFA pattern of the packed frame
+    let geom = Geometry::new(8, 6, CfaPattern::Grbg);
+    let raw = RawFrame { geometry: geom, data: test_image(geom).into() };
+    let rect = CropRect { x: 2, y: 2, width: 4, height: 2 };
+    let packed = raw.pack_flipped();
+    let res = packed.crop(rect).unpack();
+    assert_eq!(res.geometry, Geometry::new(4, 2, packed.geometry.cfa));
+    let full = packed.unpack();
+    assert_eq!(&res.data[..], &crop(&full.data, 8, 1, rect)[..]);
+}
+
+#[test]
+fn test_denoise() {
+    let geom = Geometry::new(4, 2, CfaPattern::Rggb);
+    let frame = |vals: [u16; 8]| AnyPackedFrame::U16(RawFrame {
+        geometry: geom, data: vals.to_vec().into(),
+    }.pack_flipped());
+    let unpack = |f: &AnyPackedFrame| match f {
+        AnyPackedFrame::U16(f) => f.unpack().data,
+        AnyPackedFrame::U8(_) => unreachable!(),
+    };
+    let denoise = TemporalDenoise { radius: 1, threshold: 0.05 };
+
+    // noise gets averaged, while the moving sample (the last one) is kept
+    let center = frame([1000, 2000, 3000, 4000, 100, 200, 300, 10000]);
+    let prev = frame([1100, 2100, 2900, 3950, 0, 200, 300, 60000]);
+    let next = frame([1000, 2300, 3100, 4000, 200, 500, 300, 20000]);
+    let res = denoise.apply(&center, &[&prev, &next]);
+    let expected = frame([1033, 2133, 3000, 3983, 100, 300, 300, 10000]);
+    assert_eq!(unpack(&res), unpack(&expected));
+
+    // neighbours with different geometry or bit depth are ignored
+    let other = AnyPackedFrame::U8(RawFrame {
+        geometry: geom, data: vec![0u8; 8].into(),
+    }.pack_flipped());
+    let res = denoise.apply(&center, &[&other]);
+    assert_eq!(unpack(&res), unpack(&center));
+}
+
+/// Entries of the first TIFF IFD: tag, type, count and value (or offset)
+fn tiff_entries(buf: &[u8]) -> Vec<(u16, u16, u32, u32)> {
+    assert_eq!(&buf[..4], b"II\x2A\x00");
+    let ifd = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+    ifd_entries(buf, ifd as usize)
+}
+
+/// Entries of the TIFF IFD at the given offset
+fn ifd_entries(buf: &[u8], ifd: usize) -> Vec<(u16, u16, u32, u32)> {
+    let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+    let u32_at = |i: usize| {
+        u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]])
+    };
+    (0..u16_at(ifd) as usize)
+        .map(|i| ifd + 2 + 12*i)
+        .map(|e| (u16_at(e), u16_at(e + 2), u32_at(e + 4), u32_at(e + 8)))
+        .collect()
+}
+
+/// Decoder of TIFF LZW strips
+fn lzw_decode(data: &[u8]) -> Vec<u8> {
+    let mut table: Vec<Vec<u8>> = Vec::new();
+    let reset = |table: &mut Vec<Vec<u8>>| {
+        *table = (0..256).map(|b| vec![b as u8]).collect();
+        table.extend_from_slice(&[vec![], vec![]]);
+    };
+    let (mut pos, mut len, mut res) = (0, 9, Vec::new());
+    let mut prev: Option<Vec<u8>> = None;
+    reset(&mut table);
+    loop {
+        let mut code = 0;
+        for _ in 0..len {
+            let bit = (data[pos/8] >> (7 - pos % 8)) & 1;
+            code = (code << 1) | bit as usize;
+            pos += 1;
+        }
+        match code {
+            256 => { reset(&mut table); prev = None; len = 9; continue; },
+            257 => return res,
+            _ => (),
+        }
+        let entry = match (table.get(code), &prev) {
+            (Some(e), _) => e.clone(),
+            (None, Some(p)) => [&p[..], &p[..1]].concat(),
+            (None, None) => panic!("invalid LZW code"),
+        };
+        if let Some(p) = prev {
+            table.push([&p[..], &entry[..1]].concat());
+        }
+        res.extend_from_slice(&entry);
+        prev = Some(entry);
+        // early change: width grows one code before the table is full
+        len = match table.len() {
+            n if n >= 2047 => 12,
+            n if n >= 1023 => 11,
+            n if n >= 511 => 10,
+            _ => 9,
+        };
+    }
+}
+
+#[test]
+fn test_tiff_encoder() {
+    assert_eq!("lzw".parse(), Ok(TiffCompression::Lzw));
+    assert!("jpeg".parse::<TiffCompression>().is_err());
+
+    let (width, height) = (300, 200);
+    let img: Vec<u16> = (0..3*width*height)
+        .map(|i| ((i % 900)*70 + (i/900)*3) as u16)
+        .collect();
+    let date = Tag::new(306, TagValue::Ascii("2019:01:02 03:04:05".into()));
+    let mut buf = Vec::new();
+    let compression = TiffCompression::None;
+    encode_tiff(&img, width, height, 3, compression, &[date], &mut buf)
+        .unwrap();
+    let entries = tiff_entries(&buf);
+    let tag = |id| *entries.iter().find(|e| e.0 == id).unwrap();
+    assert!(entries.windows(2).all(|w| w[0].0 < w[1].0));
+    assert_eq!(tag(256), (256, 4, 1, 300));
+    assert_eq!(tag(257), (257, 4, 1, 200));
+    assert_eq!(tag(258).2, 3);
+    assert_eq!(tag(262).3, 2);
+    let (_, _, count, offset) = tag(306);
+    let offset = offset as usize;
+    assert_eq!(&buf[offset..offset + count as usize], b"2019:01:02 03:04:05\0");
+
+    // uncompressed strips are stored contiguously
+    let rows = tag(278).3 as usize;
+    let strips = tag(273).2 as usize;
+    assert!(strips > 1);
+    assert_eq!(strips, height.div_ceil(rows));
+    let offsets = tag(273).3 as usize;
+    let start = u32::from_le_bytes([
+        buf[offsets], buf[offsets + 1], buf[offsets + 2], buf[offsets + 3],
+    ]) as usize;
+    let data: Vec<u8> = img.iter().flat_map(|v| v.to_le_bytes()).collect();
+    assert_eq!(&buf[start..start + data.len()], &data[..]);
+
+    // LZW strips decode into rows with horizontal differencing, noisy
+    // image makes the encoder reset the code table several times
+    let gray: Vec<u8> = (0..width*height)
+        .map(|i| ((i*i) % 251) as u8)
+        .collect();
+    let mut buf = Vec::new();
+    let compression = TiffCompression::Lzw;
+    encode_tiff(&gray, width, height, 1, compression, &[], &mut buf).unwrap();
+    let entries = tiff_entries(&buf);
+    let tag = |id| *entries.iter().find(|e| e.0 == id).unwrap();
+    assert_eq!(tag(259).3, 5);
+    assert_eq!(tag(317).3, 2);
+    // single strip, so offset and byte count are stored in the entries
+    assert_eq!(tag(273).2, 1);
+    let (start, len) = (tag(273).3 as usize, tag(279).3 as usize);
+    let mut res = lzw_decode(&buf[start..start + len]);
+    for row in res.chunks_exact_mut(width) {
+        for i in 1..width {
+            row[i] = row[i].wrapping_add(row[i - 1]);
+        }
+    }
+    assert_eq!(res, gray);
+}
+
+#[test]
+fn test_dng_encoder() {
+    let geom = Geometry::new(8, 6, CfaPattern::Grbg);
+    let data: Vec<u16> = (0..geom.pixels()).map(|n| (n*1000) as u16).collect();
+    let frame = RawFrame { geometry: geom, data: data.clone().into() };
+    let mut meta = DngMetadata {
+        camera: "test".to_string(),
+        black_level: 256,
+        color_matrix: ColorMatrix::default(),
+        gains: WbGains { red: 2.0, blue: 1.25 },
+        tags: vec![Tag::new(34665, TagValue::Ifd(vec![
+            Tag::new(36867, TagValue::Ascii("2019:01:02 03:04:05".into())),
+        ]))],
+    };
+    let mut buf = Vec::new();
+    encode_dng(&frame, &meta, &mut buf).unwrap();
+    let entries = tiff_entries(&buf);
+    let tag = |id| *entries.iter().find(|e| e.0 == id).unwrap();
+    assert_eq!(tag(262).3, 32803);
+    assert_eq!(tag(277).3, 1);
+    assert_eq!(tag(33421), (33421, 3, 2, 2 | 2 << 16));
+    // R, G, B pattern values in the row-major order
+    assert_eq!(tag(33422), (33422, 1, 4, u32::from_le_bytes([1, 0, 2, 1])));
+    assert_eq!(tag(50714).3, 256);
+    assert_eq!(tag(50717).3, 0xFFFF);
+    assert_eq!(tag(50721).2, 9);
+    let offset = tag(50728).3 as usize;
+    let neutral: Vec<u32> = buf[offset..offset + 24].chunks(4)
+        .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect();
+    assert_eq!(neutral, [5000, 10000, 10000, 10000, 8000, 10000]);
+    let start = tag(273).3 as usize;
+    let bytes: Vec<u8> = data.iter().flat_map(|v| v.to_le_bytes()).collect();
+    assert_eq!(&buf[start..start + bytes.len()], &bytes[..]);
+    // capture time in the EXIF IFD
+    let (_, field_type, count, exif) = tag(34665);
+    assert_eq!((field_type, count), (4, 1));
+    let exif = ifd_entries(&buf, exif as usize);
+    assert_eq!(exif.len(), 1);
+    let (id, field_type, count, offset) = exif[0];
+    assert_eq!((id, field_type, count), (36867, 2, 20));
+    let offset = offset as usize;
+    assert_eq!(&buf[offset..offset + 20], b"2019:01:02 03:04:05\0");
+
+    meta.black_level = 0xFFFF;
+    assert!(encode_dng(&frame, &meta, &mut Vec::new()).is_err());
+}
+
+/// Decode WebP image into samples with the given number of channels
+fn decode_webp(buf: &[u8], w: usize, h: usize, channels: usize) -> Vec<u8> {
+    let mut decoder = image_webp::WebPDecoder::new(io::Cursor::new(buf))
+        .unwrap();
+    assert_eq!(decoder.dimensions(), (w as u32, h as u32));
+    assert!(!decoder.has_alpha());
+    let mut rgb = vec![0u8; decoder.output_buffer_size().unwrap()];
+    decoder.read_image(&mut rgb).unwrap();
+    assert_eq!(rgb.len(), 3*w*h);
+    if channels == 3 { return rgb; }
+    // gray images are decoded with equal channels
+    rgb.chunks(3)
+        .map(|p| {
+            let avg = (p[0] as u32 + p[1] as u32 + p[2] as u32 + 1)/3;
+            avg as u8
+        })
+        .collect()
+}
+
+#[test]
+fn test_webp_encoder() {
+    let (width, height) = (300, 200);
+    let img: Vec<u8> = (0..3*width*height)
+        .map(|i| ((i % 900)/7 + (i/900)*3) as u8)
+        .collect();
+    let u32_at = |buf: &[u8], i: usize| {
+        u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]])
+    };
+    let encode = |data: &[u8], channels, mode| {
+        let mut buf = Vec::new();
+        encode_webp(data, width, height, channels, mode, &mut buf).unwrap();
+        assert_eq!(&buf[..4], b"RIFF");
+        assert_eq!(u32_at(&buf, 4) as usize, buf.len() - 8);
+        assert_eq!(&buf[8..12], b"WEBP");
+        let chunk_size = u32_at(&buf, 16) as usize;
+        assert_eq!(buf.len(), 20 + chunk_size + (chunk_size & 1));
+        buf
+    };
+
+    // VP8L header keeps dimensions minus one in 14-bit fields
+    let buf = encode(&img, 3, WebpMode::Lossless);
+    assert_eq!(&buf[12..16], b"VP8L");
+    assert_eq!(buf[20], 0x2f);
+    let bits = u32_at(&buf, 21);
+    assert_eq!(bits & 0x3fff, width as u32 - 1);
+    assert_eq!((bits >> 14) & 0x3fff, height as u32 - 1);
+    assert!(buf.len() < img.len()/4);
+    let mut png_buf = Vec::new();
+    {
+        let (w, h) = (width as u32, height as u32);
+        let mut encoder = png::Encoder::new(&mut png_buf, w, h);
+        encoder.set(png::ColorType::RGB).set(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&img).unwrap();
+    }
+    assert!(buf.len() < png_buf.len(), "{} {}", buf.len(), png_buf.len());
+
+    // VP8 key frame header, start code and dimensions
+    let gray: Vec<u8> = img.iter().step_by(3).cloned().collect();
+    let buf = encode(&gray, 1, WebpMode::Lossy(75));
+    assert_eq!(&buf[12..16], b"VP8 ");
+    assert_eq!(buf[20] & 1, 0);
+    assert_eq!(&buf[23..26], &[0x9d, 0x01, 0x2a]);
+    assert_eq!(u16::from_le_bytes([buf[26], buf[27]]) as usize, width);
+    assert_eq!(u16::from_le_bytes([buf[28], buf[29]]) as usize, height);
+    assert!(buf.len() < encode(&gray, 1, WebpMode::Lossy(100)).len());
+
+    // decoded lossless images match the input exactly, lossy ones are
+    // close to it
+    let sizes = [(1, 1), (3, 5), (17, 9), (64, 33), (300, 200)];
+    for &(w, h) in sizes.iter() {
+        for &channels in [1, 3].iter() {
+            let img: Vec<u8> = (0..channels*w*h)
+                .map(|i| {
+                    let (x, y) = ((i / channels) % w, (i / channels) / w);
+                    // smooth gradient with mild noise, which doesn't
+                    // suffer from the chroma subsampling of lossy images
+                    let noise = i*7919 % 9;
+                    (100*x/w + 60*y/h + 40*(i % channels) + noise) as u8
+                })
+                .collect();
+            let msg = format!("{}x{} {}", w, h, channels);
+            let mut buf = Vec::new();
+            encode_webp(&img, w, h, channels, WebpMode::Lossless, &mut buf)
+                .unwrap();
+            assert_eq!(decode_webp(&buf, w, h, channels), img, "{}", msg);
+            for &(q, min_psnr) in [(50, 30.0), (90, 35.0)].iter() {
+                let mut buf = Vec::new();
+                let mode = WebpMode::Lossy(q);
+                encode_webp(&img, w, h, channels, mode, &mut buf).unwrap();
+                let res = decode_webp(&buf, w, h, channels);
+                let mse = img.iter().zip(res.iter())
+                    .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+                    .sum::<f64>()/img.len() as f64;
+                let psnr = 10.0*(255.0*255.0/mse.max(1e-10)).log10();
+                assert!(psnr > min_psnr, "{} q{}: {:.1} dB", msg, q, psnr);
+            }
+        }
+    }
+
+    let wide = vec![0u8; MAX_DIMENSION + 1];
+    let res = encode_webp(
+        &wide, MAX_DIMENSION + 1, 1, 1, WebpMode::Lossless, &mut Vec::new(),
+    );
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_y4m_encoder() {
+    let ts: Vec<u64> = (0..31).map(|i| 1_000 + i*33_300).collect();
+    let rate = FrameRate::from_timestamps(&ts).unwrap();
+    assert_eq!(rate, FrameRate { num: 3003, den: 100 });
+    let ts: Vec<u64> = (0..5).map(|i| i*40_000).collect();
+    let rate = FrameRate::from_timestamps(&ts).unwrap();
+    assert_eq!(rate, FrameRate { num: 25, den: 1 });
+    assert_eq!(FrameRate::from_timestamps(&[5, 5]), None);
+    assert_eq!(FrameRate::from_timestamps(&[]), None);
+
+    // white, black and red pixels in the BT.601 limited range
+    let rgb = [255, 255, 255, 0, 0, 0, 255, 0, 0];
+    assert_eq!(
+        y4m_planes(&rgb, 3), [235, 16, 82, 128, 128, 90, 128, 128, 240],
+    );
+    assert_eq!(y4m_planes(&rgb, 1), rgb);
+
+    let mut writer = Y4mWriter::new(Vec::new(), 3, 1, 3, rate).unwrap();
+    writer.write_frame(&y4m_planes(&rgb, 3)).unwrap();
+    assert!(writer.write_frame(&rgb[..3]).is_err());
+    let buf = writer.finish().unwrap();
+    let header = b"YUV4MPEG2 W3 H1 F25:1 Ip A1:1 C444\nFRAME\n";
+    assert_eq!(&buf[..header.len()], &header[..]);
+    assert_eq!(buf.len(), header.len() + 9);
+
+    let buf = Y4mWriter::new(Vec::new(), 2, 2, 1, rate).unwrap()
+        .finish().unwrap();
+    assert_eq!(&buf[..], &b"YUV4MPEG2 W2 H2 F25:1 Ip A1:1 Cmono\n"[..]);
+    assert!(Y4mWriter::new(Vec::new(), 0, 2, 1, rate).is_err());
+}
+
+fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]])
+}
+
+/// Positions of all occurrences of the chunk ID
+fn find_id(buf: &[u8], id: &[u8; 4]) -> Vec<usize> {
+    buf.windows(4).enumerate()
+        .filter(|(_, w)| w == id)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// File which keeps only short writes, so large videos can be tested
+#[derive(Default)]
+struct SparseFile {
+    pos: u64,
+    len: u64,
+    chunks: Vec<(u64, Vec<u8>)>,
+}
+
+impl io::Write for SparseFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() <= 1 << 16 {
+            self.chunks.push((self.pos, buf.to_vec()));
+        }
+        self.pos += buf.len() as u64;
+        self.len = self.len.max(self.pos);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+impl io::Seek for SparseFile {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            io::SeekFrom::Start(pos) => pos,
+            io::SeekFrom::Current(0) => self.pos,
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported, "only absolute seeks are supported",
+            ))?,
+        };
+        Ok(self.pos)
+    }
+}
+
+impl SparseFile {
+    /// Little-endian value at the given position, the latest write wins
+    fn u32_at(&self, pos: u64) -> u32 {
+        let mut res = [0u8; 4];
+        for (i, v) in res.iter_mut().enumerate() {
+            let p = pos + i as u64;
+            *v = self.chunks.iter().rev()
+                .find(|(s, d)| p >= *s && p < *s + d.len() as u64)
+                .map(|(s, d)| d[(p - s) as usize])
+                .expect("byte isn't stored");
+        }
+        u32::from_le_bytes(res)
+    }
+
+    /// Positions of the chunk ID in the stored writes
+    fn find_id(&self, id: &[u8; 4]) -> Vec<u64> {
+        let mut res: Vec<u64> = self.chunks.iter()
+            .flat_map(|(s, d)| find_id(d, id).into_iter()
+                .map(move |i| s + i as u64))
+            .collect();
+        res.sort();
+        res.dedup();
+        res
+    }
+}
+
+#[test]
+fn test_avi_encoder() {
+    let rate = FrameRate { num: 25, den: 1 };
+    let frames: [&[u8]; 3] = [b"\xFF\xD8abc\xFF\xD9", b"\xFF\xD8", b"x"];
+    let mut writer = AviWriter::new(io::Cursor::new(Vec::new()), 4, 2, rate)
+        .unwrap();
+    for frame in frames.iter() {
+        writer.write_frame(frame).unwrap();
+    }
+    let buf = writer.finish().unwrap().into_inner();
+    assert_eq!(&buf[..4], b"RIFF");
+    assert_eq!(&buf[8..12], b"AVI ");
+    assert_eq!(u32_at(&buf, 4) as usize, buf.len() - 8);
+    let avih = find_id(&buf, b"avih")[0];
+    assert_eq!(u32_at(&buf, avih + 8), 40_000);
+    assert_eq!(u32_at(&buf, avih + 8 + 16), 3);
+    assert_eq!((u32_at(&buf, avih + 40), u32_at(&buf, avih + 44)), (4, 2));
+    let strh = find_id(&buf, b"strh")[0];
+    assert_eq!(&buf[strh + 8..strh + 16], b"vidsMJPG");
+    let dmlh = find_id(&buf, b"dmlh")[0];
+    assert_eq!(u32_at(&buf, dmlh + 8), 3);
+
+    // frames are padded to even size and indexed by both indexes
+    let movi = find_id(&buf, b"movi")[0];
+    let chunks = find_id(&buf, b"00dc");
+    let idx1 = find_id(&buf, b"idx1")[0];
+    assert_eq!(u32_at(&buf, idx1 + 4), 48);
+    let ix00 = *find_id(&buf, b"ix00").last().unwrap();
+    assert_eq!(u32_at(&buf, ix00 + 12), 3);
+    assert_eq!(u32_at(&buf, ix00 + 20) as usize, movi);
+    let mut pos = movi + 4;
+    for (i, frame) in frames.iter().enumerate() {
+        assert!(chunks.contains(&pos));
+        assert_eq!(u32_at(&buf, pos + 4) as usize, frame.len());
+        assert_eq!(&buf[pos + 8..pos + 8 + frame.len()], *frame);
+        let entry = idx1 + 8 + 16*i;
+        assert_eq!(&buf[entry..entry + 4], b"00dc");
+        assert_eq!(u32_at(&buf, entry + 4), 0x10);
+        assert_eq!(u32_at(&buf, entry + 8) as usize, pos - movi);
+        let entry = ix00 + 32 + 8*i;
+        assert_eq!(u32_at(&buf, entry) as usize, pos + 8 - movi);
+        assert_eq!(u32_at(&buf, entry + 4) as usize, frame.len());
+        pos += 8 + frame.len() + frame.len() % 2;
+    }
+    assert_eq!(pos, ix00);
+    let indx = find_id(&buf, b"indx")[0];
+    assert_eq!(u32_at(&buf, indx + 12), 1);
+    assert_eq!(u32_at(&buf, indx + 32) as usize, ix00);
+    assert_eq!(u32_at(&buf, indx + 44), 3);
+
+    // videos larger than 1 GiB are split into RIFF chunks
+    let frame = vec![0u8; (64 << 20) + 1];
+    let mut writer = AviWriter::new(SparseFile::default(), 4, 2, rate)
+        .unwrap();
+    for _ in 0..17 {
+        writer.write_frame(&frame).unwrap();
+    }
+    let file = writer.finish().unwrap();
+    let riffs = file.find_id(b"RIFF");
+    assert_eq!(riffs.len(), 2);
+    assert_eq!(file.find_id(b"AVIX"), [riffs[1] + 8]);
+    assert_eq!(riffs[1] as u32, file.u32_at(4) + 8);
+    assert_eq!(file.len, riffs[1] + file.u32_at(riffs[1] + 4) as u64 + 8);
+    let avih = file.find_id(b"avih")[0];
+    assert_eq!(file.u32_at(avih + 8 + 16), 15);
+    let dmlh = file.find_id(b"dmlh")[0];
+    assert_eq!(file.u32_at(dmlh + 8), 17);
+    let indx = file.find_id(b"indx")[0];
+    assert_eq!(file.u32_at(indx + 12), 2);
+    assert_eq!(file.u32_at(indx + 24 + 8 + 12), 15);
+    assert_eq!(file.u32_at(indx + 24 + 24 + 12), 2);
+    let ix00 = file.find_id(b"ix00");
+    assert_eq!(ix00.len(), 2);
+    assert_eq!(file.u32_at(ix00[1] + 12), 2);
+}
+
+#[test]
+fn test_error_kinds() {
+    let kind = |data: &[u8]| parse_pnm(data).unwrap_err().kind();
+    assert_eq!(kind(b"P6\n2 2\n255\n\x00\x00\x00"), ErrorKind::TruncatedData);
+    assert_eq!(kind(b"P9\n2 2\n255\n\x00\x00"), ErrorKind::InvalidHeader);
+    assert_eq!(kind(b"P5\nx 2\n255\n\x00\x00"), ErrorKind::InvalidHeader);
+    assert_eq!(kind(b"P5\n0 2\n255\n"), ErrorKind::InvalidDimensions);
+
+    let err = decode_flif_packed(b"FLIF garbage", CfaPattern::Bggr)
+        .unwrap_err();
+    assert!(err.is_invalid_frame());
+
+    let geom = Geometry::new(8, 8, CfaPattern::Bggr);
+    let frame = PackedFrame { geometry: geom, data: vec![7u8; 64].into() };
+    let mut buf = Vec::new();
+    encode_flif_packed(&frame, &mut buf).unwrap();
+    // truncated FLIF header is a frame error and not a fatal I/O error
+    for &n in [0, 4, 8].iter() {
+        let err = decode_flif_packed(&buf[..n], geom.cfa).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TruncatedData);
+        assert!(err.is_invalid_frame());
+    }
+
+}
+
+#[test]
+fn test_decode_packed() {
+    // 12-bit raw PNM frame recorded with RGGB sensor
+    let geom = Geometry::new(6, 4, CfaPattern::Rggb);
+    let samples: Vec<u16> = (0..geom.pixels())
+        .map(|n| (n as u16 * 173) & 0x0FFF)
+        .collect();
+    let mut data = b"P5\n6 4\n4095\n".to_vec();
+    for s in samples.iter() {
+        data.extend_from_slice(&s.to_be_bytes());
+    }
+
+    let raw: Vec<u16> = samples.iter().map(|s| s << 4).collect();
+    let mut packed = vec![0u16; geom.pixels()];
+    raw2rgba_flip(&raw, &mut packed, geom);
+
+    let cfa = geom.cfa.flipped();
+    match decode_packed(&data, true, cfa).unwrap() {
+        AnyPackedFrame::U16(f) => {
+            assert_eq!(f.geometry, geom.flipped());
+            assert_eq!(&f.data[..], &packed[..]);
+        },
+        AnyPackedFrame::U8(_) => panic!("expected 16-bit frame"),
+    }
+    // the same data is not a valid FLIF image
+    let err = decode_packed(&data, false, cfa).unwrap_err();
+    assert!(err.is_invalid_frame());
+}
+
+#[test]
+fn test_flif_encoder() {
+    // xorshift generator for reproducible noise
+    let mut state = 0x1234_5678u32;
+    let mut rand = move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state
+    };
+    for &(w, h) in [(2, 2), (4, 6), (8, 4), (6, 10), (128, 96)].iter() {
+        let geom = Geometry::new(w, h, CfaPattern::Bggr);
+        for &noise in [0u32, 8, 256].iter() {
+            let data: Vec<u8> = (0..geom.pixels())
+                .map(|n| {
+                    let (x, y) = ((n / 4) % (w / 2), (n / 4) / (w / 2));
+                    let v = (2*x + 3*y + n % 4) as u32 + rand() % (noise + 1);
+                    v as u8
+                })
+                .collect();
+            let frame = PackedFrame { geometry: geom, data: data.into() };
+            let mut buf = Vec::new();
+            encode_flif_packed(&frame, &mut buf).unwrap();
+            match decode_flif_packed(&buf, geom.cfa).unwrap() {
+                AnyPackedFrame::U8(res) => {
+                    assert_eq!(res.geometry, geom);
+                    assert_eq!(res.data, frame.data, "{}x{} {}", w, h, noise);
+                },
+                _ => panic!("unexpected bit depth"),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_simd() {
+    let mut state = 0x9E37_79B9u32;
+    let mut rand = move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as u8
+    };
+    // widths cover frames without full SIMD chunks and with scalar tails
+    let sizes = [(4, 4), (34, 6), (66, 4), (96, 8), (130, 6), (2448, 4)];
+    let levels = SimdLevel::supported();
+    for &(w, h) in sizes.iter() {
+        let data: Vec<u8> = (0..w*h).map(|_| rand()).collect();
+        for &cfa in PATTERNS.iter() {
+            let geom = Geometry::new(w, h, cfa);
+            let scalar = SimdLevel::Scalar;
+            let mut rgba = vec![0u8; w*h];
+            let mut raw = vec![0u8; w*h];
+            simd::raw2rgba_flip(scalar, &data, &mut rgba, geom);
+            simd::rgba2raw(scalar, &data, &mut raw, geom);
+            let mut flipped = data.clone();
+            simd::raw_flip(scalar, &mut flipped, geom);
+            let rgb = simd::bggr_bayer(scalar, &data, w, h);
+
+            for &level in levels.iter() {
+                let msg = format!("{:?} {}x{} {:?}", level, w, h, cfa);
+                let mut buf = vec![0u8; w*h];
+                simd::raw2rgba_flip(level, &data, &mut buf, geom);
+                assert_eq!(buf, rgba, "raw2rgba_flip {}", msg);
+                simd::rgba2raw(level, &data, &mut buf, geom);
+                assert_eq!(buf, raw, "rgba2raw {}", msg);
+                let mut buf = data.clone();
+                simd::raw_flip(level, &mut buf, geom);
+                assert_eq!(buf, flipped, "raw_flip {}", msg);
+                let res = simd::bggr_bayer(level, &data, w, h);
+                assert!(res == rgb, "bggr_bayer {}", msg);
+            }
+        }
+    }
 }
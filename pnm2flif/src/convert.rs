@@ -6,21 +6,46 @@ use std::process::{Command, Stdio};
 use indicatif::{ProgressBar, ProgressStyle, ParallelProgressIterator};
 use rayon::iter::{ParallelIterator, IntoParallelRefIterator};
 
-use oscar_utils::{PBAR_TEMPLATE, WIDTH, HEIGHT};
+use oscar_utils::{
+    PBAR_TEMPLATE, CfaPattern, AnyRawFrame, RawFrame, PackedFrame, Sample,
+    Error, ErrorStats,
+};
 use oscar_utils::load_frames::load_raw_pnm;
 use oscar_utils::conversions::raw2rgba_flip;
+use oscar_utils::flif_encoder::encode_flif_packed;
 
-use super::PAM_HEADER;
+use super::pam_header;
 
-fn convert_pnm2flif(src_path: &Path, dst_path: &Path) -> io::Result<()> {
-    let src = load_raw_pnm(src_path)?;
-    let mut rgba_buf = vec![0u8; WIDTH*HEIGHT];
-    raw2rgba_flip(&src, &mut rgba_buf);
+/// Write frame packed into RGBA to the PAM file
+fn write_pam<T: Sample>(src: &RawFrame<T>, mut file: impl Write) -> io::Result<()> {
+    let mut rgba_buf = vec![T::default(); src.geometry.pixels()];
+    raw2rgba_flip(&src.data, &mut rgba_buf, src.geometry);
+    file.write_all(pam_header::<T>(src.geometry).as_bytes())?;
+    file.write_all(&T::encode_be(&rgba_buf))?;
+    file.flush()
+}
+
+/// Encode frame with the built-in FLIF encoder
+fn encode_native(src: &RawFrame<u8>, dst_path: &Path) -> io::Result<()> {
+    let mut rgba_buf = vec![0u8; src.geometry.pixels()];
+    raw2rgba_flip(&src.data, &mut rgba_buf, src.geometry);
+    let frame = PackedFrame {
+        geometry: src.geometry.flipped(),
+        data: rgba_buf.into_boxed_slice(),
+    };
+    let file = io::BufWriter::new(fs::File::create(dst_path)?);
+    encode_flif_packed(&frame, file)
+}
 
+/// Encode frame with the external `flif` tool
+fn encode_tool(
+    src: &AnyRawFrame, src_path: &Path, dst_path: &Path,
+) -> io::Result<()> {
     let mut file = tempfile::NamedTempFile::new()?;
-    file.write_all(PAM_HEADER)?;
-    file.write_all(&rgba_buf)?;
-    file.flush()?;
+    match src {
+        AnyRawFrame::U8(src) => write_pam(src, &mut file)?,
+        AnyRawFrame::U16(src) => write_pam(src, &mut file)?,
+    }
 
     let status = Command::new("flif")
         .arg("-eKNBC")
@@ -32,7 +57,9 @@ fn convert_pnm2flif(src_path: &Path, dst_path: &Path) -> io::Result<()> {
         .stderr(Stdio::null())
         .stdout(Stdio::null())
         .status()
-        .expect("failed to execute process");
+        .map_err(|err| io::Error::new(
+            err.kind(), format!("failed to execute flif: {}", err),
+        ))?;
     if !status.success() {
         let err_msg = format!("flif failure: {}", src_path.display());
         Err(io::Error::new(io::ErrorKind::Other, err_msg))?;
@@ -41,6 +68,21 @@ fn convert_pnm2flif(src_path: &Path, dst_path: &Path) -> io::Result<()> {
     Ok(())
 }
 
+fn convert_pnm2flif(
+    src_path: &Path, dst_path: &Path, cfa: CfaPattern, flif_tool: bool,
+) -> Result<(), Error> {
+    let src = load_raw_pnm(src_path, cfa)?;
+    let res = match src {
+        _ if flif_tool => encode_tool(&src, src_path, dst_path),
+        AnyRawFrame::U8(src) => encode_native(&src, dst_path),
+        AnyRawFrame::U16(_) => Err(Error::UnsupportedChannels(
+            "built-in encoder supports only 8-bit frames, 16-bit frames \
+            require --flif_tool".to_string(),
+        ))?,
+    };
+    res.map_err(|err| Error::from(err).with_path(dst_path))
+}
+
 pub(crate) fn convert(args: crate::Cli) -> io::Result<()> {
     println!("Conversion: {} {}",
         args.pnm_dir.display(), args.flif_dir.display());
@@ -60,13 +102,21 @@ pub(crate) fn convert(args: crate::Cli) -> io::Result<()> {
         tasks.push((src_path, dst_path));
     }
 
+    let stats = ErrorStats::new();
     let bar = ProgressBar::new(tasks.len() as u64);
     bar.set_style(ProgressStyle::default_bar().template(PBAR_TEMPLATE));
     tasks.par_iter()
         .progress_with(bar)
-        .try_for_each(|(src_path, dst_path)| {
-            convert_pnm2flif(src_path, dst_path)
-        })?;
+        .for_each(|(src_path, dst_path)| {
+            if stats.is_aborted() { return; }
+            let res = convert_pnm2flif(
+                src_path, dst_path, args.cfa, args.flif_tool,
+            );
+            if let Err(err) = res {
+                stats.report(&src_path.display(), err);
+            }
+        });
 
+    stats.finish()?;
     Ok(())
 }
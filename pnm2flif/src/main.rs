@@ -1,18 +1,20 @@
 use std::{io, path::PathBuf};
 use structopt::StructOpt;
+use oscar_utils::{CfaPattern, Geometry, Sample};
 
 mod verify;
 mod convert;
 
-const PAM_HEADER: &[u8] = b"\
-    P7\n\
-    WIDTH 1224\n\
-    HEIGHT 1024\n\
-    DEPTH 4\n\
-    MAXVAL 255\n\
-    TUPLTYPE RGB_ALPHA\n\
-    ENDHDR\n\
-";
+/// Header of the RGBA PAM image which packs raw frame with the given geometry
+/// and samples of type `T`
+fn pam_header<T: Sample>(geom: Geometry) -> String {
+    let (width, height) = geom.packed();
+    format!(
+        "P7\nWIDTH {}\nHEIGHT {}\nDEPTH 4\nMAXVAL {}\n\
+        TUPLTYPE RGB_ALPHA\nENDHDR\n",
+        width, height, T::MAX,
+    )
+}
 
 #[derive(StructOpt)]
 #[structopt(
@@ -23,6 +25,14 @@ struct Cli {
     /// RGBA FLIF frames
     #[structopt(long = "verify")]
     pub verify: bool,
+    /// CFA pattern of the sensor used for recording of the raw PNM frames.
+    /// Supported patterns: rggb, bggr, grbg, gbrg.
+    #[structopt(long = "cfa", default_value = "rggb", parse(try_from_str))]
+    cfa: CfaPattern,
+    /// Encode frames with the external flif tool instead of the built-in
+    /// encoder (required for 16-bit frames)
+    #[structopt(long = "flif_tool")]
+    flif_tool: bool,
     /// Path to the raw PNM frames directory
     #[structopt(parse(from_os_str))]
     pnm_dir: PathBuf,
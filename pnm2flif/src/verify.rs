@@ -5,11 +5,13 @@ use std::io;
 use indicatif::{ProgressBar, ProgressStyle, ParallelProgressIterator};
 use rayon::iter::{ParallelIterator, IntoParallelRefIterator};
 
-use oscar_utils::{PBAR_TEMPLATE, WIDTH, HEIGHT};
+use oscar_utils::{
+    PBAR_TEMPLATE, CfaPattern, Geometry, AnyRawFrame, RawFrame, Sample,
+};
 use oscar_utils::conversions::{raw_flip, rgba2raw};
-use oscar_utils::load_frames::{load_raw_pnm, load_flif};
-
-use super::PAM_HEADER;
+use oscar_utils::load_frames::{
+    load_raw_pnm, load_flif, parse_pnm, PnmHeader, PnmFormat,
+};
 
 fn get_filenames(dir: &Path, ext: &str) -> io::Result<Vec<String>> {
     let ext = std::ffi::OsStr::new(ext);
@@ -31,7 +33,9 @@ fn get_filenames(dir: &Path, ext: &str) -> io::Result<Vec<String>> {
 }
 
 
-fn cpp_flif_load(path: &Path) -> io::Result<Box<[u8]>> {
+fn cpp_flif_load<T: Sample>(
+    path: &Path, geom: Geometry,
+) -> io::Result<Box<[T]>> {
     let f = tempfile::Builder::new()
         .suffix(".pam")
         .tempfile()?;
@@ -44,19 +48,29 @@ fn cpp_flif_load(path: &Path) -> io::Result<Box<[u8]>> {
         .stderr(Stdio::null())
         .stdout(Stdio::null())
         .status()
-        .expect("failed to execute process");
+        .map_err(|err| io::Error::new(
+            err.kind(), format!("failed to execute flif: {}", err),
+        ))?;
     if !status.success() {
         let err_msg = format!("flif failure: {}", path.display());
         Err(io::Error::new(io::ErrorKind::Other, err_msg))?;
     }
     let mmap = unsafe { memmap::Mmap::map(f.as_file())? };
-    let (header, image) = mmap.split_at(PAM_HEADER.len());
-    if header != PAM_HEADER || image.len() != WIDTH*HEIGHT {
+    let (header, image) = parse_pnm(&mmap)?;
+    let (width, height) = geom.packed();
+    let expected = PnmHeader {
+        format: PnmFormat::Pam,
+        width, height,
+        depth: 4,
+        maxval: T::MAX,
+        tupltype: Some("RGB_ALPHA".to_string()),
+    };
+    if header != expected {
         Err(io::Error::new(io::ErrorKind::Other,
-            "invalid PAM frame".to_string()))?;
+            format!("unexpected PAM frame: {:?}", header)))?;
     }
-    let mut raw = vec![0u8; WIDTH*HEIGHT];
-    rgba2raw(image, &mut raw);
+    let mut raw = vec![T::default(); geom.pixels()];
+    rgba2raw(&T::decode_be(image), &mut raw, geom);
     Ok(raw.into_boxed_slice())
 }
 
@@ -68,20 +82,48 @@ struct CompResult {
     rs: bool,
 }
 
-fn compare(fname: &str, flif_dir: &Path, pnm_dir: &Path) -> io::Result<CompResult> {
-    let flif_path = flif_dir.join(&fname).with_extension("flif");
-    let pnm_path = pnm_dir.join(&fname).with_extension("pnm");
+/// Compare PNM frame with the frame decoded by Rust library (`None` if its
+/// sample type differs) and by flif CLI tool
+fn compare_frames<T: Sample>(
+    pnm_frame: &RawFrame<T>, rs_flif_frame: Option<RawFrame<T>>,
+    flif_path: &Path,
+) -> io::Result<CompResult> {
+    let geom = pnm_frame.geometry.flipped();
+    let rs = match rs_flif_frame {
+        Some(mut frame) if frame.geometry == geom => {
+            raw_flip(&mut frame.data, geom);
+            &pnm_frame.data[..] == &frame.data[..]
+        },
+        _ => false,
+    };
 
-    let pnm_frame = load_raw_pnm(&pnm_path)?;
-    let mut rs_flif_frame = load_flif(&flif_path)?;
-    raw_flip(&mut rs_flif_frame);
+    let mut cpp_flif_frame = cpp_flif_load::<T>(flif_path, geom)?;
+    raw_flip(&mut cpp_flif_frame, geom);
 
-    let mut cpp_flif_frame = cpp_flif_load(&flif_path)?;
-    raw_flip(&mut cpp_flif_frame);
+    Ok(CompResult {
+        cpp: &pnm_frame.data[..] == &cpp_flif_frame[..],
+        rs,
+    })
+}
+
+fn compare(
+    fname: &str, flif_dir: &Path, pnm_dir: &Path, cfa: CfaPattern,
+) -> io::Result<CompResult> {
+    let flif_path = flif_dir.join(&fname).with_extension("flif");
+    let pnm_path = pnm_dir.join(&fname).with_extension("pnm");
 
-    let res = CompResult {
-        cpp: &pnm_frame[..] == &cpp_flif_frame[..],
-        rs: &pnm_frame[..] == &rs_flif_frame[..],
+    let pnm_frame = load_raw_pnm(&pnm_path, cfa)?;
+    // FLIF frames store flipped image
+    let rs_cfa = pnm_frame.geometry().cfa.flipped();
+    let rs_flif_frame = load_flif(&flif_path, rs_cfa)?;
+
+    let res = match (pnm_frame, rs_flif_frame) {
+        (AnyRawFrame::U8(pnm), AnyRawFrame::U8(rs)) =>
+            compare_frames(&pnm, Some(rs), &flif_path)?,
+        (AnyRawFrame::U16(pnm), AnyRawFrame::U16(rs)) =>
+            compare_frames(&pnm, Some(rs), &flif_path)?,
+        (AnyRawFrame::U8(pnm), _) => compare_frames(&pnm, None, &flif_path)?,
+        (AnyRawFrame::U16(pnm), _) => compare_frames(&pnm, None, &flif_path)?,
     };
     if !res.cpp || !res.rs {
         println!("{} {:?}\n", fname, res);
@@ -105,7 +147,7 @@ pub(crate) fn verify(args: crate::Cli) -> io::Result<()> {
 
     let res: Vec<(CompResult, String)> = fnames.par_iter()
         .progress_with(bar)
-        .map(|fname| compare(fname, &args.flif_dir, &args.pnm_dir))
+        .map(|fname| compare(fname, &args.flif_dir, &args.pnm_dir, args.cfa))
         .collect::<io::Result<Vec<CompResult>>>()?
         .iter()
         .cloned()